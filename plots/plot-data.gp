@@ -1,8 +0,0 @@
-set term qt size 1200,900
-
-plot \
-    ARG1 title ARG1 noenhanced with lines, \
-    ARG2 title ARG2 noenhanced with lines, \
-    ARG3 title ARG3 noenhanced with lines, \
-
-pause -1
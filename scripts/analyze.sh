@@ -1,4 +1,5 @@
 #!/bin/sh
 
+# analyze.sh <input> [output-dir] [svg|png]
 cargo build --release --bin analysis
-./target/release/analysis $1 /tmp/filtered-1.dat /tmp/filtered-2.dat /tmp/fft.dat /tmp/filtered-fft-1.dat /tmp/filtered-fft-2.dat
+./target/release/analysis --output-dir="${2:-.}" --format="${3:-svg}" "$1"
@@ -0,0 +1,13 @@
+#!/bin/sh
+
+# bench.sh [baseline]
+# Compares the resampler and pipeline speed to a baseline saved on this machine,
+# saving one on the first run. Exits with 1 on a regression, for use before merging.
+BASELINE="${1:-/tmp/asrc-bench-baseline.txt}"
+cargo build --release --bin asrc-bench || exit
+if [ -f "$BASELINE" ]; then
+    ./target/release/asrc-bench --baseline="$BASELINE"
+else
+    echo "No baseline yet, saving one to $BASELINE"
+    ./target/release/asrc-bench --save="$BASELINE"
+fi
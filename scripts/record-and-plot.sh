@@ -1,24 +1,24 @@
 #!/bin/sh
 
+# record-and-plot.sh <card> <period-size> <periods> [output-dir]
 CARD=$1
 FRAME_SIZE=$2
 FRAMES=$3
 
 CAPTURE_OUT="/tmp/data-$CARD-$FRAME_SIZE-$FRAMES".dat
 
-cargo build --release || exit
+cargo build --release --bin alsa-period-timings --bin analysis || exit
 
-./target/release/alsa-capture-playback \
+./target/release/alsa-period-timings capture \
 	--capture-device="$CARD" \
 	--capture-period-size="$FRAME_SIZE" \
 	--capture-periods="$FRAMES" \
+	--sample-rate=48000 \
 	--channels=2 > "$CAPTURE_OUT"
 
-./target/release/analysis "$CAPTURE_OUT" \
-    "/tmp/filtered-1.dat" \
-    "/tmp/filtered-2.dat" \
-    "/tmp/fft.dat" \
-    "/tmp/filtered-fft-1.dat" \
-    "/tmp/filtered-fft-2.dat"
-
-./scripts/plot-filtered-fft.sh
+./target/release/analysis \
+    --sample-rate=48000 \
+    --period-size="$FRAME_SIZE" \
+    --periods="$FRAMES" \
+    --output-dir="${4:-.}" \
+    "$CAPTURE_OUT"
@@ -0,0 +1,441 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use backend::{self, Capture, Config, Timestamp};
+use drift::DriftController;
+use fifo::{self, FifoReader, Overflow, Underflow};
+use member::{MemberStatus, SharedStats};
+use realtime_priority::RtConfig;
+use resampler::{self, Quality, Resampler};
+use rt_check::{self, Section};
+
+// Several capture devices presented as one multichannel capture device.
+// The clock master is read directly by the caller, every other member is read
+// by its own thread into a FIFO and resampled into the master clock domain,
+// each with its own drift controller.
+
+#[derive(Debug, Clone)]
+pub struct AggregateConfig {
+    // index of the clock master in the device list
+    pub master: usize,
+    // output channels as (device, channel), empty for every channel of every device in order
+    pub map: Vec<(usize, usize)>,
+    pub quality: Quality,
+    // largest drift correction in parts per million
+    pub max_ppm: f64,
+    pub rt: RtConfig,
+}
+
+// Parses a channel map such as 0:0,0:1,1:0 of device:channel pairs.
+pub fn parse_map(map: &str) -> Result<Vec<(usize, usize)>, String> {
+    map.split(',').map(|pair| {
+        let mut parts = pair.trim().splitn(2, ':');
+        let device = parts.next().and_then(|d| d.parse().ok());
+        let channel = parts.next().and_then(|c| c.parse().ok());
+        match (device, channel) {
+            (Some(device), Some(channel)) => Ok((device, channel)),
+            _ => Err(format!("Invalid channel map entry: {}", pair)),
+        }
+    }).collect()
+}
+
+struct Member {
+    device: String,
+    capture: Option<Box<dyn Capture>>,
+    channels: usize,
+    rate: f64,
+    // files are read as fast as the master asks for them, without drift control
+    lossless: bool,
+    reader: FifoReader,
+    writer: Option<fifo::FifoWriter>,
+    resampler: Box<dyn Resampler>,
+    drift: DriftController,
+    primed: bool,
+    fifo_events: u64,
+    in_buf: Vec<f32>,
+    in_pos: usize,
+    in_len: usize,
+    // one master period of resampled frames
+    out_buf: Vec<f32>,
+    done: Arc<AtomicBool>,
+    shared: Arc<SharedStats>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub struct AggregateCapture {
+    master: Box<dyn Capture>,
+    master_index: usize,
+    master_buf: Vec<f32>,
+    // members by device index, None at the master's place
+    members: Vec<Option<Member>>,
+    map: Vec<(usize, usize)>,
+    config: Config,
+    rt: RtConfig,
+    stop: Arc<AtomicBool>,
+}
+
+impl AggregateCapture {
+    // captures are the opened devices in command line order, devices their names
+    pub fn new(mut captures: Vec<Box<dyn Capture>>, devices: &[String], config: AggregateConfig)
+               -> backend::Result<AggregateCapture> {
+        if devices.len() != captures.len() {
+            return Err(backend::Error::Device("one name per capture device expected".to_string()));
+        }
+        if config.master >= captures.len() {
+            return Err(backend::Error::Device(format!("no capture device {} to use as clock master", config.master)));
+        }
+
+        let map = if config.map.is_empty() {
+            captures.iter().enumerate()
+                .flat_map(|(d, c)| (0..c.config().channels).map(move |ch| (d, ch)))
+                .collect()
+        } else {
+            config.map.clone()
+        };
+        for &(device, channel) in &map {
+            if device >= captures.len() || channel >= captures[device].config().channels {
+                return Err(backend::Error::Device(format!("channel map entry {}:{} does not exist", device, channel)));
+            }
+        }
+
+        let master = captures.remove(config.master);
+        let master_config = master.config().clone();
+        let master_rate = master_config.rate as f64;
+        let period = master_config.period_size;
+
+        let mut members = Vec::new();
+        let mut rest = captures.into_iter();
+        for (index, device) in devices.iter().enumerate() {
+            if index == config.master {
+                members.push(None);
+                continue;
+            }
+            let capture = rest.next().unwrap();
+            let member_config = capture.config().clone();
+            let channels = member_config.channels;
+            let nominal = master_rate / member_config.rate as f64;
+            let lossless = !master.clocked() || !capture.clocked();
+
+            let target = 3 * member_config.period_size.max((period as f64 / nominal).ceil() as usize);
+            let (writer, reader) = fifo::fifo(channels, 4 * target, target,
+                                              Overflow::DropOldest, Underflow::Silence);
+
+            members.push(Some(Member {
+                device: device.clone(),
+                capture: Some(capture),
+                channels,
+                rate: member_config.rate as f64,
+                lossless,
+                reader,
+                writer: Some(writer),
+                resampler: resampler::new(channels, config.quality, nominal),
+                drift: DriftController::new(nominal, member_config.rate as f64, config.max_ppm),
+                primed: false,
+                fifo_events: 0,
+                in_buf: vec![0.0; member_config.period_size * channels],
+                in_pos: 0,
+                in_len: 0,
+                out_buf: vec![0.0; period * channels],
+                done: Arc::new(AtomicBool::new(false)),
+                shared: Arc::new(SharedStats::new(nominal)),
+                thread: None,
+            }));
+        }
+
+        Ok(AggregateCapture {
+            master,
+            master_index: config.master,
+            master_buf: vec![0.0; period * master_config.channels],
+            members,
+            config: Config { channels: map.len(), ..master_config },
+            map,
+            rt: config.rt,
+            stop: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn status(&self) -> MemberStatus {
+        MemberStatus::new(self.members.iter().filter_map(|m| m.as_ref())
+            .map(|m| (m.device.clone(), m.shared.clone()))
+            .collect())
+    }
+}
+
+impl Capture for AggregateCapture {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn start(&mut self) -> backend::Result<()> {
+        for member in self.members.iter_mut().filter_map(|m| m.as_mut()) {
+            let mut reader = MemberReader {
+                device: member.device.clone(),
+                capture: member.capture.take().unwrap(),
+                writer: member.writer.take().unwrap(),
+                lossless: member.lossless,
+                rt: self.rt.clone(),
+                stop: self.stop.clone(),
+                done: member.done.clone(),
+                shared: member.shared.clone(),
+            };
+            reader.capture.start()?;
+            member.thread = Some(thread::spawn(move || reader.run()));
+        }
+        self.master.start()
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> backend::Result<usize> {
+        let frames = {
+            let len = (buf.len() / self.config.channels).min(self.config.period_size);
+            let master_channels = self.master.config().channels;
+            self.master.read(&mut self.master_buf[..len * master_channels])?
+        };
+        let dt = frames as f64 / self.config.rate as f64;
+
+        let mut section = rt_check::section();
+        for member in self.members.iter_mut().filter_map(|m| m.as_mut()) {
+            member.resample(frames, dt, &mut section);
+        }
+
+        let master_channels = self.master.config().channels;
+        let out_channels = self.config.channels;
+        for (out, &(device, channel)) in self.map.iter().enumerate() {
+            let (source, channels) = if device == self.master_index {
+                (&self.master_buf, master_channels)
+            } else {
+                let member = self.members[device].as_ref().unwrap();
+                (&member.out_buf, member.channels)
+            };
+            for i in 0..frames {
+                buf[i * out_channels + out] = source[i * channels + channel];
+            }
+        }
+        Ok(frames)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        self.master.timestamp()
+    }
+
+    fn clocked(&self) -> bool {
+        self.master.clocked()
+    }
+}
+
+impl Drop for AggregateCapture {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for member in self.members.iter_mut().filter_map(|m| m.as_mut()) {
+            if let Some(thread) = member.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+impl Member {
+    // fills out_buf with frames frames in the master clock domain
+    fn resample(&mut self, frames: usize, dt: f64, section: &mut Section) {
+        let ch = self.channels;
+        let mut produced = 0;
+        while produced < frames {
+            if self.in_pos == self.in_len {
+                self.in_pos = 0;
+                let n = if self.lossless {
+                    self.reader.read(&mut self.in_buf)
+                } else {
+                    self.reader.read_exact(&mut self.in_buf);
+                    self.in_buf.len() / ch
+                };
+                self.in_len = n * ch;
+
+                if n == 0 {
+                    // a finished member is silent from then on
+                    if self.done.load(Ordering::SeqCst) && self.reader.fill() == 0 {
+                        for x in self.out_buf[produced * ch..frames * ch].iter_mut() {
+                            *x = 0.0;
+                        }
+                        break;
+                    }
+                    section.suspend(|| thread::sleep(Duration::from_millis(1)));
+                }
+                continue;
+            }
+
+            let (consumed, n) = self.resampler.process(&self.in_buf[self.in_pos..self.in_len],
+                                                       &mut self.out_buf[produced * ch..frames * ch]);
+            self.in_pos += consumed * ch;
+            produced += n;
+        }
+
+        let stats = self.reader.stats();
+        if stats.overflows + stats.underflows != self.fifo_events {
+            self.fifo_events = stats.overflows + stats.underflows;
+            self.drift.restart();
+        }
+        if self.reader.priming() {
+            self.primed = false;
+        } else if !self.lossless {
+            if !self.primed {
+                self.drift.relock();
+                self.primed = true;
+            }
+            let fill = self.reader.continuous_fill(self.rate) + ((self.in_len - self.in_pos) / ch) as f64;
+            let ratio = self.drift.update(fill, dt);
+            self.resampler.set_ratio(ratio);
+        }
+
+        self.shared.update(self.drift.ratio(), self.drift.ppm(), &stats);
+    }
+}
+
+// Reads a member device into its FIFO on its own real-time thread.
+struct MemberReader {
+    device: String,
+    capture: Box<dyn Capture>,
+    writer: fifo::FifoWriter,
+    lossless: bool,
+    rt: RtConfig,
+    stop: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+    shared: Arc<SharedStats>,
+}
+
+impl MemberReader {
+    fn run(&mut self) {
+        let channels = self.capture.config().channels;
+        let mut buf = vec![0.0f32; self.capture.config().period_size * channels];
+        eprintln!("{} thread real-time: {}", self.device, self.rt.apply());
+
+        while !self.stop.load(Ordering::Relaxed) {
+            let result = self.capture.read(&mut buf);
+            let mut section = rt_check::section();
+            match result {
+                Ok(frames) => {
+                    if self.lossless {
+                        let mut queued = 0;
+                        while queued < frames && !self.stop.load(Ordering::Relaxed) {
+                            let n = (frames - queued).min(self.writer.free());
+                            self.writer.write(&buf[queued * channels..(queued + n) * channels]);
+                            queued += n;
+                            if queued < frames {
+                                section.suspend(|| thread::sleep(Duration::from_millis(1)));
+                            }
+                        }
+                    } else {
+                        self.writer.write(&buf[..frames * channels]);
+                    }
+                }
+                Err(backend::Error::XRun) => {
+                    self.shared.xrun();
+                }
+                Err(backend::Error::EndOfStream) => break,
+                Err(e) => {
+                    eprintln!("Capture error on {}: {}", self.device, e);
+                    break;
+                }
+            }
+        }
+        self.done.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::{Engine, EngineConfig};
+    use engine::tests::{self as sim, captured_frames, clocked_capture, clocked_playback, engine_config,
+                        mean, sim_capture, sim_playback};
+    use interpolator::Kernel;
+    use resampler::SincQuality;
+    use rt_check;
+    use std::sync::Mutex;
+
+    fn aggregate(captures: Vec<Box<dyn Capture>>, map: Vec<(usize, usize)>, quality: Quality) -> AggregateCapture {
+        let devices: Vec<String> = (0..captures.len()).map(|i| format!("sim{}", i)).collect();
+        AggregateCapture::new(captures, &devices, AggregateConfig {
+            master: 0,
+            map,
+            quality,
+            max_ppm: 1000.0,
+            rt: sim::rt(),
+        }).unwrap()
+    }
+
+    // sign changes of one channel, twice the frequency per second
+    fn zero_crossings(buf: &[f32], channels: usize, channel: usize) -> usize {
+        let samples: Vec<f32> = buf.iter().skip(channel).step_by(channels).cloned().collect();
+        samples.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count()
+    }
+
+    #[test]
+    fn parses_map() {
+        assert_eq!(parse_map("0:0, 1:1,0:1"), Ok(vec![(0, 0), (1, 1), (0, 1)]));
+        assert!(parse_map("0:0,1").is_err());
+        assert!(parse_map("0:x").is_err());
+    }
+
+    #[test]
+    fn members_resample_in_real_time_sections() {
+        let violations = rt_check::check(|| {
+            let tone = Some(1000.0);
+            let aggregate = aggregate(vec![sim_capture(48000, 2, tone), sim_capture(44100, 1, tone),
+                                           sim_capture(96000, 2, tone)],
+                                      Vec::new(), Quality::Sinc(SincQuality::Medium));
+            assert_eq!(aggregate.config().channels, 5);
+
+            let engine = Engine::new(Box::new(aggregate), sim_playback(48000, 5),
+                                     engine_config(Quality::Sinc(SincQuality::Medium), 0.5));
+            let (stats, _) = sim::run(engine, 0.0, || ());
+            assert_eq!(stats.captured_frames, captured_frames(48000, 0.5));
+        });
+        assert!(violations.sections > 0);
+        assert_eq!(violations.total(), 0, "{:?}", violations);
+    }
+
+    #[test]
+    fn drifting_member_stays_aligned() {
+        let _clocked = sim::exclusive();
+        // a 500 Hz master and a 1 kHz member 200 ppm off, the member channels around the master one
+        let mut aggregate = aggregate(vec![clocked_capture(48000, 1, 0.0, Some(500.0)),
+                                           clocked_capture(44100, 2, 200.0, Some(1000.0))],
+                                      vec![(1, 0), (0, 0), (1, 1)], Quality::Interpolator(Kernel::Linear));
+        // the default 10 s time constant takes minutes to settle, and three
+        // periods of FIFO do not survive the hiccups of a busy test machine
+        for member in aggregate.members.iter_mut().filter_map(|m| m.as_mut()) {
+            member.drift.set_bandwidth(0.1);
+            let (writer, reader) = fifo::fifo(2, 4 * 2048, 2048, Overflow::DropOldest, Underflow::Silence);
+            member.writer = Some(writer);
+            member.reader = reader;
+        }
+        let status = aggregate.status();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let recorded = output.clone();
+        let engine = Engine::new(Box::new(aggregate), clocked_playback(48000, 3, 0.0), EngineConfig {
+            fifo_target: 2048,
+            ..engine_config(Quality::Interpolator(Kernel::Linear), 15.0)
+        });
+        let (stats, ppm) = sim::run_with(engine, move |input: &[f32], output: &mut [f32], frames: usize| {
+            output.copy_from_slice(input);
+            recorded.lock().unwrap().extend_from_slice(&input[..frames * 3]);
+        }, 3.0, || status.members()[0].1.ppm);
+        assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+
+        // locked onto the member clock, give or take the scheduling noise, nothing dropped or inserted
+        let member = &status.members()[0].1;
+        assert!((mean(&ppm) - 200.0).abs() < 100.0, "{:?}", ppm);
+        assert_eq!((member.fifo.overflows, member.fifo.underflows), (0, 0));
+
+        // both member channels carry the 1 kHz tone in step, the master its 500 Hz
+        let output = output.lock().unwrap();
+        // a second before the capture ended, away from the flushed tail
+        let last = &output[output.len() - 48000 * 3 * 2..output.len() - 48000 * 3];
+        assert!(last.chunks(3).all(|frame| frame[0] == frame[2]));
+        let (member, master) = (zero_crossings(last, 3, 0), zero_crossings(last, 3, 1));
+        assert!((member as i64 - 2000).abs() <= 2, "{} crossings", member);
+        assert!((master as i64 - 1000).abs() <= 2, "{} crossings", master);
+    }
+}
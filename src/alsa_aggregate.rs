@@ -0,0 +1,199 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod aggregate;
+mod backend;
+mod config;
+mod control;
+mod drift;
+mod engine;
+mod fifo;
+mod interpolator;
+mod member;
+mod metrics;
+mod polyphase;
+mod realtime_priority;
+mod record;
+mod resampler;
+mod rt_check;
+mod rtp;
+mod session;
+mod shutdown;
+mod simd;
+mod spsc;
+mod telemetry;
+mod wav;
+
+use std::process;
+
+use aggregate::{AggregateCapture, AggregateConfig};
+use backend::{Capture, Config};
+use engine::{Engine, EngineConfig};
+use fifo::{Overflow, Underflow};
+
+#[global_allocator]
+static ALLOCATOR: rt_check::CheckAllocator = rt_check::CheckAllocator;
+
+const USAGE: &str = "
+ALSA aggregate device
+
+Captures several cards as one multichannel input. The clock master is used as
+is, the other cards are resampled into its clock domain following their drift.
+The aggregate stream is then played through the asrc to the playback device.
+
+Usage:
+  alsa-aggregate [--config=<file>] [--playback-device=<alsa-device> --master=<index> --channel-map=<map> --channels=<nr> --format=<format> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --capture-sample-rate=<Hz> --playback-sample-rate=<Hz> --resampler=<quality> --max-ppm=<ppm> --record=<file> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock --telemetry-interval=<seconds> --telemetry-log=<file> --control-socket=<path> --metrics=<address> --duration=<seconds>] <capture-device>...
+  alsa-aggregate (-h | --help)
+
+Options:
+  -h --help                         Show this screen.
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
+  --playback-device=<alsa-device>   ALSA device, file:<path> or sim to playback to [default: default]
+  --master=<index>                  Capture device used as clock master, counted from 0 [default: 0]
+  --channel-map=<map>               Aggregate channels as device:channel pairs, e.g. 0:0,0:1,1:0, every channel of every device by default.
+  --channels=<nr>                   Channels to capture on each device [default: 2]
+  --format=<format>                 Sample format: s16, s24, s32 or f32 [default: s16]
+  --capture-period-size=<frames>    Size of capture frames [default: 256].
+  --capture-periods=<count>         Amount of recording periods [default: 2].
+  --playback-period-size=<frames>   Size of playback frames [default: 256].
+  --playback-periods=<count>        Amount of playback periods [default: 2].
+  --capture-sample-rate=<Hz>        Recording sample rate [default: 48000].
+  --playback-sample-rate=<Hz>       Playback sample rate [default: 48000].
+  --resampler=<quality>             Resampler quality: low, medium, high or best windowed sinc, or linear,
+                                    hermite, lagrange4, lagrange6 or farrow for low latency,
+                                    or rational for an exact nominal ratio, rational-fixed
+                                    for clocks locked together [default: high].
+  --max-ppm=<ppm>                   Largest clock drift correction [default: 1000].
+  --record=<file>                   Record the aggregate stream to a WAV or raw file.
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
+  --telemetry-interval=<seconds>    Seconds between status summaries [default: 1].
+  --telemetry-log=<file>            Write every telemetry record to a file.
+  --control-socket=<path>           Accept commands from asrc-ctl on a Unix socket.
+  --metrics=<address>               Serve Prometheus metrics on http://<address>/metrics, e.g. 127.0.0.1:9100.
+  --duration=<seconds>              Stop after this much captured audio, 0 runs forever [default: 0].
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_capture_device: Vec<String>,
+    flag_playback_device: String,
+    flag_master: usize,
+    flag_channel_map: Option<String>,
+    flag_channels: usize,
+    flag_format: String,
+    flag_capture_period_size: usize,
+    flag_capture_periods: u32,
+    flag_playback_period_size: usize,
+    flag_playback_periods: u32,
+    flag_capture_sample_rate: u32,
+    flag_playback_sample_rate: u32,
+    flag_resampler: String,
+    flag_max_ppm: f64,
+    flag_record: Option<String>,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
+    flag_telemetry_interval: f64,
+    flag_telemetry_log: Option<String>,
+    flag_control_socket: Option<String>,
+    flag_metrics: Option<String>,
+    flag_duration: f64,
+}
+
+fn main() {
+    let args: Args = session::args(USAGE);
+    let rt = session::rt_config(&args.flag_rt_policy, args.flag_rt_priority,
+                                &args.flag_rt_cpus, args.flag_rt_mlock);
+    let format = session::format(&args.flag_format);
+    let quality = session::quality(&args.flag_resampler);
+
+    let map = match args.flag_channel_map {
+        Some(ref map) => aggregate::parse_map(map).unwrap_or_else(|e| {
+            eprintln!("{}", config::error("channel-map", e));
+            process::exit(1);
+        }),
+        None => Vec::new(),
+    };
+
+    let captures: Vec<Box<dyn Capture>> = args.arg_capture_device.iter().enumerate().map(|(index, device)| {
+        let capture = backend::open_capture(device, Config {
+            channels: args.flag_channels,
+            rate: args.flag_capture_sample_rate,
+            format,
+            period_size: args.flag_capture_period_size,
+            periods: args.flag_capture_periods,
+        }).unwrap_or_else(|e| {
+            eprintln!("Cannot open {}: {}", device, e);
+            process::exit(1);
+        });
+        let title = if index == args.flag_master { "Capture (clock master)" } else { "Capture" };
+        session::print_device(title, device, capture.config());
+        capture
+    }).collect();
+
+    let capture = AggregateCapture::new(captures, &args.arg_capture_device, AggregateConfig {
+        master: args.flag_master,
+        map,
+        quality,
+        max_ppm: args.flag_max_ppm,
+        rt: rt.clone(),
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot aggregate the capture devices: {}", e);
+        process::exit(1);
+    });
+    let status = capture.status();
+    let capture_config = capture.config().clone();
+
+    let playback = backend::open_playback(&args.flag_playback_device, Config {
+        channels: capture_config.channels,
+        rate: args.flag_playback_sample_rate,
+        format,
+        period_size: args.flag_playback_period_size,
+        periods: args.flag_playback_periods,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_playback_device, e);
+        process::exit(1);
+    });
+    session::print_device("Playback", &args.flag_playback_device, playback.config());
+
+    let mut engine = Engine::new(Box::new(capture), playback, EngineConfig {
+        block_size: 0,
+        quality,
+        overflow: Overflow::DropOldest,
+        underflow: Underflow::Silence,
+        fifo_target: 0,
+        drift_control: true,
+        max_ppm: args.flag_max_ppm,
+        duration: args.flag_duration,
+        rt,
+    });
+
+    let recorder = session::record(&args.flag_record, &capture_config).map(|(tap, recorder)| {
+        engine.record_capture(tap);
+        recorder
+    });
+
+    let telemetry = session::telemetry(&mut engine, args.flag_telemetry_interval,
+                                       &args.flag_telemetry_log, &args.flag_metrics);
+
+    let _control = control::serve_option(&args.flag_control_socket, engine.control());
+
+    session::run(engine, |input: &[f32], output: &mut [f32], _frames: usize| {
+        output.copy_from_slice(input);
+    }, telemetry);
+    status.print();
+
+    if let Some(recorder) = recorder {
+        recorder.stop();
+    }
+}
@@ -1,37 +1,90 @@
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate toml;
 extern crate alsa;
-extern crate thread_priority;
-extern crate rb;
-
+extern crate libc;
+
+mod backend;
+mod config;
+mod control;
+mod drift;
+mod engine;
+mod fifo;
+mod interpolator;
+mod metrics;
+mod polyphase;
 mod realtime_priority;
-
-use docopt::Docopt;
-use alsa::{Direction, ValueOr};
-use alsa::pcm::{PCM, HwParams, Format, Access};
-use std::thread;
-use rb::*;
-
+mod record;
+mod resampler;
+mod routing;
+mod rt_check;
+mod rtp;
+mod session;
+mod shutdown;
+mod simd;
+mod spsc;
+mod telemetry;
+mod wav;
+
+use std::process;
+
+use backend::Config;
+use engine::{Engine, EngineConfig};
+use fifo::{Overflow, Underflow};
+use routing::Matrix;
+
+#[global_allocator]
+static ALLOCATOR: rt_check::CheckAllocator = rt_check::CheckAllocator;
 
 const USAGE: &str = "
 ALSA asrc loopback
 
 Usage:
-  alsa-asrc-loopback [--capture-device=<alsa-device> --playback-device=<alsa-device> --channels=<nr> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --capture-sample-rate=<Hz> --playback-sample-rate=<Hz>]
+  alsa-asrc-loopback [--config=<file>] [--capture-device=<alsa-device> --playback-device=<alsa-device> --channels=<nr> --capture-channels=<nr> --playback-channels=<nr> --route=<routes> --route-file=<file> --format=<format> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --capture-sample-rate=<Hz> --playback-sample-rate=<Hz> --resampler=<quality> --block-size=<frames> --max-ppm=<ppm> --fixed-ratio --overflow=<policy> --underflow=<policy> --fifo-target=<frames> --record-capture=<file> --record-playback=<file> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock --telemetry-interval=<seconds> --telemetry-log=<file> --control-socket=<path> --metrics=<address> --duration=<seconds> --rt-check]
   alsa-asrc-loopback (-h | --help)
 
 Options:
   -h --help                         Show this screen.
-  --capture-device=<alsa-device>    ALSA device to record from [default: default]
-  --playback-device=<alsa-device>   ALSA device to playback to [default: default]
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
+  --capture-device=<alsa-device>    ALSA device, file:<path> or sim to record from [default: default]
+  --playback-device=<alsa-device>   ALSA device, file:<path> or sim to playback to [default: default]
   --channels=<nr>                   Channels to capture and play [default: 2]
+  --capture-channels=<nr>           Channels to capture, --channels by default.
+  --playback-channels=<nr>          Channels to play, the captured channels by default.
+  --route=<routes>                  Capture to playback routes <in>:<out>[:<gain>], e.g. 0:0,1:1,2:0:-3dB.
+                                    Straight through, downmixed or upmixed by default.
+  --route-file=<file>               Read the routes from a file, one <in> <out> [<gain>] per line.
+  --format=<format>                 Sample format: s16, s24, s32 or f32 [default: s16]
   --capture-period-size=<frames>    Size of capture frames [default: 256].
   --capture-periods=<count>         Amount of recording periods [default: 2].
   --playback-period-size=<frames>   Size of playback frames [default: 256].
   --playback-periods=<count>        Amount of playback periods [default: 2].
   --capture-sample-rate=<Hz>        Recording sample rate [default: 44100].
   --playback-sample-rate=<Hz>       Playback sample rate [default: 48000].
+  --resampler=<quality>             Resampler quality: low, medium, high or best windowed sinc, or linear,
+                                    hermite, lagrange4, lagrange6 or farrow for low latency,
+                                    or rational for an exact nominal ratio, rational-fixed
+                                    for clocks locked together [default: high].
+  --block-size=<frames>             Processing block size, 0 for the playback period size [default: 0].
+  --max-ppm=<ppm>                   Largest clock drift correction [default: 1000].
+  --fixed-ratio                     Resample at the nominal ratio, without following the clock drift.
+  --overflow=<policy>               FIFO overflow policy: drop or stretch [default: drop].
+  --underflow=<policy>              FIFO underflow policy: silence or stretch [default: silence].
+  --fifo-target=<frames>            FIFO fill to keep in capture frames, 0 for three periods [default: 0].
+  --record-capture=<file>           Record the captured stream to a WAV or raw file.
+  --record-playback=<file>          Record the converted stream to a WAV or raw file.
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
+  --telemetry-interval=<seconds>    Seconds between status summaries [default: 1].
+  --telemetry-log=<file>            Write every telemetry record to a file.
+  --control-socket=<path>           Accept commands from asrc-ctl on a Unix socket.
+  --metrics=<address>               Serve Prometheus metrics on http://<address>/metrics, e.g. 127.0.0.1:9100.
+  --duration=<seconds>              Stop after this much captured audio, 0 runs forever [default: 0].
+  --rt-check                        Count allocations, blocking and syscalls in the audio loops, debug builds only.
 ";
 
 
@@ -39,121 +92,161 @@ Options:
 struct Args {
     flag_capture_device: String,
     flag_playback_device: String,
-    flag_channels: u32,
+    flag_channels: usize,
+    flag_capture_channels: Option<usize>,
+    flag_playback_channels: Option<usize>,
+    flag_route: Option<String>,
+    flag_route_file: Option<String>,
+    flag_format: String,
     flag_capture_period_size: usize,
     flag_capture_periods: u32,
     flag_playback_period_size: usize,
     flag_playback_periods: u32,
     flag_capture_sample_rate: u32,
     flag_playback_sample_rate: u32,
+    flag_resampler: String,
+    flag_block_size: usize,
+    flag_max_ppm: f64,
+    flag_fixed_ratio: bool,
+    flag_overflow: String,
+    flag_underflow: String,
+    flag_fifo_target: usize,
+    flag_record_capture: Option<String>,
+    flag_record_playback: Option<String>,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
+    flag_telemetry_interval: f64,
+    flag_telemetry_log: Option<String>,
+    flag_control_socket: Option<String>,
+    flag_metrics: Option<String>,
+    flag_duration: f64,
+    flag_rt_check: bool,
 }
 
 fn main() {
-    let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
-        .unwrap_or_else(|e| e.exit());
-
-    eprintln!("Capture\n  card:    {}\n  rate:    {}\n  period:  {}\n  periods: {}",
-              args.flag_capture_device,
-              args.flag_capture_sample_rate,
-              args.flag_capture_period_size,
-              args.flag_capture_periods);
-    eprintln!("Playback\n  card:    {}\n  rate:    {}\n  period:  {}\n  periods: {}",
-              args.flag_playback_device,
-              args.flag_playback_sample_rate,
-              args.flag_playback_period_size,
-              args.flag_playback_periods);
-
-    let pcm_capture =
-        setup_card(Direction::Capture,
-                   args.flag_capture_device,
-                   args.flag_channels,
-                   args.flag_capture_sample_rate,
-                   args.flag_capture_period_size,
-                   args.flag_capture_periods);
-
-    let pcm_playback =
-        setup_card(Direction::Playback,
-                   args.flag_playback_device,
-                   args.flag_channels,
-                   args.flag_playback_sample_rate,
-                   args.flag_playback_period_size,
-                   args.flag_playback_periods);
-
-    // create ring buffer
-    let rb = SpscRb::new(4096);
-    let (prod, cons) = (rb.producer(), rb.consumer());
-
-    // start capture thread
-    let capture_handle = thread::spawn(move || {
-        // make read buffer
-        let mut buf = vec![0; get_period_buffer_size(&pcm_capture)];
-        let io = pcm_capture.io_i16().unwrap();
-
-        // set capture thread to real-time priority
-        realtime_priority::get_realtime_priority();
-
-        loop {
-            io.readi(&mut buf).unwrap();
-            prod.write(&mut buf).unwrap();
-        }
+    let args: Args = session::args(USAGE);
+    let rt = session::rt_config(&args.flag_rt_policy, args.flag_rt_priority,
+                                &args.flag_rt_cpus, args.flag_rt_mlock);
+    let format = session::format(&args.flag_format);
+    let quality = session::quality(&args.flag_resampler);
+
+    let overflow = Overflow::from_name(&args.flag_overflow).unwrap_or_else(|| {
+        eprintln!("{}", config::error("overflow", format!("Invalid overflow policy: {}", args.flag_overflow)));
+        process::exit(1);
+    });
+
+    let underflow = Underflow::from_name(&args.flag_underflow).unwrap_or_else(|| {
+        eprintln!("{}", config::error("underflow", format!("Invalid underflow policy: {}", args.flag_underflow)));
+        process::exit(1);
     });
 
-    // start playback thread
-    let playback_handle = thread::spawn(move || {
-        let hwp = pcm_playback.hw_params_current().unwrap();
-        let swp = pcm_playback.sw_params_current().unwrap();
-        let start_threshold = hwp.get_buffer_size().unwrap() - hwp.get_period_size().unwrap();
-        eprintln!("Playback start threshold: {}", start_threshold);
-        swp.set_start_threshold(start_threshold).unwrap();
-        pcm_playback.sw_params(&swp).unwrap();
-
-        // make write buffer
-        let mut buf = vec![0; get_period_buffer_size(&pcm_playback)];
-        let io = pcm_playback.io_i16().unwrap();
-
-        // set playback thread to real-time priority
-        realtime_priority::get_realtime_priority();
-
-        loop {
-            let size = cons.read_blocking(&mut buf).unwrap();
-            let written = io.writei(&buf).unwrap();
-            eprintln!("playback written: {}", written);
+    let capture = backend::open_capture(&args.flag_capture_device, Config {
+        channels: args.flag_capture_channels.unwrap_or(args.flag_channels),
+        rate: args.flag_capture_sample_rate,
+        format,
+        period_size: args.flag_capture_period_size,
+        periods: args.flag_capture_periods,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_capture_device, e);
+        process::exit(1);
+    });
+    let capture_config = capture.config().clone();
+
+    // a capture file decides of the channel count
+    let playback = backend::open_playback(&args.flag_playback_device, Config {
+        channels: args.flag_playback_channels.unwrap_or(capture_config.channels),
+        rate: args.flag_playback_sample_rate,
+        format,
+        period_size: args.flag_playback_period_size,
+        periods: args.flag_playback_periods,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_playback_device, e);
+        process::exit(1);
+    });
+    let playback_config = playback.config().clone();
+
+    session::print_device("Capture", &args.flag_capture_device, &capture_config);
+    session::print_device("Playback", &args.flag_playback_device, &playback_config);
+
+    let matrix = match (&args.flag_route, &args.flag_route_file) {
+        (&Some(ref routes), _) => Matrix::parse(capture_config.channels, playback_config.channels, routes)
+            .map_err(|e| config::error("route", e)),
+        (_, &Some(ref path)) => Matrix::from_file(capture_config.channels, playback_config.channels, path)
+            .map_err(|e| config::error("route-file", e)),
+        _ => Matrix::default_for(capture_config.channels, playback_config.channels),
+    }.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if !matrix.is_identity() {
+        eprintln!("Routing {} to {} channels:", matrix.inputs(), matrix.outputs());
+        for route in matrix.routes() {
+            eprintln!("  {} -> {}  gain {:.3} ({:.1} dB)", route.input, route.output,
+                      route.gain, 20.0 * route.gain.abs().log10());
         }
+    }
+
+    let ratio = playback_config.rate as f64 / capture_config.rate as f64;
+    eprintln!("Resampler: {}, ratio: {}", quality.name(), ratio);
+
+    let mut engine = Engine::new(capture, playback, EngineConfig {
+        block_size: args.flag_block_size,
+        quality,
+        overflow,
+        underflow,
+        fifo_target: args.flag_fifo_target,
+        drift_control: !args.flag_fixed_ratio,
+        max_ppm: args.flag_max_ppm,
+        duration: args.flag_duration,
+        rt,
     });
 
-    capture_handle.join().unwrap();
-}
+    eprintln!("FIFO target: {} frames{}", engine.fifo_target(), if engine.lossless() { ", lossless" } else { "" });
+    eprintln!("Block size: {} frames", engine.block_size());
 
-fn setup_card(direction: Direction,
-              device: String,
-              channels: u32,
-              sample_rate: u32,
-              period_size: usize,
-              periods: u32) -> PCM {
-    let pcm = PCM::new(&device, direction, false).unwrap();
-    {
-        let hwp = HwParams::any(&pcm).unwrap();
-        hwp.set_channels(channels).unwrap();
-        hwp.set_rate(sample_rate, ValueOr::Nearest).unwrap();
-        hwp.set_format(Format::s16()).unwrap();
-        hwp.set_access(Access::RWInterleaved).unwrap();
-        #[cfg(target_pointer_width = "32")]
-            hwp.set_period_size(period_size as i32, ValueOr::Nearest).unwrap();
-        #[cfg(target_pointer_width = "64")]
-            hwp.set_period_size(period_size as i64, ValueOr::Nearest).unwrap();
-        hwp.set_periods(periods, ValueOr::Nearest).unwrap();
-        pcm.hw_params(&hwp).unwrap();
-        let hwp = pcm.hw_params_current().unwrap();
-        let period_size = hwp.get_period_size().unwrap() as usize;
-        let buffer_size = hwp.get_buffer_size().unwrap() as usize;
-        eprintln!("Card period size: {}, HW buffer size: {}", period_size, buffer_size);
+    if args.flag_rt_check {
+        rt_check::enable().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
     }
 
-    pcm
-}
+    let capture_recorder = session::record(&args.flag_record_capture, &capture_config).map(|(tap, recorder)| {
+        engine.record_capture(tap);
+        recorder
+    });
+    let playback_recorder = session::record(&args.flag_record_playback, &playback_config).map(|(tap, recorder)| {
+        engine.record_playback(tap);
+        recorder
+    });
+
+    let telemetry = session::telemetry(&mut engine, args.flag_telemetry_interval,
+                                       &args.flag_telemetry_log, &args.flag_metrics);
+
+    let identity = matrix.is_identity();
+    let _control = control::serve_option(&args.flag_control_socket, engine.control());
+
+    session::run(engine, move |input: &[f32], output: &mut [f32], frames: usize| {
+        if identity {
+            output.copy_from_slice(input);
+        } else {
+            matrix.process(input, output, frames);
+        }
+    }, telemetry);
+
+    for recorder in capture_recorder.into_iter().chain(playback_recorder) {
+        recorder.stop();
+    }
 
-fn get_period_buffer_size(pcm: &alsa::pcm::PCM) -> usize {
-    let hwp = pcm.hw_params_current().unwrap();
-    hwp.get_period_size().unwrap() as usize * hwp.get_channels().unwrap() as usize
-}
\ No newline at end of file
+    if args.flag_rt_check {
+        let violations = rt_check::violations();
+        eprintln!("Real-time sections: {}, allocations: {}, context switches: {}, syscalls: {}",
+                  violations.sections, violations.allocations,
+                  violations.context_switches, violations.syscalls);
+        if violations.total() > 0 {
+            process::exit(1);
+        }
+    }
+}
@@ -1,31 +1,40 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate toml;
 extern crate alsa;
 extern crate alsa_sys;
 extern crate time;
-extern crate thread_priority;
 extern crate libc;
 
+mod config;
 mod realtime_priority;
+mod shutdown;
+mod spsc;
+mod telemetry;
 
 use std::process;
 use docopt::Docopt;
+use realtime_priority::RtConfig;
 use alsa::{Direction, ValueOr};
 use alsa::pcm::{PCM, HwParams, Format, Access, Status};
 use libc::timespec;
 use std::fs::File;
+use std::io::BufWriter;
 use std::io::prelude::*;
+use std::time::{Duration, Instant};
+use telemetry::{Event, Record, Reporter, Sink, Stream, write_record};
 
 const USAGE: &str = "
 ALSA audio_time in Rust
 
 Usage:
-  alsa-audio-time [-p -c -D <device> -t <type> -r <Hz> -s <frames> -o <periods> -w <fname>]
+  alsa-audio-time [--config=<file>] [-p -c -D <device> -t <type> -r <Hz> -s <frames> -o <periods> -w <fname> -l <fname> --capture-channels=<nr> --playback-channels=<nr> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock]
   alsa-audio-time (-h | --help)
 
 Options:
   -h --help                     Show this screen.
+  --config=<file>               Read the options from a TOML file, command line flags take precedence.
   -p --playback                 Playback tstamps
   -c --capture                  Capture tstamps.
   -D --device=<device>          Select ALSA device [default: hw:0,0].
@@ -35,9 +44,15 @@ Options:
   -o --periods=<count>          Periods [default: 4].
   -r --sample-rate=<Hz>         Recording sample rate [default: 48000].
   -w --write-to-file=<fname>    Write timestamps to file.
+  -l --log=<fname>              Write every status to a file, to replay with a sim:replay=<fname> device.
+  --capture-channels=<nr>       Channels to capture [default: 2].
+  --playback-channels=<nr>      Channels to play [default: 2].
+  --rt-policy=<policy>          Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>          Real-time priority [default: 3].
+  --rt-cpus=<list>              Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                    Lock the process memory with mlockall.
 ";
 
-const CHANNELS: u32 = 2;
 const PCM_LINK: bool = false;
 const PRE_FILL_P: bool = false;
 
@@ -52,6 +67,13 @@ struct Args {
     flag_delay: bool,
     flag_sample_rate: u32,
     flag_write_to_file: Option<String>,
+    flag_log: Option<String>,
+    flag_capture_channels: u32,
+    flag_playback_channels: u32,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
 }
 
 #[derive(Debug)]
@@ -63,16 +85,35 @@ enum TimeStampType {
 }
 
 struct PreviousStatus {
-    audio_htstamp: timespec,
-    htstamp: timespec,
+    audio_htstamp: f64,
+    htstamp: f64,
     captured_frames: u64,
 }
 
+// Prints the status of every period and writes the timestamps file, away from the audio loop.
+struct StatusSink {
+    out_file: Option<File>,
+    log: Option<BufWriter<File>>,
+    last_status_c: Option<PreviousStatus>,
+    xruns_c: u32,
+    xruns_p: u32,
+}
+
 fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let rt = RtConfig::from_options(&args.flag_rt_policy, args.flag_rt_priority,
+                                    &args.flag_rt_cpus, args.flag_rt_mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
     let ts_type = match args.flag_ts_type {
         1 => TimeStampType::Link,
         2 => TimeStampType::LinkEstimated,
@@ -102,20 +143,33 @@ fn main() {
 
     let mut handle_p: Option<PCM> = None;
     let mut handle_c: Option<PCM> = None;
-    let mut buffer_c = vec![0i16; (period_size * periods * CHANNELS) as usize];
-    let buffer_p = vec![0i16; (period_size * periods * CHANNELS) as usize];
+    let mut buffer_c = vec![0i16; (period_size * periods * args.flag_capture_channels) as usize];
+    let buffer_p = vec![0i16; (period_size * periods * args.flag_playback_channels) as usize];
     let mut xruns_p = 0;
     let mut xruns_c = 0;
     let mut frames_count_p: u64 = 0;
     let mut frames_count_c: u64 = 0;
-    let mut last_status_c: Option<PreviousStatus> = None;
-    let mut last_status_p: Option<PreviousStatus> = None;
-
-    let mut out_file = args.flag_write_to_file.map(|f| File::create(f).unwrap());
+    // frames_count_* restart on xruns, these do not
+    let mut total_p: u64 = 0;
+    let mut total_c: u64 = 0;
+
+    let out_file = args.flag_write_to_file.map(|f| File::create(f).unwrap());
+    let (device, rate) = (&args.flag_device, args.flag_sample_rate);
+    let log = args.flag_log.as_ref().map(|f| {
+        let mut log = BufWriter::new(File::create(f).unwrap());
+        writeln!(log, "# alsa-audio-time {}", device).unwrap();
+        writeln!(log, "# rate {}", rate).unwrap();
+        writeln!(log, "# time source event values").unwrap();
+        log
+    });
+    let mut reporter = Reporter::new();
+    let mut telemetry = reporter.sender("alsa", 4096);
+    let reporter = reporter.start(Duration::from_secs(1),
+                                   Box::new(StatusSink { out_file, log, last_status_c: None, xruns_c: 0, xruns_p: 0 }));
 
     if args.flag_playback {
         let mut pcm = PCM::new(&args.flag_device, Direction::Playback, false).unwrap();
-        set_params(&mut pcm, args.flag_sample_rate, period_size, periods);
+        set_params(&mut pcm, args.flag_playback_channels, args.flag_sample_rate, period_size, periods);
         {
             let hwp = pcm.hw_params_current().unwrap();
             let start_threshold = hwp.get_buffer_size().unwrap() - hwp.get_period_size().unwrap();
@@ -130,7 +184,7 @@ fn main() {
 
     if args.flag_capture {
         let mut pcm = PCM::new(&args.flag_device, Direction::Capture, false).unwrap();
-        set_params(&mut pcm, args.flag_sample_rate, period_size, periods);
+        set_params(&mut pcm, args.flag_capture_channels, args.flag_sample_rate, period_size, periods);
         handle_c = Some(pcm);
     }
 
@@ -148,6 +202,7 @@ fn main() {
             for _ in 0..periods {
                 let frames = io.writei(&buffer_p).unwrap() as u64;
                 frames_count_p += frames;
+                total_p += frames;
             }
         }
     }
@@ -159,17 +214,18 @@ fn main() {
         }
     }
 
-    realtime_priority::get_realtime_priority();
+    shutdown::install();
+    eprintln!("Real-time: {}", rt.apply());
+    let start = Instant::now();
 
-    loop {
+    while !shutdown::requested() {
         if let Some(pcm_c) = handle_c.as_ref() {
             if let Err(e) = pcm_c.wait(None) {
-                eprintln!("Recovering from Capture wait error");
                 pcm_c.try_recover(e, false).unwrap();
                 pcm_c.start().unwrap();
                 xruns_c += 1;
                 frames_count_c = 0;
-                last_status_c = None;
+                telemetry.send(Event::XRun { stream: Stream::Capture, count: xruns_c });
             }
 
             let io = pcm_c.io_i16().unwrap();
@@ -177,20 +233,16 @@ fn main() {
             match io.readi(&mut buffer_c) {
                 Ok(len) => {
                     frames_count_c += len as u64;
-                    eprint!("Capture   xruns: {}  ", xruns_c);
+                    total_c += len as u64;
                     let status = pcm_c.status().unwrap();
-                    if let Some(file) = out_file.as_mut() {
-                        write_timestamp_capture(file, &status, &mut last_status_c, frames_count_c);
-                    }
-                    print_timestamp(&status, frames_count_c);
+                    telemetry.send(status_event(Stream::Capture, &status, frames_count_c));
                 }
                 Err(e) => {
-                    eprintln!("Recovering from Capture error");
                     pcm_c.try_recover(e, false).unwrap();
                     pcm_c.start().unwrap();
                     xruns_c += 1;
                     frames_count_c = 0;
-                    last_status_c = None;
+                    telemetry.send(Event::XRun { stream: Stream::Capture, count: xruns_c });
                 }
             }
         }
@@ -201,29 +253,44 @@ fn main() {
             match io.writei(&buffer_p) {
                 Ok(len) => {
                     frames_count_p += len as u64;
-                    eprint!("Playback  xruns: {}  ", xruns_p);
+                    total_p += len as u64;
                     let status = pcm_p.status().unwrap();
-                    if let Some(file) = out_file.as_mut() {
-                        write_timestamp_playback(file, &status, frames_count_p);
-                    }
-                    print_timestamp(&status, frames_count_p);
+                    telemetry.send(status_event(Stream::Playback, &status, frames_count_p));
                 }
                 Err(e) => {
-                    eprintln!("Recovered from Playback error");
                     pcm_p.try_recover(e, false).unwrap();
                     xruns_p += 1;
                     frames_count_p = 0;
-                    last_status_p = None;
+                    telemetry.send(Event::XRun { stream: Stream::Playback, count: xruns_p });
                 }
             }
         }
     }
+
+    let latency = handle_p.as_ref().map(|pcm_p| pcm_p.delay().unwrap_or(0));
+    if let Some(pcm_p) = handle_p.as_ref() {
+        pcm_p.drain().unwrap_or_else(|e| eprintln!("Playback drain: {}", e));
+    }
+    let runtime = start.elapsed();
+    // the timestamp file is written by the reporter
+    reporter.stop();
+
+    eprintln!("Run time: {:.3} s", runtime.as_secs() as f64 + runtime.subsec_nanos() as f64 * 1e-9);
+    if args.flag_capture {
+        eprintln!("Capture: {} frames, {} xruns", total_c, xruns_c);
+    }
+    if args.flag_playback {
+        eprintln!("Playback: {} frames, {} xruns", total_p, xruns_p);
+    }
+    if let Some(latency) = latency {
+        eprintln!("Playback latency: {:.1} ms", latency as f64 * 1000.0 / args.flag_sample_rate as f64);
+    }
 }
 
 
-fn set_params(pcm: &mut PCM, sample_rate: u32, period_size: u32, periods: u32) {
+fn set_params(pcm: &mut PCM, channels: u32, sample_rate: u32, period_size: u32, periods: u32) {
     let hwp = HwParams::any(&pcm).unwrap();
-    hwp.set_channels(2).unwrap();
+    hwp.set_channels(channels).unwrap();
     hwp.set_rate(sample_rate, ValueOr::Nearest).unwrap();
     hwp.set_format(Format::s16()).unwrap();
     hwp.set_access(Access::RWInterleaved).unwrap();
@@ -240,15 +307,71 @@ fn set_params(pcm: &mut PCM, sample_rate: u32, period_size: u32, periods: u32) {
     pcm.sw_params(&swp).unwrap();
 }
 
-fn print_timestamp(status: &Status, frames_count: u64) {
-    eprint!("delay: {:5}  ", status.get_delay());
-    eprint!("avail: {:5}  ", status.get_avail());
-    eprint!("avail_max: {:5}  ", status.get_avail_max());
+fn status_event(stream: Stream, status: &Status, frames_count: u64) -> Event {
+    Event::Status {
+        stream,
+        frames: frames_count,
+        delay: status.get_delay() as i64,
+        avail: status.get_avail() as i64,
+        avail_max: status.get_avail_max() as i64,
+        audio: timespec_f64(status.get_audio_htstamp()),
+        trigger: timespec_f64(status.get_trigger_htstamp()),
+        system: timespec_f64(status.get_htstamp()),
+    }
+}
+
+impl Sink for StatusSink {
+    fn record(&mut self, source: &str, record: &Record) {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(e) = write_record(log, source, record) {
+                eprintln!("Cannot write the status log: {}", e);
+                self.log = None;
+            }
+        }
+
+        match record.event {
+            Event::Status { stream, frames, delay, avail, avail_max, audio, trigger, system } => {
+                match stream {
+                    Stream::Capture => eprint!("Capture   xruns: {}  ", self.xruns_c),
+                    Stream::Playback => eprint!("Playback  xruns: {}  ", self.xruns_p),
+                }
+                print_timestamp(frames, delay, avail, avail_max, audio, trigger, system);
+
+                if let Some(file) = self.out_file.as_mut() {
+                    match stream {
+                        Stream::Capture => write_timestamp_capture(file, &mut self.last_status_c,
+                                                                   frames, delay, audio, trigger, system),
+                        Stream::Playback => write_timestamp_playback(file, audio, trigger, system),
+                    }
+                }
+            }
+            Event::XRun { stream: Stream::Capture, count } => {
+                eprintln!("Recovered from Capture error");
+                self.xruns_c = count;
+                self.last_status_c = None;
+            }
+            Event::XRun { stream: Stream::Playback, count } => {
+                eprintln!("Recovered from Playback error");
+                self.xruns_p = count;
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(log) = self.log.as_mut() {
+            log.flush().unwrap_or_else(|e| eprintln!("Cannot write the status log: {}", e));
+        }
+    }
+}
+
+fn print_timestamp(frames_count: u64, delay: i64, avail: i64, avail_max: i64,
+                   audio_htstamp: f64, trigger_htstamp: f64, htstamp: f64) {
+    eprint!("delay: {:5}  ", delay);
+    eprint!("avail: {:5}  ", avail);
+    eprint!("avail_max: {:5}  ", avail_max);
     eprint!("frames: {}  ", frames_count);
 
-    let audio_htstamp = timespec_f64(status.get_audio_htstamp());
-    let trigger_htstamp = timespec_f64(status.get_trigger_htstamp());
-    let htstamp = timespec_f64(status.get_htstamp());
     let drift = htstamp - trigger_htstamp - audio_htstamp;
 
     eprint!("audio_htstamp: {:<18}  ", audio_htstamp);
@@ -258,20 +381,19 @@ fn print_timestamp(status: &Status, frames_count: u64) {
 }
 
 fn write_timestamp_capture(file: &mut File,
-                           status: &Status,
                            last_status: &mut Option<PreviousStatus>,
-                           frames_count: u64) {
-    let audio_elapsed = timespec_f64(status.get_audio_htstamp());
-    let trigger_tstamp = timespec_f64(status.get_trigger_htstamp());
-    let system_tstamp = timespec_f64(status.get_htstamp());
-
+                           frames_count: u64,
+                           delay: i64,
+                           audio_elapsed: f64,
+                           trigger_tstamp: f64,
+                           system_tstamp: f64) {
     let system_elapsed = system_tstamp - trigger_tstamp;
-    let captured_frames = frames_count + status.get_delay() as u64;
+    let captured_frames = frames_count + delay as u64;
 
     if let Some(last_status) = last_status.as_ref() {
         let captured_frames_from_last = captured_frames - last_status.captured_frames;
-        let system_elapsed_from_last = system_tstamp - timespec_f64(last_status.htstamp);
-        let audio_elapsed_from_last = audio_elapsed - timespec_f64(last_status.audio_htstamp);
+        let system_elapsed_from_last = system_tstamp - last_status.htstamp;
+        let audio_elapsed_from_last = audio_elapsed - last_status.audio_htstamp;
 
         let audio_rate_instant = captured_frames_from_last as f64 / audio_elapsed_from_last;
         let system_rate_instant = captured_frames_from_last as f64 / system_elapsed_from_last;
@@ -289,18 +411,14 @@ fn write_timestamp_capture(file: &mut File,
     }
 
     let saved_status = PreviousStatus {
-        audio_htstamp: status.get_audio_htstamp(),
-        htstamp: status.get_htstamp(),
+        audio_htstamp: audio_elapsed,
+        htstamp: system_tstamp,
         captured_frames,
     };
     *last_status = Some(saved_status);
 }
 
-fn write_timestamp_playback(file: &mut File, status: &Status, frames_count: u64) {
-    let audio_elapsed = timespec_f64(status.get_audio_htstamp());
-    let trigger_tstamp = timespec_f64(status.get_trigger_htstamp());
-    let system_tstamp = timespec_f64(status.get_htstamp());
-
+fn write_timestamp_playback(file: &mut File, audio_elapsed: f64, trigger_tstamp: f64, system_tstamp: f64) {
     let system_elapsed = system_tstamp - trigger_tstamp;
     let played_frames = audio_elapsed * 48000.0;
 
@@ -1,18 +1,21 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate toml;
 extern crate alsa;
 extern crate alsa_sys;
 extern crate time;
-extern crate thread_priority;
 extern crate libc;
 
+mod config;
 mod realtime_priority;
+mod shutdown;
 
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::process;
 use docopt::Docopt;
+use realtime_priority::RtConfig;
 use alsa::{Direction, ValueOr};
 use alsa::pcm::{PCM, HwParams, Format, Access};
 use alsa::direct::pcm::Status;
@@ -23,11 +26,12 @@ const USAGE: &str = "
 alsa-direct-status-test
 
 Usage:
-  alsa-audio-time [-p -c -D <device> -r <Hz> -s <frames> -o <periods> -f <Hz>]
+  alsa-audio-time [--config=<file>] [-p -c -D <device> -r <Hz> -s <frames> -o <periods> -f <Hz> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock]
   alsa-audio-time (-h | --help)
 
 Options:
   -h --help                     Show this screen.
+  --config=<file>               Read the options from a TOML file, command line flags take precedence.
   -p --playback                 Playback tstamps
   -c --capture                  Capture tstamps.
   -D --device=<device>          Select ALSA device [default: hw:0,0].
@@ -35,6 +39,10 @@ Options:
   -o --periods=<count>          Periods [default: 4].
   -r --sample-rate=<Hz>         Recording sample rate [default: 48000].
   -f --status-freq=<Hz>         Status Frequency [default: 10].
+  --rt-policy=<policy>          Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>          Real-time priority [default: 3].
+  --rt-cpus=<list>              Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                    Lock the process memory with mlockall.
 ";
 
 const CHANNELS: u32 = 2;
@@ -48,13 +56,27 @@ struct Args {
     flag_periods: u32,
     flag_sample_rate: u32,
     flag_status_freq: f64,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
 }
 
 fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let rt = RtConfig::from_options(&args.flag_rt_policy, args.flag_rt_priority,
+                                    &args.flag_rt_cpus, args.flag_rt_mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
     if !args.flag_capture && !args.flag_playback {
         eprintln!("{}", USAGE);
         eprintln!("Error: please enable capture, playback or both.");
@@ -138,39 +160,67 @@ fn main() {
         };
     });
 
-    realtime_priority::get_realtime_priority();
+    let mut frames_c: u64 = 0;
+    let mut frames_p: u64 = 0;
+    let mut xruns_c = 0;
+    let mut xruns_p = 0;
+
+    shutdown::install();
+    eprintln!("Real-time: {}", rt.apply());
+    let start = Instant::now();
 
     if let Some(pcm_c) = handle_c.as_ref() {
         pcm_c.start().unwrap();
     }
 
 
-    loop {
+    while !shutdown::requested() {
         if let Some(pcm_c) = handle_c.as_ref() {
             if let Err(e) = pcm_c.wait(None) {
                 eprintln!("Recovering from Capture wait error");
                 pcm_c.try_recover(e, false).unwrap();
                 pcm_c.start().unwrap();
+                xruns_c += 1;
             }
 
             let io = pcm_c.io_i16().unwrap();
 
-            if let Err(e) = io.readi(&mut buffer_c) {
-                eprintln!("Recovering from Capture error");
-                pcm_c.try_recover(e, false).unwrap();
-                pcm_c.start().unwrap();
+            match io.readi(&mut buffer_c) {
+                Ok(frames) => frames_c += frames as u64,
+                Err(e) => {
+                    eprintln!("Recovering from Capture error");
+                    pcm_c.try_recover(e, false).unwrap();
+                    pcm_c.start().unwrap();
+                    xruns_c += 1;
+                }
             }
         }
 
         if let Some(pcm_p) = handle_p.as_ref() {
             let io = pcm_p.io_i16().unwrap();
 
-            if let Err(e) = io.writei(&buffer_p) {
-                eprintln!("Recovered from Playback error");
-                pcm_p.try_recover(e, false).unwrap();
+            match io.writei(&buffer_p) {
+                Ok(frames) => frames_p += frames as u64,
+                Err(e) => {
+                    eprintln!("Recovered from Playback error");
+                    pcm_p.try_recover(e, false).unwrap();
+                    xruns_p += 1;
+                }
             }
         }
     }
+
+    if let Some(pcm_p) = handle_p.as_ref() {
+        pcm_p.drain().unwrap_or_else(|e| eprintln!("Playback drain: {}", e));
+    }
+    let runtime = start.elapsed();
+    eprintln!("Run time: {:.3} s", runtime.as_secs() as f64 + runtime.subsec_nanos() as f64 * 1e-9);
+    if handle_c.is_some() {
+        eprintln!("Capture: {} frames, {} xruns", frames_c, xruns_c);
+    }
+    if handle_p.is_some() {
+        eprintln!("Playback: {} frames, {} xruns", frames_p, xruns_p);
+    }
 }
 
 fn set_params(pcm: &mut PCM, sample_rate: u32, period_size: u32, periods: u32) {
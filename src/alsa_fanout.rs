@@ -0,0 +1,194 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod backend;
+mod config;
+mod control;
+mod drift;
+mod engine;
+mod fanout;
+mod fifo;
+mod interpolator;
+mod member;
+mod metrics;
+mod polyphase;
+mod realtime_priority;
+mod record;
+mod resampler;
+mod rt_check;
+mod rtp;
+mod session;
+mod shutdown;
+mod simd;
+mod spsc;
+mod telemetry;
+mod wav;
+
+use std::process;
+
+use backend::{Config, Playback};
+use engine::{Engine, EngineConfig};
+use fanout::{FanoutConfig, FanoutPlayback};
+use fifo::{Overflow, Underflow};
+
+#[global_allocator]
+static ALLOCATOR: rt_check::CheckAllocator = rt_check::CheckAllocator;
+
+const USAGE: &str = "
+ALSA fan-out device
+
+Plays one captured stream on several cards. The clock master is fed through
+the asrc from the capture device, the other cards are resampled from the
+master clock domain into their own, each keeping its latency at the FIFO target.
+
+Usage:
+  alsa-fanout [--config=<file>] [--capture-device=<alsa-device> --master=<index> --channels=<nr> --format=<format> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --capture-sample-rate=<Hz> --playback-sample-rate=<Hz> --resampler=<quality> --max-ppm=<ppm> --fifo-target=<frames> --record=<file> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock --telemetry-interval=<seconds> --telemetry-log=<file> --control-socket=<path> --metrics=<address> --duration=<seconds>] <playback-device>...
+  alsa-fanout (-h | --help)
+
+Options:
+  -h --help                         Show this screen.
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
+  --capture-device=<alsa-device>    ALSA device, file:<path> or sim to record from [default: default]
+  --master=<index>                  Playback device used as clock master, counted from 0 [default: 0]
+  --channels=<nr>                   Channels to capture and play [default: 2]
+  --format=<format>                 Sample format: s16, s24, s32 or f32 [default: s16]
+  --capture-period-size=<frames>    Size of capture frames [default: 256].
+  --capture-periods=<count>         Amount of recording periods [default: 2].
+  --playback-period-size=<frames>   Size of playback frames [default: 256].
+  --playback-periods=<count>        Amount of playback periods [default: 2].
+  --capture-sample-rate=<Hz>        Recording sample rate [default: 48000].
+  --playback-sample-rate=<Hz>       Playback sample rate [default: 48000].
+  --resampler=<quality>             Resampler quality: low, medium, high or best windowed sinc, or linear,
+                                    hermite, lagrange4, lagrange6 or farrow for low latency,
+                                    or rational for an exact nominal ratio, rational-fixed
+                                    for clocks locked together [default: high].
+  --max-ppm=<ppm>                   Largest clock drift correction [default: 1000].
+  --fifo-target=<frames>            FIFO fill to keep for every other card in master frames, 0 for three periods [default: 0].
+  --record=<file>                   Record the played stream to a WAV or raw file.
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
+  --telemetry-interval=<seconds>    Seconds between status summaries [default: 1].
+  --telemetry-log=<file>            Write every telemetry record to a file.
+  --control-socket=<path>           Accept commands from asrc-ctl on a Unix socket.
+  --metrics=<address>               Serve Prometheus metrics on http://<address>/metrics, e.g. 127.0.0.1:9100.
+  --duration=<seconds>              Stop after this much captured audio, 0 runs forever [default: 0].
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_playback_device: Vec<String>,
+    flag_capture_device: String,
+    flag_master: usize,
+    flag_channels: usize,
+    flag_format: String,
+    flag_capture_period_size: usize,
+    flag_capture_periods: u32,
+    flag_playback_period_size: usize,
+    flag_playback_periods: u32,
+    flag_capture_sample_rate: u32,
+    flag_playback_sample_rate: u32,
+    flag_resampler: String,
+    flag_max_ppm: f64,
+    flag_fifo_target: usize,
+    flag_record: Option<String>,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
+    flag_telemetry_interval: f64,
+    flag_telemetry_log: Option<String>,
+    flag_control_socket: Option<String>,
+    flag_metrics: Option<String>,
+    flag_duration: f64,
+}
+
+fn main() {
+    let args: Args = session::args(USAGE);
+    let rt = session::rt_config(&args.flag_rt_policy, args.flag_rt_priority,
+                                &args.flag_rt_cpus, args.flag_rt_mlock);
+    let format = session::format(&args.flag_format);
+    let quality = session::quality(&args.flag_resampler);
+
+    let capture = backend::open_capture(&args.flag_capture_device, Config {
+        channels: args.flag_channels,
+        rate: args.flag_capture_sample_rate,
+        format,
+        period_size: args.flag_capture_period_size,
+        periods: args.flag_capture_periods,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_capture_device, e);
+        process::exit(1);
+    });
+    let capture_config = capture.config().clone();
+    session::print_device("Capture", &args.flag_capture_device, &capture_config);
+
+    // a capture file decides of the channel count
+    let playbacks: Vec<Box<dyn Playback>> = args.arg_playback_device.iter().enumerate().map(|(index, device)| {
+        let playback = backend::open_playback(device, Config {
+            channels: capture_config.channels,
+            rate: args.flag_playback_sample_rate,
+            format,
+            period_size: args.flag_playback_period_size,
+            periods: args.flag_playback_periods,
+        }).unwrap_or_else(|e| {
+            eprintln!("Cannot open {}: {}", device, e);
+            process::exit(1);
+        });
+        let title = if index == args.flag_master { "Playback (clock master)" } else { "Playback" };
+        session::print_device(title, device, playback.config());
+        playback
+    }).collect();
+
+    let playback = FanoutPlayback::new(playbacks, &args.arg_playback_device, FanoutConfig {
+        master: args.flag_master,
+        quality,
+        max_ppm: args.flag_max_ppm,
+        fifo_target: args.flag_fifo_target,
+        bandwidth: None,
+        rt: rt.clone(),
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot fan out to the playback devices: {}", e);
+        process::exit(1);
+    });
+    let status = playback.status();
+    let playback_config = playback.config().clone();
+
+    let mut engine = Engine::new(capture, Box::new(playback), EngineConfig {
+        block_size: 0,
+        quality,
+        overflow: Overflow::DropOldest,
+        underflow: Underflow::Silence,
+        fifo_target: 0,
+        drift_control: true,
+        max_ppm: args.flag_max_ppm,
+        duration: args.flag_duration,
+        rt,
+    });
+
+    let recorder = session::record(&args.flag_record, &playback_config).map(|(tap, recorder)| {
+        engine.record_playback(tap);
+        recorder
+    });
+
+    let telemetry = session::telemetry(&mut engine, args.flag_telemetry_interval,
+                                       &args.flag_telemetry_log, &args.flag_metrics);
+
+    let _control = control::serve_option(&args.flag_control_socket, engine.control());
+
+    session::run(engine, |input: &[f32], output: &mut [f32], _frames: usize| {
+        output.copy_from_slice(input);
+    }, telemetry);
+    status.print();
+
+    if let Some(recorder) = recorder {
+        recorder.stop();
+    }
+}
@@ -1,15 +1,19 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate toml;
 extern crate alsa;
+extern crate libc;
 extern crate time;
-extern crate thread_priority;
 
+mod config;
 mod realtime_priority;
+mod shutdown;
 
 use std::process;
 
 use docopt::Docopt;
+use realtime_priority::RtConfig;
 use alsa::{Direction, ValueOr};
 use alsa::pcm::{PCM, HwParams, Format, Access, IO};
 
@@ -17,21 +21,28 @@ const USAGE: &str = "
 ALSA capture and playback period timer
 
 Usage:
-  alsa-period-timing <mode> [--duration=<seconds> --capture-device=<alsa-device> --playback-device=<alsa-device> --capture-buffer-size=<frames> --channels=<nr> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --sample-rate=<Hz>]
+  alsa-period-timing [--config=<file>] <mode> [--duration=<seconds> --capture-device=<alsa-device> --playback-device=<alsa-device> --capture-buffer-size=<frames> --channels=<nr> --capture-channels=<nr> --playback-channels=<nr> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --sample-rate=<Hz> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock]
   alsa-period-timing (-h | --help)
 
 Options:
   -h --help                         Show this screen.
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
   <mode>                            Mode: capture, playback or capture_playback
   --duration=<seconds>              Record duration in seconds [default: 5]
   --capture-device=<alsa-device>    ALSA device to record from [default: default]
   --playback-device=<alsa-device>   ALSA device to playback to [default: default]
   --channels=<nr>                   Channels to capture and play [default: 2]
+  --capture-channels=<nr>           Channels to capture, --channels by default.
+  --playback-channels=<nr>          Channels to play, --channels by default.
   --capture-period-size=<frames>    Size of capture frames [default: 128].
   --playback-period-size=<frames>   Size of playback frames [default: 128].
   --capture-periods=<count>         Amount of recording periods [default: 2].
   --playback-periods=<count>        Amount of playback periods [default: 2].
   --sample-rate=<Hz>                Recording sample rate [default: 48000].
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
 ";
 
 #[derive(Debug, Deserialize)]
@@ -41,28 +52,45 @@ struct Args {
     flag_capture_device: String,
     flag_playback_device: String,
     flag_channels: u32,
+    flag_capture_channels: Option<u32>,
+    flag_playback_channels: Option<u32>,
     flag_capture_period_size: usize,
     flag_capture_periods: u32,
     flag_playback_period_size: usize,
     flag_playback_periods: u32,
     flag_sample_rate: u32,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
 }
 
 fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let rt = RtConfig::from_options(&args.flag_rt_policy, args.flag_rt_priority,
+                                    &args.flag_rt_cpus, args.flag_rt_mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
     match args.arg_mode.as_ref() {
         "capture" => {
             let device = args.flag_capture_device;
+            let channels = args.flag_capture_channels.unwrap_or(args.flag_channels);
             eprintln!("Capture  {}, {} Hz, {} frames * {}",
                       device, args.flag_sample_rate, args.flag_capture_period_size,
                       args.flag_capture_periods);
 
             let pcm = PCM::new(&device, Direction::Capture, false).unwrap();
             let hwp = HwParams::any(&pcm).unwrap();
-            hwp.set_channels(args.flag_channels).unwrap();
+            hwp.set_channels(channels).unwrap();
             hwp.set_rate(args.flag_sample_rate, ValueOr::Nearest).unwrap();
             hwp.set_format(Format::s16()).unwrap();
             hwp.set_access(Access::RWInterleaved).unwrap();
@@ -79,23 +107,25 @@ fn main() {
             let buffer_size = hwp.get_buffer_size().unwrap() as usize;
             eprintln!("Capture period size: {}, HW buffer size: {}", period_size, buffer_size);
 
-            let buf = vec![0; period_size * args.flag_channels as usize];
+            let buf = vec![0; period_size * channels as usize];
             card_vs_systime(buf,
                             io,
                             Direction::Capture,
                             args.flag_sample_rate,
-                            args.flag_duration);
+                            args.flag_duration,
+                            &rt);
         }
 
         "playback" => {
             let device = args.flag_playback_device;
+            let channels = args.flag_playback_channels.unwrap_or(args.flag_channels);
             eprintln!("Playback {}, {} Hz, {} frames * {}",
                       device, args.flag_sample_rate, args.flag_playback_period_size,
                       args.flag_playback_periods);
 
             let pcm = PCM::new(&device, Direction::Playback, false).unwrap();
             let hwp = HwParams::any(&pcm).unwrap();
-            hwp.set_channels(args.flag_channels).unwrap();
+            hwp.set_channels(channels).unwrap();
             hwp.set_rate(args.flag_sample_rate, ValueOr::Nearest).unwrap();
             hwp.set_format(Format::s16()).unwrap();
             hwp.set_access(Access::RWInterleaved).unwrap();
@@ -112,12 +142,13 @@ fn main() {
             let buffer_size = hwp.get_buffer_size().unwrap() as usize;
             eprintln!("Playback period size: {}, HW buffer size: {}", period_size, buffer_size);
 
-            let buf = vec![0; period_size * args.flag_channels as usize];
+            let buf = vec![0; period_size * channels as usize];
             card_vs_systime(buf,
                             io,
                             Direction::Playback,
                             args.flag_sample_rate,
-                            args.flag_duration);
+                            args.flag_duration,
+                            &rt);
         }
         _ => {
             eprintln!("No valid mode specified: {}", args.arg_mode);
@@ -130,11 +161,16 @@ fn card_vs_systime(mut rec_buf: Vec<i16>,
                    io: IO<i16>,
                    direction: Direction,
                    sample_rate: u32,
-                   duration_s: u64) {
-    realtime_priority::get_realtime_priority();
+                   duration_s: u64,
+                   rt: &RtConfig) {
+    shutdown::install();
+    eprintln!("Real-time: {}", rt.apply());
 
     let start_ns = time::precise_time_ns();
     let mut time_ns = start_ns;
+    let mut periods: u64 = 0;
+    let mut total_frames: u64 = 0;
+    let mut deviation_sum = 0.0;
     loop {
         let read = match direction {
             Direction::Capture => io.readi(&mut rec_buf),
@@ -146,15 +182,23 @@ fn card_vs_systime(mut rec_buf: Vec<i16>,
         match read {
             Ok(frames) => {
                 let period_time_reference = frames as f64 / sample_rate as f64 * 1e6;
-                println!("{}", elapsed_ns as f64 / 1e3 - period_time_reference);
+                let deviation = elapsed_ns as f64 / 1e3 - period_time_reference;
+                println!("{}", deviation);
+                periods += 1;
+                total_frames += frames as u64;
+                deviation_sum += deviation;
             }
             Err(e) => {
                 eprintln!("Error: {}", e);
                 process::exit(1);
             }
         };
-        if now_ns - start_ns > duration_s * 1_000_000_000 {
+        if now_ns - start_ns > duration_s * 1_000_000_000 || shutdown::requested() {
             break;
         }
     }
+
+    eprintln!("Run time: {:.3} s", (time_ns - start_ns) as f64 / 1e9);
+    eprintln!("{} periods, {} frames, mean deviation: {:.1} us",
+              periods, total_frames, if periods > 0 { deviation_sum / periods as f64 } else { 0.0 });
 }
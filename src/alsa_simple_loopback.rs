@@ -1,12 +1,22 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate toml;
 extern crate alsa;
-extern crate thread_priority;
+extern crate libc;
 
+mod backend;
+mod config;
 mod realtime_priority;
+mod record;
+mod rtp;
+mod shutdown;
+mod spsc;
+mod wav;
 
+use std::process;
 use docopt::Docopt;
+use realtime_priority::RtConfig;
 use alsa::{Direction, ValueOr};
 use alsa::pcm::{PCM, HwParams, Format, Access, State};
 
@@ -14,11 +24,12 @@ const USAGE: &str = "
 ALSA simple loopback
 
 Usage:
-  alsa-simple-loopback [--capture-device=<alsa-device> --playback-device=<alsa-device> --channels=<nr> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --capture-sample-rate=<Hz> --playback-sample-rate=<Hz>]
+  alsa-simple-loopback [--config=<file>] [--capture-device=<alsa-device> --playback-device=<alsa-device> --channels=<nr> --capture-period-size=<frames> --capture-periods=<count> --playback-period-size=<frames> --playback-periods=<count> --capture-sample-rate=<Hz> --playback-sample-rate=<Hz> --record-capture=<file> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock]
   alsa-simple-loopback (-h | --help)
 
 Options:
   -h --help                         Show this screen.
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
   --capture-device=<alsa-device>    ALSA device to record from [default: default]
   --playback-device=<alsa-device>   ALSA device to playback to [default: default]
   --channels=<nr>                   Channels to capture and play [default: 2]
@@ -28,6 +39,11 @@ Options:
   --playback-periods=<count>        Amount of playback periods [default: 2].
   --capture-sample-rate=<Hz>        Recording sample rate [default: 48000].
   --playback-sample-rate=<Hz>       Playback sample rate [default: 48000].
+  --record-capture=<file>           Record the captured stream to a WAV or raw file.
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
 ";
 
 
@@ -42,13 +58,28 @@ struct Args {
     flag_playback_periods: u32,
     flag_capture_sample_rate: u32,
     flag_playback_sample_rate: u32,
+    flag_record_capture: Option<String>,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
 }
 
 fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let rt = RtConfig::from_options(&args.flag_rt_policy, args.flag_rt_priority,
+                                    &args.flag_rt_cpus, args.flag_rt_mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
     eprintln!("Capture\n  card:    {}\n  rate:    {}\n  period:  {}\n  periods: {}",
               args.flag_capture_device,
               args.flag_capture_sample_rate,
@@ -91,18 +122,56 @@ fn main() {
 
     let mut buf = vec![0; period_buffer_size];
 
-    realtime_priority::get_realtime_priority();
-
-    loop {
+    // the recording is written from its own thread, the loop only queues
+    let channels = args.flag_channels as usize;
+    let capture_periods = args.flag_capture_periods;
+    let mut record_buf = vec![0.0f32; period_buffer_size];
+    let mut recording = args.flag_record_capture.as_ref().map(|path| {
+        let hwp = pcm_capture.hw_params_current().unwrap();
+        let config = backend::Config {
+            channels,
+            rate: hwp.get_rate().unwrap(),
+            format: backend::SampleFormat::S32,
+            period_size: hwp.get_period_size().unwrap() as usize,
+            periods: capture_periods,
+        };
+        eprintln!("Recording to {}", path);
+        record::start(path, config).unwrap_or_else(|e| {
+            eprintln!("Cannot record to {}: {}", path, e);
+            process::exit(1);
+        })
+    });
+
+    let mut captured: u64 = 0;
+    let mut played: u64 = 0;
+    let mut xruns_c = 0;
+    let mut xruns_p = 0;
+
+    shutdown::install();
+    eprintln!("Real-time: {}", rt.apply());
+    let start = backend::monotonic_time();
+
+    while !shutdown::requested() {
         let capture_state = pcm_capture.state();
         if capture_state != State::Running { eprintln!("Capture state: {:?}", capture_state); }
         if capture_state == State::XRun {
             eprintln!("Prepare capture");
             pcm_capture.prepare().unwrap();
+            xruns_c += 1;
         }
 
-        if let Err(_) = io_capture.readi(&mut buf) {
-            pcm_capture.prepare().unwrap();
+        match io_capture.readi(&mut buf) {
+            Ok(frames) => {
+                captured += frames as u64;
+                if let Some((ref mut tap, _)) = recording {
+                    let len = frames * channels;
+                    for (y, &x) in record_buf[..len].iter_mut().zip(&buf[..len]) {
+                        *y = backend::i32_to_f32(x);
+                    }
+                    tap.push(&record_buf[..len]);
+                }
+            }
+            Err(_) => pcm_capture.prepare().unwrap(),
         }
 
         let playback_state = pcm_playback.state();
@@ -110,12 +179,26 @@ fn main() {
         if playback_state == State::XRun {
             eprintln!("Prepare playback");
             pcm_playback.prepare().unwrap();
+            xruns_p += 1;
         }
 
-        if let Err(_) = io_playback.writei(&buf) {
-            pcm_playback.prepare().unwrap();
+        match io_playback.writei(&buf) {
+            Ok(frames) => played += frames as u64,
+            Err(_) => pcm_playback.prepare().unwrap(),
         }
     }
+
+    let latency = pcm_playback.delay().unwrap_or(0);
+    pcm_playback.drain().unwrap_or_else(|e| eprintln!("Playback drain: {}", e));
+    let runtime = backend::monotonic_time() - start;
+    if let Some((_, recorder)) = recording {
+        recorder.stop();
+    }
+
+    eprintln!("Run time: {:.3} s", runtime);
+    eprintln!("Capture: {} frames, {} xruns", captured, xruns_c);
+    eprintln!("Playback: {} frames, {} xruns", played, xruns_p);
+    eprintln!("Latency: {:.1} ms", latency as f64 * 1000.0 / args.flag_playback_sample_rate as f64);
 }
 
 fn setup_card(direction: Direction,
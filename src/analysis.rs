@@ -1,86 +1,150 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate toml;
 extern crate rustfft;
 
+mod config;
 mod dsp;
+mod plot;
 
 use docopt::Docopt;
 use rustfft::FFTplanner;
 use rustfft::num_complex::Complex;
 use rustfft::num_traits::Zero;
 use std::fs::File;
+use std::path::Path;
+use std::process;
 use std::io::BufReader;
 use std::io::prelude::*;
 
 use dsp::Biquad;
 use dsp::iir;
+use plot::{Plot, Scale, Style};
 
 
 const USAGE: &str = "
 ALSA results analysis
 
+Reads one measurement per period and line: the deviation of the period time
+in microseconds, as alsa-period-timings writes it, or with --input-unit=hz the
+measured sample rate, as alsa-audio-time -w writes it. Plots the deviation of
+the rate from the nominal rate: time series, power spectral density, histogram
+and Allan deviation.
+
+The rate, period size and periods are taken from the file name when it ends
+in _<rate>_<period-size>_<periods>.dat, the options take precedence. The plots
+are written to the output directory as <name>-time, <name>-psd,
+<name>-histogram and <name>-adev, <name> being the input file name without
+its extension.
+
 Usage:
-  analysis <input> <filtered-1> <filtered-2> <fft> <filtered-fft-1> <filtered-fft-2>
+  analysis [--config=<file>] [--input-unit=<unit> --sample-rate=<Hz> --period-size=<frames> --periods=<count> --output-dir=<dir> --format=<format> --bins=<count>] <input>
   analysis (-h | --help)
 
 Options:
-  -h --help         Show this screen.
-  <input>           Input data file.
-  <filtered-1>      Filtered input data.
-  <filtered-2>      Filtered input data.
-  <fft>             Output FFT from input data.
-  <filtered-fft-1>  Output FFT from filtered data.
-  <filtered-fft-2>  Output FFT from filtered data.
+  -h --help               Show this screen.
+  --config=<file>         Read the options from a TOML file, command line flags take precedence.
+  --input-unit=<unit>     Input values: us for period time deviations or hz for rates [default: us].
+  -r --sample-rate=<Hz>   Nominal sample rate.
+  --period-size=<frames>  Frames per period, one rate measured each.
+  --periods=<count>       Periods in the buffer, only reported.
+  -o --output-dir=<dir>   Directory of the plots [default: .].
+  -f --format=<format>    Plot format: svg or png [default: svg].
+  --bins=<count>          Histogram bins [default: 50].
+  <input>                 Input data file.
 ";
 
 
 #[derive(Debug, Deserialize)]
 struct Args {
     arg_input: String,
-    arg_filtered_1: String,
-    arg_filtered_2: String,
-    arg_fft: String,
-    arg_filtered_fft_1: String,
-    arg_filtered_fft_2: String,
+    flag_input_unit: String,
+    flag_sample_rate: Option<u32>,
+    flag_period_size: Option<u32>,
+    flag_periods: Option<u32>,
+    flag_output_dir: String,
+    flag_format: String,
+    flag_bins: usize,
 }
 
-fn get_fft(data: &[f64]) -> Vec<f64> {
+// One sided power spectral density with a Hann window, in units squared per
+// Hz, from DC to rate / 2.
+fn get_psd(data: &[f64], rate: f64) -> Vec<(f64, f64)> {
+    let n = data.len();
+    let window: Vec<f64> = (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos())
+        .collect();
+    let power: f64 = window.iter().map(|w| w * w).sum();
+
     let mut fft_in: Vec<Complex<f64>> = data
         .iter()
-        .map(|value| Complex::new(*value, 0.0))
+        .zip(window.iter())
+        .map(|(value, w)| Complex::new(value * w, 0.0))
         .collect();
 
-    let mut fft_out: Vec<Complex<f64>> = vec![Complex::zero(); data.len()];
+    let mut fft_out: Vec<Complex<f64>> = vec![Complex::zero(); n];
 
     let mut planner = FFTplanner::new(false);
-    let fft = planner.plan_fft(data.len());
+    let fft = planner.plan_fft(n);
     fft.process(&mut fft_in, &mut fft_out);
 
-    let mut fft_vals = fft_out
-        .iter()
-        .map(|c| c.re.abs())
-        .collect::<Vec<f64>>();
-
-    fft_vals.truncate(data.len() / 2);
-    fft_vals
+    (0..n / 2 + 1)
+        .map(|k| {
+            // both halves but DC and Nyquist
+            let scale = if k == 0 || 2 * k == n { 1.0 } else { 2.0 };
+            (k as f64 * rate / n as f64, scale * fft_out[k].norm_sqr() / (rate * power))
+        })
+        .collect()
 }
 
-fn write_data(file_name: &str, data: &[f64], period_time: f64) {
-    eprintln!("{} average: {}", file_name, data.iter().sum::<f64>() / data.len() as f64);
-    let mut file = File::create(file_name).unwrap();
-    let mut time = 0.0;
-    for i in 0..data.len() {
-        writeln!(file, "{} {}", time, data[i]).unwrap();
-        time += period_time;
+// Overlapping Allan deviation of fractional frequencies measured every tau0
+// seconds, at averaging times of tau0 times powers of 2.
+fn get_adev(fractional: &[f64], tau0: f64) -> Vec<(f64, f64)> {
+    // phase in seconds
+    let mut phase = vec![0.0; fractional.len() + 1];
+    for (i, y) in fractional.iter().enumerate() {
+        phase[i + 1] = phase[i] + y * tau0;
+    }
+
+    let n = phase.len();
+    let mut adev = Vec::new();
+    let mut m = 1;
+    while 2 * m < n && m <= fractional.len() / 3 {
+        let tau = m as f64 * tau0;
+        let terms = n - 2 * m;
+        let sum: f64 = (0..terms)
+            .map(|i| {
+                let d = phase[i + 2 * m] - 2.0 * phase[i + m] + phase[i];
+                d * d
+            })
+            .sum();
+        adev.push((tau, (sum / (2.0 * tau * tau * terms as f64)).sqrt()));
+        m *= 2;
     }
+    adev
 }
 
-fn write_fft(file_name: &str, fft_data: &[f64]) {
-    let mut file = File::create(file_name).unwrap();
-    for i in 0..fft_data.len() {
-        writeln!(file, "{} {}", i as f64 / (fft_data.len() - 1) as f64, fft_data[i]).unwrap();
+// (bin center, count)
+fn get_histogram(data: &[f64], bins: usize) -> Vec<(f64, f64)> {
+    let min = data.iter().cloned().fold(f64::MAX, f64::min);
+    let max = data.iter().cloned().fold(f64::MIN, f64::max);
+    let width = if max > min { (max - min) / bins as f64 } else { 1.0 };
+
+    let mut counts = vec![0usize; bins];
+    for x in data {
+        let bin = (((x - min) / width) as usize).min(bins - 1);
+        counts[bin] += 1;
     }
+    counts
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| (min + (i as f64 + 0.5) * width, count as f64))
+        .collect()
+}
+
+fn time_series(data: &[f64], period_time: f64) -> Vec<(f64, f64)> {
+    data.iter().enumerate().map(|(i, &x)| (i as f64 * period_time, x)).collect()
 }
 
 fn get_biquad(magic: f64, q: f64) -> Biquad {
@@ -108,57 +172,101 @@ fn get_biquad(magic: f64, q: f64) -> Biquad {
     bq
 }
 
+fn save(plot: &Plot, path: &Path) {
+    let path = path.to_string_lossy();
+    plot.save(&path).unwrap_or_else(|e| {
+        eprintln!("Cannot write {}: {}", path, e);
+        process::exit(1);
+    });
+    eprintln!("{}", path);
+}
+
 fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    if args.flag_format != "svg" && args.flag_format != "png" {
+        eprintln!("{}", config::error("format", format!("Unknown plot format: {}, expected svg or png", args.flag_format)));
+        process::exit(1);
+    }
+    if args.flag_input_unit != "us" && args.flag_input_unit != "hz" {
+        eprintln!("{}", config::error("input-unit", format!("Unknown input unit: {}, expected us or hz", args.flag_input_unit)));
+        process::exit(1);
+    }
+    if args.flag_bins == 0 {
+        eprintln!("{}", config::error("bins", "The histogram needs at least 1 bin".to_string()));
+        process::exit(1);
+    }
 
-    let s = &args.arg_input.split(".dat")
-        .collect::<Vec<&str>>()[0]
-        .split("_")
-        .collect::<Vec<&str>>();
-    let sample_rate: u32 = s[s.len() - 3].parse().unwrap();
-    let period_size: u32 = s[s.len() - 2].parse().unwrap();
-    let period_count: u32 = s[s.len() - 1].parse().unwrap();
+    let name = Path::new(&args.arg_input)
+        .file_stem()
+        .map_or_else(|| "analysis".to_string(), |s| s.to_string_lossy().into_owned());
+
+    // ..._<rate>_<period-size>_<periods>
+    let s = name.split('_').collect::<Vec<&str>>();
+    let from_name = |back: usize| if s.len() > back { s[s.len() - 1 - back].parse::<u32>().ok() } else { None };
+    let sample_rate = args.flag_sample_rate.or_else(|| from_name(2));
+    let period_size = args.flag_period_size.or_else(|| from_name(1));
+    let period_count = args.flag_periods.or_else(|| from_name(0));
+    let (sample_rate, period_size) = match (sample_rate, period_size) {
+        (Some(rate), Some(size)) if rate > 0 && size > 0 => (rate, size),
+        _ => {
+            eprintln!("Cannot tell the sample rate and period size from {}, use --sample-rate and --period-size",
+                      args.arg_input);
+            process::exit(1);
+        }
+    };
     let period_time = 1.0 / sample_rate as f64 * period_size as f64;
 
     eprintln!("sample rate: {}, period_size: {}, period_count: {}, period_time {}",
               sample_rate,
               period_size,
-              period_count,
+              period_count.map_or_else(|| "-".to_string(), |c| c.to_string()),
               period_time);
 
-    let file = File::open(&args.arg_input).unwrap();
+    let file = File::open(&args.arg_input).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.arg_input, e);
+        process::exit(1);
+    });
     let mut buf_reader = BufReader::new(file);
     let mut contents = String::new();
-    buf_reader.read_to_string(&mut contents).unwrap();
-
-    let skip_seconds = 0.0;
-    let fade_seconds = 0.0;
-
-    let skip = (sample_rate as f64 * skip_seconds / period_size as f64) as usize;
-    let fade = (sample_rate as f64 * fade_seconds / period_size as f64) as usize;
-
-    let mut data: Vec<f64> = contents
-        .lines()
-        .map(|l| l.trim().parse().unwrap())
-        .skip(skip)
-        .map(|v: f64| v - sample_rate as f64)
-        .collect();
-
-    // fade in the data
-    for i in 0..fade {
-        let coef = i as f64 / (fade - 1) as f64;
-        data[i] = data[i] * coef;
+    buf_reader.read_to_string(&mut contents).unwrap_or_else(|e| {
+        eprintln!("Cannot read {}: {}", args.arg_input, e);
+        process::exit(1);
+    });
+
+    let mut data: Vec<f64> = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rate = match line.parse::<f64>() {
+            Ok(v) if args.flag_input_unit == "hz" => v,
+            // the period took its nominal time plus v microseconds
+            Ok(v) if v.abs() * 1e-6 < period_time => period_size as f64 / (period_time + v * 1e-6),
+            Ok(v) => {
+                eprintln!("{}:{}: {} us off a {:.0} us period, rates need --input-unit=hz",
+                          args.arg_input, n + 1, v, period_time * 1e6);
+                process::exit(1);
+            }
+            Err(_) => {
+                eprintln!("{}:{}: invalid value {}", args.arg_input, n + 1, line);
+                process::exit(1);
+            }
+        };
+        data.push(rate - sample_rate as f64);
     }
-
-    write_data("/tmp/data.dat", &data, period_time);
-    let fft = get_fft(&data);
-    write_fft(&args.arg_fft, &fft);
-
-
-    let mut filtered_data = vec![0.0; data.len()];
+    if data.len() < 4 {
+        eprintln!("{}: {} values, too few to analyze", args.arg_input, data.len());
+        process::exit(1);
+    }
+    eprintln!("{} values, average deviation: {} Hz", data.len(), data.iter().sum::<f64>() / data.len() as f64);
 
     /*
      * Biquad 1
@@ -168,10 +276,8 @@ fn main() {
 
     let mut bq = get_biquad(magic, q);
 
-    iir(&data.clone(), &mut filtered_data, &mut bq);
-    let fft = get_fft(&filtered_data);
-    write_data(&args.arg_filtered_1, &filtered_data, period_time);
-    write_fft(&args.arg_filtered_fft_1, &fft);
+    let mut filtered_1 = vec![0.0; data.len()];
+    iir(&data, &mut filtered_1, &mut bq);
 
     /*
      * Biquad 2
@@ -182,8 +288,44 @@ fn main() {
 
     let mut bq = get_biquad(magic, q);
 
-    dsp::iir(&filtered_data.clone(), &mut filtered_data, &mut bq);
-    let fft = get_fft(&filtered_data);
-    write_data(&args.arg_filtered_2, &filtered_data, period_time);
-    write_fft(&args.arg_filtered_fft_2, &fft);
-}
\ No newline at end of file
+    let mut filtered_2 = vec![0.0; data.len()];
+    iir(&filtered_1, &mut filtered_2, &mut bq);
+
+    /*
+     * Plots
+     */
+
+    let output = |kind: &str| Path::new(&args.flag_output_dir).join(format!("{}-{}.{}", name, kind, args.flag_format));
+    let period_rate = 1.0 / period_time;
+
+    let mut plot = Plot::new(&format!("Rate deviation from {} Hz", sample_rate), "time (s)", "deviation (Hz)");
+    plot.add("measured", time_series(&data, period_time), Style::Lines);
+    plot.add("filtered 1", time_series(&filtered_1, period_time), Style::Lines);
+    plot.add("filtered 2", time_series(&filtered_2, period_time), Style::Lines);
+    save(&plot, &output("time"));
+
+    // DC left out of the log axis
+    let mut plot = Plot::new("Rate deviation power spectral density", "frequency (Hz)", "PSD (Hz^2/Hz)");
+    plot.x_scale = Scale::Log;
+    plot.y_scale = Scale::Log;
+    plot.add("measured", get_psd(&data, period_rate)[1..].to_vec(), Style::Lines);
+    plot.add("filtered 1", get_psd(&filtered_1, period_rate)[1..].to_vec(), Style::Lines);
+    plot.add("filtered 2", get_psd(&filtered_2, period_rate)[1..].to_vec(), Style::Lines);
+    save(&plot, &output("psd"));
+
+    let mut plot = Plot::new("Rate deviation histogram", "deviation (Hz)", "periods");
+    plot.add("measured", get_histogram(&data, args.flag_bins), Style::Steps);
+    save(&plot, &output("histogram"));
+
+    let fractional: Vec<f64> = data.iter().map(|x| x / sample_rate as f64).collect();
+    let adev = get_adev(&fractional, period_time);
+    if adev.is_empty() {
+        eprintln!("Too few values for the Allan deviation");
+        return;
+    }
+    let mut plot = Plot::new("Overlapping Allan deviation of the rate", "tau (s)", "Allan deviation");
+    plot.x_scale = Scale::Log;
+    plot.y_scale = Scale::Log;
+    plot.add("measured", adev, Style::Points);
+    save(&plot, &output("adev"));
+}
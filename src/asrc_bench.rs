@@ -0,0 +1,302 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod backend;
+mod config;
+mod control;
+mod drift;
+mod engine;
+mod fifo;
+mod interpolator;
+mod polyphase;
+mod realtime_priority;
+mod record;
+mod resampler;
+mod rt_check;
+mod rtp;
+mod simd;
+mod spsc;
+mod telemetry;
+mod wav;
+
+use docopt::Docopt;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::process;
+use std::time::{Duration, Instant};
+
+use backend::{Config, SampleFormat, SimCapture, SimOptions, SimPlayback};
+use engine::{Engine, EngineConfig};
+use fifo::{Overflow, Underflow};
+use realtime_priority::RtConfig;
+use resampler::Quality;
+
+#[global_allocator]
+static ALLOCATOR: rt_check::CheckAllocator = rt_check::CheckAllocator;
+
+const USAGE: &str = "
+ASRC benchmarks
+
+Measures the time per output frame of the resamplers, for each quality,
+channel count and conversion, and of the whole capture, FIFO, resampler and
+playback pipeline on simulated devices running as fast as they can. The
+headroom is how many times faster than real time that is. Results can be saved
+and compared to a previous run to catch regressions, scripts/bench.sh does
+both against a baseline kept in /tmp.
+
+Usage:
+  asrc-bench [--config=<file>] [--quality=<names> --channels=<list> --rates=<list> --drift=<ppm> --time=<seconds> --block-size=<frames> --pipeline-seconds=<seconds> --simd=<kernels> --save=<file> --baseline=<file> --tolerance=<percent>]
+  asrc-bench (-h | --help)
+
+Options:
+  -h --help                     Show this screen.
+  --config=<file>               Read the options from a TOML file, command line flags take precedence.
+  --quality=<names>             Comma separated resampler qualities, or all [default: all].
+  --channels=<list>             Comma separated channel counts [default: 1,2,8,32].
+  --rates=<list>                Comma separated <input Hz>:<output Hz> conversions
+                                [default: 44100:48000,48000:44100,48000:48000].
+  --drift=<ppm>                 Clock drift applied on top of the nominal ratios [default: 100].
+  --time=<seconds>              Time spent measuring each resampler case [default: 0.25].
+  --block-size=<frames>         Input frames per resampler call [default: 256].
+  --pipeline-seconds=<seconds>  Audio run through the pipeline per case, at the first conversion, 0 skips [default: 5].
+  --simd=<kernels>              Vector kernels: scalar, sse2, avx2 or neon, the fastest supported by default.
+  --save=<file>                 Write the results to a file, one case per line.
+  --baseline=<file>             Compare to results saved before, exit with 1 on a regression.
+  --tolerance=<percent>         Slowdown against the baseline counted as a regression [default: 10].
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_quality: String,
+    flag_channels: String,
+    flag_rates: String,
+    flag_drift: f64,
+    flag_time: f64,
+    flag_block_size: usize,
+    flag_pipeline_seconds: f64,
+    flag_simd: Option<String>,
+    flag_save: Option<String>,
+    flag_baseline: Option<String>,
+    flag_tolerance: f64,
+}
+
+struct Measurement {
+    // tab separated case description, the key to compare runs
+    case: String,
+    ns_per_frame: f64,
+}
+
+fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    let qualities: Vec<Quality> = if args.flag_quality == "all" {
+        Quality::all()
+    } else {
+        args.flag_quality.split(',').map(|name| Quality::from_name(name).unwrap_or_else(|| {
+            eprintln!("{}", config::error("quality", format!("Unknown resampler quality: {}", name)));
+            process::exit(1);
+        })).collect()
+    };
+    let channels: Vec<usize> = args.flag_channels.split(',').map(|c| match c.parse() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            eprintln!("{}", config::error("channels", format!("Invalid channel count: {}", c)));
+            process::exit(1);
+        }
+    }).collect();
+    let rates: Vec<(u32, u32)> = args.flag_rates.split(',').map(|pair| {
+        let mut rates = pair.splitn(2, ':').map(|r| r.parse::<u32>().ok().filter(|&r| r > 0));
+        match (rates.next(), rates.next()) {
+            (Some(Some(input)), Some(Some(output))) => (input, output),
+            _ => {
+                eprintln!("{}", config::error("rates", format!("Invalid conversion: {}, expected <input Hz>:<output Hz>", pair)));
+                process::exit(1);
+            }
+        }
+    }).collect();
+
+    if let Some(ref name) = args.flag_simd {
+        let isa = simd::Isa::from_name(name).ok_or_else(|| format!("Unknown vector kernels: {}", name));
+        isa.and_then(simd::set).unwrap_or_else(|e| {
+            eprintln!("{}", config::error("simd", e));
+            process::exit(1);
+        });
+    }
+    let baseline = args.flag_baseline.as_ref().map(|path| read_results(path).unwrap_or_else(|e| {
+        eprintln!("Cannot read {}: {}", path, e);
+        process::exit(1);
+    }));
+
+    println!("kernels: {}, drift: {} ppm, block: {} frames", simd::isa().name(), args.flag_drift, args.flag_block_size);
+    println!("{:<9} {:<14} {:>8} {:>13} {:>11} {:>12} {:>10}",
+             "bench", "quality", "channels", "rates", "ns/frame", "ns/sample", "headroom");
+
+    let mut results = Vec::new();
+    for &(input_rate, output_rate) in &rates {
+        for &quality in &qualities {
+            for &n in &channels {
+                let ns = bench_resampler(quality, n, input_rate, output_rate, args.flag_drift,
+                                         args.flag_block_size, args.flag_time);
+                results.push(report("resampler", quality, n, input_rate, output_rate, ns, &baseline));
+            }
+        }
+    }
+
+    if args.flag_pipeline_seconds > 0.0 {
+        let (input_rate, output_rate) = rates[0];
+        for &quality in &qualities {
+            for &n in &channels {
+                let ns = bench_pipeline(quality, n, input_rate, output_rate, args.flag_pipeline_seconds);
+                results.push(report("pipeline", quality, n, input_rate, output_rate, ns, &baseline));
+            }
+        }
+    }
+
+    if let Some(ref path) = args.flag_save {
+        let text: String = results.iter().map(|r| format!("{}\t{:.3}\n", r.case, r.ns_per_frame)).collect();
+        fs::write(path, text).unwrap_or_else(|e| {
+            eprintln!("Cannot write {}: {}", path, e);
+            process::exit(1);
+        });
+    }
+
+    if let Some(baseline) = baseline {
+        let limit = 1.0 + args.flag_tolerance / 100.0;
+        let regressions = results.iter()
+            .filter(|r| baseline.get(&r.case).map_or(false, |&before| r.ns_per_frame > before * limit))
+            .count();
+        let compared = results.iter().filter(|r| baseline.contains_key(&r.case)).count();
+        println!("{} of {} cases compared to the baseline slower by more than {}%",
+                 regressions, compared, args.flag_tolerance);
+        if regressions > 0 {
+            process::exit(1);
+        }
+    }
+}
+
+// Prints a result line, with the change to the baseline when there is one.
+fn report(bench: &str, quality: Quality, channels: usize, input_rate: u32, output_rate: u32, ns: f64,
+          baseline: &Option<HashMap<String, f64>>) -> Measurement {
+    let case = format!("{}\t{}\t{}\t{}:{}", bench, quality.name(), channels, input_rate, output_rate);
+    let headroom = 1e9 / output_rate as f64 / ns;
+    let change = match baseline.as_ref().and_then(|b| b.get(&case)) {
+        Some(&before) => format!(" {:+6.1}%", (ns / before - 1.0) * 100.0),
+        None => String::new(),
+    };
+    println!("{:<9} {:<14} {:>8} {:>13} {:>11.1} {:>12.2} {:>9.1}x{}",
+             bench, quality.name(), channels, format!("{}:{}", input_rate, output_rate),
+             ns, ns / channels as f64, headroom, change);
+    Measurement { case, ns_per_frame: ns }
+}
+
+// Wall time per output frame of the resampler alone.
+fn bench_resampler(quality: Quality, channels: usize, input_rate: u32, output_rate: u32, drift: f64,
+                   block: usize, time: f64) -> f64 {
+    let nominal = output_rate as f64 / input_rate as f64;
+    let ratio = nominal * (1.0 + drift * 1e-6);
+    let mut resampler = resampler::new(channels, quality, nominal);
+    resampler.set_ratio(ratio);
+
+    // white noise, so that nothing is faster on silence
+    let mut seed = 1u32;
+    let input: Vec<f32> = (0..block * channels).map(|_| {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed as f64 / u32::MAX as f64 - 0.5) as f32
+    }).collect();
+    let mut output = vec![0.0f32; ((block as f64 * ratio).ceil() as usize + 2) * channels];
+
+    let mut run = |blocks: usize| -> u64 {
+        let mut produced = 0;
+        for _ in 0..blocks {
+            let mut pos = 0;
+            while pos < block {
+                let (consumed, frames) = resampler.process(&input[pos * channels..], &mut output);
+                pos += consumed;
+                produced += frames as u64;
+            }
+        }
+        produced
+    };
+
+    // warm up, then as many blocks as fit in the time
+    let start = Instant::now();
+    let mut blocks = 1;
+    while seconds(start.elapsed()) < time / 10.0 {
+        run(blocks);
+        blocks *= 2;
+    }
+    let start = Instant::now();
+    let mut produced = 0;
+    while seconds(start.elapsed()) < time {
+        produced += run(blocks);
+    }
+    seconds(start.elapsed()) * 1e9 / produced as f64
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+// Process CPU time per output frame of the engine between fast simulated devices.
+fn bench_pipeline(quality: Quality, channels: usize, input_rate: u32, output_rate: u32, seconds: f64) -> f64 {
+    let fast = || SimOptions { ppm: 0.0, fast: true, tone: Some(997.0), replay: None };
+    let config = |rate| Config {
+        channels,
+        rate,
+        format: SampleFormat::F32,
+        period_size: 256,
+        periods: 2,
+    };
+    let capture = SimCapture::new(config(input_rate), fast());
+    let playback = SimPlayback::new(config(output_rate), fast());
+    let engine = Engine::new(Box::new(capture), Box::new(playback), EngineConfig {
+        block_size: 0,
+        quality,
+        overflow: Overflow::DropOldest,
+        underflow: Underflow::Silence,
+        fifo_target: 0,
+        drift_control: true,
+        max_ppm: 1000.0,
+        duration: seconds,
+        rt: RtConfig::from_options("other", 0, &None, false).unwrap(),
+    });
+
+    let start = cpu_time();
+    let running = engine.start(|input: &[f32], output: &mut [f32], _frames: usize| {
+        output.copy_from_slice(input);
+    });
+    let stats = running.wait();
+    (cpu_time() - start) * 1e9 / stats.played_frames.max(1) as f64
+}
+
+fn cpu_time() -> f64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts);
+    }
+    ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9
+}
+
+fn read_results(path: &str) -> std::io::Result<HashMap<String, f64>> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(text.lines()
+        .filter_map(|line| {
+            let split = line.rfind('\t')?;
+            let ns = line[split + 1..].parse().ok()?;
+            Some((line[..split].to_string(), ns))
+        })
+        .collect())
+}
@@ -0,0 +1,96 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+
+mod config;
+
+use docopt::Docopt;
+use std::io::prelude::*;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::process;
+
+const USAGE: &str = "
+ASRC control client
+
+Sends a command to the control socket of a running alsa-asrc-loopback,
+alsa-aggregate or alsa-fanout and prints the answer.
+
+Usage:
+  asrc-ctl [--config=<file>] [--socket=<path>] status
+  asrc-ctl [--config=<file>] [--socket=<path>] set-target-latency <ms>
+  asrc-ctl [--config=<file>] [--socket=<path>] set-loop-bandwidth <hz>
+  asrc-ctl [--config=<file>] [--socket=<path>] freeze-ratio [on | off]
+  asrc-ctl [--config=<file>] [--socket=<path>] reset
+  asrc-ctl (-h | --help)
+
+Commands:
+  status                Fill level, ratio, estimated drift, xruns and FIFO events.
+  set-target-latency    FIFO latency in ms, reached at once by dropping or inserting frames.
+  set-loop-bandwidth    Drift controller bandwidth in Hz.
+  freeze-ratio          Keep the current ratio, or follow the drift again with off.
+  reset                 Go back to the nominal ratio and lock onto the current fill.
+
+Options:
+  -h --help             Show this screen.
+  --config=<file>       Read the options from a TOML file, command line flags take precedence.
+  --socket=<path>       Control socket given to --control-socket [default: /tmp/asrc.sock].
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_status: bool,
+    cmd_set_target_latency: bool,
+    cmd_set_loop_bandwidth: bool,
+    cmd_freeze_ratio: bool,
+    cmd_off: bool,
+    cmd_reset: bool,
+    arg_ms: Option<f64>,
+    arg_hz: Option<f64>,
+    flag_socket: String,
+}
+
+fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    let command = if args.cmd_status {
+        "status".to_string()
+    } else if args.cmd_set_target_latency {
+        format!("set-target-latency {}", args.arg_ms.unwrap())
+    } else if args.cmd_set_loop_bandwidth {
+        format!("set-loop-bandwidth {}", args.arg_hz.unwrap())
+    } else if args.cmd_freeze_ratio {
+        format!("freeze-ratio {}", if args.cmd_off { "off" } else { "on" })
+    } else if args.cmd_reset {
+        "reset".to_string()
+    } else {
+        unreachable!()
+    };
+
+    let mut stream = UnixStream::connect(&args.flag_socket).unwrap_or_else(|e| {
+        eprintln!("Cannot connect to {}: {}", args.flag_socket, e);
+        process::exit(1);
+    });
+    let mut answer = String::new();
+    writeln!(stream, "{}", command)
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .and_then(|_| stream.read_to_string(&mut answer))
+        .unwrap_or_else(|e| {
+            eprintln!("Control socket error: {}", e);
+            process::exit(1);
+        });
+
+    if answer.starts_with("error: ") {
+        eprint!("{}", answer);
+        process::exit(1);
+    }
+    print!("{}", answer);
+}
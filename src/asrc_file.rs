@@ -0,0 +1,367 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod backend;
+mod config;
+mod interpolator;
+mod polyphase;
+mod realtime_priority;
+mod resampler;
+mod rtp;
+mod simd;
+mod wav;
+
+use docopt::Docopt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::process;
+
+use backend::SampleFormat;
+use resampler::{Quality, Resampler};
+use wav::{WavReader, WavSpec, WavWriter};
+
+const USAGE: &str = "
+Offline WAV resampling
+
+Converts a WAV file with the resamplers used live, at a fixed ratio or
+following a ratio curve, e.g. drift measured on a device, to replay it offline.
+The resampler delay is compensated to the nearest output frame.
+
+The curve file has one point per line, fields separated by commas or spaces,
+lines starting with # ignored: a time in seconds of input in the first field
+and the value in the field given by --curve-column, or only the value on each
+line, the lines being --curve-interval seconds apart. The ratio is interpolated linearly between
+points and held before the first and after the last one. The value, in the
+unit --curve-unit names, is:
+  ratio  output rate / input rate, for the whole conversion
+  ppm    drift on top of the nominal ratio of the file rates
+  hz     actual input rate, e.g. the rate alsa-audio-time -w measured
+alsa-audio-time -w writes a rate per period, the interval is then the period
+time of that recording: period size / rate, e.g. 0.005333 for 256 frames at
+48 kHz.
+
+Usage:
+  asrc-file [--config=<file>] [--output-rate=<Hz> --ratio=<ratio> --curve=<file> --curve-unit=<unit> --curve-column=<index> --curve-interval=<seconds> --resampler=<quality> --format=<format> --block-size=<frames>] <input> <output>
+  asrc-file (-h | --help)
+
+Options:
+  -h --help                   Show this screen.
+  --config=<file>             Read the options from a TOML file, command line flags take precedence.
+  -r --output-rate=<Hz>       Sample rate written to the output file, the input rate by default.
+  --ratio=<ratio>             Conversion ratio, output rate / input rate by default.
+  --curve=<file>              Time varying ratio, replaces --ratio.
+  --curve-unit=<unit>         Curve values: ratio, ppm or hz, needed with --curve.
+  --curve-column=<index>      Field of the value, counted from 0, when lines have several [default: 1].
+  --curve-interval=<seconds>  Time between lines with only a value, the period time for alsa-audio-time -w
+                              files [default: 1].
+  --resampler=<quality>       Resampler quality: low, medium, high or best windowed sinc, linear,
+                              hermite, lagrange4, lagrange6, farrow, rational or rational-fixed [default: high].
+  -f --format=<format>        Output sample format: s16, s24, s32 or f32, the input format by default.
+  --block-size=<frames>       Input frames per resampler call, the curve is followed block by block [default: 256].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_input: String,
+    arg_output: String,
+    flag_output_rate: Option<u32>,
+    flag_ratio: Option<f64>,
+    flag_curve: Option<String>,
+    flag_curve_unit: Option<String>,
+    flag_curve_column: usize,
+    flag_curve_interval: f64,
+    flag_resampler: String,
+    flag_format: Option<String>,
+    flag_block_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CurveUnit {
+    Ratio,
+    Ppm,
+    Hz,
+}
+
+// Ratio against input time, from points sorted by time.
+struct Curve {
+    points: Vec<(f64, f64)>,
+}
+
+impl Curve {
+    fn read(path: &str, unit: CurveUnit, column: usize, interval: f64, input_rate: f64,
+            nominal: f64) -> Result<Curve, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+
+        let mut points: Vec<(f64, f64)> = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|f| !f.is_empty())
+                .collect();
+            let parse = |s: &str| s.parse::<f64>().map_err(|_| format!("{}:{}: invalid number {}", path, n + 1, s));
+            let (time, value) = if fields.len() == 1 {
+                (points.len() as f64 * interval, parse(fields[0])?)
+            } else {
+                match fields.get(column) {
+                    Some(field) => (parse(fields[0])?, parse(field)?),
+                    None => return Err(format!("{}:{}: no field {}", path, n + 1, column)),
+                }
+            };
+            let ratio = match unit {
+                CurveUnit::Ratio => value,
+                CurveUnit::Ppm => nominal * (1.0 + value * 1e-6),
+                CurveUnit::Hz => nominal * input_rate / value,
+            };
+            if !ratio.is_finite() || ratio <= 0.0 {
+                return Err(format!("{}:{}: ratio {} out of range", path, n + 1, ratio));
+            }
+            if let Some(&(last, _)) = points.last() {
+                if time < last {
+                    return Err(format!("{}:{}: time going backwards", path, n + 1));
+                }
+            }
+            points.push((time, ratio));
+        }
+        if points.is_empty() {
+            return Err(format!("{}: no points", path));
+        }
+
+        // times relative to the first point
+        let start = points[0].0;
+        for point in points.iter_mut() {
+            point.0 -= start;
+        }
+        Ok(Curve { points })
+    }
+
+    fn ratio(&self, time: f64) -> f64 {
+        let i = self.points.partition_point(|&(t, _)| t <= time);
+        if i == 0 {
+            return self.points[0].1;
+        }
+        if i == self.points.len() {
+            return self.points[i - 1].1;
+        }
+        let (t0, r0) = self.points[i - 1];
+        let (t1, r1) = self.points[i];
+        r0 + (r1 - r0) * (time - t0) / (t1 - t0)
+    }
+
+    fn range(&self) -> (f64, f64) {
+        self.points.iter().fold((f64::MAX, f64::MIN), |(min, max), &(_, r)| (min.min(r), max.max(r)))
+    }
+}
+
+fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    let quality = Quality::from_name(&args.flag_resampler).unwrap_or_else(|| {
+        eprintln!("{}", config::error("resampler", format!("Unknown resampler quality: {}", args.flag_resampler)));
+        process::exit(1);
+    });
+    // no default: ppm and hz curves both hold plain numbers
+    let unit = match args.flag_curve_unit.as_deref() {
+        Some("ratio") => CurveUnit::Ratio,
+        Some("ppm") => CurveUnit::Ppm,
+        Some("hz") => CurveUnit::Hz,
+        None if args.flag_curve.is_none() => CurveUnit::Ratio,
+        None => {
+            eprintln!("--curve needs --curve-unit: ratio, ppm or hz");
+            process::exit(1);
+        }
+        Some(unit) => {
+            eprintln!("{}", config::error("curve-unit", format!("Unknown curve unit: {}, expected ratio, ppm or hz", unit)));
+            process::exit(1);
+        }
+    };
+    if args.flag_block_size == 0 {
+        eprintln!("{}", config::error("block-size", "The block size must be at least 1 frame".to_string()));
+        process::exit(1);
+    }
+
+    let mut reader = WavReader::open(&args.arg_input).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.arg_input, e);
+        process::exit(1);
+    });
+    let input_spec = reader.spec().clone();
+    let channels = input_spec.channels;
+    let input_rate = input_spec.rate as f64;
+
+    let format = match args.flag_format {
+        Some(ref name) => SampleFormat::from_name(name).unwrap_or_else(|| {
+            eprintln!("{}", config::error("format", format!("Unknown sample format: {}", name)));
+            process::exit(1);
+        }),
+        None => input_spec.format,
+    };
+    let output_rate = args.flag_output_rate.unwrap_or(input_spec.rate);
+    let nominal = output_rate as f64 / input_rate;
+
+    let curve = args.flag_curve.as_ref().map(|path| {
+        Curve::read(path, unit, args.flag_curve_column, args.flag_curve_interval, input_rate, nominal)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            })
+    });
+    let fixed = args.flag_ratio.unwrap_or(nominal);
+    if !fixed.is_finite() || fixed <= 0.0 {
+        eprintln!("{}", config::error("ratio", format!("Invalid ratio: {}", fixed)));
+        process::exit(1);
+    }
+    let ratio_at = |time: f64| curve.as_ref().map_or(fixed, |c| c.ratio(time));
+    if curve.is_some() && quality == Quality::RationalFixed {
+        eprintln!("{}", config::error("resampler", "rational-fixed cannot follow a ratio curve".to_string()));
+        process::exit(1);
+    }
+
+    let spec = WavSpec { channels, rate: output_rate, format, channel_mask: input_spec.channel_mask };
+    let mut writer = WavWriter::create(&args.arg_output, spec).unwrap_or_else(|e| {
+        eprintln!("Cannot create {}: {}", args.arg_output, e);
+        process::exit(1);
+    });
+
+    eprintln!("{}: {} channels, {} Hz, {}", args.arg_input, channels, input_spec.rate, input_spec.format.name());
+    match curve {
+        Some(ref curve) => {
+            let (min, max) = curve.range();
+            eprintln!("Ratio curve: {} points, {:.9}..{:.9}", curve.points.len(), min, max);
+        }
+        None => eprintln!("Ratio: {:.9}", fixed),
+    }
+
+    // made for the lowest ratio of the curve, so that the cutoff suits all of a drift curve
+    let (min_ratio, max_ratio) = curve.as_ref().map_or((fixed, fixed), |c| c.range());
+    let mut resampler = resampler::new(channels, quality, min_ratio);
+    resampler.set_ratio(ratio_at(0.0));
+    let block = args.flag_block_size;
+    let mut input = vec![0.0f32; block * channels];
+    let mut output = vec![0.0f32; ((block as f64 * max_ratio).ceil() as usize + 2) * channels];
+
+    // the first output frames are the resampler delay, dropped
+    let mut skip = (resampler.latency() * ratio_at(0.0)).round() as usize;
+    let mut read_frames = 0u64;
+    // output frames due for the input read so far
+    let mut expected = 0.0;
+    let mut written = 0u64;
+
+    let mut convert = |resampler: &mut dyn Resampler, input: &[f32], expected: f64, written: &mut u64| {
+        let mut pos = 0;
+        while pos < input.len() {
+            let (consumed, produced) = resampler.process(&input[pos..], &mut output);
+            pos += consumed * channels;
+            let dropped = produced.min(skip);
+            skip -= dropped;
+            let due = (expected.round() as u64).saturating_sub(*written) as usize;
+            let keep = (produced - dropped).min(due);
+            writer.write(&output[dropped * channels..(dropped + keep) * channels]).unwrap_or_else(|e| {
+                eprintln!("Cannot write {}: {}", args.arg_output, e);
+                process::exit(1);
+            });
+            *written += keep as u64;
+        }
+    };
+
+    loop {
+        let frames = reader.read(&mut input).unwrap_or_else(|e| {
+            eprintln!("Cannot read {}: {}", args.arg_input, e);
+            process::exit(1);
+        });
+        if frames == 0 {
+            break;
+        }
+        let ratio = ratio_at(read_frames as f64 / input_rate);
+        resampler.set_ratio(ratio);
+        read_frames += frames as u64;
+        expected += frames as f64 * ratio;
+        convert(&mut *resampler, &input[..frames * channels], expected, &mut written);
+    }
+
+    // zeros through the delay line for the last frames
+    for x in input.iter_mut() {
+        *x = 0.0;
+    }
+    let mut flushed = 0;
+    while written < expected.round() as u64 && flushed < resampler.latency() as usize * 2 + block {
+        convert(&mut *resampler, &input, expected, &mut written);
+        flushed += block;
+    }
+
+    writer.finish().unwrap_or_else(|e| {
+        eprintln!("Cannot write {}: {}", args.arg_output, e);
+        process::exit(1);
+    });
+    eprintln!("{}: {} frames in, {} frames out, {} Hz, {}", args.arg_output, read_frames, written,
+              output_rate, format.name());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn read(name: &str, text: &str, unit: CurveUnit, column: usize) -> Result<Curve, String> {
+        let path = env::temp_dir().join(format!("curve-{}-{}.txt", process::id(), name));
+        fs::write(&path, text).unwrap();
+        let curve = Curve::read(path.to_str().unwrap(), unit, column, 0.5, 44100.0, 160.0 / 147.0);
+        fs::remove_file(&path).unwrap();
+        curve.map_err(|e| e.replace(path.to_str().unwrap(), "file"))
+    }
+
+    #[test]
+    fn ratio_between_points() {
+        let curve = read("ratio", "# time ratio\n10 1.0\n\n12, 1.5\n13\t1.5 0.25\n", CurveUnit::Ratio, 1).unwrap();
+        assert_eq!(curve.points, [(0.0, 1.0), (2.0, 1.5), (3.0, 1.5)]);
+        assert_eq!(curve.ratio(-1.0), 1.0);
+        assert_eq!(curve.ratio(1.0), 1.25);
+        assert_eq!(curve.ratio(2.5), 1.5);
+        assert_eq!(curve.ratio(10.0), 1.5);
+        assert_eq!(curve.range(), (1.0, 1.5));
+
+        let curve = read("column", "0 1.0 2.0\n1 1.0 3.0\n", CurveUnit::Ratio, 2).unwrap();
+        assert_eq!(curve.points, [(0.0, 2.0), (1.0, 3.0)]);
+    }
+
+    #[test]
+    fn units() {
+        let nominal = 160.0 / 147.0;
+        let curve = read("ppm", "0 100\n1 -50\n", CurveUnit::Ppm, 1).unwrap();
+        assert_eq!(curve.points, [(0.0, nominal * (1.0 + 100e-6)), (1.0, nominal * (1.0 - 50e-6))]);
+        // a faster input asks for a lower ratio
+        let curve = read("hz", "0 44104.41\n", CurveUnit::Hz, 1).unwrap();
+        assert_eq!(curve.points, [(0.0, nominal * 44100.0 / 44104.41)]);
+    }
+
+    #[test]
+    fn values_every_interval() {
+        let curve = read("interval", "1.0\n1.5\n# gap\n2.0\n", CurveUnit::Ratio, 1).unwrap();
+        assert_eq!(curve.points, [(0.0, 1.0), (0.5, 1.5), (1.0, 2.0)]);
+    }
+
+    #[test]
+    fn invalid_curves() {
+        assert_eq!(read("backwards", "0 1\n2 1\n1 1\n", CurveUnit::Ratio, 1).err().unwrap(),
+                   "file:3: time going backwards");
+        assert_eq!(read("number", "0 1\n1 x\n", CurveUnit::Ratio, 1).err().unwrap(), "file:2: invalid number x");
+        assert_eq!(read("field", "0 1\n", CurveUnit::Ratio, 2).err().unwrap(), "file:1: no field 2");
+        assert_eq!(read("range", "0 -1\n", CurveUnit::Ratio, 1).err().unwrap(), "file:1: ratio -1 out of range");
+        assert_eq!(read("empty", "# nothing\n", CurveUnit::Ratio, 1).err().unwrap(), "file: no points");
+    }
+}
@@ -0,0 +1,1080 @@
+use alsa::{Direction, ValueOr};
+use alsa::pcm::{PCM, HwParams, Format, Access, TstampType};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::io::prelude::*;
+use std::thread;
+use std::time::Duration;
+
+use rtp::{RtpCapture, RtpOptions, RtpPlayback};
+use wav::{self, WavReader, WavWriter, WavSpec};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    S16,
+    S24,
+    S32,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn from_name(name: &str) -> Option<SampleFormat> {
+        match name {
+            "s16" => Some(SampleFormat::S16),
+            "s24" => Some(SampleFormat::S24),
+            "s32" => Some(SampleFormat::S32),
+            "f32" => Some(SampleFormat::F32),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SampleFormat::S16 => "s16",
+            SampleFormat::S24 => "s24",
+            SampleFormat::S32 => "s32",
+            SampleFormat::F32 => "f32",
+        }
+    }
+
+    // bytes per sample in raw files
+    pub fn bytes(&self) -> usize {
+        match *self {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::S32 | SampleFormat::F32 => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    XRun,
+    EndOfStream,
+    Device(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::XRun => write!(f, "xrun"),
+            Error::EndOfStream => write!(f, "end of stream"),
+            Error::Device(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Device(e.to_string())
+    }
+}
+
+impl From<alsa::Error> for Error {
+    fn from(e: alsa::Error) -> Error {
+        Error::Device(e.to_string())
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub channels: usize,
+    pub rate: u32,
+    pub format: SampleFormat,
+    pub period_size: usize,
+    pub periods: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    // monotonic time in seconds the stream was started
+    pub trigger: f64,
+    // monotonic time in seconds the status was taken
+    pub system: f64,
+    // frames between the application and the hardware pointer
+    pub delay: i64,
+}
+
+// Audio source delivering interleaved f32 frames.
+pub trait Capture: Send {
+    fn config(&self) -> &Config;
+
+    fn start(&mut self) -> Result<()>;
+
+    // returns the number of frames read
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize>;
+
+    fn timestamp(&self) -> Option<Timestamp>;
+
+    // false when frames are not paced by a clock, e.g. files
+    fn clocked(&self) -> bool {
+        true
+    }
+}
+
+// Audio sink accepting interleaved f32 frames.
+pub trait Playback: Send {
+    fn config(&self) -> &Config;
+
+    // returns the number of frames written
+    fn write(&mut self, buf: &[f32]) -> Result<usize>;
+
+    fn drain(&mut self) -> Result<()>;
+
+    fn timestamp(&self) -> Option<Timestamp>;
+
+    // false when frames are not paced by a clock, e.g. files
+    fn clocked(&self) -> bool {
+        true
+    }
+}
+
+// device is an ALSA device name, file:<path>, sim[:<options>] or rtp:<address>:<port>[,<options>]
+// Files ending in .wav are RIFF WAV, anything else raw interleaved samples.
+pub fn open_capture(device: &str, config: Config) -> Result<Box<dyn Capture>> {
+    if let Some(path) = strip_prefix(device, "file:") {
+        if is_wav(path) {
+            Ok(Box::new(WavCapture::open(path, config)?))
+        } else {
+            Ok(Box::new(RawFileCapture::open(path, config)?))
+        }
+    } else if let Some(options) = sim_options(device) {
+        Ok(Box::new(SimCapture::new(config, SimOptions::parse(options)?)))
+    } else if let Some(options) = strip_prefix(device, "rtp:") {
+        Ok(Box::new(RtpCapture::open(RtpOptions::parse(options)?, config)?))
+    } else {
+        Ok(Box::new(AlsaCapture::open(device, config)?))
+    }
+}
+
+pub fn open_playback(device: &str, config: Config) -> Result<Box<dyn Playback>> {
+    if let Some(path) = strip_prefix(device, "file:") {
+        if is_wav(path) {
+            Ok(Box::new(WavPlayback::create(path, config)?))
+        } else {
+            Ok(Box::new(RawFilePlayback::create(path, config)?))
+        }
+    } else if let Some(options) = sim_options(device) {
+        Ok(Box::new(SimPlayback::new(config, SimOptions::parse(options)?)))
+    } else if let Some(options) = strip_prefix(device, "rtp:") {
+        Ok(Box::new(RtpPlayback::open(RtpOptions::parse(options)?, config)?))
+    } else {
+        Ok(Box::new(AlsaPlayback::open(device, config)?))
+    }
+}
+
+fn strip_prefix<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    if s.starts_with(prefix) { Some(&s[prefix.len()..]) } else { None }
+}
+
+fn is_wav(path: &str) -> bool {
+    path.to_lowercase().ends_with(".wav")
+}
+
+fn sim_options(device: &str) -> Option<&str> {
+    if device == "sim" { Some("") } else { strip_prefix(device, "sim:") }
+}
+
+pub fn monotonic_time() -> f64 {
+    let mut ts = ::libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        ::libc::clock_gettime(::libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9
+}
+
+fn timespec_f64(ts: ::libc::timespec) -> f64 {
+    ts.tv_sec as f64 + (ts.tv_nsec as f64) / 1e9
+}
+
+/*
+ * Sample conversion
+ */
+
+// Integer samples map to x / 2^(bits - 1) and back, so that integer data
+// going through f32 comes out bit exact (up to 24 bits).
+
+pub fn f32_to_i16(x: f32) -> i16 {
+    (x as f64 * 32768.0).round().max(-32768.0).min(32767.0) as i16
+}
+
+pub fn f32_to_i24(x: f32) -> i32 {
+    (x as f64 * 8388608.0).round().max(-8388608.0).min(8388607.0) as i32
+}
+
+pub fn f32_to_i32(x: f32) -> i32 {
+    (x as f64 * 2147483648.0).round().max(-2147483648.0).min(2147483647.0) as i32
+}
+
+pub fn i16_to_f32(x: i16) -> f32 {
+    x as f32 / 32768.0
+}
+
+pub fn i24_to_f32(x: i32) -> f32 {
+    (x as f64 / 8388608.0) as f32
+}
+
+pub fn i32_to_f32(x: i32) -> f32 {
+    (x as f64 / 2147483648.0) as f32
+}
+
+/*
+ * ALSA
+ */
+
+fn setup_pcm(device: &str, direction: Direction, config: &mut Config) -> Result<PCM> {
+    let pcm = PCM::new(device, direction, false)?;
+    {
+        let hwp = HwParams::any(&pcm)?;
+        hwp.set_channels(config.channels as u32)?;
+        hwp.set_rate(config.rate, ValueOr::Nearest)?;
+        hwp.set_format(match config.format {
+            SampleFormat::S16 => Format::s16(),
+            SampleFormat::S24 => Format::s24(),
+            SampleFormat::S32 => Format::s32(),
+            SampleFormat::F32 => Format::float(),
+        })?;
+        hwp.set_access(Access::RWInterleaved)?;
+        #[cfg(target_pointer_width = "32")]
+        hwp.set_period_size(config.period_size as i32, ValueOr::Nearest)?;
+        #[cfg(target_pointer_width = "64")]
+        hwp.set_period_size(config.period_size as i64, ValueOr::Nearest)?;
+        hwp.set_periods(config.periods, ValueOr::Nearest)?;
+        pcm.hw_params(&hwp)?;
+
+        let hwp = pcm.hw_params_current()?;
+        config.rate = hwp.get_rate()?;
+        config.period_size = hwp.get_period_size()? as usize;
+        config.periods = hwp.get_periods()?;
+    }
+    {
+        let swp = pcm.sw_params_current()?;
+        swp.set_tstamp_mode(true)?;
+        // the same clock as monotonic_time(), not the default wall clock
+        swp.set_tstamp_type(TstampType::Monotonic)?;
+        if direction == Direction::Playback {
+            // start once the whole buffer is filled so that the first written frame
+            // is also the first one played
+            let hwp = pcm.hw_params_current()?;
+            swp.set_start_threshold(hwp.get_buffer_size()?)?;
+        }
+        pcm.sw_params(&swp)?;
+    }
+
+    Ok(pcm)
+}
+
+fn alsa_timestamp(pcm: &PCM) -> Option<Timestamp> {
+    pcm.status().ok().map(|status| Timestamp {
+        trigger: timespec_f64(status.get_trigger_htstamp()),
+        system: timespec_f64(status.get_htstamp()),
+        delay: status.get_delay() as i64,
+    })
+}
+
+// Interleaved buffer in the device sample format.
+enum AlsaBuffer {
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+}
+
+impl AlsaBuffer {
+    fn new(format: SampleFormat, len: usize) -> AlsaBuffer {
+        match format {
+            SampleFormat::S16 => AlsaBuffer::I16(vec![0; len]),
+            SampleFormat::S24 | SampleFormat::S32 => AlsaBuffer::I32(vec![0; len]),
+            SampleFormat::F32 => AlsaBuffer::F32(vec![0.0; len]),
+        }
+    }
+}
+
+pub struct AlsaCapture {
+    pcm: PCM,
+    config: Config,
+    buf: AlsaBuffer,
+}
+
+impl AlsaCapture {
+    pub fn open(device: &str, mut config: Config) -> Result<AlsaCapture> {
+        let pcm = setup_pcm(device, Direction::Capture, &mut config)?;
+        let len = config.period_size * config.periods as usize * config.channels;
+        Ok(AlsaCapture { pcm, buf: AlsaBuffer::new(config.format, len), config })
+    }
+
+    fn recover(&self, e: alsa::Error) -> Error {
+        match self.pcm.try_recover(e, true).and_then(|_| self.pcm.start()) {
+            Ok(_) => Error::XRun,
+            Err(e) => Error::from(e),
+        }
+    }
+}
+
+impl Capture for AlsaCapture {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(self.pcm.start()?)
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize> {
+        let len = buf.len().min(self.config.period_size * self.config.periods as usize
+            * self.config.channels);
+        let format = self.config.format;
+        let result = match self.buf {
+            AlsaBuffer::I16(ref mut b) => self.pcm.io_i16()?.readi(&mut b[..len]),
+            AlsaBuffer::I32(ref mut b) => self.pcm.io_i32()?.readi(&mut b[..len]),
+            AlsaBuffer::F32(ref mut b) => self.pcm.io_f32()?.readi(&mut b[..len]),
+        };
+
+        let frames = match result {
+            Ok(frames) => frames,
+            Err(e) => return Err(self.recover(e)),
+        };
+
+        let samples = frames * self.config.channels;
+        match self.buf {
+            AlsaBuffer::I16(ref b) => for i in 0..samples {
+                buf[i] = i16_to_f32(b[i]);
+            },
+            // S24 sits in the low bits of 32, the top byte need not carry the sign
+            AlsaBuffer::I32(ref b) if format == SampleFormat::S24 => for i in 0..samples {
+                buf[i] = i24_to_f32((b[i] << 8) >> 8);
+            },
+            AlsaBuffer::I32(ref b) => for i in 0..samples {
+                buf[i] = i32_to_f32(b[i]);
+            },
+            AlsaBuffer::F32(ref b) => buf[..samples].copy_from_slice(&b[..samples]),
+        }
+
+        Ok(frames)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        alsa_timestamp(&self.pcm)
+    }
+}
+
+pub struct AlsaPlayback {
+    pcm: PCM,
+    config: Config,
+    buf: AlsaBuffer,
+}
+
+impl AlsaPlayback {
+    pub fn open(device: &str, mut config: Config) -> Result<AlsaPlayback> {
+        let pcm = setup_pcm(device, Direction::Playback, &mut config)?;
+        let len = config.period_size * config.periods as usize * config.channels;
+        Ok(AlsaPlayback { pcm, buf: AlsaBuffer::new(config.format, len), config })
+    }
+}
+
+impl Playback for AlsaPlayback {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn write(&mut self, buf: &[f32]) -> Result<usize> {
+        let len = buf.len().min(self.config.period_size * self.config.periods as usize
+            * self.config.channels);
+        let format = self.config.format;
+        let result = match self.buf {
+            AlsaBuffer::I16(ref mut b) => {
+                for i in 0..len {
+                    b[i] = f32_to_i16(buf[i]);
+                }
+                self.pcm.io_i16()?.writei(&b[..len])
+            }
+            AlsaBuffer::I32(ref mut b) => {
+                for i in 0..len {
+                    b[i] = if format == SampleFormat::S24 { f32_to_i24(buf[i]) } else { f32_to_i32(buf[i]) };
+                }
+                self.pcm.io_i32()?.writei(&b[..len])
+            }
+            AlsaBuffer::F32(ref mut b) => {
+                b[..len].copy_from_slice(&buf[..len]);
+                self.pcm.io_f32()?.writei(&b[..len])
+            }
+        };
+
+        match result {
+            Ok(frames) => Ok(frames),
+            Err(e) => match self.pcm.try_recover(e, true) {
+                Ok(_) => Err(Error::XRun),
+                Err(e) => Err(Error::from(e)),
+            },
+        }
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        Ok(self.pcm.drain()?)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        alsa_timestamp(&self.pcm)
+    }
+}
+
+/*
+ * Raw interleaved little endian sample files
+ */
+
+pub fn encode_sample(out: &mut Vec<u8>, x: f32, format: SampleFormat) {
+    match format {
+        SampleFormat::S16 => {
+            let v = f32_to_i16(x) as u16;
+            out.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+        }
+        SampleFormat::S24 => {
+            let v = f32_to_i24(x) as u32;
+            out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8]);
+        }
+        SampleFormat::S32 => {
+            let v = f32_to_i32(x) as u32;
+            out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+        }
+        SampleFormat::F32 => {
+            let v = x.to_bits();
+            out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+        }
+    }
+}
+
+pub fn decode_sample(b: &[u8], format: SampleFormat) -> f32 {
+    match format {
+        SampleFormat::S16 => i16_to_f32((b[0] as u16 | (b[1] as u16) << 8) as i16),
+        SampleFormat::S24 => {
+            let v = (b[0] as u32) << 8 | (b[1] as u32) << 16 | (b[2] as u32) << 24;
+            i24_to_f32(v as i32 >> 8)
+        }
+        SampleFormat::S32 => {
+            i32_to_f32((b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24) as i32)
+        }
+        SampleFormat::F32 => {
+            f32::from_bits(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+        }
+    }
+}
+
+pub struct RawFileCapture {
+    reader: BufReader<File>,
+    config: Config,
+    bytes: Vec<u8>,
+}
+
+impl RawFileCapture {
+    pub fn open(path: &str, config: Config) -> Result<RawFileCapture> {
+        let bytes = vec![0; config.period_size * config.channels * config.format.bytes()];
+        Ok(RawFileCapture { reader: BufReader::new(File::open(path)?), config, bytes })
+    }
+}
+
+impl Capture for RawFileCapture {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize> {
+        let sample_bytes = self.config.format.bytes();
+        let frame_bytes = sample_bytes * self.config.channels;
+        let len = (buf.len() / self.config.channels * frame_bytes).min(self.bytes.len());
+
+        let mut filled = 0;
+        while filled < len {
+            match self.reader.read(&mut self.bytes[filled..len])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+
+        let frames = filled / frame_bytes;
+        if frames == 0 {
+            return Err(Error::EndOfStream);
+        }
+        for i in 0..frames * self.config.channels {
+            buf[i] = decode_sample(&self.bytes[i * sample_bytes..], self.config.format);
+        }
+        Ok(frames)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        None
+    }
+
+    fn clocked(&self) -> bool {
+        false
+    }
+}
+
+pub struct RawFilePlayback {
+    writer: BufWriter<File>,
+    config: Config,
+    bytes: Vec<u8>,
+}
+
+impl RawFilePlayback {
+    pub fn create(path: &str, config: Config) -> Result<RawFilePlayback> {
+        let bytes = Vec::with_capacity(config.period_size * config.channels * config.format.bytes());
+        Ok(RawFilePlayback { writer: BufWriter::new(File::create(path)?), config, bytes })
+    }
+}
+
+impl Playback for RawFilePlayback {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn write(&mut self, buf: &[f32]) -> Result<usize> {
+        self.bytes.clear();
+        for &x in buf {
+            encode_sample(&mut self.bytes, x, self.config.format);
+        }
+        self.writer.write_all(&self.bytes)?;
+        Ok(buf.len() / self.config.channels)
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        None
+    }
+
+    fn clocked(&self) -> bool {
+        false
+    }
+}
+
+/*
+ * RIFF WAV files
+ */
+
+pub struct WavCapture {
+    reader: WavReader,
+    config: Config,
+}
+
+impl WavCapture {
+    // channels, rate and format come from the file
+    pub fn open(path: &str, mut config: Config) -> Result<WavCapture> {
+        let reader = WavReader::open(path)?;
+        config.channels = reader.spec().channels;
+        config.rate = reader.spec().rate;
+        config.format = reader.spec().format;
+        Ok(WavCapture { reader, config })
+    }
+}
+
+impl Capture for WavCapture {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize> {
+        let len = buf.len().min(self.config.period_size * self.config.channels);
+        match self.reader.read(&mut buf[..len])? {
+            0 => Err(Error::EndOfStream),
+            frames => Ok(frames),
+        }
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        None
+    }
+
+    fn clocked(&self) -> bool {
+        false
+    }
+}
+
+pub struct WavPlayback {
+    writer: WavWriter,
+    config: Config,
+}
+
+impl WavPlayback {
+    pub fn create(path: &str, config: Config) -> Result<WavPlayback> {
+        let spec = WavSpec {
+            channels: config.channels,
+            rate: config.rate,
+            format: config.format,
+            channel_mask: if config.channels > 2 { wav::default_channel_mask(config.channels) } else { 0 },
+        };
+        Ok(WavPlayback { writer: WavWriter::create(path, spec)?, config })
+    }
+}
+
+impl Playback for WavPlayback {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn write(&mut self, buf: &[f32]) -> Result<usize> {
+        Ok(self.writer.write(buf)?)
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        None
+    }
+
+    fn clocked(&self) -> bool {
+        false
+    }
+}
+
+/*
+ * Simulated device running from the system clock
+ */
+
+pub struct SimOptions {
+    // clock deviation from the nominal rate
+    pub ppm: f64,
+    // run as fast as possible instead of in real time
+    pub fast: bool,
+    // capture a sine at this frequency instead of silence
+    pub tone: Option<f64>,
+    // hardware pointer timing recorded on a real device
+    pub replay: Option<ReplayLog>,
+}
+
+impl SimOptions {
+    // comma separated: ppm=<ppm>, fast, tone=<Hz>, replay=<log file>
+    pub fn parse(options: &str) -> Result<SimOptions> {
+        let mut sim = SimOptions { ppm: 0.0, fast: false, tone: None, replay: None };
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            let mut kv = option.splitn(2, '=');
+            let key = kv.next().unwrap();
+            if key == "replay" {
+                if let Some(path) = kv.next() {
+                    sim.replay = Some(ReplayLog::open(path)?);
+                    continue;
+                }
+            }
+            let value = kv.next().map(|v| v.parse::<f64>());
+            match (key, value) {
+                ("ppm", Some(Ok(v))) => sim.ppm = v,
+                ("tone", Some(Ok(v))) => sim.tone = Some(v),
+                ("fast", None) => sim.fast = true,
+                _ => return Err(Error::Device(format!("invalid sim option: {}", option))),
+            }
+        }
+        if sim.fast && sim.replay.is_some() {
+            return Err(Error::Device("sim options fast and replay exclude each other".to_string()));
+        }
+        Ok(sim)
+    }
+}
+
+// Virtual hardware pointer advancing at the simulated rate, or following a
+// replayed log.
+pub struct SimClock {
+    rate: f64,
+    fast: bool,
+    start: Option<f64>,
+    frames: u64,
+    // (seconds since start, hardware frames), scaled to the rate
+    replay: Option<Vec<(f64, f64)>>,
+}
+
+impl SimClock {
+    pub fn new(config: &Config, options: &SimOptions) -> SimClock {
+        SimClock {
+            rate: config.rate as f64 * (1.0 + options.ppm * 1e-6),
+            fast: options.fast,
+            start: None,
+            frames: 0,
+            replay: None,
+        }
+    }
+
+    // Follows the pointer of the stream in the log instead of running at the rate.
+    pub fn replay(&mut self, log: &ReplayLog, direction: Direction, rate: u32) {
+        let scale = self.rate / log.rate.unwrap_or(rate as f64);
+        let points = log.points(direction).iter().map(|&(t, hw)| (t, hw * scale)).collect();
+        self.replay = Some(points);
+    }
+
+    pub fn start(&mut self) {
+        self.start = Some(monotonic_time());
+        self.frames = 0;
+    }
+
+    pub fn started(&self) -> bool {
+        self.start.is_some()
+    }
+
+    // frames elapsed on the device since start
+    pub fn hw_frames(&self) -> u64 {
+        match self.start {
+            Some(start) if !self.fast => {
+                let elapsed = monotonic_time() - start;
+                match self.replay {
+                    Some(ref points) => interpolate(points, elapsed, false) as u64,
+                    None => (elapsed * self.rate) as u64,
+                }
+            }
+            Some(_) => self.frames,
+            None => 0,
+        }
+    }
+
+    pub fn sleep_until(&self, hw_frames: u64) {
+        if self.fast {
+            return;
+        }
+        if let Some(start) = self.start {
+            let wake = start + match self.replay {
+                Some(ref points) => interpolate(points, hw_frames as f64, true),
+                None => hw_frames as f64 / self.rate,
+            };
+            let now = monotonic_time();
+            if wake > now {
+                let wait = wake - now;
+                thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+            }
+        }
+    }
+
+    pub fn timestamp(&self, delay: i64) -> Option<Timestamp> {
+        self.start.map(|trigger| Timestamp { trigger, system: monotonic_time(), delay })
+    }
+}
+
+pub struct SimCapture {
+    config: Config,
+    clock: SimClock,
+    tone: Option<(f64, f64)>,
+}
+
+impl SimCapture {
+    pub fn new(config: Config, options: SimOptions) -> SimCapture {
+        let mut clock = SimClock::new(&config, &options);
+        if let Some(ref log) = options.replay {
+            clock.replay(log, Direction::Capture, config.rate);
+        }
+        let tone = options.tone.map(|f| (f / clock.rate, 0.0));
+        SimCapture { config, clock, tone }
+    }
+}
+
+impl Capture for SimCapture {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.clock.start();
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize> {
+        if self.clock.start.is_none() {
+            self.clock.start();
+        }
+
+        let buffer_size = (self.config.period_size * self.config.periods as usize) as u64;
+        let frames = (buf.len() / self.config.channels).min(buffer_size as usize);
+        let hw = self.clock.hw_frames();
+        if hw > self.clock.frames + buffer_size {
+            self.clock.start();
+            return Err(Error::XRun);
+        }
+
+        self.clock.sleep_until(self.clock.frames + frames as u64);
+        self.clock.frames += frames as u64;
+
+        match self.tone {
+            Some((step, ref mut phase)) => {
+                for frame in buf[..frames * self.config.channels].chunks_mut(self.config.channels) {
+                    let v = (0.5 * (2.0 * ::std::f64::consts::PI * *phase).sin()) as f32;
+                    *phase = (*phase + step).fract();
+                    for x in frame.iter_mut() {
+                        *x = v;
+                    }
+                }
+            }
+            None => for x in buf[..frames * self.config.channels].iter_mut() {
+                *x = 0.0;
+            },
+        }
+
+        Ok(frames)
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        let delay = self.clock.hw_frames() as i64 - self.clock.frames as i64;
+        self.clock.timestamp(delay)
+    }
+
+    fn clocked(&self) -> bool {
+        !self.clock.fast
+    }
+}
+
+pub struct SimPlayback {
+    config: Config,
+    clock: SimClock,
+    written: u64,
+}
+
+impl SimPlayback {
+    pub fn new(config: Config, options: SimOptions) -> SimPlayback {
+        let mut clock = SimClock::new(&config, &options);
+        if let Some(ref log) = options.replay {
+            clock.replay(log, Direction::Playback, config.rate);
+        }
+        SimPlayback { config, clock, written: 0 }
+    }
+}
+
+impl Playback for SimPlayback {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn write(&mut self, buf: &[f32]) -> Result<usize> {
+        let buffer_size = (self.config.period_size * self.config.periods as usize) as u64;
+        let frames = ((buf.len() / self.config.channels) as u64).min(buffer_size);
+
+        match self.clock.start {
+            None => {
+                // starts once the buffer is full, like the ALSA start threshold
+                self.written += frames;
+                if self.written >= buffer_size {
+                    self.clock.start();
+                }
+                return Ok(frames as usize);
+            }
+            Some(_) => {
+                let hw = self.clock.hw_frames();
+                if hw > self.written {
+                    self.clock.start = None;
+                    self.written = 0;
+                    return Err(Error::XRun);
+                }
+                // wait for room in the buffer
+                self.clock.sleep_until(self.written + frames - buffer_size);
+                self.written += frames;
+                if self.clock.fast {
+                    self.clock.frames = self.written - buffer_size;
+                }
+            }
+        }
+
+        Ok(frames as usize)
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        self.clock.sleep_until(self.written);
+        Ok(())
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        let delay = self.written as i64 - self.clock.hw_frames() as i64;
+        self.clock.timestamp(delay)
+    }
+
+    fn clocked(&self) -> bool {
+        !self.clock.fast
+    }
+}
+
+/*
+ * Replayed device timing
+ */
+
+// Hardware pointer positions against time of a real device, from the status
+// lines of a telemetry log or an alsa-audio-time --log file:
+//   <time> <source> status <stream> <frames> <delay> <avail> <avail_max> <audio> <trigger> <system>
+// A "# rate <Hz>" line gives the rate of the device, the log is scaled when
+// replayed at another rate.
+pub struct ReplayLog {
+    pub rate: Option<f64>,
+    // (seconds since the first trigger, hardware frames)
+    capture: Vec<(f64, f64)>,
+    playback: Vec<(f64, f64)>,
+}
+
+impl ReplayLog {
+    pub fn open(path: &str) -> Result<ReplayLog> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| Error::Device(format!("cannot read {}: {}", path, e)))?;
+
+        let mut rate = None;
+        let mut capture = PointerTrack::new();
+        let mut playback = PointerTrack::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() == 3 && fields[0] == "#" && fields[1] == "rate" {
+                rate = fields[2].parse().ok();
+            }
+            if fields.len() != 11 || fields[2] != "status" {
+                continue;
+            }
+            let number = |i: usize| fields[i].parse::<f64>()
+                .map_err(|_| Error::Device(format!("{}: invalid status line: {}", path, line)));
+            let (frames, delay, trigger, system) = (number(4)?, number(5)?, number(9)?, number(10)?);
+            match fields[3] {
+                "capture" => capture.add(trigger, system, frames + delay),
+                "playback" => playback.add(trigger, system, frames - delay),
+                _ => {}
+            }
+        }
+        if capture.points.len() < 2 && playback.points.len() < 2 {
+            return Err(Error::Device(format!("{}: no device status to replay", path)));
+        }
+        Ok(ReplayLog { rate, capture: capture.points, playback: playback.points })
+    }
+
+    // points of the stream, or of the other one when the log has only that
+    pub fn points(&self, direction: Direction) -> &[(f64, f64)] {
+        let (wanted, other) = match direction {
+            Direction::Capture => (&self.capture, &self.playback),
+            Direction::Playback => (&self.playback, &self.capture),
+        };
+        if wanted.len() >= 2 { wanted } else { other }
+    }
+}
+
+// Joins the pointer of successive starts of a stream into one track, the
+// device clock running on at the rate seen so far between an xrun and the
+// restart.
+struct PointerTrack {
+    points: Vec<(f64, f64)>,
+    origin: f64,
+    trigger: f64,
+    offset: f64,
+}
+
+impl PointerTrack {
+    fn new() -> PointerTrack {
+        PointerTrack { points: vec![(0.0, 0.0)], origin: 0.0, trigger: 0.0, offset: 0.0 }
+    }
+
+    fn add(&mut self, trigger: f64, system: f64, hw: f64) {
+        if self.points.len() == 1 {
+            self.origin = trigger;
+            self.trigger = trigger;
+        } else if trigger != self.trigger {
+            let t = trigger - self.origin;
+            let &(last_t, last_hw) = self.points.last().unwrap();
+            let rate = last_hw / last_t;
+            self.offset = last_hw + (t - last_t).max(0.0) * rate;
+            self.points.push((t, self.offset));
+            self.trigger = trigger;
+        }
+        let point = (system - self.origin, self.offset + hw);
+        let &(last_t, last_hw) = self.points.last().unwrap();
+        // statuses out of order or around an xrun would run the clock backwards
+        if point.0 > last_t && point.1 >= last_hw {
+            self.points.push(point);
+        }
+    }
+}
+
+// Linear interpolation of the hardware frames at a time, or the time of
+// hardware frames when inverse, going on at the mean rate past the end.
+fn interpolate(points: &[(f64, f64)], x: f64, inverse: bool) -> f64 {
+    let get = |p: &(f64, f64)| if inverse { (p.1, p.0) } else { (p.0, p.1) };
+    let i = points.partition_point(|p| get(p).0 <= x);
+    let (x0, y0, x1, y1) = if i == 0 || i == points.len() {
+        let (x1, y1) = get(&points[points.len() - 1]);
+        let (x0, y0) = if i == 0 { (0.0, 0.0) } else { (x1, y1) };
+        // along the mean slope from the last point
+        (x0, y0, x0 + x1, y0 + y1)
+    } else {
+        let (x0, y0) = get(&points[i - 1]);
+        let (x1, y1) = get(&points[i]);
+        (x0, y0, x1, y1)
+    };
+    if x1 == x0 { y0 } else { y0 + (y1 - y0) * (x - x0) / (x1 - x0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn pointer_runs_on_through_a_restart() {
+        let mut track = PointerTrack::new();
+        track.add(100.0, 100.5, 24000.0);
+        track.add(100.0, 101.0, 48000.0);
+        // out of order, dropped
+        track.add(100.0, 100.9, 47000.0);
+        // restarted half a second after the last status, the clock ran on at 48 kHz
+        track.add(101.5, 102.0, 24000.0);
+        track.add(101.5, 102.5, 48000.0);
+        assert_eq!(track.points, [(0.0, 0.0), (0.5, 24000.0), (1.0, 48000.0), (1.5, 72000.0), (2.0, 96000.0),
+                                  (2.5, 120000.0)]);
+    }
+
+    #[test]
+    fn interpolates_both_ways() {
+        let points = [(0.0, 0.0), (1.0, 48000.0), (2.0, 96010.0)];
+        assert_eq!(interpolate(&points, 0.5, false), 24000.0);
+        assert_eq!(interpolate(&points, 1.5, false), 72005.0);
+        assert_eq!(interpolate(&points, 24000.0, true), 0.5);
+        assert_eq!(interpolate(&points, 72005.0, true), 1.5);
+        // at the mean rate past the last point
+        assert_eq!(interpolate(&points, 3.0, false), 144015.0);
+        assert_eq!(interpolate(&points, 144015.0, true), 3.0);
+    }
+
+    fn open(name: &str, text: &str) -> Result<ReplayLog> {
+        let path = env::temp_dir().join(format!("replay-{}-{}.log", process::id(), name));
+        fs::write(&path, text).unwrap();
+        let log = ReplayLog::open(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        log
+    }
+
+    #[test]
+    fn replays_a_log_with_a_restart() {
+        let log = open("restart", "# rate 48000
+100.500000 hw:0 status capture 23744 256 256 512 0.5 100.0 100.5
+100.500000 hw:0 period capture 256
+101.000000 hw:0 status capture 47744 256 256 512 1.0 100.0 101.0
+101.200000 hw:0 xrun capture 1
+102.000000 hw:0 status capture 23744 256 256 512 0.5 101.5 102.0
+").unwrap();
+        assert_eq!(log.rate, Some(48000.0));
+        let points = [(0.0, 0.0), (0.5, 24000.0), (1.0, 48000.0), (1.5, 72000.0), (2.0, 96000.0)];
+        assert_eq!(log.points(Direction::Capture), points);
+        // a capture log stands in for the playback
+        assert_eq!(log.points(Direction::Playback), points);
+    }
+
+    #[test]
+    fn invalid_logs() {
+        let message = |result: Result<ReplayLog>| match result {
+            Err(Error::Device(message)) => message,
+            _ => panic!("no error"),
+        };
+        assert!(message(open("empty", "100.0 hw:0 period capture 256\n")).ends_with(": no device status to replay"));
+        assert!(message(open("invalid", "100.5 hw:0 status playback 256 x 0 0 0 100.0 100.5\n"))
+            .contains(": invalid status line: "));
+    }
+}
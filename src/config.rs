@@ -0,0 +1,351 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+use toml::Value;
+
+// TOML configuration files for the binaries, given with --config=<file>.
+// Every key is a long option of the binary's USAGE, nested tables are joined
+// with '-' so these are the same:
+//
+//   capture-period-size = 128
+//
+//   [capture]
+//   period-size = 128
+//
+// Switches take true or false, lists are joined with ',' (rt.cpus = [2, 3]).
+// Positional arguments are keys as well, repeated ones such as
+// <capture-device>... take a list. Flags given on the command line take
+// precedence over the file, positional arguments replace all of the file's.
+
+thread_local! {
+    // option or positional argument name to the file and key its value came from
+    static ORIGINS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Message about an invalid option value, naming the file and key when the
+// value came from the --config file:
+//   session.toml: format: Invalid sample format: s17
+pub fn error(option: &str, message: String) -> String {
+    ORIGINS.with(|origins| {
+        match origins.borrow().iter().find(|o| o.0 == option) {
+            Some((_, origin)) => format!("{}: {}", origin, message),
+            None => message,
+        }
+    })
+}
+
+// Returns the command line with the options of the --config file merged in,
+// ready for Docopt::argv.
+pub fn argv(usage: &str) -> Result<Vec<String>, String> {
+    merge(usage, env::args().collect())
+}
+
+fn merge(usage: &str, mut args: Vec<String>) -> Result<Vec<String>, String> {
+    let path = match take_config(&mut args)? {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+
+    let mut text = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let value = text.parse::<Value>().map_err(|e| format!("{}: {}", path, e))?;
+
+    let options = Options::parse(usage);
+    let given = options.given(&args[1..]);
+    let mut flags = Vec::new();
+    let mut positional: Vec<(usize, String)> = Vec::new();
+    let mut keys = Vec::new();
+    let mut origins = Vec::new();
+    flatten("", &value, &mut keys);
+
+    for (key, value) in keys {
+        let name = key.replace(['.', '_'], "-");
+        let error = |message: &str| format!("{}: {}: {}", path, key, message);
+
+        if let Some(index) = options.positionals.iter().position(|p| *p == name) {
+            match value {
+                Value::Array(ref items) if options.lists.contains(&name) => for item in items {
+                    positional.push((index, scalar(item).ok_or_else(|| error("expected a list of values"))?));
+                },
+                _ => positional.push((index, scalar(&value).ok_or_else(|| error("expected a value"))?)),
+            }
+            if !given.positional {
+                origins.push((name, format!("{}: {}", path, key)));
+            }
+            continue;
+        }
+
+        if !options.switches.contains(&name) && !options.values.contains(&name) {
+            return Err(error("unknown option"));
+        }
+        if given.flags.contains(&name) {
+            continue;
+        }
+
+        if options.switches.contains(&name) {
+            match value {
+                Value::Boolean(true) => flags.push(format!("--{}", name)),
+                Value::Boolean(false) => {}
+                _ => return Err(error("expected true or false")),
+            }
+            origins.push((name, format!("{}: {}", path, key)));
+            continue;
+        }
+
+        let text = match value {
+            Value::Array(ref items) => items.iter()
+                .map(|item| scalar(item).ok_or_else(|| error("expected a list of values")))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            Value::Boolean(_) => return Err(error("expected a value, not true or false")),
+            _ => scalar(&value).ok_or_else(|| error("expected a value"))?,
+        };
+        if options.numbers.contains(&name) && text.parse::<f64>().is_err() {
+            return Err(error("expected a number"));
+        }
+        flags.push(format!("--{}={}", name, text));
+        origins.push((name, format!("{}: {}", path, key)));
+    }
+    ORIGINS.with(|o| *o.borrow_mut() = origins);
+
+    let mut merged = vec![args[0].clone()];
+    merged.extend(flags);
+    merged.extend(args.drain(1..));
+    if !given.positional {
+        // in the order of the usage, a stable sort keeps the lists in order
+        positional.sort_by_key(|&(index, _)| index);
+        merged.extend(positional.into_iter().map(|(_, arg)| arg));
+    }
+    Ok(merged)
+}
+
+// removes --config=<file> or --config <file> from args
+fn take_config(args: &mut Vec<String>) -> Result<Option<String>, String> {
+    let index = match args.iter().position(|a| a == "--config" || a.starts_with("--config=")) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let arg = args.remove(index);
+    if let Some(path) = arg.strip_prefix("--config=") {
+        return Ok(Some(path.to_string()));
+    }
+    if index < args.len() {
+        Ok(Some(args.remove(index)))
+    } else {
+        Err("--config needs a file".to_string())
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, keys: &mut Vec<(String, Value)>) {
+    match *value {
+        Value::Table(ref table) => for (key, value) in table {
+            let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+            flatten(&key, value, keys);
+        },
+        _ => keys.push((prefix.to_string(), value.clone())),
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        _ => None,
+    }
+}
+
+// What the USAGE of a binary accepts.
+struct Options {
+    // long options without a value
+    switches: BTreeSet<String>,
+    // long options taking a value
+    values: BTreeSet<String>,
+    // long options with a numeric default
+    numbers: BTreeSet<String>,
+    // short option letter to long option, and whether it takes a value
+    shorts: Vec<(char, String, bool)>,
+    // positional arguments in the order of the usage
+    positionals: Vec<String>,
+    // repeated positional arguments, <name>...
+    lists: BTreeSet<String>,
+}
+
+// Options and positional arguments found on the command line.
+struct Given {
+    flags: BTreeSet<String>,
+    positional: bool,
+}
+
+impl Options {
+    fn parse(usage: &str) -> Options {
+        let mut options = Options {
+            switches: BTreeSet::new(),
+            values: BTreeSet::new(),
+            numbers: BTreeSet::new(),
+            shorts: Vec::new(),
+            positionals: Vec::new(),
+            lists: BTreeSet::new(),
+        };
+
+        let mut rest = usage;
+        while let Some(start) = rest.find("--") {
+            rest = &rest[start + 2..];
+            let name: String = rest.chars().take_while(|&c| c.is_alphanumeric() || c == '-').collect();
+            let after = &rest[name.len()..];
+            if name.is_empty() || name == "help" || name == "config" {
+                continue;
+            }
+            if after.starts_with("=<") || after.starts_with(" <") || after.starts_with("=") {
+                options.values.insert(name);
+            } else if !options.values.contains(&name) {
+                options.switches.insert(name);
+            }
+        }
+        let values = options.values.clone();
+        options.switches.retain(|name| !values.contains(name));
+
+        // the usage patterns run from Usage: to the first blank line
+        let patterns = usage.lines()
+            .skip_while(|l| !l.trim().starts_with("Usage:"))
+            .skip(1)
+            .take_while(|l| !l.trim().is_empty());
+        for line in patterns {
+            let mut previous = "";
+            for word in line.split_whitespace() {
+                let word = word.trim_matches(|c| c == '[' || c == ']' || c == '(' || c == ')' || c == '|');
+                // an argument of a short option such as -D <device>
+                let short_value = previous.starts_with('-') && !previous.starts_with("--");
+                previous = word;
+                if !word.starts_with('<') || short_value {
+                    continue;
+                }
+                let name = word[1..word.find('>').unwrap_or(word.len())].to_string();
+                if word.ends_with("...") {
+                    options.lists.insert(name.clone());
+                }
+                if !options.positionals.contains(&name) {
+                    options.positionals.push(name);
+                }
+            }
+        }
+
+        for line in usage.lines().map(|l| l.trim()) {
+            if !line.starts_with('-') {
+                continue;
+            }
+            let long = line.split_whitespace()
+                .find_map(|w| w.strip_prefix("--"))
+                .map(|w| w.split(['=', ',']).next().unwrap().to_string());
+            let long = match long {
+                Some(long) => long,
+                None => continue,
+            };
+
+            if line.starts_with('-') && !line.starts_with("--") {
+                if let Some(short) = line[1..].chars().next() {
+                    options.shorts.push((short, long.clone(), options.values.contains(&long)));
+                }
+            }
+
+            if let Some(start) = line.find("[default: ") {
+                let default = &line[start + "[default: ".len()..];
+                let default = &default[..default.find(']').unwrap_or(default.len())];
+                if default.parse::<f64>().is_ok() {
+                    options.numbers.insert(long);
+                }
+            }
+        }
+        options
+    }
+
+    fn given(&self, args: &[String]) -> Given {
+        let mut given = Given { flags: BTreeSet::new(), positional: false };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                given.positional |= args.next().is_some();
+                break;
+            } else if let Some(long) = arg.strip_prefix("--") {
+                let name = long.split('=').next().unwrap().to_string();
+                if self.values.contains(&name) && !arg.contains('=') {
+                    args.next();
+                }
+                given.flags.insert(name);
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                // stacked short options, the first one taking a value ends them
+                for (i, c) in arg[1..].char_indices() {
+                    if let Some(&(_, ref long, value)) = self.shorts.iter().find(|s| s.0 == c) {
+                        given.flags.insert(long.clone());
+                        if value {
+                            if i + c.len_utf8() == arg.len() - 1 {
+                                args.next();
+                            }
+                            break;
+                        }
+                    }
+                }
+            } else {
+                given.positional = true;
+            }
+        }
+        given
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    const USAGE: &str = "
+Usage:
+  test [--config=<file>] [--format=<format> --periods=<count> --rt-mlock] <device>...
+
+Options:
+  --config=<file>       Options file.
+  --format=<format>     Sample format [default: s16]
+  --periods=<count>     Periods [default: 2]
+  --rt-mlock            Lock memory.
+";
+
+    fn merged(name: &str, toml: &str, args: &[&str]) -> Result<Vec<String>, String> {
+        let path = env::temp_dir().join(format!("config-{}-{}.toml", process::id(), name));
+        fs::write(&path, toml).unwrap();
+        let mut argv = vec!["test".to_string(), format!("--config={}", path.display())];
+        argv.extend(args.iter().map(|a| a.to_string()));
+        let result = merge(USAGE, argv);
+        fs::remove_file(&path).unwrap();
+        result.map_err(|e| e.replace(&path.display().to_string(), "file"))
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let args = merged("precedence", "format = \"s32\"\nperiods = 3\nrt.mlock = true\ndevice = [\"a\", \"b\"]\n",
+                          &["--periods", "4"]).unwrap();
+        assert_eq!(args, ["test", "--format=s32", "--rt-mlock", "--periods", "4", "a", "b"]);
+        let args = merged("positional", "device = [\"a\", \"b\"]\n", &["c"]).unwrap();
+        assert_eq!(args, ["test", "c"]);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        assert_eq!(merged("unknown", "[capture]\nformat = \"s16\"\n", &[]).unwrap_err(),
+                   "file: capture.format: unknown option");
+        assert_eq!(merged("number", "periods = \"two\"\n", &[]).unwrap_err(),
+                   "file: periods: expected a number");
+
+        merged("origin", "format = \"s17\"\n", &["x"]).unwrap();
+        assert!(error("format", "Invalid sample format: s17".to_string())
+                    .ends_with(".toml: format: Invalid sample format: s17"));
+        assert_eq!(error("periods", "bad".to_string()), "bad");
+        merged("override", "format = \"s17\"\n", &["--format=s18", "x"]).unwrap();
+        assert_eq!(error("format", "bad".to_string()), "bad");
+    }
+}
@@ -0,0 +1,370 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use fifo::FifoStats;
+
+// Runtime control of a running engine over a Unix domain socket.
+// The socket is served by its own thread, which only talks to the audio
+// threads through the atomics of Control: commands are posted for the playback
+// thread to pick up once per block, the status is published the other way.
+//
+// One command per connection, a line of text answered with lines of text:
+//   status                     fill, ratio, drift, xruns...
+//   set-target-latency <ms>    FIFO latency, reached at once by dropping or inserting frames
+//   set-loop-bandwidth <Hz>    drift controller bandwidth
+//   freeze-ratio [on|off]      keep the current ratio
+//   reset                      back to the nominal ratio, locking again
+// Errors are answered with a line starting with "error:".
+
+// no command pending
+const NONE: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Status {
+    // input frames waiting to be resampled, and the FIFO target
+    pub fill: f64,
+    pub target: usize,
+    pub ratio: f64,
+    pub ppm: f64,
+    pub bandwidth: f64,
+    pub frozen: bool,
+    pub capture_xruns: u32,
+    pub playback_xruns: u32,
+    pub fifo: FifoStats,
+}
+
+pub struct Control {
+    // capture rate and largest FIFO target, to check the commands
+    rate: f64,
+    max_target: usize,
+
+    // commands
+    set_target: AtomicU64,
+    set_bandwidth: AtomicU64,
+    freeze: AtomicBool,
+    reset: AtomicBool,
+
+    // status
+    fill: AtomicU64,
+    target: AtomicU64,
+    ratio: AtomicU64,
+    ppm: AtomicU64,
+    bandwidth: AtomicU64,
+    capture_xruns: AtomicU32,
+    playback_xruns: AtomicU32,
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+    dropped_frames: AtomicU64,
+    inserted_frames: AtomicU64,
+}
+
+impl Control {
+    pub fn new(rate: f64, max_target: usize) -> Control {
+        Control {
+            rate,
+            max_target,
+            set_target: AtomicU64::new(NONE),
+            set_bandwidth: AtomicU64::new(NONE),
+            freeze: AtomicBool::new(false),
+            reset: AtomicBool::new(false),
+            fill: AtomicU64::new(0.0f64.to_bits()),
+            target: AtomicU64::new(0),
+            ratio: AtomicU64::new(1.0f64.to_bits()),
+            ppm: AtomicU64::new(0.0f64.to_bits()),
+            bandwidth: AtomicU64::new(0.0f64.to_bits()),
+            capture_xruns: AtomicU32::new(0),
+            playback_xruns: AtomicU32::new(0),
+            overflows: AtomicU64::new(0),
+            underflows: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            inserted_frames: AtomicU64::new(0),
+        }
+    }
+
+    /*
+     * Audio thread side, wait-free
+     */
+
+    // new FIFO target in capture frames
+    pub fn take_target(&self) -> Option<usize> {
+        match self.set_target.swap(NONE, Ordering::Relaxed) {
+            NONE => None,
+            target => Some(target as usize),
+        }
+    }
+
+    // new loop bandwidth in Hz
+    pub fn take_bandwidth(&self) -> Option<f64> {
+        match self.set_bandwidth.swap(NONE, Ordering::Relaxed) {
+            NONE => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    pub fn take_reset(&self) -> bool {
+        self.reset.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.freeze.load(Ordering::Relaxed)
+    }
+
+    pub fn publish(&self, fill: f64, target: usize, ratio: f64, ppm: f64, bandwidth: f64, fifo: &FifoStats) {
+        self.fill.store(fill.to_bits(), Ordering::Relaxed);
+        self.target.store(target as u64, Ordering::Relaxed);
+        self.ratio.store(ratio.to_bits(), Ordering::Relaxed);
+        self.ppm.store(ppm.to_bits(), Ordering::Relaxed);
+        self.bandwidth.store(bandwidth.to_bits(), Ordering::Relaxed);
+        self.overflows.store(fifo.overflows, Ordering::Relaxed);
+        self.underflows.store(fifo.underflows, Ordering::Relaxed);
+        self.dropped_frames.store(fifo.dropped_frames, Ordering::Relaxed);
+        self.inserted_frames.store(fifo.inserted_frames, Ordering::Relaxed);
+    }
+
+    pub fn capture_xruns(&self, count: u32) {
+        self.capture_xruns.store(count, Ordering::Relaxed);
+    }
+
+    pub fn playback_xruns(&self, count: u32) {
+        self.playback_xruns.store(count, Ordering::Relaxed);
+    }
+
+    /*
+     * Control side
+     */
+
+    pub fn status(&self) -> Status {
+        Status {
+            fill: f64::from_bits(self.fill.load(Ordering::Relaxed)),
+            target: self.target.load(Ordering::Relaxed) as usize,
+            ratio: f64::from_bits(self.ratio.load(Ordering::Relaxed)),
+            ppm: f64::from_bits(self.ppm.load(Ordering::Relaxed)),
+            bandwidth: f64::from_bits(self.bandwidth.load(Ordering::Relaxed)),
+            frozen: self.frozen(),
+            capture_xruns: self.capture_xruns.load(Ordering::Relaxed),
+            playback_xruns: self.playback_xruns.load(Ordering::Relaxed),
+            fifo: FifoStats {
+                overflows: self.overflows.load(Ordering::Relaxed),
+                underflows: self.underflows.load(Ordering::Relaxed),
+                dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+                inserted_frames: self.inserted_frames.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    // Runs a command line, returns the answer.
+    pub fn command(&self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |what: &str| -> Result<f64, String> {
+            words.get(1)
+                .and_then(|w| w.parse::<f64>().ok())
+                .filter(|v| *v > 0.0 && v.is_finite())
+                .ok_or_else(|| format!("{} needs a positive number", what))
+        };
+
+        match words.first().map(|w| *w) {
+            Some("status") => {
+                let status = self.status();
+                Ok(format!("fill {:.1}\ntarget {}\nlatency-ms {:.2}\nratio {:.9}\nppm {:.2}\n\
+                            bandwidth-hz {:.5}\nfrozen {}\ncapture-xruns {}\nplayback-xruns {}\n\
+                            overflows {}\nunderflows {}\ndropped-frames {}\ninserted-frames {}\n",
+                           status.fill, status.target, status.fill * 1000.0 / self.rate,
+                           status.ratio, status.ppm, status.bandwidth, status.frozen,
+                           status.capture_xruns, status.playback_xruns,
+                           status.fifo.overflows, status.fifo.underflows,
+                           status.fifo.dropped_frames, status.fifo.inserted_frames))
+            }
+            Some("set-target-latency") => {
+                let frames = (number("set-target-latency")? * self.rate / 1000.0).round() as usize;
+                if frames == 0 || frames > self.max_target {
+                    return Err(format!("target latency needs to be between {:.2} and {:.2} ms",
+                                       1000.0 / self.rate, self.max_target as f64 * 1000.0 / self.rate));
+                }
+                self.set_target.store(frames as u64, Ordering::Relaxed);
+                Ok(format!("target {} frames\n", frames))
+            }
+            Some("set-loop-bandwidth") => {
+                let hz = number("set-loop-bandwidth")?;
+                self.set_bandwidth.store(hz.to_bits(), Ordering::Relaxed);
+                Ok(format!("bandwidth {} Hz\n", hz))
+            }
+            Some("freeze-ratio") => {
+                let freeze = match words.get(1).map(|w| *w) {
+                    None | Some("on") => true,
+                    Some("off") => false,
+                    Some(other) => return Err(format!("freeze-ratio takes on or off, not {}", other)),
+                };
+                self.freeze.store(freeze, Ordering::Relaxed);
+                Ok(format!("ratio {}\n", if freeze { "frozen" } else { "following the drift" }))
+            }
+            Some("reset") => {
+                self.reset.store(true, Ordering::Relaxed);
+                Ok("reset\n".to_string())
+            }
+            Some(other) => Err(format!("unknown command: {}", other)),
+            None => Err("empty command".to_string()),
+        }
+    }
+}
+
+// Serves the control socket until dropped.
+pub struct Server {
+    path: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn serve(path: &str, control: Arc<Control>) -> io::Result<Server> {
+    // a socket left over by a previous run
+    if UnixStream::connect(path).is_err() {
+        let _ = fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = answer(stream, &control) {
+                            eprintln!("Control socket: {}", e);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => {
+                        eprintln!("Control socket: {}", e);
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    Ok(Server { path: path.to_string(), stop, thread: Some(thread) })
+}
+
+// Serves --control-socket=<path> when given, exits when the socket cannot be
+// created. Commands from asrc-ctl are answered from their own thread.
+pub fn serve_option(path: &Option<String>, control: Arc<Control>) -> Option<Server> {
+    path.as_ref().map(|path| {
+        let server = serve(path, control).unwrap_or_else(|e| {
+            eprintln!("Cannot listen on {}: {}", path, e);
+            process::exit(1);
+        });
+        eprintln!("Control socket: {}", path);
+        server
+    })
+}
+
+fn answer(stream: UnixStream, control: &Control) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let mut stream = stream;
+    match control.command(line.trim()) {
+        Ok(answer) => stream.write_all(answer.as_bytes()),
+        Err(e) => writeln!(stream, "error: {}", e),
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    // 48 kHz capture, targets up to 100 ms
+    fn control() -> Control {
+        Control::new(48000.0, 4800)
+    }
+
+    #[test]
+    fn set_target_latency() {
+        let control = control();
+        assert_eq!(control.command("set-target-latency 20"), Ok("target 960 frames\n".to_string()));
+        assert_eq!(control.take_target(), Some(960));
+        assert_eq!(control.take_target(), None);
+
+        assert!(control.command("set-target-latency 100").is_ok());
+        assert_eq!(control.take_target(), Some(4800));
+        for line in &["set-target-latency 100.1", "set-target-latency 0.001", "set-target-latency 0",
+                      "set-target-latency -5", "set-target-latency ms", "set-target-latency"] {
+            assert!(control.command(line).unwrap_err().contains("latency"), "{}", line);
+        }
+        assert_eq!(control.take_target(), None);
+    }
+
+    #[test]
+    fn freeze_ratio() {
+        let control = control();
+        assert!(control.command("freeze-ratio").is_ok());
+        assert!(control.frozen());
+        assert!(control.command("freeze-ratio off").is_ok());
+        assert!(!control.frozen());
+        assert!(control.command("freeze-ratio on").is_ok());
+        assert!(control.frozen());
+        assert_eq!(control.command("freeze-ratio maybe"),
+                   Err("freeze-ratio takes on or off, not maybe".to_string()));
+        assert!(control.frozen());
+    }
+
+    #[test]
+    fn other_commands() {
+        let control = control();
+        assert_eq!(control.command("set-loop-bandwidth 0.5"), Ok("bandwidth 0.5 Hz\n".to_string()));
+        assert_eq!(control.take_bandwidth(), Some(0.5));
+        assert!(control.command("set-loop-bandwidth -1").is_err());
+        assert!(control.command("reset").is_ok());
+        assert!(control.take_reset());
+        assert!(!control.take_reset());
+
+        assert_eq!(control.command("resume"), Err("unknown command: resume".to_string()));
+        assert_eq!(control.command(""), Err("empty command".to_string()));
+        assert_eq!(control.command("   "), Err("empty command".to_string()));
+    }
+
+    #[test]
+    fn socket() {
+        let path = env::temp_dir().join(format!("asrc-control-{}.sock", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let control = Arc::new(control());
+        control.publish(480.0, 960, 1.0001, 100.0, 0.1, &FifoStats::default());
+        let server = serve_option(&Some(path.clone()), control).unwrap();
+
+        let ask = |line: &str| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(line.as_bytes()).unwrap();
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer).unwrap();
+            answer
+        };
+        assert!(ask("status\n").contains("latency-ms 10.00\n"));
+        assert_eq!(ask("bogus\n"), "error: unknown command: bogus\n");
+
+        drop(server);
+        assert!(UnixStream::connect(&path).is_err());
+    }
+}
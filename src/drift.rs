@@ -0,0 +1,139 @@
+use std::f64::consts::PI;
+
+// PI controller keeping the FIFO between two clock domains at a constant fill
+// by nudging the resampling ratio.
+//
+// The fill is sampled at one point of the period sized sawtooth, so its mean
+// sits some way off the FIFO target. Instead of pulling it there, which takes
+// many seconds at a small max_ppm, the controller locks onto the mean fill seen
+// over the first SMOOTHING seconds and keeps that latency.
+//
+// The fill error is expressed in seconds of input. A fill above the target means
+// the capture clock runs faster than the playback clock consumes, so the ratio
+// (output rate / input rate) has to go down, and the other way around.
+
+// relative ratio correction per second of fill error, a 10 s time constant
+const KP: f64 = 0.1;
+// integral time constant in seconds, critically damped with KP
+const TI: f64 = 4.0 / KP;
+// fill smoothing time constant in seconds, hides the period sized sawtooth
+const SMOOTHING: f64 = 1.0;
+
+pub struct DriftController {
+    nominal: f64,
+    input_rate: f64,
+    // locked fill, None until the first SMOOTHING seconds went by
+    target: Option<f64>,
+    settle: f64,
+    // largest correction in parts per million
+    max_ppm: f64,
+    fill: Option<f64>,
+    integral: f64,
+    ratio: f64,
+    kp: f64,
+    ti: f64,
+}
+
+impl DriftController {
+    // nominal is output rate / input rate
+    pub fn new(nominal: f64, input_rate: f64, max_ppm: f64) -> DriftController {
+        DriftController {
+            nominal,
+            input_rate,
+            target: None,
+            settle: 0.0,
+            max_ppm,
+            fill: None,
+            integral: 0.0,
+            ratio: nominal,
+            kp: KP,
+            ti: TI,
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    // estimated clock deviation of the capture relative to the playback
+    pub fn ppm(&self) -> f64 {
+        (self.nominal / self.ratio - 1.0) * 1e6
+    }
+
+    // loop bandwidth in Hz
+    pub fn bandwidth(&self) -> f64 {
+        self.kp / (2.0 * PI)
+    }
+
+    // Changes the loop bandwidth, keeping the loop critically damped.
+    pub fn set_bandwidth(&mut self, hz: f64) {
+        self.kp = 2.0 * PI * hz;
+        self.ti = 4.0 / self.kp;
+    }
+
+    // Drops the drift estimate, back to the nominal ratio, and locks again.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.ratio = self.nominal;
+        self.relock();
+    }
+
+    // Starts from a measured input rate instead of the nominal ratio, e.g. the
+    // sender rate of an RTP stream against the local clock: the integral takes
+    // the matching correction, so that the loop only has to follow what is left.
+    pub fn seed(&mut self, input_rate: f64) {
+        let max = self.max_ppm * 1e-6;
+        let correction = (1.0 - self.input_rate / input_rate).max(-max).min(max);
+        self.integral = correction * self.ti / self.kp;
+        self.ratio = self.nominal * (1.0 - correction);
+    }
+
+    // Restarts the fill smoothing after the fill level jumped, e.g. on an xrun or
+    // when the FIFO dropped or inserted frames. The drift estimate is kept.
+    pub fn restart(&mut self) {
+        self.fill = None;
+    }
+
+    // Locks onto a new fill, after the FIFO was primed again.
+    pub fn relock(&mut self) {
+        self.fill = None;
+        self.target = None;
+        self.settle = 0.0;
+    }
+
+    // fill is the measured input frames waiting to be resampled, dt the seconds
+    // since the last update. Returns the new ratio.
+    pub fn update(&mut self, fill: f64, dt: f64) -> f64 {
+        let alpha = (dt / SMOOTHING).min(1.0);
+        let smoothed = match self.fill {
+            Some(previous) => previous + alpha * (fill - previous),
+            None => fill,
+        };
+        self.fill = Some(smoothed);
+
+        let target = match self.target {
+            Some(target) => target,
+            None => {
+                self.settle += dt;
+                if self.settle >= SMOOTHING {
+                    self.target = Some(smoothed);
+                }
+                return self.ratio;
+            }
+        };
+
+        let error = (smoothed - target) / self.input_rate;
+        let max = self.max_ppm * 1e-6;
+
+        // anti windup: stop integrating once the correction saturates
+        let integral = self.integral + error * dt;
+        let correction = self.kp * (error + integral / self.ti);
+        if correction.abs() <= max {
+            self.integral = integral;
+        }
+
+        let correction = (self.kp * (error + self.integral / self.ti)).max(-max).min(max);
+        self.ratio = self.nominal * (1.0 - correction);
+        self.ratio
+    }
+}
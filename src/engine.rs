@@ -0,0 +1,805 @@
+use std::os::unix::thread::JoinHandleExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use backend::{self, monotonic_time, Capture, Config, Playback};
+use control::Control;
+use drift::DriftController;
+use fifo::{self, FifoReader, FifoStats, FifoWriter, Overflow, Underflow};
+use libc;
+use realtime_priority::RtConfig;
+use record::RecordTap;
+use resampler::{self, Quality};
+use rt_check;
+use telemetry::{Event, Reporter, Sender, Stream};
+
+// User processing, called from the playback thread with blocks of
+// EngineConfig::block_size frames, interleaved, capture channels in and
+// playback channels out. The input is already resampled to the playback rate.
+// At the end of the stream frames can be less than the block size, only that
+// many frames of the output are played.
+// Runs in the real-time section: no allocation, locking or blocking.
+pub trait Processor: Send {
+    fn process(&mut self, input: &[f32], output: &mut [f32], frames: usize);
+}
+
+impl<F> Processor for F where F: FnMut(&[f32], &mut [f32], usize) + Send {
+    fn process(&mut self, input: &[f32], output: &mut [f32], frames: usize) {
+        self(input, output, frames)
+    }
+}
+
+// Measured capture rate against the local clock, None until known.
+pub type RateHint = Box<dyn Fn() -> Option<f64> + Send>;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    // frames per process call, 0 for the playback period size
+    pub block_size: usize,
+    pub quality: Quality,
+    pub overflow: Overflow,
+    pub underflow: Underflow,
+    // FIFO fill to keep in capture frames, 0 for three periods
+    pub fifo_target: usize,
+    // follow the clock drift between the devices, the nominal ratio is used otherwise
+    pub drift_control: bool,
+    // largest drift correction in parts per million
+    pub max_ppm: f64,
+    // seconds of capture before stopping, 0 runs until stopped or the end of the stream
+    pub duration: f64,
+    pub rt: RtConfig,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EngineStats {
+    pub captured_frames: u64,
+    pub played_frames: u64,
+    pub capture_xruns: u32,
+    pub playback_xruns: u32,
+    pub fifo: FifoStats,
+    pub ratio: f64,
+    // estimated capture clock deviation from the playback clock
+    pub ppm: f64,
+    // mean ratio and drift over the played frames
+    pub average_ratio: f64,
+    pub average_ppm: f64,
+    // FIFO and playback device latency in seconds when the capture stopped
+    pub latency: f64,
+    // seconds from the start to the end of the playback
+    pub runtime: f64,
+    // device errors that ended the capture or the playback early
+    pub errors: Vec<String>,
+}
+
+impl EngineStats {
+    pub fn print(&self) {
+        eprintln!("Run time: {:.3} s", self.runtime);
+        eprintln!("Capture: {} frames, {} xruns", self.captured_frames, self.capture_xruns);
+        eprintln!("Playback: {} frames, {} xruns", self.played_frames, self.playback_xruns);
+        eprintln!("Ratio: {:.9}, average {:.9}", self.ratio, self.average_ratio);
+        eprintln!("Clock drift: {:.1} ppm, average {:.1} ppm", self.ppm, self.average_ppm);
+        eprintln!("Latency: {:.1} ms", self.latency * 1000.0);
+        eprintln!("FIFO overflows: {}, underflows: {}, dropped frames: {}, inserted frames: {}",
+                  self.fifo.overflows, self.fifo.underflows,
+                  self.fifo.dropped_frames, self.fifo.inserted_frames);
+        for error in &self.errors {
+            eprintln!("Error: {}", error);
+        }
+    }
+}
+
+// Duplex engine: a capture device feeding a playback device through the
+// ASRC, each on its own clock and real-time thread.
+pub struct Engine {
+    capture: Box<dyn Capture>,
+    playback: Box<dyn Playback>,
+    config: EngineConfig,
+    target: usize,
+    capture_tap: Option<RecordTap>,
+    playback_tap: Option<RecordTap>,
+    capture_telemetry: Option<Sender>,
+    playback_telemetry: Option<Sender>,
+    rate_hint: Option<RateHint>,
+    control: Arc<Control>,
+}
+
+impl Engine {
+    pub fn new(capture: Box<dyn Capture>, playback: Box<dyn Playback>, mut config: EngineConfig) -> Engine {
+        let ratio = playback.config().rate as f64 / capture.config().rate as f64;
+        if config.block_size == 0 {
+            config.block_size = playback.config().period_size;
+        }
+        let target = if config.fifo_target > 0 {
+            config.fifo_target
+        } else {
+            3 * capture.config().period_size.max((config.block_size as f64 / ratio).ceil() as usize)
+        };
+
+        // the FIFO holds four times the target, the overflow policy needs two
+        let control = Arc::new(Control::new(capture.config().rate as f64, 2 * target));
+        Engine {
+            capture,
+            playback,
+            config,
+            target,
+            capture_tap: None,
+            playback_tap: None,
+            capture_telemetry: None,
+            playback_telemetry: None,
+            rate_hint: None,
+            control,
+        }
+    }
+
+    pub fn capture_config(&self) -> &Config {
+        self.capture.config()
+    }
+
+    pub fn playback_config(&self) -> &Config {
+        self.playback.config()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.config.block_size
+    }
+
+    pub fn fifo_target(&self) -> usize {
+        self.target
+    }
+
+    // Files are not paced by a clock, the FIFO then waits for them instead of
+    // applying its policies and the ratio stays nominal.
+    pub fn lossless(&self) -> bool {
+        !self.capture.clocked() || !self.playback.clocked()
+    }
+
+    pub fn record_capture(&mut self, tap: RecordTap) {
+        self.capture_tap = Some(tap);
+    }
+
+    // records the processed output
+    pub fn record_playback(&mut self, tap: RecordTap) {
+        self.playback_tap = Some(tap);
+    }
+
+    pub fn telemetry(&mut self, reporter: &mut Reporter) {
+        self.capture_telemetry = Some(reporter.sender("capture", 1024));
+        self.playback_telemetry = Some(reporter.sender("playback", 1024));
+    }
+
+    // Polled by the playback thread until it returns the capture rate, e.g. the
+    // sender rate of an RTP stream. The drift control then starts from it
+    // instead of the nominal ratio.
+    pub fn rate_hint(&mut self, hint: RateHint) {
+        self.rate_hint = Some(hint);
+    }
+
+    // Runtime control and status of the running engine, see control.rs.
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    // Starts the capture and playback threads.
+    pub fn start<P: Processor + 'static>(self, processor: P) -> Running {
+        let lossless = self.lossless();
+        let capture_channels = self.capture.config().channels;
+        let (writer, reader) = fifo::fifo(capture_channels, 4 * self.target, self.target,
+                                          self.config.overflow, self.config.underflow);
+        let stop = Arc::new(AtomicBool::new(false));
+        let capture_done = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let capture_thread = CaptureThread {
+            capture: self.capture,
+            writer,
+            tap: self.capture_tap,
+            telemetry: self.capture_telemetry,
+            rt: self.config.rt.clone(),
+            duration: self.config.duration,
+            lossless,
+            stop: stop.clone(),
+            done: capture_done.clone(),
+            control: self.control.clone(),
+        };
+        let capture_config = capture_thread.capture.config().clone();
+
+        let playback_thread = PlaybackThread {
+            playback: self.playback,
+            reader,
+            processor: Box::new(processor),
+            tap: self.playback_tap,
+            telemetry: self.playback_telemetry,
+            rate_hint: self.rate_hint,
+            capture_config,
+            config: self.config,
+            target: self.target,
+            lossless,
+            done: capture_done,
+            finished: finished.clone(),
+            control: self.control,
+        };
+
+        Running {
+            stop,
+            finished,
+            started: monotonic_time(),
+            capture: thread::spawn(move || capture_thread.run()),
+            playback: thread::spawn(move || playback_thread.run()),
+        }
+    }
+}
+
+pub struct Running {
+    stop: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    started: f64,
+    capture: JoinHandle<(u64, u32, Option<String>)>,
+    playback: JoinHandle<EngineStats>,
+}
+
+impl Running {
+    // Stops capturing, the playback thread plays what is left in the FIFO.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    // CPU time clocks of the capture and playback threads, for clock_gettime
+    pub fn cpu_clocks(&self) -> Vec<(Stream, libc::clockid_t)> {
+        let threads = [(Stream::Capture, self.capture.as_pthread_t()),
+                       (Stream::Playback, self.playback.as_pthread_t())];
+        threads.iter().filter_map(|&(stream, thread)| {
+            let mut clock: libc::clockid_t = 0;
+            if unsafe { libc::pthread_getcpuclockid(thread, &mut clock) } == 0 {
+                Some((stream, clock))
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    // true once the playback played out everything
+    pub fn finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    // Waits for both threads to finish.
+    pub fn wait(self) -> EngineStats {
+        let (captured_frames, capture_xruns, capture_error) = self.capture.join()
+            .unwrap_or_else(|_| (0, 0, Some("capture thread panicked".to_string())));
+        let mut stats = self.playback.join().unwrap_or_else(|_| EngineStats {
+            errors: vec!["playback thread panicked".to_string()],
+            ..EngineStats::default()
+        });
+        stats.captured_frames = captured_frames;
+        stats.capture_xruns = capture_xruns;
+        if let Some(error) = capture_error {
+            stats.errors.insert(0, error);
+        }
+        stats.runtime = monotonic_time() - self.started;
+        stats
+    }
+}
+
+fn send(telemetry: &mut Option<Sender>, event: Event) {
+    if let Some(ref mut telemetry) = *telemetry {
+        telemetry.send(event);
+    }
+}
+
+struct CaptureThread {
+    capture: Box<dyn Capture>,
+    writer: FifoWriter,
+    tap: Option<RecordTap>,
+    telemetry: Option<Sender>,
+    rt: RtConfig,
+    duration: f64,
+    lossless: bool,
+    stop: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+    control: Arc<Control>,
+}
+
+impl CaptureThread {
+    fn run(mut self) -> (u64, u32, Option<String>) {
+        let channels = self.capture.config().channels;
+        let max_frames = (self.duration * self.capture.config().rate as f64) as u64;
+
+        // make read buffer
+        let mut buf = vec![0.0f32; self.capture.config().period_size * channels];
+        let mut captured = 0;
+        let mut xruns = 0;
+        let mut error = None;
+
+        // set capture thread to real-time priority
+        eprintln!("Capture thread real-time: {}", self.rt.apply());
+
+        if let Err(e) = self.capture.start() {
+            eprintln!("Cannot start the capture: {}", e);
+            self.done.store(true, Ordering::SeqCst);
+            return (0, 0, Some(format!("cannot start the capture: {}", e)));
+        }
+        while (max_frames == 0 || captured < max_frames) && !self.stop.load(Ordering::Relaxed) {
+            let result = self.capture.read(&mut buf);
+            let mut section = rt_check::section();
+            match result {
+                Ok(frames) => {
+                    captured += frames as u64;
+                    send(&mut self.telemetry, Event::Period { stream: Stream::Capture, frames: frames as u32 });
+                    if let Some(ref mut tap) = self.tap {
+                        tap.push(&buf[..frames * channels]);
+                    }
+                    if self.lossless {
+                        let mut queued = 0;
+                        while queued < frames {
+                            let n = (frames - queued).min(self.writer.free());
+                            self.writer.write(&buf[queued * channels..(queued + n) * channels]);
+                            queued += n;
+                            if queued < frames {
+                                section.suspend(|| thread::sleep(Duration::from_millis(1)));
+                            }
+                        }
+                    } else {
+                        self.writer.write(&buf[..frames * channels]);
+                    }
+                }
+                Err(backend::Error::XRun) => {
+                    xruns += 1;
+                    self.control.capture_xruns(xruns);
+                    send(&mut self.telemetry, Event::XRun { stream: Stream::Capture, count: xruns });
+                }
+                Err(backend::Error::EndOfStream) => break,
+                Err(e) => {
+                    eprintln!("Capture error: {}", e);
+                    error = Some(format!("capture: {}", e));
+                    break;
+                }
+            }
+        }
+
+        self.done.store(true, Ordering::SeqCst);
+        (captured, xruns, error)
+    }
+}
+
+struct PlaybackThread {
+    playback: Box<dyn Playback>,
+    reader: FifoReader,
+    processor: Box<dyn Processor>,
+    tap: Option<RecordTap>,
+    telemetry: Option<Sender>,
+    rate_hint: Option<RateHint>,
+    capture_config: Config,
+    config: EngineConfig,
+    target: usize,
+    lossless: bool,
+    done: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    control: Arc<Control>,
+}
+
+impl PlaybackThread {
+    fn run(mut self) -> EngineStats {
+        let _finished = Finished(self.finished.clone());
+        let in_channels = self.capture_config.channels;
+        let out_channels = self.playback.config().channels;
+        let out_rate = self.playback.config().rate as f64;
+        let block = self.config.block_size;
+        let nominal = out_rate / self.capture_config.rate as f64;
+
+        let mut resampler = resampler::new(in_channels, self.config.quality, nominal);
+        let mut drift = DriftController::new(nominal, self.capture_config.rate as f64, self.config.max_ppm);
+        let drift_control = self.config.drift_control && !self.lossless;
+        let bypass = self.capture_config.rate == self.playback.config().rate && !drift_control;
+
+        // make read, process and write buffers
+        let mut in_buf = vec![0.0f32; self.capture_config.period_size * in_channels];
+        let mut in_pos = 0;
+        let mut in_len = 0;
+        let mut in_block = vec![0.0f32; block * in_channels];
+        let mut out_block = vec![0.0f32; block * out_channels];
+        let mut finished = false;
+        let mut played = 0;
+        let mut xruns = 0;
+        let mut fifo_events = 0;
+        let mut primed = false;
+        // set once the capture is done, the FIFO then only drains
+        let mut draining = false;
+        // silent frames left to push the last input out of the resampler
+        let mut tail = None;
+        // for the average ratio over the produced frames
+        let mut ratio_sum = 0.0;
+        let mut ratio_frames = 0;
+        // FIFO fill and device delay when the capture ended
+        let mut latency_fill = 0.0;
+        let mut latency_delay = None;
+        let mut frozen = false;
+        let mut errors = Vec::new();
+
+        // set playback thread to real-time priority
+        eprintln!("Playback thread real-time: {}", self.config.rt.apply());
+
+        while !finished {
+            let mut section = rt_check::section();
+            let mut produced = 0;
+            while produced < block {
+                if in_pos == in_len {
+                    in_pos = 0;
+
+                    // everything was queued once done is set, so an empty read after
+                    // seeing it means the end of the stream
+                    let capture_finished = self.done.load(Ordering::SeqCst);
+                    draining |= capture_finished;
+                    let frames = if self.lossless || capture_finished {
+                        self.reader.read(&mut in_buf)
+                    } else {
+                        self.reader.read_exact(&mut in_buf);
+                        self.capture_config.period_size
+                    };
+                    in_len = frames * in_channels;
+
+                    if frames == 0 {
+                        if capture_finished {
+                            let left = tail.unwrap_or(if bypass { 0 } else { resampler.latency().ceil() as usize });
+                            if left == 0 {
+                                finished = true;
+                                break;
+                            }
+                            let n = left.min(self.capture_config.period_size);
+                            for x in in_buf[..n * in_channels].iter_mut() {
+                                *x = 0.0;
+                            }
+                            in_len = n * in_channels;
+                            tail = Some(left - n);
+                            continue;
+                        }
+                        section.suspend(|| thread::sleep(Duration::from_millis(1)));
+                    }
+                    continue;
+                }
+
+                let input = &in_buf[in_pos..in_len];
+                let output = &mut in_block[produced * in_channels..];
+                let (consumed, frames) = if bypass {
+                    // same rates, pass the samples through untouched
+                    let n = (input.len() / in_channels).min(output.len() / in_channels);
+                    output[..n * in_channels].copy_from_slice(&input[..n * in_channels]);
+                    (n, n)
+                } else {
+                    resampler.process(input, output)
+                };
+                in_pos += consumed * in_channels;
+                produced += frames;
+            }
+
+            if produced == 0 {
+                break;
+            }
+
+            // the last block is padded with silence, frames tells how much of it is real
+            for x in in_block[produced * in_channels..].iter_mut() {
+                *x = 0.0;
+            }
+            self.processor.process(&in_block, &mut out_block, produced);
+
+            // commands from the control socket
+            if let Some(target) = self.control.take_target() {
+                self.reader.retarget(target);
+                self.target = self.reader.target();
+                drift.relock();
+            }
+            if let Some(hz) = self.control.take_bandwidth() {
+                drift.set_bandwidth(hz);
+            }
+            if self.control.take_reset() {
+                drift.reset();
+                resampler.set_ratio(drift.ratio());
+            }
+            if frozen && !self.control.frozen() {
+                drift.restart();
+            }
+            frozen = self.control.frozen();
+
+            // frames waiting in front of the resampler
+            let fill = self.reader.continuous_fill(self.capture_config.rate as f64)
+                + ((in_len - in_pos) / in_channels) as f64;
+            let stats = self.reader.stats();
+            if stats.overflows + stats.underflows != fifo_events {
+                fifo_events = stats.overflows + stats.underflows;
+                drift.restart();
+            }
+            if self.reader.priming() {
+                primed = false;
+            } else if drift_control && !draining && !frozen {
+                if !primed {
+                    drift.relock();
+                    primed = true;
+                }
+                if let Some(rate) = self.rate_hint.as_ref().and_then(|hint| hint()) {
+                    drift.seed(rate);
+                    self.rate_hint = None;
+                }
+                let ratio = drift.update(fill, produced as f64 / out_rate);
+                resampler.set_ratio(ratio);
+            }
+            send(&mut self.telemetry, Event::Fill { frames: fill as u32, target: self.target as u32 });
+            send(&mut self.telemetry, Event::Ratio(drift.ratio()));
+            self.control.publish(fill, self.target, drift.ratio(), drift.ppm(), drift.bandwidth(), &stats);
+            ratio_sum += drift.ratio() * produced as f64;
+            ratio_frames += produced;
+            if !draining {
+                latency_fill = fill;
+            }
+
+            if let Some(ref mut tap) = self.tap {
+                tap.push(&out_block[..produced * out_channels]);
+            }
+
+            // the device write blocks until there is room
+            drop(section);
+            let result = self.playback.write(&out_block[..produced * out_channels]);
+            if draining && latency_delay.is_none() {
+                latency_delay = Some(self.playback.timestamp().map_or(0, |ts| ts.delay));
+            }
+            let _section = rt_check::section();
+            match result {
+                Ok(frames) => {
+                    played += frames as u64;
+                    send(&mut self.telemetry, Event::Period { stream: Stream::Playback, frames: frames as u32 });
+                }
+                Err(backend::Error::XRun) => {
+                    xruns += 1;
+                    drift.restart();
+                    self.control.playback_xruns(xruns);
+                    send(&mut self.telemetry, Event::XRun { stream: Stream::Playback, count: xruns });
+                }
+                Err(e) => {
+                    eprintln!("Playback error: {}", e);
+                    errors.push(format!("playback: {}", e));
+                    break;
+                }
+            }
+        }
+
+        if let Err(e) = self.playback.drain() {
+            eprintln!("Cannot drain the playback: {}", e);
+            errors.push(format!("cannot drain the playback: {}", e));
+        }
+
+        let average_ratio = if ratio_frames > 0 { ratio_sum / ratio_frames as f64 } else { drift.ratio() };
+        EngineStats {
+            played_frames: played,
+            playback_xruns: xruns,
+            fifo: self.reader.stats(),
+            ratio: drift.ratio(),
+            ppm: drift.ppm(),
+            average_ratio,
+            average_ppm: (nominal / average_ratio - 1.0) * 1e6,
+            latency: latency_fill / self.capture_config.rate as f64
+                + latency_delay.unwrap_or(0) as f64 / out_rate,
+            errors,
+            ..EngineStats::default()
+        }
+    }
+}
+
+// Marks the playback as finished when dropped, also when the thread panics.
+struct Finished(Arc<AtomicBool>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use alsa::Direction;
+    use backend::{ReplayLog, SampleFormat, SimCapture, SimOptions, SimPlayback};
+    use interpolator::Kernel;
+    use realtime_priority::Policy;
+    use resampler::SincQuality;
+    use std::env;
+    use std::fs::{self, File};
+    use std::process;
+    use std::sync::{Mutex, MutexGuard};
+    use telemetry::{self, Record};
+
+    /*
+     * Sim devices and engine setup, shared with the tests of the devices built on the engine
+     */
+
+    pub fn config(rate: u32, channels: usize) -> Config {
+        Config { channels, rate, format: SampleFormat::F32, period_size: 256, periods: 4 }
+    }
+
+    // runs as fast as possible, the engine then treats it like a file
+    pub fn sim_capture(rate: u32, channels: usize, tone: Option<f64>) -> Box<dyn Capture> {
+        let options = SimOptions { ppm: 0.0, fast: true, tone, replay: None };
+        Box::new(SimCapture::new(config(rate, channels), options))
+    }
+
+    pub fn sim_playback(rate: u32, channels: usize) -> Box<dyn Playback> {
+        let options = SimOptions { ppm: 0.0, fast: true, tone: None, replay: None };
+        Box::new(SimPlayback::new(config(rate, channels), options))
+    }
+
+    // Runs in real time with the clock off by ppm. The buffer is large enough
+    // to ride out the scheduling hiccups of a busy test machine, the FIFO
+    // targets need to be as well.
+    pub fn clocked_capture(rate: u32, channels: usize, ppm: f64, tone: Option<f64>) -> Box<dyn Capture> {
+        let options = SimOptions { ppm, fast: false, tone, replay: None };
+        Box::new(SimCapture::new(Config { periods: 16, ..config(rate, channels) }, options))
+    }
+
+    pub fn clocked_playback(rate: u32, channels: usize, ppm: f64) -> Box<dyn Playback> {
+        let options = SimOptions { ppm, fast: false, tone: None, replay: None };
+        Box::new(SimPlayback::new(Config { periods: 16, ..config(rate, channels) }, options))
+    }
+
+    static CLOCKED: Mutex<()> = Mutex::new(());
+
+    // Held by the tests running clocked devices, one at a time: on a single
+    // CPU they would take the time the others measure.
+    pub fn exclusive() -> MutexGuard<'static, ()> {
+        CLOCKED.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn rt() -> RtConfig {
+        RtConfig { policy: Policy::Other, priority: 0, cpus: None, mlock: false }
+    }
+
+    pub fn engine_config(quality: Quality, duration: f64) -> EngineConfig {
+        EngineConfig {
+            block_size: 0,
+            quality,
+            overflow: Overflow::DropOldest,
+            underflow: Underflow::Silence,
+            fifo_target: 0,
+            drift_control: true,
+            max_ppm: 1000.0,
+            duration,
+            rt: rt(),
+        }
+    }
+
+    // capture frames of a run, the last period is read whole
+    pub fn captured_frames(rate: u32, duration: f64) -> u64 {
+        let period = config(rate, 1).period_size as f64;
+        ((rate as f64 * duration / period).ceil() * period) as u64
+    }
+
+    // Runs the engine with the input copied to the output. Every 100 ms of
+    // the last seconds of the capture probe is called, what it returns is
+    // collected.
+    pub fn run<T, F: FnMut() -> T>(engine: Engine, last: f64, probe: F) -> (EngineStats, Vec<T>) {
+        let block = engine.block_size();
+        run_with(engine, move |input: &[f32], output: &mut [f32], frames: usize| {
+            // always whole blocks, the last one padded
+            assert_eq!(input.len(), output.len());
+            assert!(frames > 0 && frames <= block);
+            output.copy_from_slice(input);
+        }, last, probe)
+    }
+
+    pub fn run_with<P, T, F>(engine: Engine, processor: P, last: f64, mut probe: F) -> (EngineStats, Vec<T>)
+        where P: Processor + 'static, F: FnMut() -> T {
+        let duration = engine.config.duration;
+        let running = engine.start(processor);
+
+        let mut probes = Vec::new();
+        while !running.finished() {
+            let elapsed = monotonic_time() - running.started;
+            if elapsed >= duration - last && elapsed < duration {
+                probes.push(probe());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        (running.wait(), probes)
+    }
+
+    pub fn mean(values: &[f64]) -> f64 {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+
+    fn run_fast(capture_rate: u32, playback_rate: u32, quality: Quality) -> EngineStats {
+        let engine = Engine::new(sim_capture(capture_rate, 2, Some(1000.0)), sim_playback(playback_rate, 2),
+                                 engine_config(quality, 0.5));
+        let (stats, _) = run(engine, 0.0, || ());
+        assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+        stats
+    }
+
+    #[test]
+    fn real_time_sections() {
+        let violations = rt_check::check(|| {
+            let stats = run_fast(48000, 48000, Quality::Sinc(SincQuality::Medium));
+            assert_eq!(stats.captured_frames, captured_frames(48000, 0.5));
+            assert_eq!(stats.played_frames, stats.captured_frames);
+            run_fast(44100, 48000, Quality::Sinc(SincQuality::Medium));
+            run_fast(48000, 44100, Quality::Sinc(SincQuality::High));
+            run_fast(44100, 48000, Quality::Rational);
+        });
+        assert!(violations.sections > 0);
+        assert_eq!(violations.total(), 0, "{:?}", violations);
+    }
+
+    #[test]
+    fn locks_to_the_capture_clock() {
+        let _clocked = exclusive();
+        let engine = Engine::new(clocked_capture(48000, 2, 200.0, None), clocked_playback(48000, 2, 0.0),
+                                 EngineConfig {
+            fifo_target: 2048,
+            ..engine_config(Quality::Interpolator(Kernel::Linear), 10.0)
+        });
+        let period = engine.capture_config().period_size as f64;
+        // the default 10 s time constant takes minutes to settle
+        let control = engine.control();
+        control.command("set-loop-bandwidth 0.1").unwrap();
+
+        let (stats, status) = run(engine, 3.0, || control.status());
+        assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+        // the estimate follows the capture clock, give or take the scheduling
+        // noise, and the FIFO holds the latency it locked onto, which is up to
+        // a period off the target
+        let ppm: Vec<f64> = status.iter().map(|s| s.ppm).collect();
+        let fill: Vec<f64> = status.iter().map(|s| s.fill).collect();
+        assert!((mean(&ppm) - 200.0).abs() < 100.0, "{:?}", ppm);
+        assert!((mean(&fill) - 2048.0).abs() < period, "{:?}", fill);
+    }
+
+    struct Unplugged(Config);
+
+    impl Capture for Unplugged {
+        fn config(&self) -> &Config {
+            &self.0
+        }
+
+        fn start(&mut self) -> backend::Result<()> {
+            Err(backend::Error::Device("unplugged".to_string()))
+        }
+
+        fn read(&mut self, _buf: &mut [f32]) -> backend::Result<usize> {
+            Err(backend::Error::Device("unplugged".to_string()))
+        }
+
+        fn timestamp(&self) -> Option<backend::Timestamp> {
+            None
+        }
+    }
+
+    #[test]
+    fn device_errors_end_up_in_the_stats() {
+        let engine = Engine::new(Box::new(Unplugged(config(48000, 2))), sim_playback(48000, 2),
+                                 engine_config(Quality::Sinc(SincQuality::Medium), 0.5));
+        let (stats, _) = run(engine, 0.0, || ());
+        assert_eq!(stats.captured_frames, 0);
+        assert_eq!(stats.errors, vec!["cannot start the capture: unplugged".to_string()]);
+    }
+
+    #[test]
+    fn telemetry_log_replays_as_device_timing() {
+        let status = |frames, delay, trigger, system| Event::Status {
+            stream: Stream::Playback, frames, delay, avail: 0, avail_max: 0, audio: 0.0, trigger, system,
+        };
+        let path = env::temp_dir().join(format!("engine-{}.log", process::id()));
+        {
+            let mut file = File::create(&path).unwrap();
+            for &event in [status(24512, 512, 100.0, 100.5), Event::Period { stream: Stream::Playback, frames: 256 },
+                           status(48512, 512, 100.0, 101.0), Event::XRun { stream: Stream::Playback, count: 1 },
+                           status(24512, 512, 101.5, 102.0)].iter() {
+                telemetry::write_record(&mut file, "engine", &Record { time: 102.0, event }).unwrap();
+            }
+        }
+        let log = ReplayLog::open(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // read back like an alsa-audio-time log, across the restart after the xrun
+        let log = log.unwrap();
+        assert_eq!(log.rate, None);
+        assert_eq!(log.points(Direction::Playback),
+                   [(0.0, 0.0), (0.5, 24000.0), (1.0, 48000.0), (1.5, 72000.0), (2.0, 96000.0)]);
+    }
+}
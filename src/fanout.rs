@@ -0,0 +1,372 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use backend::{self, Config, Playback, Timestamp};
+use drift::DriftController;
+use fifo::{self, FifoReader, FifoWriter, Overflow, Underflow};
+use member::{MemberStatus, SharedStats};
+use realtime_priority::RtConfig;
+use resampler::{self, Quality, Resampler};
+use rt_check;
+
+// Several playback devices presented as one, each playing the same frames.
+// The clock master is written directly by the caller, every other member is
+// fed through a FIFO and resampled into its own clock domain on its own
+// thread, with a drift controller keeping its latency at the FIFO target.
+
+#[derive(Debug, Clone)]
+pub struct FanoutConfig {
+    // index of the clock master in the device list
+    pub master: usize,
+    pub quality: Quality,
+    // largest drift correction in parts per million
+    pub max_ppm: f64,
+    // FIFO fill to keep for every member in master frames, 0 for three periods
+    pub fifo_target: usize,
+    // member drift loop bandwidth in Hz, None for the default
+    pub bandwidth: Option<f64>,
+    pub rt: RtConfig,
+}
+
+struct Member {
+    device: String,
+    writer: FifoWriter,
+    lossless: bool,
+    shared: Arc<SharedStats>,
+    // set when the member thread stopped playing, on an error or once drained
+    exited: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub struct FanoutPlayback {
+    master: Box<dyn Playback>,
+    members: Vec<Member>,
+    stop: Arc<AtomicBool>,
+    // set once the caller drains, members then play out what is queued
+    done: Arc<AtomicBool>,
+}
+
+impl FanoutPlayback {
+    // playbacks are the opened devices in command line order, devices their names
+    pub fn new(mut playbacks: Vec<Box<dyn Playback>>, devices: &[String], config: FanoutConfig)
+               -> backend::Result<FanoutPlayback> {
+        if devices.len() != playbacks.len() {
+            return Err(backend::Error::Device("one name per playback device expected".to_string()));
+        }
+        if config.master >= playbacks.len() {
+            return Err(backend::Error::Device(format!("no playback device {} to use as clock master", config.master)));
+        }
+
+        let master = playbacks.remove(config.master);
+        let master_config = master.config().clone();
+        for playback in &playbacks {
+            if playback.config().channels != master_config.channels {
+                return Err(backend::Error::Device(format!("playback devices need {} channels, not {}",
+                                                          master_config.channels, playback.config().channels)));
+            }
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicBool::new(false));
+        let names = devices.iter().enumerate().filter(|&(i, _)| i != config.master).map(|(_, d)| d);
+
+        let mut members = Vec::new();
+        for (playback, device) in playbacks.into_iter().zip(names) {
+            let nominal = playback.config().rate as f64 / master_config.rate as f64;
+            let lossless = !master.clocked() || !playback.clocked();
+            let target = if config.fifo_target > 0 {
+                config.fifo_target
+            } else {
+                3 * master_config.period_size.max((playback.config().period_size as f64 / nominal).ceil() as usize)
+            };
+            let (writer, reader) = fifo::fifo(master_config.channels, 4 * target, target,
+                                              Overflow::DropOldest, Underflow::Silence);
+            let shared = Arc::new(SharedStats::new(nominal));
+            let exited = Arc::new(AtomicBool::new(false));
+            let mut drift = DriftController::new(nominal, master_config.rate as f64, config.max_ppm);
+            if let Some(hz) = config.bandwidth {
+                drift.set_bandwidth(hz);
+            }
+
+            let mut thread = MemberThread {
+                device: device.clone(),
+                resampler: resampler::new(master_config.channels, config.quality, nominal),
+                drift,
+                playback,
+                reader,
+                input_rate: master_config.rate as f64,
+                chunk: master_config.period_size,
+                lossless,
+                rt: config.rt.clone(),
+                stop: stop.clone(),
+                done: done.clone(),
+                shared: shared.clone(),
+                exited: exited.clone(),
+            };
+            members.push(Member {
+                device: device.clone(),
+                writer,
+                lossless,
+                shared,
+                exited,
+                thread: Some(thread::spawn(move || thread.run())),
+            });
+        }
+
+        Ok(FanoutPlayback { master, members, stop, done })
+    }
+
+    pub fn status(&self) -> MemberStatus {
+        MemberStatus::new(self.members.iter().map(|m| (m.device.clone(), m.shared.clone())).collect())
+    }
+
+    fn join(&mut self) {
+        for member in &mut self.members {
+            if let Some(thread) = member.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+impl Playback for FanoutPlayback {
+    fn config(&self) -> &Config {
+        self.master.config()
+    }
+
+    fn write(&mut self, buf: &[f32]) -> backend::Result<usize> {
+        let channels = self.master.config().channels;
+        let frames = buf.len() / channels;
+
+        // members are queued first, an xrun on the master is its own business
+        let mut section = rt_check::section();
+        for member in &mut self.members {
+            if member.lossless {
+                let mut queued = 0;
+                while queued < frames && !member.exited.load(Ordering::Relaxed) {
+                    let n = (frames - queued).min(member.writer.free());
+                    member.writer.write(&buf[queued * channels..(queued + n) * channels]);
+                    queued += n;
+                    if queued < frames {
+                        section.suspend(|| thread::sleep(Duration::from_millis(1)));
+                    }
+                }
+            } else {
+                member.writer.write(buf);
+            }
+        }
+        drop(section);
+
+        self.master.write(buf)
+    }
+
+    fn drain(&mut self) -> backend::Result<()> {
+        self.done.store(true, Ordering::SeqCst);
+        let result = self.master.drain();
+        self.join();
+        result
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        self.master.timestamp()
+    }
+
+    fn clocked(&self) -> bool {
+        self.master.clocked()
+    }
+}
+
+impl Drop for FanoutPlayback {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.join();
+    }
+}
+
+// Plays a member device from its FIFO on its own real-time thread.
+struct MemberThread {
+    device: String,
+    playback: Box<dyn Playback>,
+    reader: FifoReader,
+    resampler: Box<dyn Resampler>,
+    drift: DriftController,
+    // master rate and frames read from the FIFO at once
+    input_rate: f64,
+    chunk: usize,
+    lossless: bool,
+    rt: RtConfig,
+    stop: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+    shared: Arc<SharedStats>,
+    exited: Arc<AtomicBool>,
+}
+
+impl MemberThread {
+    fn run(&mut self) {
+        self.play();
+        self.exited.store(true, Ordering::SeqCst);
+    }
+
+    fn play(&mut self) {
+        let channels = self.playback.config().channels;
+        let period = self.playback.config().period_size;
+        let dt = period as f64 / self.playback.config().rate as f64;
+
+        let mut in_buf = vec![0.0f32; self.chunk * channels];
+        let mut in_pos = 0;
+        let mut in_len = 0;
+        let mut out_buf = vec![0.0f32; period * channels];
+        let mut finished = false;
+        let mut primed = false;
+        let mut fifo_events = 0;
+
+        eprintln!("{} thread real-time: {}", self.device, self.rt.apply());
+
+        while !finished && !self.stop.load(Ordering::Relaxed) {
+            let mut section = rt_check::section();
+            let mut produced = 0;
+            while produced < period {
+                if in_pos == in_len {
+                    in_pos = 0;
+
+                    // everything was queued once done is set
+                    let done = self.done.load(Ordering::SeqCst);
+                    let frames = if self.lossless || done {
+                        self.reader.read(&mut in_buf)
+                    } else {
+                        self.reader.read_exact(&mut in_buf);
+                        self.chunk
+                    };
+                    in_len = frames * channels;
+
+                    if frames == 0 {
+                        if done {
+                            finished = true;
+                            break;
+                        }
+                        section.suspend(|| thread::sleep(Duration::from_millis(1)));
+                    }
+                    continue;
+                }
+
+                let (consumed, frames) = self.resampler.process(&in_buf[in_pos..in_len],
+                                                                &mut out_buf[produced * channels..]);
+                in_pos += consumed * channels;
+                produced += frames;
+            }
+
+            if produced == 0 {
+                break;
+            }
+
+            let stats = self.reader.stats();
+            if stats.overflows + stats.underflows != fifo_events {
+                fifo_events = stats.overflows + stats.underflows;
+                self.drift.restart();
+            }
+            if self.reader.priming() {
+                primed = false;
+            } else if !self.lossless && !self.done.load(Ordering::Relaxed) {
+                if !primed {
+                    self.drift.relock();
+                    primed = true;
+                }
+                let fill = self.reader.continuous_fill(self.input_rate) + ((in_len - in_pos) / channels) as f64;
+                let ratio = self.drift.update(fill, dt);
+                self.resampler.set_ratio(ratio);
+            }
+            // the controller follows the master relative to the member
+            self.shared.update(self.drift.ratio(), -self.drift.ppm(), &stats);
+
+            // the device write blocks until there is room
+            drop(section);
+            match self.playback.write(&out_buf[..produced * channels]) {
+                Ok(_) => {}
+                Err(backend::Error::XRun) => {
+                    self.shared.xrun();
+                    self.drift.restart();
+                }
+                Err(e) => {
+                    eprintln!("Playback error on {}: {}", self.device, e);
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = self.playback.drain() {
+            eprintln!("Playback error on {}: {}", self.device, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::{Engine, EngineConfig};
+    use engine::tests::{self as sim, clocked_capture, clocked_playback, engine_config, mean, sim_capture,
+                        sim_playback};
+    use interpolator::Kernel;
+    use resampler::SincQuality;
+    use rt_check;
+
+    fn fanout(playbacks: Vec<Box<dyn Playback>>, fifo_target: usize, bandwidth: Option<f64>,
+              quality: Quality) -> FanoutPlayback {
+        let devices: Vec<String> = (0..playbacks.len()).map(|i| format!("sim{}", i)).collect();
+        FanoutPlayback::new(playbacks, &devices, FanoutConfig {
+            master: 0,
+            quality,
+            max_ppm: 1000.0,
+            fifo_target,
+            bandwidth,
+            rt: sim::rt(),
+        }).unwrap()
+    }
+
+    #[test]
+    fn members_play_in_real_time_sections() {
+        let violations = rt_check::check(|| {
+            let fanout = fanout(vec![sim_playback(48000, 2), sim_playback(44100, 2), sim_playback(96000, 2)],
+                                0, None, Quality::Sinc(SincQuality::Medium));
+            let engine = Engine::new(sim_capture(48000, 2, Some(1000.0)), Box::new(fanout),
+                                     engine_config(Quality::Sinc(SincQuality::Medium), 0.5));
+            let (stats, _) = sim::run(engine, 0.0, || ());
+            assert_eq!(stats.played_frames, stats.captured_frames);
+        });
+        assert!(violations.sections > 0);
+        assert_eq!(violations.total(), 0, "{:?}", violations);
+    }
+
+    #[test]
+    fn members_follow_their_own_clocks() {
+        let _clocked = sim::exclusive();
+        let offsets = [150.0, -250.0];
+        // the default 10 s time constant takes minutes to settle, and the
+        // member FIFOs take in the whole master buffer at the start: they only
+        // finish priming after the members filled their own buffers
+        let fanout = fanout(vec![clocked_playback(48000, 2, 0.0), clocked_playback(44100, 2, offsets[0]),
+                                 clocked_playback(96000, 2, offsets[1])],
+                            8192, Some(0.1), Quality::Interpolator(Kernel::Linear));
+        let status = fanout.status();
+        let engine = Engine::new(clocked_capture(48000, 2, 0.0, Some(1000.0)), Box::new(fanout), EngineConfig {
+            fifo_target: 2048,
+            ..engine_config(Quality::Interpolator(Kernel::Linear), 20.0)
+        });
+
+        let (stats, probes) = sim::run(engine, 5.0, || status.members());
+        assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+
+        // each member locked onto its clock, give or take the scheduling
+        // noise, and kept its latency without dropping or inserting frames
+        // once locked
+        let (first, last) = (&probes[0], &probes[probes.len() - 1]);
+        for (i, &offset) in offsets.iter().enumerate() {
+            let ppm: Vec<f64> = probes.iter().map(|members| members[i].1.ppm).collect();
+            assert!((mean(&ppm) - offset).abs() < 100.0, "{:?}", ppm);
+            let (before, after) = (&first[i].1, &last[i].1);
+            assert_eq!((after.fifo.overflows - before.fifo.overflows, after.fifo.underflows - before.fifo.underflows,
+                        after.xruns - before.xruns), (0, 0, 0), "{:?} {:?}", before, after);
+        }
+    }
+}
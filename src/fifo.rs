@@ -0,0 +1,472 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use backend::monotonic_time;
+use spsc::{self, Consumer, Producer};
+
+// What the reader does when the fill level rises above twice the target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    // discard the oldest frames down to the target fill
+    DropOldest,
+    // read slightly more frames than asked and squeeze them into the block
+    Stretch,
+}
+
+// What the reader does when fewer frames than asked are queued.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Underflow {
+    // fill with silence and keep playing silence until the target fill is reached again
+    Silence,
+    // spread the queued frames over the block, silence when nothing is queued
+    Stretch,
+}
+
+impl Overflow {
+    pub fn from_name(name: &str) -> Option<Overflow> {
+        match name {
+            "drop" => Some(Overflow::DropOldest),
+            "stretch" => Some(Overflow::Stretch),
+            _ => None,
+        }
+    }
+}
+
+impl Underflow {
+    pub fn from_name(name: &str) -> Option<Underflow> {
+        match name {
+            "silence" => Some(Underflow::Silence),
+            "stretch" => Some(Underflow::Stretch),
+            _ => None,
+        }
+    }
+}
+
+// at most 1/STRETCH_LIMIT of a block is gained or lost when stretching
+const STRETCH_LIMIT: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FifoStats {
+    pub overflows: u64,
+    pub underflows: u64,
+    // frames lost, either not queued because the fifo was full or dropped by the reader
+    pub dropped_frames: u64,
+    // silent frames played in place of missing ones
+    pub inserted_frames: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+    dropped_frames: AtomicU64,
+    inserted_frames: AtomicU64,
+    // monotonic time in f64 bits and size of the last write
+    last_write_time: AtomicU64,
+    last_write_frames: AtomicU64,
+}
+
+impl Stats {
+    fn snapshot(&self) -> FifoStats {
+        FifoStats {
+            overflows: self.overflows.load(Ordering::Relaxed),
+            underflows: self.underflows.load(Ordering::Relaxed),
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+            inserted_frames: self.inserted_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct FifoWriter {
+    producer: Producer<f32>,
+    channels: usize,
+    stats: Arc<Stats>,
+}
+
+pub struct FifoReader {
+    consumer: Consumer<f32>,
+    channels: usize,
+    target: usize,
+    overflow: Overflow,
+    underflow: Underflow,
+    // waiting for the target fill after an underflow
+    priming: bool,
+    scratch: Vec<f32>,
+    stats: Arc<Stats>,
+}
+
+// Wait-free audio FIFO of interleaved frames, reads and writes are always whole frames.
+// target is the fill level in frames the reader tries to keep.
+pub fn fifo(channels: usize, capacity: usize, target: usize,
+            overflow: Overflow, underflow: Underflow) -> (FifoWriter, FifoReader) {
+    let capacity = capacity.max(2 * target);
+    let (producer, consumer) = spsc::ring(capacity * channels);
+    let stats = Arc::new(Stats::default());
+
+    (FifoWriter { producer, channels, stats: stats.clone() },
+     FifoReader {
+         consumer,
+         channels,
+         target,
+         overflow,
+         underflow,
+         priming: true,
+         scratch: vec![0.0; capacity * channels],
+         stats,
+     })
+}
+
+impl FifoWriter {
+    // queued frames
+    pub fn fill(&self) -> usize {
+        self.producer.len() / self.channels
+    }
+
+    pub fn free(&self) -> usize {
+        self.producer.free() / self.channels
+    }
+
+    // Queues as many whole frames as fit, returns the number of frames queued.
+    // Frames that do not fit are counted as dropped.
+    pub fn write(&mut self, buf: &[f32]) -> usize {
+        let frames = buf.len() / self.channels;
+        let n = frames.min(self.free());
+        self.producer.push_slice(&buf[..n * self.channels]);
+        if n > 0 {
+            self.stats.last_write_time.store(monotonic_time().to_bits(), Ordering::Relaxed);
+            self.stats.last_write_frames.store(n as u64, Ordering::Relaxed);
+        }
+
+        if n < frames {
+            self.stats.overflows.fetch_add(1, Ordering::Relaxed);
+            self.stats.dropped_frames.fetch_add((frames - n) as u64, Ordering::Relaxed);
+        }
+        n
+    }
+
+    pub fn stats(&self) -> FifoStats {
+        self.stats.snapshot()
+    }
+}
+
+impl FifoReader {
+    // queued frames
+    pub fn fill(&self) -> usize {
+        self.consumer.len() / self.channels
+    }
+
+    // Fill as if the writer queued frames continuously at rate instead of in
+    // blocks: the frames due since the last write are counted, up to the size
+    // of that write. Unlike fill() it does not stay put while blocks of the
+    // same size are written and read in lockstep.
+    pub fn continuous_fill(&self, rate: f64) -> f64 {
+        let time = f64::from_bits(self.stats.last_write_time.load(Ordering::Relaxed));
+        let frames = self.stats.last_write_frames.load(Ordering::Relaxed) as f64;
+        let due = ((monotonic_time() - time) * rate).max(0.0).min(frames);
+        self.fill() as f64 + due
+    }
+
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    // largest target the overflow policy leaves room for
+    pub fn max_target(&self) -> usize {
+        self.consumer.capacity() / self.channels / 2
+    }
+
+    // Moves to a new target fill at once: frames above it are dropped, below it
+    // silence is played until the FIFO filled up again.
+    pub fn retarget(&mut self, target: usize) {
+        self.target = target.max(1).min(self.max_target());
+        let fill = self.fill();
+        if fill > self.target {
+            let drop = fill - self.target;
+            self.consumer.skip(drop * self.channels);
+            self.stats.dropped_frames.fetch_add(drop as u64, Ordering::Relaxed);
+        } else {
+            self.priming = true;
+        }
+    }
+
+    // true until the target fill is reached, at the start and after an underflow
+    pub fn priming(&self) -> bool {
+        self.priming
+    }
+
+    pub fn stats(&self) -> FifoStats {
+        self.stats.snapshot()
+    }
+
+    // Reads up to buf.len() / channels queued frames without applying any policy,
+    // returns the number of frames read.
+    pub fn read(&mut self, buf: &mut [f32]) -> usize {
+        let frames = (buf.len() / self.channels).min(self.fill());
+        self.consumer.pop_slice(&mut buf[..frames * self.channels]);
+        frames
+    }
+
+    // Fills buf completely, applying the overflow and underflow policies.
+    // Returns the number of frames taken from the queue.
+    pub fn read_exact(&mut self, buf: &mut [f32]) -> usize {
+        let want = buf.len() / self.channels;
+        let mut fill = self.fill();
+
+        if self.priming {
+            if fill < self.target {
+                silence(buf);
+                self.stats.inserted_frames.fetch_add(want as u64, Ordering::Relaxed);
+                return 0;
+            }
+            self.priming = false;
+        }
+
+        if fill > 2 * self.target.max(want) {
+            self.stats.overflows.fetch_add(1, Ordering::Relaxed);
+            match self.overflow {
+                Overflow::DropOldest => {
+                    let drop = fill - self.target.max(want);
+                    self.consumer.skip(drop * self.channels);
+                    self.stats.dropped_frames.fetch_add(drop as u64, Ordering::Relaxed);
+                    fill -= drop;
+                }
+                Overflow::Stretch => {
+                    let take = want + (want / STRETCH_LIMIT).max(1);
+                    return self.read_stretched(buf, take);
+                }
+            }
+        }
+
+        if fill >= want {
+            return self.read(buf);
+        }
+
+        self.stats.underflows.fetch_add(1, Ordering::Relaxed);
+        match self.underflow {
+            Underflow::Stretch if fill > 0 => self.read_stretched(buf, fill),
+            _ => {
+                let read = self.read(buf);
+                silence(&mut buf[read * self.channels..]);
+                self.stats.inserted_frames.fetch_add((want - read) as u64, Ordering::Relaxed);
+                self.priming = self.underflow == Underflow::Silence;
+                read
+            }
+        }
+    }
+
+    // reads take frames and linearly interpolates them over buf
+    fn read_stretched(&mut self, buf: &mut [f32], take: usize) -> usize {
+        let channels = self.channels;
+        let want = buf.len() / channels;
+        let take = take.min(self.scratch.len() / channels);
+        let mut scratch = ::std::mem::take(&mut self.scratch);
+        let read = self.read(&mut scratch[..take * channels]);
+
+        let step = if want > 1 { (read - 1) as f64 / (want - 1) as f64 } else { 0.0 };
+        for i in 0..want {
+            let pos = i as f64 * step;
+            let j = (pos as usize).min(read - 1);
+            let k = (j + 1).min(read - 1);
+            let t = (pos - j as f64) as f32;
+            for c in 0..channels {
+                let a = scratch[j * channels + c];
+                let b = scratch[k * channels + c];
+                buf[i * channels + c] = a + (b - a) * t;
+            }
+        }
+
+        self.scratch = scratch;
+        read
+    }
+}
+
+fn silence(buf: &mut [f32]) {
+    for x in buf.iter_mut() {
+        *x = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    // stereo frames numbered from 1, the right channel negated so that a
+    // frame torn apart shows
+    fn frames(start: usize, count: usize) -> Vec<f32> {
+        (start..start + count)
+            .flat_map(|i| vec![(i + 1) as f32, -((i + 1) as f32)])
+            .collect()
+    }
+
+    fn assert_whole_frames(buf: &[f32]) {
+        for frame in buf.chunks(2) {
+            assert_eq!(frame[0], -frame[1], "partial frame {:?}", frame);
+        }
+    }
+
+    fn primed(capacity: usize, target: usize, queued: usize,
+              overflow: Overflow, underflow: Underflow) -> (FifoWriter, FifoReader) {
+        let (mut writer, reader) = fifo(2, capacity, target, overflow, underflow);
+        assert_eq!(writer.write(&frames(0, queued)), queued);
+        assert!(reader.priming());
+        (writer, reader)
+    }
+
+    #[test]
+    fn plays_silence_until_primed() {
+        let (mut writer, mut reader) = fifo(2, 64, 16, Overflow::DropOldest, Underflow::Silence);
+        let mut buf = vec![1.0; 8 * 2];
+
+        writer.write(&frames(0, 10));
+        assert_eq!(reader.read_exact(&mut buf), 0);
+        assert!(buf.iter().all(|&x| x == 0.0));
+        assert!(reader.priming());
+        assert_eq!(reader.stats().inserted_frames, 8);
+
+        writer.write(&frames(10, 6));
+        assert_eq!(reader.read_exact(&mut buf), 8);
+        assert!(!reader.priming());
+        assert_eq!(buf, frames(0, 8));
+        assert_eq!(reader.fill(), 8);
+    }
+
+    #[test]
+    fn full_fifo_drops_new_frames() {
+        let (mut writer, reader) = fifo(2, 64, 16, Overflow::DropOldest, Underflow::Silence);
+        assert_eq!(writer.write(&frames(0, 70)), 64);
+        assert_eq!(writer.free(), 0);
+        assert_eq!(writer.write(&frames(70, 3)), 0);
+
+        let stats = reader.stats();
+        assert_eq!(stats.overflows, 2);
+        assert_eq!(stats.dropped_frames, 9);
+    }
+
+    #[test]
+    fn overflow_drops_oldest() {
+        let (_writer, mut reader) = primed(64, 4, 40, Overflow::DropOldest, Underflow::Silence);
+        let mut buf = vec![0.0; 8 * 2];
+
+        assert_eq!(reader.read_exact(&mut buf), 8);
+        assert_eq!(buf, frames(32, 8));
+        let stats = reader.stats();
+        assert_eq!((stats.overflows, stats.dropped_frames, stats.inserted_frames), (1, 32, 0));
+        assert_eq!(reader.fill(), 0);
+    }
+
+    #[test]
+    fn overflow_stretch_reads_ahead() {
+        let (_writer, mut reader) = primed(64, 4, 40, Overflow::Stretch, Underflow::Silence);
+        let mut buf = vec![0.0; 16 * 2];
+
+        // a 16th more frames than asked for, squeezed into the block
+        assert_eq!(reader.read_exact(&mut buf), 17);
+        assert_whole_frames(&buf);
+        assert_eq!(&buf[..2], &frames(0, 1)[..]);
+        assert_eq!(&buf[30..], &frames(16, 1)[..]);
+        let left: Vec<f32> = buf.chunks(2).map(|frame| frame[0]).collect();
+        assert!(left.windows(2).all(|w| w[0] <= w[1]));
+
+        let stats = reader.stats();
+        assert_eq!((stats.overflows, stats.dropped_frames, stats.inserted_frames), (1, 0, 0));
+        assert_eq!(reader.fill(), 40 - 17);
+    }
+
+    #[test]
+    fn underflow_silence_reprimes() {
+        let (mut writer, mut reader) = primed(64, 4, 5, Overflow::DropOldest, Underflow::Silence);
+        let mut buf = vec![1.0; 8 * 2];
+
+        assert_eq!(reader.read_exact(&mut buf), 5);
+        assert_eq!(&buf[..10], &frames(0, 5)[..]);
+        assert!(buf[10..].iter().all(|&x| x == 0.0));
+        assert!(reader.priming());
+        let stats = reader.stats();
+        assert_eq!((stats.underflows, stats.inserted_frames, stats.dropped_frames), (1, 3, 0));
+
+        // silence again until the target is queued
+        writer.write(&frames(5, 3));
+        assert_eq!(reader.read_exact(&mut buf), 0);
+        assert_eq!(reader.stats().inserted_frames, 3 + 8);
+        writer.write(&frames(8, 5));
+        assert_eq!(reader.read_exact(&mut buf), 8);
+        assert_eq!(buf, frames(5, 8));
+    }
+
+    #[test]
+    fn underflow_stretch_spreads_what_is_queued() {
+        let (_writer, mut reader) = primed(64, 4, 4, Overflow::DropOldest, Underflow::Stretch);
+        let mut buf = vec![0.0; 8 * 2];
+
+        assert_eq!(reader.read_exact(&mut buf), 4);
+        assert_whole_frames(&buf);
+        assert_eq!(&buf[..2], &frames(0, 1)[..]);
+        assert_eq!(&buf[14..], &frames(3, 1)[..]);
+        assert!(!reader.priming());
+        let stats = reader.stats();
+        assert_eq!((stats.underflows, stats.inserted_frames), (1, 0));
+
+        // nothing left to stretch
+        assert_eq!(reader.read_exact(&mut buf), 0);
+        assert!(buf.iter().all(|&x| x == 0.0));
+        assert!(!reader.priming());
+        let stats = reader.stats();
+        assert_eq!((stats.underflows, stats.inserted_frames), (2, 8));
+    }
+
+    #[test]
+    fn reads_whole_frames_only() {
+        let (_writer, mut reader) = primed(64, 8, 20, Overflow::DropOldest, Underflow::Silence);
+        // an odd sample count, the last one belongs to no frame
+        let mut buf = vec![7.0; 5 * 2 + 1];
+
+        assert_eq!(reader.read(&mut buf), 5);
+        assert_eq!(&buf[..10], &frames(0, 5)[..]);
+        assert_eq!(buf[10], 7.0);
+        assert_eq!(reader.read_exact(&mut buf), 5);
+        assert_eq!(&buf[..10], &frames(5, 5)[..]);
+        assert_eq!(reader.fill(), 10);
+    }
+
+    #[test]
+    fn retarget_drops_or_reprimes() {
+        let (_writer, mut reader) = primed(64, 16, 40, Overflow::DropOldest, Underflow::Silence);
+        let mut buf = vec![0.0; 4 * 2];
+        reader.read_exact(&mut buf);
+        assert!(!reader.priming());
+
+        reader.retarget(10);
+        assert_eq!(reader.target(), 10);
+        assert_eq!(reader.fill(), 10);
+        assert_eq!(reader.stats().dropped_frames, 26);
+        assert!(!reader.priming());
+        assert_eq!(reader.read_exact(&mut buf), 4);
+        assert_eq!(buf, frames(30, 4));
+
+        // beyond what the overflow policy leaves room for
+        reader.retarget(1000);
+        assert_eq!(reader.target(), reader.max_target());
+        assert_eq!(reader.max_target(), 32);
+        assert!(reader.priming());
+
+        reader.retarget(0);
+        assert_eq!(reader.target(), 1);
+    }
+
+    #[test]
+    fn continuous_fill_counts_frames_due() {
+        let (mut writer, reader) = fifo(2, 64, 16, Overflow::DropOldest, Underflow::Silence);
+        assert_eq!(reader.continuous_fill(48000.0), 0.0);
+
+        writer.write(&frames(0, 16));
+        let fill = reader.continuous_fill(48000.0);
+        assert!((16.0..32.0).contains(&fill), "{}", fill);
+
+        // never more than the last write
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(reader.continuous_fill(48000.0), 32.0);
+        assert_eq!(reader.continuous_fill(0.0), 16.0);
+    }
+}
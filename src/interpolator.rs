@@ -0,0 +1,322 @@
+use resampler::{kaiser, sinc, Resampler};
+use simd;
+
+// Short polynomial fractional delay interpolators, for paths where the delay
+// of a long sinc filter is too much. They do not band limit, so expect images
+// and, downsampling, aliasing, the more the shorter the kernel.
+
+// taps and polynomial order of the Farrow structure
+const FARROW_TAPS: usize = 8;
+const FARROW_ORDER: usize = 5;
+// prototype Kaiser window beta and cutoff as a fraction of the lowest Nyquist frequency
+const FARROW_BETA: f64 = 6.0;
+const FARROW_ROLLOFF: f64 = 0.85;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kernel {
+    Linear,
+    // Catmull-Rom cubic Hermite spline
+    Hermite,
+    Lagrange4,
+    Lagrange6,
+    // windowed sinc with coefficients polynomial in the fractional position
+    Farrow,
+}
+
+impl Kernel {
+    pub fn from_name(name: &str) -> Option<Kernel> {
+        match name {
+            "linear" => Some(Kernel::Linear),
+            "hermite" => Some(Kernel::Hermite),
+            "lagrange4" => Some(Kernel::Lagrange4),
+            "lagrange6" => Some(Kernel::Lagrange6),
+            "farrow" => Some(Kernel::Farrow),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kernel::Linear => "linear",
+            Kernel::Hermite => "hermite",
+            Kernel::Lagrange4 => "lagrange4",
+            Kernel::Lagrange6 => "lagrange6",
+            Kernel::Farrow => "farrow",
+        }
+    }
+
+    pub fn all() -> [Kernel; 5] {
+        [Kernel::Linear, Kernel::Hermite, Kernel::Lagrange4, Kernel::Lagrange6, Kernel::Farrow]
+    }
+
+    // input frames the kernel spans
+    pub fn points(&self) -> usize {
+        match *self {
+            Kernel::Linear => 2,
+            Kernel::Hermite | Kernel::Lagrange4 => 4,
+            Kernel::Lagrange6 => 6,
+            Kernel::Farrow => FARROW_TAPS,
+        }
+    }
+}
+
+// Interpolates between the two middle points of the kernel, the frames being
+// kept like in SincResampler.
+pub struct Interpolator {
+    channels: usize,
+    kernel: Kernel,
+    points: usize,
+    history: Vec<f32>,
+    write_pos: usize,
+    weights: Vec<f32>,
+    // Farrow coefficients, FARROW_ORDER + 1 rows of taps, lowest power first
+    farrow: Vec<f64>,
+    ratio: f64,
+    step: f64,
+    frac: f64,
+}
+
+impl Interpolator {
+    pub fn new(channels: usize, kernel: Kernel, ratio: f64) -> Interpolator {
+        let points = kernel.points();
+        simd::isa();
+        let farrow = if kernel == Kernel::Farrow {
+            farrow_coefficients(FARROW_ROLLOFF * ratio.min(1.0))
+        } else {
+            Vec::new()
+        };
+
+        Interpolator {
+            channels,
+            kernel,
+            points,
+            history: vec![0.0; channels * points * 2],
+            write_pos: 0,
+            weights: vec![0.0; points],
+            farrow,
+            ratio,
+            step: 1.0 / ratio,
+            frac: 0.0,
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[f32]) {
+        let len = self.points * 2;
+        for (c, &x) in frame.iter().enumerate() {
+            self.history[c * len + self.write_pos] = x;
+            self.history[c * len + self.write_pos + self.points] = x;
+        }
+        self.write_pos = (self.write_pos + 1) % self.points;
+    }
+
+    // weights of the points, oldest first, for the position mu past the middle left one
+    fn update_weights(&mut self, mu: f64) {
+        let w = &mut self.weights;
+        match self.kernel {
+            Kernel::Linear => {
+                w[0] = (1.0 - mu) as f32;
+                w[1] = mu as f32;
+            }
+            Kernel::Hermite => {
+                let mu2 = mu * mu;
+                let mu3 = mu2 * mu;
+                w[0] = (-0.5 * mu + mu2 - 0.5 * mu3) as f32;
+                w[1] = (1.0 - 2.5 * mu2 + 1.5 * mu3) as f32;
+                w[2] = (0.5 * mu + 2.0 * mu2 - 1.5 * mu3) as f32;
+                w[3] = (-0.5 * mu2 + 0.5 * mu3) as f32;
+            }
+            Kernel::Lagrange4 | Kernel::Lagrange6 => {
+                let n = self.points;
+                let x = (n / 2 - 1) as f64 + mu;
+                for (j, wj) in w.iter_mut().enumerate() {
+                    let mut l = 1.0;
+                    for k in 0..n {
+                        if k != j {
+                            l *= (x - k as f64) / (j as f64 - k as f64);
+                        }
+                    }
+                    *wj = l as f32;
+                }
+            }
+            Kernel::Farrow => {
+                // Horner over the polynomial of each tap
+                let taps = self.points;
+                for (k, wk) in w.iter_mut().enumerate() {
+                    let mut v = 0.0;
+                    for m in (0..FARROW_ORDER + 1).rev() {
+                        v = v * mu + self.farrow[m * taps + k];
+                    }
+                    *wk = v as f32;
+                }
+            }
+        }
+    }
+
+    fn interpolate(&mut self, out: &mut [f32]) {
+        let frac = self.frac;
+        self.update_weights(frac);
+
+        let start = self.write_pos;
+        for (x, history) in out.iter_mut().zip(self.history.chunks(self.points * 2)) {
+            *x = simd::dot(&history[start..start + self.points], &self.weights);
+        }
+    }
+}
+
+impl Resampler for Interpolator {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+        self.step = 1.0 / ratio;
+    }
+
+    fn latency(&self) -> f64 {
+        (self.points / 2 + 1) as f64
+    }
+
+    fn reset(&mut self) {
+        for x in self.history.iter_mut() {
+            *x = 0.0;
+        }
+        self.write_pos = 0;
+        self.frac = 0.0;
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize) {
+        let ch = self.channels;
+        let in_frames = input.len() / ch;
+        let out_frames = output.len() / ch;
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        loop {
+            while self.frac >= 1.0 {
+                if consumed == in_frames {
+                    return (consumed, produced);
+                }
+                self.push_frame(&input[consumed * ch..(consumed + 1) * ch]);
+                consumed += 1;
+                self.frac -= 1.0;
+            }
+
+            if produced == out_frames {
+                return (consumed, produced);
+            }
+
+            self.interpolate(&mut output[produced * ch..(produced + 1) * ch]);
+            produced += 1;
+            self.frac += self.step;
+        }
+    }
+}
+
+// Least squares fit of each tap of a Kaiser windowed sinc, normalized for
+// unity gain at DC, by a polynomial in the fractional position.
+fn farrow_coefficients(cutoff: f64) -> Vec<f64> {
+    let taps = FARROW_TAPS;
+    let terms = FARROW_ORDER + 1;
+    let half = (taps / 2) as f64;
+    let grid = 64;
+
+    // normal equations, shared by all taps: a c = b
+    let mut a = vec![0.0; terms * terms];
+    let mut b = vec![0.0; terms * taps];
+    for g in 0..grid + 1 {
+        let mu = g as f64 / grid as f64;
+        let row: Vec<f64> = (0..taps)
+            .map(|k| {
+                let d = k as f64 - (half - 1.0) - mu;
+                cutoff * sinc(cutoff * d) * kaiser(d / half, FARROW_BETA)
+            })
+            .collect();
+        let sum: f64 = row.iter().sum();
+
+        let powers: Vec<f64> = (0..terms).map(|m| mu.powi(m as i32)).collect();
+        for i in 0..terms {
+            for j in 0..terms {
+                a[i * terms + j] += powers[i] * powers[j];
+            }
+            for k in 0..taps {
+                b[i * taps + k] += powers[i] * row[k] / sum;
+            }
+        }
+    }
+
+    // Gaussian elimination with partial pivoting, on all taps at once
+    for col in 0..terms {
+        let pivot = (col..terms)
+            .max_by(|&i, &j| a[i * terms + col].abs().partial_cmp(&a[j * terms + col].abs()).unwrap())
+            .unwrap();
+        for j in 0..terms {
+            a.swap(col * terms + j, pivot * terms + j);
+        }
+        for k in 0..taps {
+            b.swap(col * taps + k, pivot * taps + k);
+        }
+        for i in 0..terms {
+            if i == col {
+                continue;
+            }
+            let f = a[i * terms + col] / a[col * terms + col];
+            for j in 0..terms {
+                a[i * terms + j] -= f * a[col * terms + j];
+            }
+            for k in 0..taps {
+                b[i * taps + k] -= f * b[col * taps + k];
+            }
+        }
+    }
+    for i in 0..terms {
+        let d = a[i * terms + i];
+        for k in 0..taps {
+            b[i * taps + k] /= d;
+        }
+    }
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resampler::tests::{fit_sine, run, sine, sine_phase};
+    use std::f64::consts::PI;
+
+    #[test]
+    fn kernels_pass_a_low_tone() {
+        let ratio = 48000.0 / 44100.0;
+        // largest gain error and images, relative to the tone, the longer the kernel the less
+        let limits = [(Kernel::Linear, 3e-3, 1e-3), (Kernel::Hermite, 1e-4, 1e-4), (Kernel::Lagrange4, 1e-4, 1e-5),
+                      (Kernel::Lagrange6, 1e-6, 1e-6), (Kernel::Farrow, 1e-3, 1e-3)];
+        for &(kernel, gain, images) in limits.iter() {
+            let mut interpolator = Interpolator::new(1, kernel, ratio);
+            let output = run(&mut interpolator, &sine(1000.0, 44100.0, 44100));
+
+            let (amplitude, residual) = fit_sine(&output[200..], 1000.0, 48000.0);
+            assert!((amplitude / 0.5 - 1.0).abs() < gain, "{:?} gain {}", kernel, amplitude / 0.5);
+            assert!(residual / 0.5 < images, "{:?} residual {}", kernel, residual / 0.5);
+        }
+    }
+
+    #[test]
+    fn latency_matches_the_delay() {
+        let (freq, rate, ratio) = (1000.0, 44100.0, 48000.0 / 44100.0);
+        for &kernel in Kernel::all().iter() {
+            let mut interpolator = Interpolator::new(1, kernel, ratio);
+            let output = run(&mut interpolator, &sine(freq, rate, 44100));
+
+            // output frame n is the input at n / ratio - latency
+            let skip = 200;
+            let w = 2.0 * PI * freq / rate;
+            let expected = w * (skip as f64 / ratio - interpolator.latency());
+            let error = (sine_phase(&output[skip..], freq, rate * ratio) - expected + PI).rem_euclid(2.0 * PI) - PI;
+            assert!((error / w).abs() < 1e-3, "{:?} off by {} frames", kernel, error / w);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use fifo::FifoStats;
+
+// Statistics of the extra devices of an aggregate or a fan-out, each resampled
+// to or from the clock master. Written by the audio threads without locking.
+
+#[derive(Debug, Clone, Default)]
+pub struct MemberStats {
+    pub ratio: f64,
+    // estimated clock deviation of the member relative to the master
+    pub ppm: f64,
+    pub xruns: u32,
+    pub fifo: FifoStats,
+}
+
+pub struct SharedStats {
+    ratio: AtomicU64,
+    ppm: AtomicU64,
+    xruns: AtomicU32,
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+    dropped_frames: AtomicU64,
+    inserted_frames: AtomicU64,
+}
+
+impl SharedStats {
+    pub fn new(ratio: f64) -> SharedStats {
+        SharedStats {
+            ratio: AtomicU64::new(ratio.to_bits()),
+            ppm: AtomicU64::new(0.0f64.to_bits()),
+            xruns: AtomicU32::new(0),
+            overflows: AtomicU64::new(0),
+            underflows: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            inserted_frames: AtomicU64::new(0),
+        }
+    }
+
+    pub fn update(&self, ratio: f64, ppm: f64, fifo: &FifoStats) {
+        self.ratio.store(ratio.to_bits(), Ordering::Relaxed);
+        self.ppm.store(ppm.to_bits(), Ordering::Relaxed);
+        self.overflows.store(fifo.overflows, Ordering::Relaxed);
+        self.underflows.store(fifo.underflows, Ordering::Relaxed);
+        self.dropped_frames.store(fifo.dropped_frames, Ordering::Relaxed);
+        self.inserted_frames.store(fifo.inserted_frames, Ordering::Relaxed);
+    }
+
+    pub fn xrun(&self) {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MemberStats {
+        MemberStats {
+            ratio: f64::from_bits(self.ratio.load(Ordering::Relaxed)),
+            ppm: f64::from_bits(self.ppm.load(Ordering::Relaxed)),
+            xruns: self.xruns.load(Ordering::Relaxed),
+            fifo: FifoStats {
+                overflows: self.overflows.load(Ordering::Relaxed),
+                underflows: self.underflows.load(Ordering::Relaxed),
+                dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+                inserted_frames: self.inserted_frames.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+// Handle on the member statistics, usable after the devices were handed over.
+#[derive(Clone)]
+pub struct MemberStatus {
+    members: Vec<(String, Arc<SharedStats>)>,
+}
+
+impl MemberStatus {
+    pub fn new(members: Vec<(String, Arc<SharedStats>)>) -> MemberStatus {
+        MemberStatus { members }
+    }
+
+    // (device, stats) of every member, the master excluded
+    pub fn members(&self) -> Vec<(String, MemberStats)> {
+        self.members.iter().map(|&(ref device, ref shared)| (device.clone(), shared.snapshot())).collect()
+    }
+
+    pub fn print(&self) {
+        for (device, member) in self.members() {
+            eprintln!("{}: ratio {:.9}, clock drift {:.1} ppm, {} xruns, FIFO overflows: {}, underflows: {}",
+                      device, member.ratio, member.ppm, member.xruns,
+                      member.fifo.overflows, member.fifo.underflows);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use libc;
+
+use telemetry::{Event, Record, Sink, Stream};
+
+// Prometheus text format metrics of a running loopback, served over HTTP on
+// GET /metrics. The state is fed by the telemetry reporter thread through
+// the Sink and read by the server thread, the audio threads are not involved.
+
+// upper bounds of the period jitter histogram in seconds
+const JITTER_BUCKETS: [f64; 8] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025];
+// seconds left out of the rate and jitter after the start or an xrun, while
+// the playback buffer fills up faster than real time
+const WARMUP: f64 = 2.0;
+
+#[derive(Default)]
+struct StreamState {
+    frames: u64,
+    xruns: u64,
+    // time of the first period after the start or an xrun
+    start: Option<f64>,
+    // time and frames counted since the first period after the warmup
+    first: Option<f64>,
+    last: f64,
+    frames_since_first: u64,
+    // absolute deviation of the period interval from the nominal period
+    jitter_counts: [u64; 8],
+    jitter_count: u64,
+    jitter_sum: f64,
+}
+
+impl StreamState {
+    fn period(&mut self, time: f64, frames: u32, rate: f64) {
+        self.frames += frames as u64;
+        let start = *self.start.get_or_insert(time);
+        if time - start < WARMUP {
+            return;
+        }
+        match self.first {
+            None => {
+                self.first = Some(time);
+                self.frames_since_first = 0;
+            }
+            Some(_) => {
+                let jitter = (time - self.last - frames as f64 / rate).abs();
+                for (count, &bound) in self.jitter_counts.iter_mut().zip(JITTER_BUCKETS.iter()) {
+                    if jitter <= bound {
+                        *count += 1;
+                    }
+                }
+                self.jitter_count += 1;
+                self.jitter_sum += jitter;
+                self.frames_since_first += frames as u64;
+            }
+        }
+        self.last = time;
+    }
+
+    fn xrun(&mut self) {
+        self.xruns += 1;
+        self.start = None;
+        self.first = None;
+    }
+
+    // frames per second measured over the periods since the first one
+    fn rate(&self) -> Option<f64> {
+        match self.first {
+            Some(first) if self.last > first => Some(self.frames_since_first as f64 / (self.last - first)),
+            _ => None,
+        }
+    }
+}
+
+struct State {
+    // nominal capture and playback rates
+    rates: [f64; 2],
+    streams: [StreamState; 2],
+    fill: Option<(u32, u32)>,
+    ratio: Option<f64>,
+    cpu_clocks: Vec<(Stream, libc::clockid_t)>,
+}
+
+fn index(stream: Stream) -> usize {
+    match stream {
+        Stream::Capture => 0,
+        Stream::Playback => 1,
+    }
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    state: Arc<Mutex<State>>,
+}
+
+impl Metrics {
+    pub fn new(capture_rate: u32, playback_rate: u32) -> Metrics {
+        Metrics {
+            state: Arc::new(Mutex::new(State {
+                rates: [capture_rate as f64, playback_rate as f64],
+                streams: Default::default(),
+                fill: None,
+                ratio: None,
+                cpu_clocks: Vec::new(),
+            })),
+        }
+    }
+
+    // Telemetry sink updating the metrics, for the reporter.
+    pub fn sink(&self) -> Box<dyn Sink> {
+        Box::new(self.clone())
+    }
+
+    // CPU time clocks of the real-time threads, from Running::cpu_clocks.
+    pub fn cpu_clocks(&self, clocks: Vec<(Stream, libc::clockid_t)>) {
+        self.state.lock().unwrap().cpu_clocks = clocks;
+    }
+
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+        let streams = [Stream::Capture, Stream::Playback];
+
+        if let Some((fill, target)) = state.fill {
+            metric(&mut out, "asrc_fifo_fill_frames", "gauge", "Capture frames waiting in the FIFO.");
+            let _ = writeln!(out, "asrc_fifo_fill_frames {}", fill);
+            metric(&mut out, "asrc_fifo_target_frames", "gauge", "FIFO fill the loopback keeps.");
+            let _ = writeln!(out, "asrc_fifo_target_frames {}", target);
+        }
+        if let Some(ratio) = state.ratio {
+            metric(&mut out, "asrc_ratio", "gauge", "Resampling ratio, playback rate over capture rate.");
+            let _ = writeln!(out, "asrc_ratio {:.12}", ratio);
+        }
+
+        metric(&mut out, "asrc_rate_hz", "gauge", "Sample rate measured from the period times since the start or the last xrun.");
+        for &stream in &streams {
+            if let Some(rate) = state.streams[index(stream)].rate() {
+                let _ = writeln!(out, "asrc_rate_hz{{stream=\"{}\"}} {:.6}", stream.name(), rate);
+            }
+        }
+        metric(&mut out, "asrc_nominal_rate_hz", "gauge", "Configured sample rate.");
+        for &stream in &streams {
+            let _ = writeln!(out, "asrc_nominal_rate_hz{{stream=\"{}\"}} {}", stream.name(), state.rates[index(stream)]);
+        }
+
+        metric(&mut out, "asrc_frames_total", "counter", "Frames through the device.");
+        for &stream in &streams {
+            let _ = writeln!(out, "asrc_frames_total{{stream=\"{}\"}} {}", stream.name(), state.streams[index(stream)].frames);
+        }
+        metric(&mut out, "asrc_xruns_total", "counter", "Device overruns and underruns.");
+        for &stream in &streams {
+            let _ = writeln!(out, "asrc_xruns_total{{stream=\"{}\"}} {}", stream.name(), state.streams[index(stream)].xruns);
+        }
+
+        metric(&mut out, "asrc_period_jitter_seconds", "histogram",
+               "Absolute deviation of the time between periods from the nominal period.");
+        for &stream in &streams {
+            let s = &state.streams[index(stream)];
+            for (count, bound) in s.jitter_counts.iter().zip(JITTER_BUCKETS.iter()) {
+                let _ = writeln!(out, "asrc_period_jitter_seconds_bucket{{stream=\"{}\",le=\"{}\"}} {}",
+                                 stream.name(), bound, count);
+            }
+            let _ = writeln!(out, "asrc_period_jitter_seconds_bucket{{stream=\"{}\",le=\"+Inf\"}} {}",
+                             stream.name(), s.jitter_count);
+            let _ = writeln!(out, "asrc_period_jitter_seconds_sum{{stream=\"{}\"}} {:.9}", stream.name(), s.jitter_sum);
+            let _ = writeln!(out, "asrc_period_jitter_seconds_count{{stream=\"{}\"}} {}", stream.name(), s.jitter_count);
+        }
+
+        if !state.cpu_clocks.is_empty() {
+            metric(&mut out, "asrc_thread_cpu_seconds_total", "counter", "CPU time of the real-time threads.");
+            for &(stream, clock) in &state.cpu_clocks {
+                // the clock is gone once the thread exited
+                if let Some(seconds) = cpu_time(clock) {
+                    let _ = writeln!(out, "asrc_thread_cpu_seconds_total{{thread=\"{}\"}} {:.6}", stream.name(), seconds);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn cpu_time(clock: libc::clockid_t) -> Option<f64> {
+    unsafe {
+        let mut ts: libc::timespec = mem::zeroed();
+        if libc::clock_gettime(clock, &mut ts) == 0 {
+            Some(ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9)
+        } else {
+            None
+        }
+    }
+}
+
+impl Sink for Metrics {
+    fn record(&mut self, _source: &str, record: &Record) {
+        let mut state = self.state.lock().unwrap();
+        match record.event {
+            Event::Period { stream, frames } => {
+                let rate = state.rates[index(stream)];
+                state.streams[index(stream)].period(record.time, frames, rate);
+            }
+            Event::XRun { stream, .. } => state.streams[index(stream)].xrun(),
+            Event::Fill { frames, target } => state.fill = Some((frames, target)),
+            Event::Ratio(ratio) => state.ratio = Some(ratio),
+            Event::Status { .. } => {}
+        }
+    }
+}
+
+// Serves the metrics until dropped.
+pub struct Server {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    // the bound address, with the port picked for port 0
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+// address is host:port, e.g. 127.0.0.1:9100
+pub fn serve(address: &str, metrics: Metrics) -> io::Result<Server> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let address = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = answer(stream, &metrics) {
+                            eprintln!("Metrics endpoint: {}", e);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => {
+                        eprintln!("Metrics endpoint: {}", e);
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    Ok(Server { address, stop, thread: Some(thread) })
+}
+
+// Serves --metrics=<address> when given, exits when the address cannot be
+// bound. The metrics are fed by the same telemetry as the other sinks, their
+// sink is added to sinks.
+pub fn serve_option(address: &Option<String>, capture_rate: u32, playback_rate: u32,
+                    sinks: &mut Vec<Box<dyn Sink>>) -> Option<(Metrics, Server)> {
+    address.as_ref().map(|address| {
+        let metrics = Metrics::new(capture_rate, playback_rate);
+        let server = serve(address, metrics.clone()).unwrap_or_else(|e| {
+            eprintln!("Cannot serve metrics on {}: {}", address, e);
+            process::exit(1);
+        });
+        eprintln!("Metrics on http://{}/metrics", server.address());
+        sinks.push(metrics.sink());
+        (metrics, server)
+    })
+}
+
+fn answer(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers are not needed, only read past them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Metrics are on /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    let mut stream = &stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    // value of the sample line starting with name
+    fn sample(body: &str, name: &str) -> f64 {
+        body.lines()
+            .find(|l| l.starts_with(name))
+            .and_then(|l| l.rsplit(' ').next())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| panic!("no sample {}", name))
+    }
+
+    #[test]
+    fn served_over_http() {
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        let (_, server) = serve_option(&Some("127.0.0.1:0".to_string()), 48000, 48000, &mut sinks).unwrap();
+        assert_eq!(sinks.len(), 1);
+
+        // 256 frame capture periods for 4 s, every fourth one 0.4 to 3.2 ms late
+        let period = 256.0 / 48000.0;
+        let mut time = 100.0;
+        for n in 0..750 {
+            let late = if n % 4 == 1 { 0.0004 * (1 << (n / 4 % 4)) as f64 } else { 0.0 };
+            sinks.record("capture", &Record {
+                time: time + late,
+                event: Event::Period { stream: Stream::Capture, frames: 256 },
+            });
+            time += period;
+        }
+        sinks.record("playback", &Record { time, event: Event::Fill { frames: 500, target: 768 } });
+
+        let response = get(server.address(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert_eq!(sample(body, "asrc_fifo_fill_frames"), 500.0);
+        assert_eq!(sample(body, "asrc_frames_total{stream=\"capture\"}"), 750.0 * 256.0);
+        assert!((sample(body, "asrc_rate_hz{stream=\"capture\"}") - 48000.0).abs() < 100.0);
+
+        let prefix = "asrc_period_jitter_seconds_bucket{stream=\"capture\",le=";
+        let buckets: Vec<f64> = body.lines()
+            .filter(|l| l.starts_with(prefix))
+            .map(|l| l.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(buckets.len(), JITTER_BUCKETS.len() + 1);
+        assert!(buckets.windows(2).all(|w| w[0] <= w[1]), "{:?}", buckets);
+        assert!(buckets[0] > 0.0 && buckets[0] < buckets[buckets.len() - 1]);
+        let inf = sample(body, &format!("{}\"+Inf\"}}", prefix));
+        assert_eq!(inf, buckets[buckets.len() - 1]);
+        assert_eq!(sample(body, "asrc_period_jitter_seconds_count{stream=\"capture\"}"), inf);
+
+        assert!(get(server.address(), "/").starts_with("HTTP/1.1 404"));
+    }
+}
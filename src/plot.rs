@@ -0,0 +1,814 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::io::prelude::*;
+
+// Line plots written as SVG, or PNG with a built in bitmap font, chosen by
+// the file extension. Enough for quick looks at measurement data without
+// gnuplot, not a general plotting library.
+
+pub const WIDTH: usize = 1000;
+pub const HEIGHT: usize = 600;
+
+const MARGIN_LEFT: f64 = 90.0;
+const MARGIN_RIGHT: f64 = 30.0;
+const MARGIN_TOP: f64 = 40.0;
+const MARGIN_BOTTOM: f64 = 60.0;
+
+const COLORS: [(u8, u8, u8); 6] =
+    [(0x1f, 0x77, 0xb4), (0xd6, 0x27, 0x28), (0x2c, 0xa0, 0x2c), (0xff, 0x7f, 0x0e), (0x94, 0x67, 0xbd), (0x8c, 0x56, 0x4b)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    Linear,
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Lines,
+    // vertical bars from the x axis, for histograms
+    Steps,
+    Points,
+}
+
+pub struct Series {
+    pub label: String,
+    pub points: Vec<(f64, f64)>,
+    pub style: Style,
+}
+
+pub struct Plot {
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub x_scale: Scale,
+    pub y_scale: Scale,
+    pub series: Vec<Series>,
+}
+
+impl Plot {
+    pub fn new(title: &str, x_label: &str, y_label: &str) -> Plot {
+        Plot {
+            title: title.to_string(),
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            x_scale: Scale::Linear,
+            y_scale: Scale::Linear,
+            series: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, label: &str, points: Vec<(f64, f64)>, style: Style) {
+        self.series.push(Series { label: label.to_string(), points, style });
+    }
+
+    // .svg or .png
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if path.to_lowercase().ends_with(".png") {
+            let mut canvas = Canvas::new(WIDTH, HEIGHT);
+            self.draw(&mut canvas);
+            out.write_all(&canvas.png())?;
+        } else {
+            let mut svg = Svg::new(WIDTH, HEIGHT);
+            self.draw(&mut svg);
+            out.write_all(svg.finish().as_bytes())?;
+        }
+        out.flush()
+    }
+
+    fn draw<S: Surface>(&self, s: &mut S) {
+        let (w, h) = (WIDTH as f64, HEIGHT as f64);
+        let x_axis = Axis::new(self.x_scale, self.series.iter().flat_map(|s| s.points.iter().map(|p| p.0)),
+                               MARGIN_LEFT, w - MARGIN_RIGHT);
+        let y_axis = Axis::new(self.y_scale, self.series.iter().flat_map(|s| s.points.iter().map(|p| p.1)),
+                               h - MARGIN_BOTTOM, MARGIN_TOP);
+
+        s.rect(0.0, 0.0, w, h, (255, 255, 255));
+
+        // grid and tick labels
+        let grid = (0xdd, 0xdd, 0xdd);
+        let ink = (0x33, 0x33, 0x33);
+        for &(value, major) in &x_axis.ticks() {
+            let x = x_axis.map(value);
+            s.line(x, MARGIN_TOP, x, h - MARGIN_BOTTOM, if major { grid } else { (0xf0, 0xf0, 0xf0) });
+            if major {
+                s.text(x, h - MARGIN_BOTTOM + 18.0, &x_axis.label(value), Anchor::Middle, ink);
+            }
+        }
+        for &(value, major) in &y_axis.ticks() {
+            let y = y_axis.map(value);
+            s.line(MARGIN_LEFT, y, w - MARGIN_RIGHT, y, if major { grid } else { (0xf0, 0xf0, 0xf0) });
+            if major {
+                s.text(MARGIN_LEFT - 6.0, y + 4.0, &y_axis.label(value), Anchor::End, ink);
+            }
+        }
+        s.line(MARGIN_LEFT, MARGIN_TOP, MARGIN_LEFT, h - MARGIN_BOTTOM, ink);
+        s.line(MARGIN_LEFT, h - MARGIN_BOTTOM, w - MARGIN_RIGHT, h - MARGIN_BOTTOM, ink);
+
+        s.text(w / 2.0, MARGIN_TOP - 16.0, &self.title, Anchor::Middle, ink);
+        s.text(w / 2.0, h - 16.0, &self.x_label, Anchor::Middle, ink);
+        s.vertical_text(20.0, h / 2.0, &self.y_label, ink);
+
+        for (i, series) in self.series.iter().enumerate() {
+            let color = COLORS[i % COLORS.len()];
+            let points: Vec<(f64, f64)> = series.points.iter()
+                .filter(|p| x_axis.contains(p.0) && y_axis.contains(p.1))
+                .map(|&(x, y)| (x_axis.map(x), y_axis.map(y)))
+                .collect();
+            match series.style {
+                Style::Lines => s.polyline(&points, color),
+                Style::Steps => {
+                    let base = y_axis.map(y_axis.base());
+                    let width = if points.len() > 1 { (points[1].0 - points[0].0).abs() } else { 4.0 };
+                    for &(x, y) in &points {
+                        s.rect(x - width / 2.0, y.min(base), width.max(1.0), (base - y).abs(), color);
+                    }
+                }
+                Style::Points => for &(x, y) in &points {
+                    s.rect(x - 2.5, y - 2.5, 5.0, 5.0, color);
+                },
+            }
+        }
+
+        // legend, top right, over the data
+        let longest = self.series.iter().map(|s| s.label.chars().count()).max().unwrap_or(0);
+        s.rect(w - MARGIN_RIGHT - 176.0, MARGIN_TOP + 2.0, 34.0 + longest as f64 * 7.0,
+               self.series.len() as f64 * 16.0 + 4.0, (255, 255, 255));
+        for (i, series) in self.series.iter().enumerate() {
+            let y = MARGIN_TOP + 14.0 + i as f64 * 16.0;
+            s.rect(w - MARGIN_RIGHT - 170.0, y - 5.0, 16.0, 3.0, COLORS[i % COLORS.len()]);
+            s.text(w - MARGIN_RIGHT - 148.0, y, &series.label, Anchor::Start, ink);
+        }
+    }
+}
+
+/*
+ * Axes
+ */
+
+struct Axis {
+    scale: Scale,
+    min: f64,
+    max: f64,
+    // pixel positions of min and max
+    from: f64,
+    to: f64,
+}
+
+impl Axis {
+    fn new<I: Iterator<Item = f64>>(scale: Scale, values: I, from: f64, to: f64) -> Axis {
+        let (mut min, mut max) = (f64::MAX, f64::MIN);
+        for v in values.filter(|v| v.is_finite() && (scale == Scale::Linear || *v > 0.0)) {
+            min = min.min(v);
+            max = max.max(v);
+        }
+        if min > max {
+            min = if scale == Scale::Log { 1.0 } else { 0.0 };
+            max = min;
+        }
+        match scale {
+            Scale::Linear => {
+                if max - min <= max.abs().max(min.abs()) * 1e-12 {
+                    let pad = if min == 0.0 { 1.0 } else { min.abs() * 0.1 };
+                    min -= pad;
+                    max += pad;
+                }
+                let step = nice_step((max - min) / 8.0);
+                min = (min / step).floor() * step;
+                max = (max / step).ceil() * step;
+            }
+            Scale::Log => {
+                min = 10f64.powf(min.log10().floor());
+                max = 10f64.powf(max.log10().ceil());
+                if max <= min {
+                    max = min * 10.0;
+                }
+            }
+        }
+        Axis { scale, min, max, from, to }
+    }
+
+    fn contains(&self, v: f64) -> bool {
+        v.is_finite() && (self.scale == Scale::Linear || v > 0.0)
+    }
+
+    fn map(&self, v: f64) -> f64 {
+        let f = match self.scale {
+            Scale::Linear => (v - self.min) / (self.max - self.min),
+            Scale::Log => (v / self.min).log10() / (self.max / self.min).log10(),
+        };
+        self.from + (self.to - self.from) * f
+    }
+
+    // where bars start
+    fn base(&self) -> f64 {
+        match self.scale {
+            Scale::Linear => 0f64.max(self.min).min(self.max),
+            Scale::Log => self.min,
+        }
+    }
+
+    // (value, major)
+    fn ticks(&self) -> Vec<(f64, bool)> {
+        match self.scale {
+            Scale::Linear => {
+                let step = nice_step((self.max - self.min) / 8.0);
+                let n = ((self.max - self.min) / step).round() as i64;
+                (0..n + 1).map(|i| (self.min + i as f64 * step, true)).collect()
+            }
+            Scale::Log => {
+                let first = self.min.log10().round() as i32;
+                let decades = (self.max / self.min).log10().round() as i32;
+                // about 10 labels at most
+                let every = (decades + 9) / 10;
+                let mut ticks = Vec::new();
+                for d in 0..decades + 1 {
+                    let decade = format!("1e{}", first + d).parse::<f64>().unwrap();
+                    ticks.push((decade, d % every == 0));
+                    if d < decades && decades <= 6 {
+                        ticks.extend((2..10).map(|m| (decade * m as f64, false)));
+                    }
+                }
+                ticks
+            }
+        }
+    }
+
+    fn label(&self, v: f64) -> String {
+        // significant digits as fine as the step between labels
+        let (magnitude, step) = match self.scale {
+            Scale::Linear => (self.max.abs().max(self.min.abs()), nice_step((self.max - self.min) / 8.0)),
+            Scale::Log => (v, v),
+        };
+        if v.abs() < step * 1e-9 {
+            "0".to_string()
+        } else if !(1e-3..1e5).contains(&magnitude) {
+            let digits = (magnitude.log10().floor() - step.log10().floor()).max(0.0) as usize;
+            format!("{:.*e}", digits, v)
+        } else {
+            let decimals = (-step.log10().floor()).max(0.0) as usize;
+            format!("{:.*}", decimals, v)
+        }
+    }
+}
+
+// 1, 2 or 5 times a power of 10, at least x
+fn nice_step(x: f64) -> f64 {
+    let power = 10f64.powf(x.log10().floor());
+    let m = x / power;
+    let nice = if m <= 1.0 { 1.0 } else if m <= 2.0 { 2.0 } else if m <= 5.0 { 5.0 } else { 10.0 };
+    nice * power
+}
+
+/*
+ * Output surfaces
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+type Color = (u8, u8, u8);
+
+trait Surface {
+    fn rect(&mut self, x: f64, y: f64, w: f64, h: f64, color: Color);
+    fn line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Color);
+    fn polyline(&mut self, points: &[(f64, f64)], color: Color);
+    // baseline at y
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, color: Color);
+    // rotated a quarter turn counterclockwise, centered on x, y
+    fn vertical_text(&mut self, x: f64, y: f64, text: &str, color: Color);
+}
+
+struct Svg {
+    out: String,
+}
+
+impl Svg {
+    fn new(width: usize, height: usize) -> Svg {
+        let mut out = String::new();
+        out += &format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+                         viewBox=\"0 0 {} {}\" font-family=\"sans-serif\" font-size=\"12\">\n",
+                        width, height, width, height);
+        Svg { out }
+    }
+
+    fn finish(mut self) -> String {
+        self.out += "</svg>\n";
+        self.out
+    }
+}
+
+fn svg_color(c: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", c.0, c.1, c.2)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl Surface for Svg {
+    fn rect(&mut self, x: f64, y: f64, w: f64, h: f64, color: Color) {
+        self.out += &format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"/>\n",
+                             x, y, w, h, svg_color(color));
+    }
+
+    fn line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Color) {
+        self.out += &format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{}\"/>\n",
+                             x0, y0, x1, y1, svg_color(color));
+    }
+
+    fn polyline(&mut self, points: &[(f64, f64)], color: Color) {
+        self.out += "<polyline fill=\"none\" stroke-width=\"1.2\" stroke=\"";
+        self.out += &svg_color(color);
+        self.out += "\" points=\"";
+        for &(x, y) in points {
+            self.out += &format!("{:.1},{:.1} ", x, y);
+        }
+        self.out += "\"/>\n";
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, color: Color) {
+        let anchor = match anchor {
+            Anchor::Start => "start",
+            Anchor::Middle => "middle",
+            Anchor::End => "end",
+        };
+        self.out += &format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"{}\" fill=\"{}\">{}</text>\n",
+                             x, y, anchor, svg_color(color), xml_escape(text));
+    }
+
+    fn vertical_text(&mut self, x: f64, y: f64, text: &str, color: Color) {
+        self.out += &format!("<text transform=\"translate({:.1},{:.1}) rotate(-90)\" text-anchor=\"middle\" \
+                              fill=\"{}\">{}</text>\n", x, y, svg_color(color), xml_escape(text));
+    }
+}
+
+// RGB raster
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Canvas {
+        Canvas { width, height, pixels: vec![255; width * height * 3] }
+    }
+
+    fn set(&mut self, x: i64, y: i64, color: Color) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            let i = (y as usize * self.width + x as usize) * 3;
+            self.pixels[i] = color.0;
+            self.pixels[i + 1] = color.1;
+            self.pixels[i + 2] = color.2;
+        }
+    }
+
+    // glyph columns of the 5x7 font, left to right at x, bottom row at y
+    fn glyphs(&mut self, x: i64, y: i64, text: &str, vertical: bool, color: Color) {
+        for (n, c) in text.chars().enumerate() {
+            let code = c as usize;
+            let glyph = if (0x20..0x7f).contains(&code) { &FONT[code - 0x20] } else { &FONT[b'?' as usize - 0x20] };
+            for (col, bits) in glyph.iter().enumerate() {
+                for row in 0..8 {
+                    if bits & (1 << row) != 0 {
+                        let along = n as i64 * 6 + col as i64;
+                        let across = row as i64 - 7;
+                        if vertical {
+                            self.set(x + across, y - along, color);
+                        } else {
+                            self.set(x + along, y + across, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // filter type 0 rows, deflated
+    fn png(&self) -> Vec<u8> {
+        let stride = self.width * 3 + 1;
+        let mut raw = Vec::with_capacity(stride * self.height);
+        for row in self.pixels.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        zlib.extend(deflate(&raw, &[3, stride]));
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bit RGB, deflate, no interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        png_chunk(&mut png, b"IHDR", &ihdr);
+        png_chunk(&mut png, b"IDAT", &zlib);
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Single deflate block with the fixed Huffman codes, matching only at the
+// given distances: the previous pixel and the row above cover most of a plot.
+fn deflate(data: &[u8], distances: &[usize]) -> Vec<u8> {
+    const LENGTH_BASE: [usize; 29] =
+        [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195,
+         227, 258];
+    const LENGTH_EXTRA: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+    const DIST_BASE: [usize; 30] =
+        [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073,
+         4097, 6145, 8193, 12289, 16385, 24577];
+
+    let mut bits = BitWriter { out: Vec::new(), acc: 0, len: 0 };
+    // final block, fixed codes
+    bits.put(0b011, 3);
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        for &d in distances.iter().filter(|&&d| d > 0 && d <= i && d <= 32768) {
+            let max = (data.len() - i).min(258);
+            let len = (0..max).take_while(|&k| data[i + k] == data[i + k - d]).count();
+            if len > best.0 {
+                best = (len, d);
+            }
+        }
+
+        if best.0 >= 3 {
+            let (len, dist) = best;
+            let code = LENGTH_BASE.iter().rposition(|&b| b <= len).unwrap();
+            bits.symbol(257 + code);
+            bits.put((len - LENGTH_BASE[code]) as u32, LENGTH_EXTRA[code]);
+            let code = DIST_BASE.iter().rposition(|&b| b <= dist).unwrap();
+            bits.code(code as u32, 5);
+            bits.put((dist - DIST_BASE[code]) as u32, (code.max(2) as u32 - 2) / 2);
+            i += len;
+        } else {
+            bits.symbol(data[i] as usize);
+            i += 1;
+        }
+    }
+    bits.symbol(256);
+    bits.finish()
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    len: u32,
+}
+
+impl BitWriter {
+    // least significant bit first
+    fn put(&mut self, value: u32, bits: u32) {
+        self.acc |= value << self.len;
+        self.len += bits;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn code(&mut self, code: u32, bits: u32) {
+        let reversed = code.reverse_bits() >> (32 - bits);
+        self.put(reversed, bits);
+    }
+
+    // literal or length symbol in the fixed code
+    fn symbol(&mut self, symbol: usize) {
+        let s = symbol as u32;
+        match symbol {
+            0..=143 => self.code(0x30 + s, 8),
+            144..=255 => self.code(0x190 + s - 144, 9),
+            256..=279 => self.code(s - 256, 7),
+            _ => self.code(0xc0 + s - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+impl Surface for Canvas {
+    fn rect(&mut self, x: f64, y: f64, w: f64, h: f64, color: Color) {
+        let (x0, y0) = (x.round() as i64, y.round() as i64);
+        let (x1, y1) = ((x + w).round().max(x0 as f64 + 1.0) as i64, (y + h).round().max(y0 as f64 + 1.0) as i64);
+        for py in y0.max(0)..y1.min(self.height as i64) {
+            for px in x0.max(0)..x1.min(self.width as i64) {
+                self.set(px, py, color);
+            }
+        }
+    }
+
+    fn line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Color) {
+        // Bresenham
+        let (mut x, mut y) = (x0.round() as i64, y0.round() as i64);
+        let (x1, y1) = (x1.round() as i64, y1.round() as i64);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (sx, sy) = (if x < x1 { 1 } else { -1 }, if y < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        loop {
+            self.set(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    fn polyline(&mut self, points: &[(f64, f64)], color: Color) {
+        for pair in points.windows(2) {
+            self.line(pair[0].0, pair[0].1, pair[1].0, pair[1].1, color);
+        }
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, color: Color) {
+        let width = text.chars().count() as f64 * 6.0;
+        let x = match anchor {
+            Anchor::Start => x,
+            Anchor::Middle => x - width / 2.0,
+            Anchor::End => x - width,
+        };
+        self.glyphs(x.round() as i64, y.round() as i64, text, false, color);
+    }
+
+    fn vertical_text(&mut self, x: f64, y: f64, text: &str, color: Color) {
+        let width = text.chars().count() as f64 * 6.0;
+        self.glyphs(x.round() as i64 + 4, (y + width / 2.0).round() as i64, text, true, color);
+    }
+}
+
+// 5x7 font of the printable ASCII characters, columns with the top row in
+// bit 0 and descenders in bit 7.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5f, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], [0x24, 0x2a, 0x7f, 0x2a, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x56, 0x20, 0x50], [0x00, 0x08, 0x07, 0x03, 0x00], [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00], [0x2a, 0x1c, 0x7f, 0x1c, 0x2a], [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x80, 0x70, 0x30, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x00, 0x60, 0x60, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3e, 0x51, 0x49, 0x45, 0x3e], [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x72, 0x49, 0x49, 0x49, 0x46], [0x21, 0x41, 0x49, 0x4d, 0x33], [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3c, 0x4a, 0x49, 0x49, 0x31], [0x41, 0x21, 0x11, 0x09, 0x07],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x46, 0x49, 0x49, 0x29, 0x1e], [0x00, 0x00, 0x14, 0x00, 0x00],
+    [0x00, 0x40, 0x34, 0x00, 0x00], [0x00, 0x08, 0x14, 0x22, 0x41], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x59, 0x09, 0x06], [0x3e, 0x41, 0x5d, 0x59, 0x4e],
+    [0x7c, 0x12, 0x11, 0x12, 0x7c], [0x7f, 0x49, 0x49, 0x49, 0x36], [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x41, 0x3e], [0x7f, 0x49, 0x49, 0x49, 0x41], [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x41, 0x51, 0x73], [0x7f, 0x08, 0x08, 0x08, 0x7f], [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01], [0x7f, 0x08, 0x14, 0x22, 0x41], [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x1c, 0x02, 0x7f], [0x7f, 0x04, 0x08, 0x10, 0x7f], [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06], [0x3e, 0x41, 0x51, 0x21, 0x5e], [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x26, 0x49, 0x49, 0x49, 0x32], [0x03, 0x01, 0x7f, 0x01, 0x03], [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], [0x3f, 0x40, 0x38, 0x40, 0x3f], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03], [0x61, 0x59, 0x49, 0x4d, 0x43], [0x00, 0x7f, 0x41, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x41, 0x7f], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x03, 0x07, 0x08, 0x00], [0x20, 0x54, 0x54, 0x78, 0x40],
+    [0x7f, 0x28, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x28], [0x38, 0x44, 0x44, 0x28, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x00, 0x08, 0x7e, 0x09, 0x02], [0x18, 0xa4, 0xa4, 0x9c, 0x78],
+    [0x7f, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7d, 0x40, 0x00], [0x20, 0x40, 0x40, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7f, 0x40, 0x00], [0x7c, 0x04, 0x78, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0xfc, 0x18, 0x24, 0x24, 0x18],
+    [0x18, 0x24, 0x24, 0x18, 0xfc], [0x7c, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x24],
+    [0x04, 0x04, 0x3f, 0x44, 0x24], [0x3c, 0x40, 0x40, 0x20, 0x7c], [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], [0x44, 0x28, 0x10, 0x28, 0x44], [0x4c, 0x90, 0x90, 0x90, 0x7c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x77, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00], [0x02, 0x01, 0x02, 0x04, 0x02],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // past the 5552 bytes between the modulo reductions
+        let data = vec![0xffu8; 100_000];
+        let (a, b) = data.iter().fold((1u64, 0u64), |(a, b), &x| ((a + x as u64) % 65521, (b + a + x as u64) % 65521));
+        assert_eq!(adler32(&data), (b << 16 | a) as u32);
+    }
+
+    // Reference inflater of a single fixed Huffman block, with the length and
+    // distance tables rebuilt from RFC 1951 rather than taken from deflate().
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn bits(&mut self, n: u32) -> usize {
+            let mut value = 0;
+            for i in 0..n {
+                let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                value |= (bit as usize) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        fn code(&mut self, n: u32) -> usize {
+            (0..n).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn symbol(&mut self) -> usize {
+            let code = self.code(7);
+            if code < 0x18 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | self.bits(1)) - 0x190,
+            }
+        }
+    }
+
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        let mut lengths = vec![(3, 0)];
+        for code in 0..28 {
+            let extra = if code < 8 { 0 } else { (code - 4) / 4 };
+            let base = lengths[code as usize].0 + (1 << extra);
+            lengths[code as usize].1 = extra;
+            lengths.push((base, 0));
+        }
+        // one short of what the last code with extra bits would give
+        lengths[28] = (258, 0);
+        let distances: Vec<(usize, u32)> = (0..30).scan(1, |base, code| {
+            let extra = if code < 4 { 0 } else { (code - 2) / 2 };
+            let entry = (*base, extra);
+            *base += 1 << extra;
+            Some(entry)
+        }).collect();
+
+        let mut bits = BitReader { data: stream, pos: 0 };
+        assert_eq!((bits.bits(1), bits.bits(2)), (1, 1), "final block with fixed codes");
+        let mut out = Vec::new();
+        loop {
+            let symbol = bits.symbol();
+            if symbol < 256 {
+                out.push(symbol as u8);
+            } else if symbol == 256 {
+                break;
+            } else {
+                let (base, extra) = lengths[symbol - 257];
+                let len = base + bits.bits(extra);
+                let (base, extra) = distances[bits.code(5)];
+                let dist = base + bits.bits(extra);
+                for _ in 0..len {
+                    let x = out[out.len() - dist];
+                    out.push(x);
+                }
+            }
+        }
+        // nothing but padding after the end of the block
+        assert_eq!(stream.len(), bits.pos.div_ceil(8));
+        out
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        // no match, the fixed code of "a" as zlib writes it
+        assert_eq!(deflate(b"a", &[1]), [0x4b, 0x04, 0x00]);
+
+        // runs long enough for every length code, rows repeating at a distance
+        // that needs extra bits, and all the literals
+        let mut data: Vec<u8> = (0..=255).collect();
+        for n in 1..300 {
+            data.extend((0..n).map(|_| (n % 7) as u8));
+        }
+        let row: Vec<u8> = (0..3001).map(|i| (i * 31 % 251) as u8).collect();
+        for _ in 0..4 {
+            data.extend_from_slice(&row);
+        }
+        for &distances in [&[][..], &[1][..], &[1, 3001][..], &[3, 3001][..]].iter() {
+            assert!(inflate(&deflate(&data, distances)) == data, "distances {:?}", distances);
+        }
+        assert!(deflate(&data, &[1, 3001]).len() < data.len() / 4);
+    }
+
+    #[test]
+    fn png_chunks() {
+        let mut canvas = Canvas::new(7, 5);
+        canvas.set(0, 0, (255, 0, 0));
+        canvas.set(6, 4, (0, 0, 255));
+        canvas.set(3, 2, (1, 2, 3));
+        let png = canvas.png();
+
+        assert_eq!(png[..8], [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < png.len() {
+            let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+            let body = &png[pos + 4..pos + 8 + len];
+            let crc = &png[pos + 8 + len..pos + 12 + len];
+            assert_eq!(crc, crc32(body).to_be_bytes());
+            chunks.push((body[..4].to_vec(), body[4..].to_vec()));
+            pos += 12 + len;
+        }
+        assert_eq!(pos, png.len());
+        let kinds: Vec<&[u8]> = chunks.iter().map(|c| &c.0[..]).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], &b"IDAT"[..], &b"IEND"[..]]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 7, 0, 0, 0, 5, 8, 2, 0, 0, 0]);
+        assert!(chunks[2].1.is_empty());
+
+        // zlib: header, deflate, Adler-32 of the filtered rows
+        let zlib = &chunks[1].1;
+        assert_eq!((zlib[0] as u16 * 256 + zlib[1] as u16) % 31, 0);
+        let raw = inflate(&zlib[2..zlib.len() - 4]);
+        assert_eq!(zlib[zlib.len() - 4..], adler32(&raw).to_be_bytes());
+        assert_eq!(raw.len(), 5 * (1 + 7 * 3));
+        for (y, row) in raw.chunks(1 + 7 * 3).enumerate() {
+            assert_eq!(row[0], 0);
+            assert_eq!(row[1..], canvas.pixels[y * 7 * 3..(y + 1) * 7 * 3]);
+        }
+        assert_eq!(raw[1..4], [255, 0, 0]);
+        assert_eq!(raw[2 * 22 + 1 + 9..2 * 22 + 1 + 12], [1, 2, 3]);
+    }
+
+    #[test]
+    fn svg_document() {
+        let mut plot = Plot::new("Drift <ppm> & fill", "time (s)", "ppm");
+        plot.add("capture", vec![(0.0, 1.0), (1.0, 2.0), (2.0, f64::NAN), (3.0, 4.0)], Style::Lines);
+        plot.add("histogram", vec![(0.0, 3.0), (1.0, 5.0)], Style::Steps);
+        let mut svg = Svg::new(WIDTH, HEIGHT);
+        plot.draw(&mut svg);
+        let svg = svg.finish();
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"1000\" height=\"600\""));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<svg").count(), 1);
+        assert!(svg.contains(">Drift &lt;ppm&gt; &amp; fill</text>"));
+        assert!(svg.contains("rotate(-90)\" text-anchor=\"middle\" fill=\"#333333\">ppm</text>"));
+
+        // the point that is not a number is left out of the line
+        let polylines: Vec<&str> = svg.lines().filter(|l| l.starts_with("<polyline")).collect();
+        assert_eq!(polylines.len(), 1);
+        assert!(polylines[0].contains(&svg_color(COLORS[0])));
+        assert_eq!(polylines[0].split("points=\"").nth(1).unwrap().split_whitespace().count(), 4);
+
+        // one bar per point of the histogram, in the second color
+        let bars = svg.lines().filter(|l| l.starts_with("<rect") && l.contains(&svg_color(COLORS[1]))).count();
+        assert_eq!(bars, 2 + 1);
+        for line in svg.lines() {
+            assert!(line.starts_with('<') && line.ends_with('>'), "{}", line);
+        }
+    }
+}
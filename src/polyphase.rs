@@ -0,0 +1,370 @@
+use std::sync::{Arc, Mutex};
+
+use resampler::{kaiser, sinc, Resampler, SincResampler};
+use simd;
+
+// Exact rational resampling: up by L, low pass, down by M, computed as a
+// polyphase filter bank of L phases so that only the kept outputs are
+// computed. 44100 to 48000 Hz is L/M = 160/147.
+//
+// RationalResampler puts a short windowed sinc after it for the drift. Working
+// at a ratio close to 1 on an already band limited signal, it does not need
+// to low pass and a few taps are enough. The resampler is made for the nominal
+// ratio, set_ratio then only moves the second stage.
+//
+// Filter banks are made once per ratio and shared by every resampler at it.
+// Those of the common ratios below are all made ahead, on the first use.
+
+// largest L, the bank holds L * TAPS coefficients
+const MAX_PHASES: u64 = 1024;
+// taps per phase, Kaiser beta and cutoff as a fraction of the lowest Nyquist frequency
+const TAPS: usize = 64;
+const BETA: f64 = 9.0;
+const ROLLOFF: f64 = 0.9;
+// drift stage, no low pass needed at a ratio this close to 1
+const DRIFT_TAPS: usize = 48;
+const DRIFT_PHASES: usize = 512;
+const DRIFT_BETA: f64 = 9.0;
+const DRIFT_CUTOFF: f64 = 0.97;
+// frames between the two stages
+const MID_FRAMES: usize = 256;
+// up / down of 44100 <-> 48000 Hz and of the octave steps
+const COMMON: [(usize, usize); 5] = [(160, 147), (147, 160), (2, 1), (4, 1), (1, 2)];
+
+// up, down and the bank
+type Bank = (usize, usize, Arc<Vec<f32>>);
+static BANKS: Mutex<Vec<Bank>> = Mutex::new(Vec::new());
+
+// Closest L/M to ratio with L up to MAX_PHASES, by continued fractions.
+pub fn rational(ratio: f64) -> (usize, usize) {
+    let (mut p0, mut q0, mut p1, mut q1) = (0u64, 1u64, 1u64, 0u64);
+    let mut x = ratio;
+    for _ in 0..64 {
+        let a = x.floor() as u64;
+        let (p2, q2) = (a * p1 + p0, a * q1 + q0);
+        if p2 > MAX_PHASES || q2 > MAX_PHASES * 64 {
+            break;
+        }
+        p0 = p1;
+        q0 = q1;
+        p1 = p2;
+        q1 = q2;
+        let rest = x - a as f64;
+        if rest < 1e-9 || (p1 as f64 / q1 as f64 / ratio - 1.0).abs() < 1e-12 {
+            break;
+        }
+        x = 1.0 / rest;
+    }
+    if p1 == 0 || q1 == 0 {
+        (1, 1)
+    } else {
+        (p1 as usize, q1 as usize)
+    }
+}
+
+// Fixed ratio up / down polyphase resampler.
+pub struct Polyphase {
+    channels: usize,
+    up: usize,
+    down: usize,
+    // up rows of TAPS coefficients, oldest frame first
+    bank: Arc<Vec<f32>>,
+    history: Vec<f32>,
+    write_pos: usize,
+    // current phase, and input frames to take before the next output
+    phase: usize,
+    pending: usize,
+}
+
+impl Polyphase {
+    pub fn new(channels: usize, up: usize, down: usize) -> Polyphase {
+        simd::isa();
+        Polyphase {
+            channels,
+            up,
+            down,
+            bank: bank(up, down),
+            history: vec![0.0; channels * TAPS * 2],
+            write_pos: 0,
+            phase: 0,
+            pending: 0,
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[f32]) {
+        let len = TAPS * 2;
+        for (c, &x) in frame.iter().enumerate() {
+            self.history[c * len + self.write_pos] = x;
+            self.history[c * len + self.write_pos + TAPS] = x;
+        }
+        self.write_pos = (self.write_pos + 1) % TAPS;
+    }
+}
+
+impl Resampler for Polyphase {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn ratio(&self) -> f64 {
+        self.up as f64 / self.down as f64
+    }
+
+    // the ratio is fixed, see RationalResampler to follow a drift
+    fn set_ratio(&mut self, _ratio: f64) {}
+
+    fn latency(&self) -> f64 {
+        (TAPS / 2 + 1) as f64
+    }
+
+    fn reset(&mut self) {
+        for x in self.history.iter_mut() {
+            *x = 0.0;
+        }
+        self.write_pos = 0;
+        self.phase = 0;
+        self.pending = 0;
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize) {
+        let ch = self.channels;
+        let in_frames = input.len() / ch;
+        let out_frames = output.len() / ch;
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        loop {
+            while self.pending > 0 {
+                if consumed == in_frames {
+                    return (consumed, produced);
+                }
+                self.push_frame(&input[consumed * ch..(consumed + 1) * ch]);
+                consumed += 1;
+                self.pending -= 1;
+            }
+
+            if produced == out_frames {
+                return (consumed, produced);
+            }
+
+            let coefs = &self.bank[self.phase * TAPS..(self.phase + 1) * TAPS];
+            let len = TAPS * 2;
+            for c in 0..ch {
+                let start = c * len + self.write_pos;
+                output[produced * ch + c] = simd::dot(&self.history[start..start + TAPS], coefs);
+            }
+            produced += 1;
+
+            self.phase += self.down;
+            self.pending = self.phase / self.up;
+            self.phase %= self.up;
+        }
+    }
+}
+
+fn bank(up: usize, down: usize) -> Arc<Vec<f32>> {
+    let mut banks = BANKS.lock().unwrap();
+    if banks.is_empty() {
+        for &(up, down) in COMMON.iter() {
+            banks.push((up, down, Arc::new(make_bank(up, down))));
+        }
+    }
+    if let Some((_, _, bank)) = banks.iter().find(|b| b.0 == up && b.1 == down) {
+        return bank.clone();
+    }
+    let bank = Arc::new(make_bank(up, down));
+    banks.push((up, down, bank.clone()));
+    bank
+}
+
+// Kaiser windowed sinc prototype at up times the input rate, split in phases,
+// each normalized for unity gain at DC.
+fn make_bank(up: usize, down: usize) -> Vec<f32> {
+    let len = up * TAPS;
+    // on a sample, so that the delay is a whole number of input frames
+    let half = (len / 2) as f64;
+    // cutoff relative to the Nyquist frequency of the upsampled rate
+    let cutoff = ROLLOFF / up.max(down) as f64;
+
+    let prototype: Vec<f64> = (0..len)
+        .map(|n| {
+            let d = n as f64 - half;
+            cutoff * sinc(cutoff * d) * kaiser(d / half, BETA)
+        })
+        .collect();
+
+    let mut bank = Vec::with_capacity(len);
+    for phase in 0..up {
+        // the newest frame, last in the history, takes the first tap of the phase
+        let row: Vec<f64> = (0..TAPS).map(|j| prototype[phase + (TAPS - 1 - j) * up]).collect();
+        let sum: f64 = row.iter().sum();
+        bank.extend(row.iter().map(|c| (c / sum) as f32));
+    }
+    bank
+}
+
+// Polyphase stage at the closest rational of the nominal ratio, followed by
+// a windowed sinc for the rest: the drift and any rounding of the ratio.
+pub struct RationalResampler {
+    channels: usize,
+    nominal: f64,
+    ratio: f64,
+    stage: Polyphase,
+    drift: SincResampler,
+    mid: Vec<f32>,
+    mid_pos: usize,
+    mid_len: usize,
+}
+
+impl RationalResampler {
+    pub fn new(channels: usize, ratio: f64) -> RationalResampler {
+        let (up, down) = rational(ratio);
+        let nominal = up as f64 / down as f64;
+        RationalResampler {
+            channels,
+            nominal,
+            ratio,
+            stage: Polyphase::new(channels, up, down),
+            drift: SincResampler::with_filter(channels, DRIFT_TAPS, DRIFT_PHASES, DRIFT_CUTOFF, DRIFT_BETA,
+                                              ratio / nominal),
+            mid: vec![0.0; MID_FRAMES * channels],
+            mid_pos: 0,
+            mid_len: 0,
+        }
+    }
+}
+
+impl Resampler for RationalResampler {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+        self.drift.set_ratio(ratio / self.nominal);
+    }
+
+    fn latency(&self) -> f64 {
+        self.stage.latency() + self.drift.latency() / self.nominal
+    }
+
+    fn reset(&mut self) {
+        self.stage.reset();
+        self.drift.reset();
+        self.mid_pos = 0;
+        self.mid_len = 0;
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize) {
+        let ch = self.channels;
+        let in_frames = input.len() / ch;
+        let out_frames = output.len() / ch;
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        loop {
+            if self.mid_pos < self.mid_len {
+                let (used, frames) = self.drift.process(&self.mid[self.mid_pos * ch..self.mid_len * ch],
+                                                        &mut output[produced * ch..]);
+                self.mid_pos += used;
+                produced += frames;
+            }
+            if produced == out_frames || consumed == in_frames {
+                return (consumed, produced);
+            }
+            if self.mid_pos < self.mid_len {
+                continue;
+            }
+
+            // only about what the output still needs, so that little input waits in between
+            let need = ((out_frames - produced) as f64 * self.nominal / self.ratio).ceil() as usize + 2;
+            let frames = need.min(MID_FRAMES);
+            let (used, made) = self.stage.process(&input[consumed * ch..], &mut self.mid[..frames * ch]);
+            consumed += used;
+            self.mid_pos = 0;
+            self.mid_len = made;
+            if used == 0 && made == 0 {
+                return (consumed, produced);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resampler::tests::{fit_sine, run, sine, sine_phase};
+    use std::f64::consts::PI;
+
+    #[test]
+    fn common_ratios() {
+        assert_eq!(rational(48000.0 / 44100.0), (160, 147));
+        assert_eq!(rational(44100.0 / 48000.0), (147, 160));
+        assert_eq!(rational(2.0), (2, 1));
+        assert_eq!(rational(4.0), (4, 1));
+        assert_eq!(rational(0.5), (1, 2));
+    }
+
+    #[test]
+    fn banks_are_shared() {
+        let (a, b) = (Polyphase::new(1, 160, 147), Polyphase::new(2, 160, 147));
+        assert!(Arc::ptr_eq(&a.bank, &b.bank));
+        assert!(!Arc::ptr_eq(&a.bank, &Polyphase::new(1, 147, 160).bank));
+    }
+
+    fn rms(signal: &[f32]) -> f64 {
+        (signal.iter().map(|&x| x as f64 * x as f64).sum::<f64>() / signal.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn bank_passband_and_images() {
+        for &(up, down, rate) in [(160, 147, 44100.0), (147, 160, 48000.0), (2, 1, 44100.0), (4, 1, 44100.0),
+                                  (1, 2, 96000.0)].iter() {
+            let out_rate = rate * up as f64 / down as f64;
+            let nyquist = rate.min(out_rate) / 2.0;
+
+            let mut polyphase = Polyphase::new(1, up, down);
+            let output = run(&mut polyphase, &sine(1000.0, rate, rate as usize));
+            let (amplitude, residual) = fit_sine(&output[200..], 1000.0, out_rate);
+            assert!((amplitude / 0.5 - 1.0).abs() < 1e-4, "{}/{} gain {}", up, down, amplitude / 0.5);
+            assert!(residual / 0.5 < 1e-4, "{}/{} residual {}", up, down, residual / 0.5);
+
+            // up in the transition band the images, or the aliases, are all that is
+            // left once the tone is taken out
+            let freq = 0.8 * nyquist;
+            let mut polyphase = Polyphase::new(1, up, down);
+            let output = run(&mut polyphase, &sine(freq, rate, rate as usize));
+            let (amplitude, residual) = fit_sine(&output[200..], freq, out_rate);
+            assert!((amplitude / 0.5 - 1.0).abs() < 0.05, "{}/{} gain at {} Hz {}", up, down, freq, amplitude / 0.5);
+            assert!(residual / 0.5 < 1e-4, "{}/{} images at {} Hz {}", up, down, freq, residual / 0.5);
+
+            // above the output Nyquist frequency, downsampling
+            let freq = 1.2 * nyquist;
+            if freq < rate / 2.0 {
+                let mut polyphase = Polyphase::new(1, up, down);
+                let output = run(&mut polyphase, &sine(freq, rate, rate as usize));
+                assert!(rms(&output[200..]) / 0.5 < 1e-4, "{}/{} alias level {}", up, down, rms(&output[200..]) / 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn latency_of_both_stages() {
+        let (freq, rate) = (1000.0, 44100.0);
+        for &ratio in [48000.0 / 44100.0, 48000.0 / 44100.0 * (1.0 + 500e-6), 2.0 * (1.0 - 200e-6)].iter() {
+            let mut resampler = RationalResampler::new(1, ratio);
+            let output = run(&mut resampler, &sine(freq, rate, 44100));
+
+            // output frame n is the input at n / ratio - latency
+            let skip = 1000;
+            let w = 2.0 * PI * freq / rate;
+            let expected = w * (skip as f64 / ratio - resampler.latency());
+            let error = (sine_phase(&output[skip..], freq, rate * ratio) - expected + PI).rem_euclid(2.0 * PI) - PI;
+            assert!((error / w).abs() < 1e-3, "ratio {} off by {} frames", ratio, error / w);
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::f64::consts::PI;
+
+use resampler::Resampler;
+
+pub struct Report {
+    pub thd_n_db: f64,
+    pub snr_db: f64,
+    // over the sine sweep
+    pub ripple_db: f64,
+    pub stopband_db: Option<f64>,
+    pub aliasing_db: f64,
+    pub group_delay_ms: f64,
+    pub latency_frames: f64,
+    // gain in dB against frequency along the sine sweep
+    pub sweep: Vec<(f64, f64)>,
+    // what is left of the multitone once its tones are removed
+    pub residual: Vec<f64>,
+}
+
+pub struct ResponsePoint {
+    pub freq: f64,
+    pub gain_db: f64,
+    pub phase: f64,
+    pub group_delay_ms: f64,
+}
+
+// Test conditions: input rate and the exact conversion ratio, drift included.
+pub struct Setup {
+    pub input_rate: f64,
+    pub ratio: f64,
+    pub frames: usize,
+    pub passband: f64,
+}
+
+impl Setup {
+    pub fn output_rate(&self) -> f64 {
+        self.input_rate * self.ratio
+    }
+
+    // highest frequency expected to go through unaltered
+    pub fn passband_edge(&self) -> f64 {
+        self.passband * self.input_rate.min(self.output_rate()) / 2.0
+    }
+}
+
+const LEVEL: f64 = 0.5;
+const THD_FREQ: f64 = 997.0;
+const GROUP_DELAY_DELTA: f64 = 10.0;
+const SWEEP_START: f64 = 20.0;
+// sweep cycles per gain estimate
+const SWEEP_CYCLES: f64 = 16.0;
+
+pub fn sine(freq: f64, rate: f64, frames: usize, amplitude: f64) -> Vec<f32> {
+    (0..frames)
+        .map(|n| (amplitude * (2.0 * PI * freq * n as f64 / rate).sin()) as f32)
+        .collect()
+}
+
+// Exponential sine sweep from f1 to f2 Hz over length seconds, phase at time t.
+fn sweep_phase(f1: f64, f2: f64, length: f64, t: f64) -> f64 {
+    let k = (f2 / f1).ln();
+    2.0 * PI * f1 * length / k * ((t / length * k).exp() - 1.0)
+}
+
+fn sweep_frequency(f1: f64, f2: f64, length: f64, t: f64) -> f64 {
+    f1 * (t / length * (f2 / f1).ln()).exp()
+}
+
+pub fn sweep(f1: f64, f2: f64, rate: f64, frames: usize, amplitude: f64) -> Vec<f32> {
+    let length = frames as f64 / rate;
+    (0..frames)
+        .map(|n| (amplitude * sweep_phase(f1, f2, length, n as f64 / rate).sin()) as f32)
+        .collect()
+}
+
+pub fn multitone(freqs: &[f64], rate: f64, frames: usize, amplitude: f64) -> Vec<f32> {
+    let a = amplitude / freqs.len() as f64;
+    (0..frames)
+        .map(|n| {
+            freqs.iter()
+                .map(|f| a * (2.0 * PI * f * n as f64 / rate).sin())
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+pub fn log_frequencies(start: f64, end: f64, count: usize) -> Vec<f64> {
+    (0..count)
+        .map(|i| start * (end / start).powf(i as f64 / (count - 1) as f64))
+        .collect()
+}
+
+// Runs a mono signal through the resampler in period sized blocks,
+// dropping the filter start-up transient from the output.
+pub fn resample(resampler: &mut dyn Resampler, input: &[f32]) -> Vec<f64> {
+    let block = 256;
+    let ratio = resampler.ratio();
+    let mut out_buf = vec![0.0f32; (block as f64 * ratio) as usize + 2];
+    let mut output = Vec::with_capacity((input.len() as f64 * ratio) as usize + block);
+
+    resampler.reset();
+    let mut pos = 0;
+    while pos < input.len() {
+        let end = (pos + block).min(input.len());
+        let mut chunk = &input[pos..end];
+        while !chunk.is_empty() {
+            let (consumed, produced) = resampler.process(chunk, &mut out_buf);
+            output.extend(out_buf[..produced].iter().map(|&x| x as f64));
+            chunk = &chunk[consumed..];
+        }
+        pos = end;
+    }
+
+    let skip = ((resampler.latency() * 2.0 + 1.0) * ratio).ceil() as usize;
+    output.drain(..skip.min(output.len()));
+    output
+}
+
+// Least squares fit of sinusoids at known frequencies.
+// Returns (amplitude, phase) per frequency, phase relative to sample `offset`,
+// and the residual left once they are removed.
+pub fn fit_tones(signal: &[f64], freqs: &[f64], rate: f64, offset: usize)
+                 -> (Vec<(f64, f64)>, Vec<f64>) {
+    let n = freqs.len() * 2;
+    let mut ata = vec![0.0; n * n];
+    let mut atb = vec![0.0; n];
+    let mut row = vec![0.0; n];
+
+    for (i, &y) in signal.iter().enumerate() {
+        basis(&mut row, freqs, rate, (i + offset) as f64);
+        for r in 0..n {
+            atb[r] += row[r] * y;
+            for c in 0..n {
+                ata[r * n + c] += row[r] * row[c];
+            }
+        }
+    }
+
+    let coefs = solve(&mut ata, &mut atb, n);
+
+    let mut residual = Vec::with_capacity(signal.len());
+    for (i, &y) in signal.iter().enumerate() {
+        basis(&mut row, freqs, rate, (i + offset) as f64);
+        let model: f64 = row.iter().zip(coefs.iter()).map(|(b, c)| b * c).sum();
+        residual.push(y - model);
+    }
+
+    let tones = (0..freqs.len())
+        .map(|k| {
+            let (s, c) = (coefs[2 * k], coefs[2 * k + 1]);
+            ((s * s + c * c).sqrt(), c.atan2(s))
+        })
+        .collect();
+
+    (tones, residual)
+}
+
+fn basis(row: &mut [f64], freqs: &[f64], rate: f64, n: f64) {
+    for (k, f) in freqs.iter().enumerate() {
+        let w = 2.0 * PI * f / rate * n;
+        row[2 * k] = w.sin();
+        row[2 * k + 1] = w.cos();
+    }
+}
+
+// Gaussian elimination with partial pivoting, a is n * n row major.
+fn solve(a: &mut [f64], b: &mut [f64], n: usize) -> Vec<f64> {
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i * n + col].abs().partial_cmp(&a[j * n + col].abs()).unwrap())
+            .unwrap();
+        if pivot != col {
+            for k in 0..n {
+                a.swap(col * n + k, pivot * n + k);
+            }
+            b.swap(col, pivot);
+        }
+
+        let p = a[col * n + col];
+        if p.abs() < 1e-300 {
+            continue;
+        }
+        for r in col + 1..n {
+            let f = a[r * n + col] / p;
+            for k in col..n {
+                a[r * n + k] -= f * a[col * n + k];
+            }
+            b[r] -= f * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for r in (0..n).rev() {
+        let mut sum = b[r];
+        for k in r + 1..n {
+            sum -= a[r * n + k] * x[k];
+        }
+        let p = a[r * n + r];
+        x[r] = if p.abs() < 1e-300 { 0.0 } else { sum / p };
+    }
+    x
+}
+
+pub fn rms(signal: &[f64]) -> f64 {
+    (signal.iter().map(|x| x * x).sum::<f64>() / signal.len() as f64).sqrt()
+}
+
+pub fn db(ratio: f64) -> f64 {
+    20.0 * ratio.max(1e-15).log10()
+}
+
+fn wrap_phase(p: f64) -> f64 {
+    let mut p = p % (2.0 * PI);
+    if p > PI {
+        p -= 2.0 * PI;
+    } else if p < -PI {
+        p += 2.0 * PI;
+    }
+    p
+}
+
+// Gain and phase at the output of a single tone.
+fn tone_phase(resampler: &mut dyn Resampler, setup: &Setup, freq: f64) -> (f64, f64) {
+    let input = sine(freq, setup.input_rate, setup.frames, LEVEL);
+    let output = resample(resampler, &input);
+    let skip = ((resampler.latency() * 2.0 + 1.0) * setup.ratio).ceil() as usize;
+    let (tones, _) = fit_tones(&output, &[freq], setup.output_rate(), skip);
+    (tones[0].0 / LEVEL, tones[0].1)
+}
+
+pub fn response_point(resampler: &mut dyn Resampler, setup: &Setup, freq: f64) -> ResponsePoint {
+    let (gain, phase) = tone_phase(resampler, setup, freq);
+    let (_, phase_2) = tone_phase(resampler, setup, freq + GROUP_DELAY_DELTA);
+
+    // the fitted phase is that of a sine, the input started at phase 0
+    let delta = wrap_phase(phase_2 - phase);
+    let delay_frames = -delta / (2.0 * PI * GROUP_DELAY_DELTA / setup.input_rate);
+
+    ResponsePoint {
+        freq,
+        gain_db: db(gain),
+        phase,
+        group_delay_ms: delay_frames / setup.input_rate * 1e3,
+    }
+}
+
+pub fn frequency_response(resampler: &mut dyn Resampler, setup: &Setup, points: usize)
+                          -> Vec<ResponsePoint> {
+    log_frequencies(20.0, setup.passband_edge(), points)
+        .iter()
+        .map(|&f| response_point(resampler, setup, f))
+        .collect()
+}
+
+// Gain along an exponential sine sweep from 20 Hz to the passband edge. The
+// output is fitted to the sweep delayed by the resampler latency, over windows
+// of a few cycles, so that the gain is measured at every frequency on the way.
+pub fn sweep_response(resampler: &mut dyn Resampler, setup: &Setup) -> Vec<(f64, f64)> {
+    let (f1, f2) = (SWEEP_START, setup.passband_edge());
+    let length = setup.frames as f64 / setup.input_rate;
+    let input = sweep(f1, f2, setup.input_rate, setup.frames, LEVEL);
+    let output = resample(resampler, &input);
+    let rate = setup.output_rate();
+    // resample() dropped the first output frames, the latency is in input frames
+    let skip = ((resampler.latency() * 2.0 + 1.0) * setup.ratio).ceil() as usize;
+    let time = |i: usize| (i + skip) as f64 / rate - resampler.latency() / setup.input_rate;
+
+    let mut points = Vec::new();
+    let mut pos = 0;
+    loop {
+        let cycles = (SWEEP_CYCLES / sweep_frequency(f1, f2, length, time(pos)) * rate) as usize;
+        // at the low end the window gets a fraction of a cycle rather than the whole sweep
+        let len = cycles.min(output.len() / 64).max(32);
+        if pos + len > output.len() || time(pos + len) > length {
+            break;
+        }
+        let (mut ss, mut sc, mut cc, mut ys, mut yc) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (i, &y) in output[pos..pos + len].iter().enumerate() {
+            let (s, c) = sweep_phase(f1, f2, length, time(pos + i)).sin_cos();
+            ss += s * s;
+            sc += s * c;
+            cc += c * c;
+            ys += y * s;
+            yc += y * c;
+        }
+        let det = ss * cc - sc * sc;
+        let a = (ys * cc - yc * sc) / det;
+        let b = (yc * ss - ys * sc) / det;
+        let freq = sweep_frequency(f1, f2, length, time(pos + len / 2));
+        points.push((freq, db((a * a + b * b).sqrt() / LEVEL)));
+        pos += len;
+    }
+    points
+}
+
+pub fn ripple_db(gains_db: &[f64]) -> f64 {
+    let max = gains_db.iter().cloned().fold(f64::MIN, f64::max);
+    let min = gains_db.iter().cloned().fold(f64::MAX, f64::min);
+    max - min
+}
+
+// Returns (THD+N dB, SNR dB) for a -6 dBFS sine.
+pub fn thd_n(resampler: &mut dyn Resampler, setup: &Setup) -> (f64, f64) {
+    let input = sine(THD_FREQ, setup.input_rate, setup.frames, LEVEL);
+    let output = resample(resampler, &input);
+    let rate = setup.output_rate();
+
+    let (tones, residual) = fit_tones(&output, &[THD_FREQ], rate, 0);
+    let fundamental = tones[0].0 / 2f64.sqrt();
+    let thd_n = db(rms(&residual) / fundamental);
+
+    let harmonics: Vec<f64> = (1..11)
+        .map(|h| THD_FREQ * h as f64)
+        .filter(|&f| f < setup.passband_edge())
+        .collect();
+    let (_, noise) = fit_tones(&output, &harmonics, rate, 0);
+    let snr = db(fundamental / rms(&noise));
+
+    (thd_n, snr)
+}
+
+// Worst level of what should have been filtered out: aliases of tones above
+// the output Nyquist frequency when downsampling, images of tones near the
+// input Nyquist frequency when upsampling. None when there is no such band.
+pub fn stopband_rejection(resampler: &mut dyn Resampler, setup: &Setup) -> Option<f64> {
+    let in_nyquist = setup.input_rate / 2.0;
+    let out_nyquist = setup.output_rate() / 2.0;
+    let end = in_nyquist * 0.99;
+    let start = if setup.ratio < 1.0 {
+        out_nyquist
+    } else {
+        setup.input_rate - out_nyquist * 0.99
+    };
+    if end - start < 1.0 {
+        return None;
+    }
+
+    let worst = log_frequencies(start, end, 12)
+        .iter()
+        .map(|&f| {
+            let input = sine(f, setup.input_rate, setup.frames, LEVEL);
+            let output = resample(resampler, &input);
+            if setup.ratio < 1.0 {
+                db(rms(&output) / (LEVEL / 2f64.sqrt()))
+            } else {
+                let image = setup.input_rate - f;
+                let (tones, _) = fit_tones(&output, &[image], setup.output_rate(), 0);
+                db(tones[0].0 / LEVEL)
+            }
+        })
+        .fold(f64::MIN, f64::max);
+
+    Some(worst)
+}
+
+pub fn multitone_frequencies(setup: &Setup) -> Vec<f64> {
+    log_frequencies(50.0, setup.passband_edge(), 8)
+}
+
+// Level of everything that is not one of the input tones, relative to the tones.
+// Returns it along with the residual for plotting.
+pub fn aliasing(resampler: &mut dyn Resampler, setup: &Setup) -> (f64, Vec<f64>) {
+    let freqs = multitone_frequencies(setup);
+    let input = multitone(&freqs, setup.input_rate, setup.frames, LEVEL);
+    let output = resample(resampler, &input);
+    let (_, residual) = fit_tones(&output, &freqs, setup.output_rate(), 0);
+    let level = db(rms(&residual) / rms(&output));
+    (level, residual)
+}
+
+pub fn measure(resampler: &mut dyn Resampler, setup: &Setup, response: &[ResponsePoint]) -> Report {
+    let (thd_n_db, snr_db) = thd_n(resampler, setup);
+    let (aliasing_db, residual) = aliasing(resampler, setup);
+    let group_delay_ms = response.iter().map(|p| p.group_delay_ms).sum::<f64>()
+        / response.len() as f64;
+    let sweep = sweep_response(resampler, setup);
+    let gains: Vec<f64> = sweep.iter().map(|&(_, gain)| gain).collect();
+
+    Report {
+        thd_n_db,
+        snr_db,
+        ripple_db: ripple_db(&gains),
+        stopband_db: stopband_rejection(resampler, setup),
+        aliasing_db,
+        group_delay_ms,
+        latency_frames: resampler.latency(),
+        sweep,
+        residual,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resampler::{self, Quality, SincQuality};
+
+    #[test]
+    fn sweep_follows_the_passband() {
+        let setup = Setup { input_rate: 44100.0, ratio: 48000.0 / 44100.0, frames: 44100, passband: 0.8 };
+        let mut resampler = resampler::new(1, Quality::Sinc(SincQuality::High), setup.ratio);
+        let sweep = sweep_response(&mut *resampler, &setup);
+
+        assert!(sweep.len() > 100, "{} points", sweep.len());
+        assert!(sweep[0].0 < 30.0 && sweep[sweep.len() - 1].0 > 0.9 * setup.passband_edge());
+        for &(freq, gain) in sweep.iter() {
+            assert!(gain.abs() < 0.01, "{} dB at {} Hz", gain, freq);
+        }
+    }
+}
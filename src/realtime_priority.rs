@@ -1,10 +1,216 @@
-use thread_priority::*;
+use std::fmt;
+use std::io;
+use std::mem;
 
-pub fn get_realtime_priority() {
-    // attempt to acquire real-time priority
-    let thread_id = thread_native_id();
-    let policy = ThreadSchedulePolicy::Realtime(RealtimeThreadSchedulePolicy::Fifo);
-    let params = ScheduleParams { sched_priority: 3 };
-    set_thread_schedule_policy(thread_id, policy, params).unwrap();
+use config;
+use libc;
+
+// not exported by libc
+const SCHED_DEADLINE: libc::c_int = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Fifo,
+    RoundRobin,
+    Other,
+    Deadline { runtime_us: u64, period_us: u64 },
+}
+
+impl Policy {
+    pub fn parse(spec: &str) -> Option<Policy> {
+        let mut parts = spec.split(':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("fifo"), None, _, _) => Some(Policy::Fifo),
+            (Some("rr"), None, _, _) => Some(Policy::RoundRobin),
+            (Some("other"), None, _, _) => Some(Policy::Other),
+            (Some("deadline"), Some(runtime), Some(period), None) => {
+                match (runtime.parse(), period.parse()) {
+                    (Ok(runtime_us), Ok(period_us)) if runtime_us > 0 && runtime_us <= period_us =>
+                        Some(Policy::Deadline { runtime_us, period_us }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn sched(&self) -> libc::c_int {
+        match *self {
+            Policy::Fifo => libc::SCHED_FIFO,
+            Policy::RoundRobin => libc::SCHED_RR,
+            Policy::Other => libc::SCHED_OTHER,
+            Policy::Deadline { .. } => SCHED_DEADLINE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RtConfig {
+    pub policy: Policy,
+    pub priority: i32,
+    pub cpus: Option<Vec<usize>>,
+    pub mlock: bool,
 }
 
+// What the kernel granted the calling thread.
+#[derive(Debug, Clone)]
+pub struct Granted {
+    pub policy: libc::c_int,
+    pub priority: i32,
+    pub cpus: Vec<usize>,
+    pub mlocked: bool,
+}
+
+impl fmt::Display for Granted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpus: Vec<String> = self.cpus.iter().map(|c| c.to_string()).collect();
+        write!(f, "{} priority {}, cpus {}, memory {}",
+               sched_name(self.policy), self.priority, cpus.join(","),
+               if self.mlocked { "locked" } else { "not locked" })
+    }
+}
+
+impl RtConfig {
+    // builds the configuration from the --rt-* command line options
+    pub fn from_options(policy: &str, priority: i32, cpus: &Option<String>, mlock: bool)
+                        -> Result<RtConfig, String> {
+        let policy = Policy::parse(policy)
+            .ok_or_else(|| config::error("rt-policy", format!("invalid scheduling policy: {}", policy)))?;
+        let cpus = match *cpus {
+            Some(ref list) => Some(parse_cpus(list)
+                .ok_or_else(|| config::error("rt-cpus", format!("invalid cpu list: {}", list)))?),
+            None => None,
+        };
+        Ok(RtConfig { policy, priority, cpus, mlock })
+    }
+
+    // Applies the configuration to the calling thread. Whatever cannot be set is
+    // reported as a warning and the thread keeps running with what it has.
+    pub fn apply(&self) -> Granted {
+        let mut mlocked = false;
+        if self.mlock {
+            match unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } {
+                0 => mlocked = true,
+                _ => eprintln!("Warning: cannot lock memory: {}", io::Error::last_os_error()),
+            }
+        }
+
+        if let Some(ref cpus) = self.cpus {
+            if let Err(e) = set_affinity(cpus) {
+                eprintln!("Warning: cannot set CPU affinity: {}", e);
+            }
+        }
+
+        if let Err(e) = self.set_policy() {
+            eprintln!("Warning: cannot set {} priority {}: {}, keeping the current scheduling \
+                       (real-time scheduling needs CAP_SYS_NICE or an rtprio limit)",
+                      sched_name(self.policy.sched()), self.priority, e);
+        }
+
+        granted(mlocked)
+    }
+
+    fn set_policy(&self) -> io::Result<()> {
+        let sched = self.policy.sched();
+        match self.policy {
+            Policy::Deadline { runtime_us, period_us } => {
+                let attr = SchedAttr {
+                    size: mem::size_of::<SchedAttr>() as u32,
+                    sched_policy: sched as u32,
+                    sched_flags: 0,
+                    sched_nice: 0,
+                    sched_priority: 0,
+                    sched_runtime: runtime_us * 1000,
+                    sched_deadline: period_us * 1000,
+                    sched_period: period_us * 1000,
+                };
+                match unsafe { libc::syscall(libc::SYS_sched_setattr, 0, &attr as *const SchedAttr, 0) } {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            }
+            _ => {
+                let (min, max) = unsafe {
+                    (libc::sched_get_priority_min(sched), libc::sched_get_priority_max(sched))
+                };
+                let priority = self.priority.max(min).min(max);
+                if priority != self.priority && self.policy != Policy::Other {
+                    eprintln!("Warning: priority {} out of range {}-{}, using {}",
+                              self.priority, min, max, priority);
+                }
+                let param = libc::sched_param { sched_priority: priority };
+                match unsafe { libc::pthread_setschedparam(libc::pthread_self(), sched, &param) } {
+                    0 => Ok(()),
+                    e => Err(io::Error::from_raw_os_error(e)),
+                }
+            }
+        }
+    }
+}
+
+fn sched_name(policy: libc::c_int) -> &'static str {
+    match policy {
+        libc::SCHED_FIFO => "SCHED_FIFO",
+        libc::SCHED_RR => "SCHED_RR",
+        libc::SCHED_OTHER => "SCHED_OTHER",
+        SCHED_DEADLINE => "SCHED_DEADLINE",
+        _ => "unknown",
+    }
+}
+
+#[repr(C)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+// comma separated cpus and ranges: 0,2-3
+fn parse_cpus(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',') {
+        let mut range = part.splitn(2, '-');
+        let first: usize = range.next()?.trim().parse().ok()?;
+        let last: usize = match range.next() {
+            Some(last) => last.trim().parse().ok()?,
+            None => first,
+        };
+        if last < first || last >= libc::CPU_SETSIZE as usize {
+            return None;
+        }
+        cpus.extend(first..last + 1);
+    }
+    Some(cpus)
+}
+
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        match libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+fn granted(mlocked: bool) -> Granted {
+    unsafe {
+        let policy = libc::sched_getscheduler(0);
+        let mut param: libc::sched_param = mem::zeroed();
+        libc::sched_getparam(0, &mut param);
+
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set);
+        let cpus = (0..libc::CPU_SETSIZE as usize).filter(|&c| libc::CPU_ISSET(c, &set)).collect();
+
+        Granted { policy, priority: param.sched_priority, cpus, mlocked }
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use backend::{self, Config};
+use spsc::{self, Producer};
+
+// queue length in seconds of audio
+const QUEUE_SECONDS: f64 = 2.0;
+const WRITER_POLL: Duration = Duration::from_millis(10);
+const HEADER_UPDATE: Duration = Duration::from_secs(1);
+
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+}
+
+// Real-time side of a recording: never blocks, drops whole blocks when the
+// writer thread falls behind.
+pub struct RecordTap {
+    producer: Producer<f32>,
+    channels: usize,
+    counters: Arc<Counters>,
+}
+
+impl RecordTap {
+    pub fn push(&mut self, buf: &[f32]) {
+        if self.producer.free() >= buf.len() {
+            self.producer.push_slice(buf);
+        } else {
+            let frames = (buf.len() / self.channels) as u64;
+            self.counters.dropped.fetch_add(frames, Ordering::Relaxed);
+        }
+    }
+}
+
+// Writer thread side of a recording.
+pub struct Recorder {
+    name: String,
+    handle: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+}
+
+impl Recorder {
+    // writes what is left in the queue and closes the file
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let Recorder { name, handle, counters, .. } = self;
+        handle.join().unwrap();
+        eprintln!("Recorded {} frames to {}, {} dropped",
+                  counters.written.load(Ordering::Relaxed), name,
+                  counters.dropped.load(Ordering::Relaxed));
+    }
+}
+
+// Starts writing to path, a WAV file when it ends in .wav, raw samples otherwise.
+pub fn start(path: &str, config: Config) -> backend::Result<(RecordTap, Recorder)> {
+    let device = format!("file:{}", path);
+    let mut file = backend::open_playback(&device, config.clone())?;
+    let channels = config.channels;
+
+    let capacity = (config.rate as f64 * QUEUE_SECONDS) as usize * channels;
+    let (producer, mut consumer) = spsc::ring(capacity);
+    let counters = Arc::new(Counters { written: AtomicU64::new(0), dropped: AtomicU64::new(0) });
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let writer_counters = counters.clone();
+    let writer_stop = stop.clone();
+    let name = path.to_string();
+    let handle = thread::spawn(move || {
+        let mut buf = vec![0.0f32; config.period_size.max(1024) * channels];
+        let mut reported_drops = 0;
+        let mut last_update = Instant::now();
+
+        loop {
+            // read the flag first so nothing pushed before the stop gets lost
+            let stopping = writer_stop.load(Ordering::SeqCst);
+            let available = consumer.len() / channels * channels;
+            let len = available.min(buf.len());
+
+            if len > 0 {
+                consumer.pop_slice(&mut buf[..len]);
+                if let Err(e) = file.write(&buf[..len]) {
+                    eprintln!("Recording to {} failed: {}", name, e);
+                    break;
+                }
+                writer_counters.written.fetch_add((len / channels) as u64, Ordering::Relaxed);
+                continue;
+            }
+
+            if stopping {
+                break;
+            }
+
+            let dropped = writer_counters.dropped.load(Ordering::Relaxed);
+            if dropped != reported_drops {
+                eprintln!("Recording to {} fell behind, {} frames dropped", name, dropped);
+                reported_drops = dropped;
+            }
+
+            // keep the file readable should the process get killed
+            if last_update.elapsed() > HEADER_UPDATE {
+                file.drain().unwrap_or_else(|e| eprintln!("Flushing {} failed: {}", name, e));
+                last_update = Instant::now();
+            }
+
+            thread::sleep(WRITER_POLL);
+        }
+
+        file.drain().unwrap_or_else(|e| eprintln!("Flushing {} failed: {}", name, e));
+    });
+
+    Ok((RecordTap { producer, channels, counters: counters.clone() },
+        Recorder { name: path.to_string(), handle, stop, counters }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_whole_blocks() {
+        // no writer thread, as if it were stuck on the disk
+        let (producer, mut consumer) = spsc::ring(8 * 2);
+        let counters = Arc::new(Counters { written: AtomicU64::new(0), dropped: AtomicU64::new(0) });
+        let mut tap = RecordTap { producer, channels: 2, counters: counters.clone() };
+
+        tap.push(&[0.5; 6 * 2]);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 0);
+        tap.push(&[0.25; 3 * 2]);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 3);
+        tap.push(&[0.25; 2 * 2]);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 3);
+        tap.push(&[0.25; 2]);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 4);
+
+        // what was queued is left intact
+        let mut buf = [0.0; 16];
+        assert_eq!(consumer.pop_slice(&mut buf), 16);
+        assert_eq!(&buf[..12], &[0.5; 12]);
+        assert_eq!(&buf[12..], &[0.25; 4]);
+    }
+}
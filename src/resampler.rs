@@ -0,0 +1,452 @@
+use std::f64::consts::PI;
+
+use interpolator::{Interpolator, Kernel};
+use polyphase::{self, Polyphase, RationalResampler};
+use simd;
+
+// Windowed sinc qualities, a short interpolator for low latency, or an exact
+// rational filter bank for the nominal ratio with a short interpolator for the
+// drift. RationalFixed is the filter bank alone, it ignores ratio changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Sinc(SincQuality),
+    Interpolator(Kernel),
+    Rational,
+    RationalFixed,
+}
+
+impl Quality {
+    pub fn from_name(name: &str) -> Option<Quality> {
+        match name {
+            "rational" => Some(Quality::Rational),
+            "rational-fixed" => Some(Quality::RationalFixed),
+            _ => SincQuality::from_name(name).map(Quality::Sinc)
+                .or_else(|| Kernel::from_name(name).map(Quality::Interpolator)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Quality::Sinc(sinc) => sinc.name(),
+            Quality::Interpolator(kernel) => kernel.name(),
+            Quality::Rational => "rational",
+            Quality::RationalFixed => "rational-fixed",
+        }
+    }
+
+    pub fn all() -> Vec<Quality> {
+        let mut all: Vec<Quality> = SincQuality::all().iter().map(|&sinc| Quality::Sinc(sinc)).collect();
+        all.extend(Kernel::all().iter().map(|&kernel| Quality::Interpolator(kernel)));
+        all.push(Quality::Rational);
+        all.push(Quality::RationalFixed);
+        all
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SincQuality {
+    Low,
+    Medium,
+    High,
+    Best,
+}
+
+impl SincQuality {
+    pub fn from_name(name: &str) -> Option<SincQuality> {
+        match name {
+            "low" => Some(SincQuality::Low),
+            "medium" => Some(SincQuality::Medium),
+            "high" => Some(SincQuality::High),
+            "best" => Some(SincQuality::Best),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SincQuality::Low => "low",
+            SincQuality::Medium => "medium",
+            SincQuality::High => "high",
+            SincQuality::Best => "best",
+        }
+    }
+
+    pub fn all() -> [SincQuality; 4] {
+        [SincQuality::Low, SincQuality::Medium, SincQuality::High, SincQuality::Best]
+    }
+
+    // filter length in input frames
+    fn taps(&self) -> usize {
+        match *self {
+            SincQuality::Low => 16,
+            SincQuality::Medium => 32,
+            SincQuality::High => 64,
+            SincQuality::Best => 128,
+        }
+    }
+
+    // number of precomputed fractional positions, linearly interpolated in between
+    fn phases(&self) -> usize {
+        match *self {
+            SincQuality::Low => 64,
+            SincQuality::Medium => 256,
+            SincQuality::High => 512,
+            SincQuality::Best => 1024,
+        }
+    }
+
+    // Kaiser window beta
+    fn beta(&self) -> f64 {
+        match *self {
+            SincQuality::Low => 5.0,
+            SincQuality::Medium => 7.0,
+            SincQuality::High => 9.0,
+            SincQuality::Best => 11.0,
+        }
+    }
+
+    // cutoff as a fraction of the lowest Nyquist frequency
+    fn rolloff(&self) -> f64 {
+        match *self {
+            SincQuality::Low => 0.75,
+            SincQuality::Medium => 0.85,
+            SincQuality::High => 0.90,
+            SincQuality::Best => 0.94,
+        }
+    }
+}
+
+// Streaming sample rate converter working on interleaved f32 frames.
+// The ratio is output rate / input rate and may be changed between calls
+// to follow a drifting clock.
+pub trait Resampler: Send {
+    fn channels(&self) -> usize;
+
+    fn ratio(&self) -> f64;
+
+    fn set_ratio(&mut self, ratio: f64);
+
+    // group delay in input frames
+    fn latency(&self) -> f64;
+
+    fn reset(&mut self);
+
+    // Consumes input and produces output until one of both runs out.
+    // Returns (input frames consumed, output frames produced).
+    fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize);
+}
+
+// Resampler of the quality.
+pub fn new(channels: usize, quality: Quality, ratio: f64) -> Box<dyn Resampler> {
+    match quality {
+        Quality::Interpolator(kernel) => Box::new(Interpolator::new(channels, kernel, ratio)),
+        Quality::Rational => Box::new(RationalResampler::new(channels, ratio)),
+        Quality::RationalFixed => {
+            let (up, down) = polyphase::rational(ratio);
+            Box::new(Polyphase::new(channels, up, down))
+        }
+        Quality::Sinc(sinc) => Box::new(SincResampler::new(channels, sinc, ratio)),
+    }
+}
+
+// a cutoff moved by more than this is recomputed, drift alone never gets there
+const CUTOFF_TOLERANCE: f64 = 0.01;
+
+// Windowed sinc interpolator with a polyphase coefficient table.
+pub struct SincResampler {
+    channels: usize,
+    taps: usize,
+    phases: usize,
+    beta: f64,
+    cutoff: f64,
+    // set when the cutoff follows the ratio
+    rolloff: Option<f64>,
+    coefs: Vec<f32>,
+    // per channel history, stored twice so the last taps frames are always contiguous
+    history: Vec<f32>,
+    write_pos: usize,
+    kernel: Vec<f32>,
+    ratio: f64,
+    step: f64,
+    frac: f64,
+}
+
+impl SincResampler {
+    // The cutoff follows set_ratio once the ratio moves away from this one,
+    // so that going below 1 later does not alias.
+    pub fn new(channels: usize, quality: SincQuality, ratio: f64) -> SincResampler {
+        let mut resampler = SincResampler::with_filter(channels, quality.taps(), quality.phases(),
+                                                       quality.rolloff() * ratio.min(1.0), quality.beta(), ratio);
+        resampler.rolloff = Some(quality.rolloff());
+        resampler
+    }
+
+    // cutoff relative to the input Nyquist frequency, kept whatever the ratio
+    pub fn with_filter(channels: usize, taps: usize, phases: usize, cutoff: f64, beta: f64,
+                       ratio: f64) -> SincResampler {
+        simd::isa();
+
+        let mut coefs = Vec::with_capacity((phases + 1) * taps);
+        make_table(&mut coefs, taps, phases, cutoff, beta);
+
+        SincResampler {
+            channels,
+            taps,
+            phases,
+            beta,
+            cutoff,
+            rolloff: None,
+            coefs,
+            history: vec![0.0; channels * taps * 2],
+            write_pos: 0,
+            kernel: vec![0.0; taps],
+            ratio,
+            step: 1.0 / ratio,
+            frac: 0.0,
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[f32]) {
+        let len = self.taps * 2;
+        for (c, &x) in frame.iter().enumerate() {
+            self.history[c * len + self.write_pos] = x;
+            self.history[c * len + self.write_pos + self.taps] = x;
+        }
+        self.write_pos = (self.write_pos + 1) % self.taps;
+    }
+
+    fn interpolate(&mut self, out: &mut [f32]) {
+        let taps = self.taps;
+        let pos = self.frac * self.phases as f64;
+        let phase = (pos as usize).min(self.phases - 1);
+        let t = (pos - phase as f64) as f32;
+
+        simd::lerp(&self.coefs[phase * taps..(phase + 1) * taps],
+                   &self.coefs[(phase + 1) * taps..(phase + 2) * taps],
+                   t, &mut self.kernel);
+
+        let start = self.write_pos;
+        for (x, history) in out.iter_mut().zip(self.history.chunks(taps * 2)) {
+            *x = simd::dot(&history[start..start + taps], &self.kernel);
+        }
+    }
+}
+
+impl Resampler for SincResampler {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+        self.step = 1.0 / ratio;
+
+        if let Some(rolloff) = self.rolloff {
+            let cutoff = rolloff * ratio.min(1.0);
+            if (cutoff / self.cutoff - 1.0).abs() > CUTOFF_TOLERANCE {
+                // same size, the table is rewritten in place
+                make_table(&mut self.coefs, self.taps, self.phases, cutoff, self.beta);
+                self.cutoff = cutoff;
+            }
+        }
+    }
+
+    fn latency(&self) -> f64 {
+        (self.taps / 2 + 1) as f64
+    }
+
+    fn reset(&mut self) {
+        for x in self.history.iter_mut() {
+            *x = 0.0;
+        }
+        self.write_pos = 0;
+        self.frac = 0.0;
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize) {
+        let ch = self.channels;
+        let in_frames = input.len() / ch;
+        let out_frames = output.len() / ch;
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        loop {
+            while self.frac >= 1.0 {
+                if consumed == in_frames {
+                    return (consumed, produced);
+                }
+                self.push_frame(&input[consumed * ch..(consumed + 1) * ch]);
+                consumed += 1;
+                self.frac -= 1.0;
+            }
+
+            if produced == out_frames {
+                return (consumed, produced);
+            }
+
+            self.interpolate(&mut output[produced * ch..(produced + 1) * ch]);
+            produced += 1;
+            self.frac += self.step;
+        }
+    }
+}
+
+// phases + 1 rows of taps coefficients, the last row closing the interpolation
+fn make_table(table: &mut Vec<f32>, taps: usize, phases: usize, cutoff: f64, beta: f64) {
+    let half = (taps / 2) as f64;
+    let coef = |frac: f64, k: usize| {
+        let d = k as f64 - (half - 1.0) - frac;
+        cutoff * sinc(cutoff * d) * kaiser(d / half, beta)
+    };
+
+    table.clear();
+    for p in 0..phases + 1 {
+        let frac = p as f64 / phases as f64;
+
+        // normalize for unity gain at DC
+        let sum: f64 = (0..taps).map(|k| coef(frac, k)).sum();
+        table.extend((0..taps).map(|k| (coef(frac, k) / sum) as f32));
+    }
+}
+
+pub fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+pub fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta)
+}
+
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..50 {
+        term *= half_x / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // sine and cosine coefficients of a sine at freq, least squares
+    fn fit(signal: &[f32], freq: f64, rate: f64) -> (f64, f64) {
+        let (mut ss, mut sc, mut cc, mut ys, mut yc) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (n, &y) in signal.iter().enumerate() {
+            let w = 2.0 * PI * freq * n as f64 / rate;
+            let (s, c) = w.sin_cos();
+            ss += s * s;
+            sc += s * c;
+            cc += c * c;
+            ys += y as f64 * s;
+            yc += y as f64 * c;
+        }
+        let det = ss * cc - sc * sc;
+        ((ys * cc - yc * sc) / det, (yc * ss - ys * sc) / det)
+    }
+
+    // Amplitude of a sine at freq and the rms of what is left, least squares.
+    pub fn fit_sine(signal: &[f32], freq: f64, rate: f64) -> (f64, f64) {
+        let (a, b) = fit(signal, freq, rate);
+        let residual = signal.iter().enumerate().map(|(n, &y)| {
+            let w = 2.0 * PI * freq * n as f64 / rate;
+            let e = y as f64 - a * w.sin() - b * w.cos();
+            e * e
+        }).sum::<f64>();
+        ((a * a + b * b).sqrt(), (residual / signal.len() as f64).sqrt())
+    }
+
+    // phase in radians of a sine at freq, 0 for sin(2 pi freq n / rate)
+    pub fn sine_phase(signal: &[f32], freq: f64, rate: f64) -> f64 {
+        let (a, b) = fit(signal, freq, rate);
+        b.atan2(a)
+    }
+
+    pub fn sine(freq: f64, rate: f64, frames: usize) -> Vec<f32> {
+        (0..frames).map(|n| (0.5 * (2.0 * PI * freq * n as f64 / rate).sin()) as f32).collect()
+    }
+
+    // in blocks of 256 input frames, like a capture period
+    pub fn run(resampler: &mut dyn Resampler, input: &[f32]) -> Vec<f32> {
+        let ch = resampler.channels();
+        let mut output = Vec::new();
+        let mut buf = vec![0.0f32; 1024 * ch];
+        for block in input.chunks(256 * ch) {
+            let mut block = block;
+            while !block.is_empty() {
+                let (consumed, produced) = resampler.process(block, &mut buf);
+                output.extend_from_slice(&buf[..produced * ch]);
+                block = &block[consumed * ch..];
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn passband_tone() {
+        let ratio = 48000.0 / 44100.0;
+        let mut resampler = SincResampler::new(1, SincQuality::High, ratio);
+        let output = run(&mut resampler, &sine(1000.0, 44100.0, 44100));
+
+        let (amplitude, residual) = fit_sine(&output[200..], 1000.0, 48000.0);
+        assert!((amplitude / 0.5 - 1.0).abs() < 1e-3, "gain {}", amplitude / 0.5);
+        assert!(residual / 0.5 < 1e-4, "residual {}", residual / 0.5);
+    }
+
+    #[test]
+    fn produced_frames() {
+        let ratio = 48000.0 / 44100.0;
+        let mut resampler = SincResampler::new(2, SincQuality::Medium, ratio);
+        let output = run(&mut resampler, &vec![0.0; 44100 * 2]);
+        // outputs are made up to the last input frame, the frames after it wait for more input
+        let frames = output.len() / 2;
+        assert!((frames as i64 - 48000).abs() <= 2, "{} frames", frames);
+    }
+
+    #[test]
+    fn cutoff_follows_ratio() {
+        // made at 1, then halving the rate: 15 kHz is above the new Nyquist frequency
+        let mut resampler = SincResampler::new(1, SincQuality::High, 1.0);
+        resampler.set_ratio(0.5);
+        let output = run(&mut resampler, &sine(15000.0, 44100.0, 44100));
+
+        let rms = (output[200..].iter().map(|&x| x as f64 * x as f64).sum::<f64>()
+                   / (output.len() - 200) as f64).sqrt();
+        assert!(rms / 0.5 < 1e-3, "alias level {}", rms / 0.5);
+    }
+    #[test]
+    fn names() {
+        for &quality in Quality::all().iter() {
+            assert_eq!(Quality::from_name(quality.name()), Some(quality));
+        }
+        assert_eq!(Quality::from_name("rational-fixed"), Some(Quality::RationalFixed));
+    }
+
+    #[test]
+    fn rational_fixed_ignores_ratio_changes() {
+        let mut resampler = new(1, Quality::RationalFixed, 48000.0 / 44100.0);
+        resampler.set_ratio(48000.0 / 44100.0 * (1.0 + 1e-3));
+        let output = run(&mut *resampler, &sine(1000.0, 44100.0, 44100));
+
+        let (amplitude, residual) = fit_sine(&output[200..], 1000.0, 48000.0);
+        assert!((amplitude / 0.5 - 1.0).abs() < 1e-3, "gain {}", amplitude / 0.5);
+        assert!(residual / 0.5 < 1e-4, "residual {}", residual / 0.5);
+    }
+}
@@ -0,0 +1,185 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate rustfft;
+
+mod config;
+mod interpolator;
+mod polyphase;
+mod resampler;
+mod quality;
+mod simd;
+
+use docopt::Docopt;
+use rustfft::FFTplanner;
+use rustfft::num_complex::Complex;
+use rustfft::num_traits::Zero;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+use quality::{Setup, ResponsePoint};
+use resampler::Quality;
+
+
+const USAGE: &str = "
+Resampler quality measurement
+
+Usage:
+  resampler-quality [--config=<file>] [--quality=<name> --input-rate=<Hz> --output-rate=<Hz> --drift=<ppm> --duration=<seconds> --points=<count> --passband=<fraction> --output-dir=<dir> --simd=<kernels>]
+  resampler-quality (-h | --help)
+
+Options:
+  -h --help                 Show this screen.
+  --config=<file>           Read the options from a TOML file, command line flags take precedence.
+  --quality=<name>          Resampler quality: low, medium, high, best, linear, hermite, lagrange4,
+                            lagrange6, farrow, rational, rational-fixed or all [default: all].
+  --input-rate=<Hz>         Input sample rate [default: 44100].
+  --output-rate=<Hz>        Output sample rate [default: 48000].
+  --drift=<ppm>             Clock drift applied on top of the nominal ratio [default: 0].
+  --duration=<seconds>      Length of each test signal [default: 0.5].
+  --points=<count>          Frequencies measured in the passband [default: 24].
+  --passband=<fraction>     Passband edge relative to the lowest Nyquist frequency [default: 0.8].
+  --output-dir=<dir>        Directory for the plot data files [default: .].
+  --simd=<kernels>          Vector kernels: scalar, sse2, avx2 or neon, the fastest supported by default.
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_quality: String,
+    flag_input_rate: f64,
+    flag_output_rate: f64,
+    flag_drift: f64,
+    flag_duration: f64,
+    flag_points: usize,
+    flag_passband: f64,
+    flag_output_dir: String,
+    flag_simd: Option<String>,
+}
+
+fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if let Some(ref name) = args.flag_simd {
+        let isa = simd::Isa::from_name(name).ok_or_else(|| format!("Unknown vector kernels: {}", name));
+        isa.and_then(simd::set).unwrap_or_else(|e| {
+            eprintln!("{}", config::error("simd", e));
+            process::exit(1);
+        });
+    }
+
+    let qualities: Vec<Quality> = if args.flag_quality == "all" {
+        Quality::all()
+    } else {
+        match Quality::from_name(&args.flag_quality) {
+            Some(q) => vec![q],
+            None => {
+                eprintln!("{}", config::error("quality", format!("Unknown resampler quality: {}", args.flag_quality)));
+                process::exit(1);
+            }
+        }
+    };
+
+    let setup = Setup {
+        input_rate: args.flag_input_rate,
+        ratio: args.flag_output_rate / args.flag_input_rate * (1.0 + args.flag_drift * 1e-6),
+        frames: (args.flag_input_rate * args.flag_duration) as usize,
+        passband: args.flag_passband,
+    };
+
+    eprintln!("input rate: {}, output rate: {}, ratio: {:.9}, passband edge: {:.0} Hz, kernels: {}",
+              setup.input_rate,
+              setup.output_rate(),
+              setup.ratio,
+              setup.passband_edge(),
+              simd::isa().name());
+
+    eprintln!("{:<14} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10}",
+              "quality", "THD+N", "SNR", "ripple", "stopband", "aliasing", "delay ms", "latency", "latency ms");
+
+    for quality in qualities {
+        // made for the nominal ratio and then set to drift, like the engine does
+        let mut resampler = resampler::new(1, quality, args.flag_output_rate / args.flag_input_rate);
+        resampler.set_ratio(setup.ratio);
+
+        let response = quality::frequency_response(&mut *resampler, &setup, args.flag_points);
+        let report = quality::measure(&mut *resampler, &setup, &response);
+
+        eprintln!("{:<14} {:>9.2} {:>9.2} {:>9.4} {:>9} {:>9.2} {:>9.3} {:>9.1} {:>10.3}",
+                  quality.name(),
+                  report.thd_n_db,
+                  report.snr_db,
+                  report.ripple_db,
+                  report.stopband_db.map_or("n/a".to_string(), |s| format!("{:.2}", s)),
+                  report.aliasing_db,
+                  report.group_delay_ms,
+                  report.latency_frames,
+                  report.latency_frames * 1000.0 / setup.input_rate);
+
+        let prefix = format!("{}/quality_{}", args.flag_output_dir, quality.name());
+        let freqs = quality::multitone_frequencies(&setup);
+        let input = quality::multitone(&freqs, setup.input_rate, setup.frames, 0.5);
+        let output = quality::resample(&mut *resampler, &input);
+
+        let written = write_response(&format!("{}_response.dat", prefix), &response)
+            .and_then(|_| write_sweep(&format!("{}_sweep.dat", prefix), &report.sweep))
+            .and_then(|_| write_spectrum(&format!("{}_spectrum.dat", prefix), &output, setup.output_rate()))
+            .and_then(|_| write_spectrum(&format!("{}_residual.dat", prefix), &report.residual, setup.output_rate()));
+        if let Err(e) = written {
+            eprintln!("Cannot write the plot data to {}: {}", args.flag_output_dir, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn write_response(file_name: &str, response: &[ResponsePoint]) -> io::Result<()> {
+    let mut file = File::create(file_name)?;
+    for p in response {
+        writeln!(file, "{} {} {} {}", p.freq, p.gain_db, p.phase, p.group_delay_ms)?;
+    }
+    Ok(())
+}
+
+// frequency in Hz and gain in dB along the sine sweep
+fn write_sweep(file_name: &str, sweep: &[(f64, f64)]) -> io::Result<()> {
+    let mut file = File::create(file_name)?;
+    for &(freq, gain_db) in sweep {
+        writeln!(file, "{} {}", freq, gain_db)?;
+    }
+    Ok(())
+}
+
+// Hann windowed magnitude spectrum in dB relative to full scale, frequency in Hz
+fn write_spectrum(file_name: &str, data: &[f64], sample_rate: f64) -> io::Result<()> {
+    let len = data.len();
+    let mut fft_in: Vec<Complex<f64>> = data
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let w = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / len as f64).cos();
+            Complex::new(*value * w, 0.0)
+        })
+        .collect();
+
+    let mut fft_out: Vec<Complex<f64>> = vec![Complex::zero(); len];
+
+    let mut planner = FFTplanner::new(false);
+    let fft = planner.plan_fft(len);
+    fft.process(&mut fft_in, &mut fft_out);
+
+    let mut file = File::create(file_name)?;
+    for (i, bin) in fft_out.iter().take(len / 2).enumerate() {
+        let magnitude = bin.norm() * 4.0 / len as f64;
+        writeln!(file, "{} {}", i as f64 * sample_rate / len as f64, quality::db(magnitude))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::fs::File;
+use std::io::Read;
+
+// Routing and mixing matrix from capture to playback channels.
+// Each route adds an input channel to an output channel with a gain, outputs
+// without any route are silent.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    pub input: usize,
+    pub output: usize,
+    pub gain: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Matrix {
+    inputs: usize,
+    outputs: usize,
+    routes: Vec<Route>,
+}
+
+impl Matrix {
+    pub fn new(inputs: usize, outputs: usize, routes: Vec<Route>) -> Result<Matrix, String> {
+        check_channels(inputs, outputs)?;
+        for route in &routes {
+            if route.input >= inputs {
+                return Err(format!("Route from input {} but there are {} input channels", route.input, inputs));
+            }
+            if route.output >= outputs {
+                return Err(format!("Route to output {} but there are {} output channels", route.output, outputs));
+            }
+        }
+        Ok(Matrix { inputs, outputs, routes })
+    }
+
+    // Straight through when the channel counts match. Downmixes by averaging
+    // input i into output i % outputs, upmixes by repeating input j % inputs
+    // on output j.
+    pub fn default_for(inputs: usize, outputs: usize) -> Result<Matrix, String> {
+        check_channels(inputs, outputs)?;
+        let routes = if inputs >= outputs {
+            (0..inputs).map(|i| {
+                let sharing = (0..inputs).filter(|k| k % outputs == i % outputs).count();
+                Route { input: i, output: i % outputs, gain: 1.0 / sharing as f32 }
+            }).collect()
+        } else {
+            (0..outputs).map(|j| Route { input: j % inputs, output: j, gain: 1.0 }).collect()
+        };
+        Ok(Matrix { inputs, outputs, routes })
+    }
+
+    // Parses comma separated routes <input>:<output>[:<gain>], the gain being
+    // linear or in dB with a dB suffix, e.g. 0:0,1:1,2:0:-3dB,3:1:0.5
+    pub fn parse(inputs: usize, outputs: usize, spec: &str) -> Result<Matrix, String> {
+        let routes = spec.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| parse_route(s, s.split(':').collect()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Matrix::new(inputs, outputs, routes)
+    }
+
+    // Reads routes from a file, one <input> <output> [<gain>] per line separated
+    // by spaces or tabs, blank lines and lines starting with # are skipped.
+    pub fn from_file(inputs: usize, outputs: usize, path: &str) -> Result<Matrix, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+
+        let routes = text.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| parse_route(l, l.split_whitespace().collect()).map_err(|e| format!("{}: {}", path, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Matrix::new(inputs, outputs, routes)
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // true when every input goes to the same output untouched
+    pub fn is_identity(&self) -> bool {
+        self.inputs == self.outputs
+            && self.routes.len() == self.inputs
+            && self.routes.iter().all(|r| r.input == r.output && r.gain == 1.0)
+            && (0..self.inputs).all(|c| self.routes.iter().any(|r| r.input == c))
+    }
+
+    // Mixes interleaved frames, real-time safe.
+    pub fn process(&self, input: &[f32], output: &mut [f32], frames: usize) {
+        for x in output[..frames * self.outputs].iter_mut() {
+            *x = 0.0;
+        }
+        for i in 0..frames {
+            let frame_in = &input[i * self.inputs..(i + 1) * self.inputs];
+            let frame_out = &mut output[i * self.outputs..(i + 1) * self.outputs];
+            for route in &self.routes {
+                frame_out[route.output] += frame_in[route.input] * route.gain;
+            }
+        }
+    }
+}
+
+fn check_channels(inputs: usize, outputs: usize) -> Result<(), String> {
+    if inputs == 0 || outputs == 0 {
+        return Err(format!("Cannot route {} to {} channels", inputs, outputs));
+    }
+    Ok(())
+}
+
+// fields of the route s
+fn parse_route(s: &str, fields: Vec<&str>) -> Result<Route, String> {
+    let invalid = || format!("Invalid route: {}", s);
+    if fields.len() < 2 || fields.len() > 3 {
+        return Err(invalid());
+    }
+
+    let input = fields[0].parse().map_err(|_| invalid())?;
+    let output = fields[1].parse().map_err(|_| invalid())?;
+    let gain = match fields.get(2) {
+        Some(gain) => parse_gain(gain).ok_or_else(invalid)?,
+        None => 1.0,
+    };
+    Ok(Route { input, output, gain })
+}
+
+fn parse_gain(s: &str) -> Option<f32> {
+    let lower = s.to_lowercase();
+    if lower.ends_with("db") {
+        lower[..lower.len() - 2].trim().parse::<f32>().ok().map(|db| 10f32.powf(db / 20.0))
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn default_routes() {
+        assert!(Matrix::default_for(2, 2).unwrap().is_identity());
+        let down = Matrix::default_for(4, 2).unwrap();
+        assert_eq!(down.routes()[2], Route { input: 2, output: 0, gain: 0.5 });
+        let up = Matrix::default_for(1, 2).unwrap();
+        assert_eq!(up.routes(), &[Route { input: 0, output: 0, gain: 1.0 },
+                                  Route { input: 0, output: 1, gain: 1.0 }]);
+    }
+
+    #[test]
+    fn no_channels() {
+        assert!(Matrix::default_for(2, 0).is_err());
+        assert!(Matrix::default_for(0, 2).is_err());
+        assert!(Matrix::parse(2, 0, "").is_err());
+    }
+
+    #[test]
+    fn parses_routes() {
+        let matrix = Matrix::parse(4, 2, "0:0, 1:1,2:0:-6dB,3:1:0.5").unwrap();
+        assert_eq!(matrix.routes()[3], Route { input: 3, output: 1, gain: 0.5 });
+        assert!((matrix.routes()[2].gain - 0.501).abs() < 1e-3);
+        assert!(Matrix::parse(2, 2, "0:2").is_err());
+        assert!(Matrix::parse(2, 2, "0:0:1:1").is_err());
+        assert!(Matrix::parse(2, 2, "0::1").is_err());
+    }
+
+    #[test]
+    fn reads_file() {
+        let path = env::temp_dir().join(format!("routing-{}.txt", process::id()));
+        fs::write(&path, "# input output gain\n0 0\n\n1\t1  -3dB\n 1 0\t0.5\n").unwrap();
+        let matrix = Matrix::from_file(2, 2, path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let routes = matrix.unwrap().routes().to_vec();
+        assert_eq!(routes.len(), 3);
+        assert_eq!((routes[1].input, routes[1].output), (1, 1));
+        assert_eq!(routes[2], Route { input: 1, output: 0, gain: 0.5 });
+    }
+}
@@ -0,0 +1,247 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use libc;
+
+// Debug build checker for the real-time sections of the audio threads.
+// A section runs from rt_check::section() until the guard is dropped, device
+// reads and writes are expected to block and stay outside of it, as do waits
+// run through Section::suspend().
+// Detected while in a section:
+//  - heap allocations and frees, through CheckAllocator
+//  - voluntary context switches: contended locks, sleeps, blocking syscalls
+//  - read and write syscalls such as printing to stderr
+// Uncontended locks do not switch and go unnoticed.
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SECTIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static CONTEXT_SWITCHES: AtomicU64 = AtomicU64::new(0);
+static SYSCALLS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static IN_SECTION: Cell<bool> = Cell::new(false);
+    static PROC_IO: RefCell<Option<File>> = RefCell::new(None);
+}
+
+// Forwards to the system allocator, counting calls made inside a section.
+// Binaries using sections declare it:
+//   #[global_allocator]
+//   static ALLOCATOR: rt_check::CheckAllocator = rt_check::CheckAllocator;
+pub struct CheckAllocator;
+
+unsafe impl GlobalAlloc for CheckAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocation();
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+fn count_allocation() {
+    if cfg!(debug_assertions) && IN_SECTION.try_with(|s| s.get()).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Violations {
+    pub sections: u64,
+    pub allocations: u64,
+    pub context_switches: u64,
+    pub syscalls: u64,
+}
+
+impl Violations {
+    pub fn total(&self) -> u64 {
+        self.allocations + self.context_switches + self.syscalls
+    }
+}
+
+// Turns the checks on, only available in debug builds.
+pub fn enable() -> Result<(), String> {
+    if !cfg!(debug_assertions) {
+        return Err("real-time checks need a debug build".to_string());
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn violations() -> Violations {
+    Violations {
+        sections: SECTIONS.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        context_switches: CONTEXT_SWITCHES.load(Ordering::Relaxed),
+        syscalls: SYSCALLS.load(Ordering::Relaxed),
+    }
+}
+
+// Runs f with the checks enabled and returns what its sections violated.
+// The counters are global, tests using them run one at a time through here.
+#[cfg(test)]
+pub fn check<F: FnOnce()>(f: F) -> Violations {
+    use std::sync::Mutex;
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    enable().unwrap();
+    let before = violations();
+    f();
+    let after = violations();
+    Violations {
+        sections: after.sections - before.sections,
+        allocations: after.allocations - before.allocations,
+        context_switches: after.context_switches - before.context_switches,
+        syscalls: after.syscalls - before.syscalls,
+    }
+}
+
+pub struct Section {
+    active: bool,
+    context_switches: i64,
+    syscalls: u64,
+}
+
+pub fn section() -> Section {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Section { active: false, context_switches: 0, syscalls: 0 };
+    }
+
+    // opened on the first use, before the section starts
+    PROC_IO.with(|f| {
+        let mut f = f.borrow_mut();
+        if f.is_none() {
+            *f = File::open("/proc/thread-self/io").ok();
+        }
+    });
+
+    let mut section = Section { active: true, context_switches: 0, syscalls: 0 };
+    section.enter();
+    section
+}
+
+impl Section {
+    // Runs f outside of the section, for waits that are expected to block such
+    // as the lossless FIFO waiting for a file.
+    pub fn suspend<R, F: FnOnce() -> R>(&mut self, f: F) -> R {
+        if !self.active {
+            return f();
+        }
+        self.leave();
+        let result = f();
+        self.enter();
+        result
+    }
+
+    fn enter(&mut self) {
+        self.syscalls = io_syscalls();
+        self.context_switches = voluntary_switches();
+        IN_SECTION.with(|s| s.set(true));
+    }
+
+    fn leave(&mut self) {
+        IN_SECTION.with(|s| s.set(false));
+        let context_switches = voluntary_switches() - self.context_switches;
+        // the read taking the first snapshot is counted as well
+        let syscalls = io_syscalls().saturating_sub(self.syscalls + 1);
+
+        CONTEXT_SWITCHES.fetch_add(context_switches as u64, Ordering::Relaxed);
+        SYSCALLS.fetch_add(syscalls, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        self.leave();
+        SECTIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn voluntary_switches() -> i64 {
+    unsafe {
+        let mut usage: libc::rusage = mem::zeroed();
+        libc::getrusage(libc::RUSAGE_THREAD, &mut usage);
+        usage.ru_nvcsw as i64
+    }
+}
+
+// read and write syscalls made by the calling thread, parsed without allocating
+fn io_syscalls() -> u64 {
+    PROC_IO.with(|f| {
+        let mut f = f.borrow_mut();
+        let file = match *f {
+            Some(ref mut file) => file,
+            None => return 0,
+        };
+        let mut buf = [0u8; 512];
+        if file.seek(SeekFrom::Start(0)).is_err() {
+            return 0;
+        }
+        let len = file.read(&mut buf).unwrap_or(0);
+        field(&buf[..len], b"syscr: ") + field(&buf[..len], b"syscw: ")
+    })
+}
+
+fn field(text: &[u8], name: &[u8]) -> u64 {
+    let start = match text.windows(name.len()).position(|w| w == name) {
+        Some(pos) => pos + name.len(),
+        None => return 0,
+    };
+    text[start..].iter()
+        .take_while(|c| c.is_ascii_digit())
+        .fold(0, |n, &c| n * 10 + (c - b'0') as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn violations_are_counted() {
+        let mut null = File::create("/dev/null").unwrap();
+        let violations = check(|| {
+            let _section = section();
+            let v = Box::new([0u8; 64]);
+            null.write_all(&v[..]).unwrap();
+        });
+        assert_eq!(violations.sections, 1);
+        // allocation and free
+        assert_eq!(violations.allocations, 2);
+        assert_eq!(violations.syscalls, 1);
+    }
+
+    #[test]
+    fn suspended_waits_are_not_counted() {
+        let violations = check(|| {
+            let mut section = section();
+            section.suspend(|| thread::sleep(Duration::from_millis(2)));
+        });
+        assert_eq!(violations.sections, 1);
+        assert_eq!(violations.total(), 0, "{:?}", violations);
+
+        let violations = check(|| {
+            let _section = section();
+            thread::sleep(Duration::from_millis(2));
+        });
+        assert_eq!(violations.context_switches, 1);
+    }
+}
@@ -0,0 +1,822 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use backend::{self, f32_to_i16, f32_to_i24, i16_to_f32, i24_to_f32, monotonic_time};
+use backend::{Capture, Config, Playback, SimClock, SimOptions, Timestamp};
+
+// RTP audio over UDP (RFC 3550) with big endian L16 or L24 payloads, as used
+// by AES67. rtp:<address>:<port>[,<options>] receives on the address, joining
+// it when multicast, or sends to it. Options, comma separated:
+//   l16, l24       payload encoding [l24]
+//   pt=<n>         payload type sent, or the only one received [96 sent, any received]
+//   ptime=<ms>     packet time of the sender [1]
+//   ppm=<ppm>      sender clock deviation, to stand in for a remote device [0]
+//   jitter=<ms>    receiver jitter buffer depth [5]
+//   timeout=<s>    receiver end of stream after this long without packets [1]
+//   ttl=<hops>     sender multicast time to live [32]
+
+const HEADER_SIZE: usize = 12;
+const DEFAULT_PAYLOAD_TYPE: u8 = 96;
+// receive buffer, enough for jumbo frames
+const MAX_PACKET: usize = 9000;
+// longest wait in a receiver read, so that the capture thread can be stopped
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    L16,
+    L24,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match &*name.to_lowercase() {
+            "l16" => Some(Encoding::L16),
+            "l24" => Some(Encoding::L24),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::L16 => "L16",
+            Encoding::L24 => "L24",
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match *self {
+            Encoding::L16 => 2,
+            Encoding::L24 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RtpOptions {
+    pub address: SocketAddrV4,
+    pub encoding: Encoding,
+    pub payload_type: Option<u8>,
+    // seconds
+    pub ptime: f64,
+    pub ppm: f64,
+    pub jitter: f64,
+    pub timeout: f64,
+    // multicast hops
+    pub ttl: u32,
+}
+
+impl RtpOptions {
+    pub fn new(address: &str) -> backend::Result<RtpOptions> {
+        let invalid = || backend::Error::Device(format!("invalid RTP address: {}", address));
+        let address = address.to_socket_addrs()
+            .map_err(|_| invalid())?
+            .filter_map(|a| match a {
+                ::std::net::SocketAddr::V4(a) => Some(a),
+                _ => None,
+            })
+            .next()
+            .ok_or_else(invalid)?;
+        Ok(RtpOptions {
+            address,
+            encoding: Encoding::L24,
+            payload_type: None,
+            ptime: 0.001,
+            ppm: 0.0,
+            jitter: 0.005,
+            timeout: 1.0,
+            ttl: 32,
+        })
+    }
+
+    // <address>:<port>[,<options>]
+    pub fn parse(s: &str) -> backend::Result<RtpOptions> {
+        let mut parts = s.split(',');
+        let mut rtp = RtpOptions::new(parts.next().unwrap())?;
+        for option in parts.filter(|o| !o.is_empty()) {
+            let mut kv = option.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let value = kv.next().map(|v| v.parse::<f64>());
+            match (key, value) {
+                (_, None) if Encoding::from_name(key).is_some() => rtp.encoding = Encoding::from_name(key).unwrap(),
+                ("pt", Some(Ok(v))) if (0.0..128.0).contains(&v) => rtp.payload_type = Some(v as u8),
+                ("ptime", Some(Ok(v))) if v > 0.0 => rtp.ptime = v / 1000.0,
+                ("ppm", Some(Ok(v))) => rtp.ppm = v,
+                ("jitter", Some(Ok(v))) if v >= 0.0 => rtp.jitter = v / 1000.0,
+                ("timeout", Some(Ok(v))) if v > 0.0 => rtp.timeout = v,
+                ("ttl", Some(Ok(v))) if (1.0..256.0).contains(&v) => rtp.ttl = v as u32,
+                _ => return Err(backend::Error::Device(format!("invalid RTP option: {}", option))),
+            }
+        }
+        Ok(rtp)
+    }
+}
+
+/*
+ * Packets
+ */
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+// Returns the header and the payload range of an RTP packet.
+pub fn parse(packet: &[u8]) -> Option<(Header, usize, usize)> {
+    if packet.len() < HEADER_SIZE || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let be32 = |i: usize| (packet[i] as u32) << 24 | (packet[i + 1] as u32) << 16
+        | (packet[i + 2] as u32) << 8 | packet[i + 3] as u32;
+    let header = Header {
+        marker: packet[1] & 0x80 != 0,
+        payload_type: packet[1] & 0x7f,
+        sequence: (packet[2] as u16) << 8 | packet[3] as u16,
+        timestamp: be32(4),
+        ssrc: be32(8),
+    };
+
+    // CSRC list, header extension and padding
+    let mut start = HEADER_SIZE + 4 * (packet[0] & 0x0f) as usize;
+    if packet[0] & 0x10 != 0 {
+        if packet.len() < start + 4 {
+            return None;
+        }
+        start += 4 + 4 * ((packet[start + 2] as usize) << 8 | packet[start + 3] as usize);
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(packet[end - 1] as usize)?;
+    }
+    if start > end {
+        return None;
+    }
+    Some((header, start, end))
+}
+
+pub fn write_header(out: &mut Vec<u8>, header: &Header) {
+    out.push(2 << 6);
+    out.push(if header.marker { 0x80 } else { 0 } | header.payload_type);
+    out.extend_from_slice(&[(header.sequence >> 8) as u8, header.sequence as u8]);
+    for &v in &[header.timestamp, header.ssrc] {
+        out.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+    }
+}
+
+pub fn encode(out: &mut Vec<u8>, samples: &[f32], encoding: Encoding) {
+    for &x in samples {
+        match encoding {
+            Encoding::L16 => {
+                let v = f32_to_i16(x) as u16;
+                out.extend_from_slice(&[(v >> 8) as u8, v as u8]);
+            }
+            Encoding::L24 => {
+                let v = f32_to_i24(x) as u32;
+                out.extend_from_slice(&[(v >> 16) as u8, (v >> 8) as u8, v as u8]);
+            }
+        }
+    }
+}
+
+pub fn decode(payload: &[u8], out: &mut [f32], encoding: Encoding) {
+    for (x, b) in out.iter_mut().zip(payload.chunks(encoding.bytes())) {
+        *x = match encoding {
+            Encoding::L16 => i16_to_f32(((b[0] as u16) << 8 | b[1] as u16) as i16),
+            Encoding::L24 => i24_to_f32(((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8) as i32 >> 8),
+        };
+    }
+}
+
+// Builds consecutive packets of a stream.
+pub struct Packetizer {
+    header: Header,
+    encoding: Encoding,
+    channels: usize,
+    packet: Vec<u8>,
+}
+
+impl Packetizer {
+    pub fn new(payload_type: u8, encoding: Encoding, channels: usize) -> Packetizer {
+        // a random enough SSRC and initial sequence number and timestamp
+        let seed = (monotonic_time().fract() * 4294967296.0) as u32 ^ process::id().rotate_left(16);
+        Packetizer {
+            header: Header {
+                marker: true,
+                payload_type,
+                sequence: seed as u16,
+                timestamp: seed.wrapping_mul(2654435761),
+                ssrc: seed,
+            },
+            encoding,
+            channels,
+            packet: Vec::with_capacity(MAX_PACKET),
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.header.ssrc
+    }
+
+    // RTP timestamp of the next packet
+    pub fn timestamp(&self) -> u32 {
+        self.header.timestamp
+    }
+
+    // Starts the next packet at timestamp, e.g. after frames were lost, marked
+    // as the start of a talkspurt.
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        self.header.timestamp = timestamp;
+        self.header.marker = true;
+    }
+
+    // Returns the packet carrying the interleaved samples.
+    pub fn packet(&mut self, samples: &[f32]) -> &[u8] {
+        self.packet.clear();
+        write_header(&mut self.packet, &self.header);
+        encode(&mut self.packet, samples, self.encoding);
+
+        self.header.marker = false;
+        self.header.sequence = self.header.sequence.wrapping_add(1);
+        self.header.timestamp = self.header.timestamp.wrapping_add((samples.len() / self.channels) as u32);
+        &self.packet
+    }
+}
+
+/*
+ * Receiver statistics
+ */
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RtpStats {
+    pub packets: u64,
+    // packets never received, from the sequence numbers
+    pub lost: u64,
+    // packets received after their frames were played
+    pub late: u64,
+    // malformed packets, other payload types or sources
+    pub ignored: u64,
+    // interarrival jitter in seconds (RFC 3550)
+    pub jitter: f64,
+    // sender rate measured from the RTP timestamps against the arrival times, 0 until known
+    pub sender_rate: f64,
+}
+
+#[derive(Default)]
+pub struct RtpStatus {
+    packets: AtomicU64,
+    lost: AtomicU64,
+    late: AtomicU64,
+    ignored: AtomicU64,
+    jitter: AtomicU64,
+    sender_rate: AtomicU64,
+}
+
+impl RtpStatus {
+    pub fn snapshot(&self) -> RtpStats {
+        RtpStats {
+            packets: self.packets.load(Ordering::Relaxed),
+            lost: self.lost.load(Ordering::Relaxed),
+            late: self.late.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            jitter: f64::from_bits(self.jitter.load(Ordering::Relaxed)),
+            sender_rate: f64::from_bits(self.sender_rate.load(Ordering::Relaxed)),
+        }
+    }
+
+    // None until a second of packets came in
+    pub fn sender_rate(&self) -> Option<f64> {
+        Some(f64::from_bits(self.sender_rate.load(Ordering::Relaxed))).filter(|&rate| rate > 0.0)
+    }
+}
+
+// Least squares fit of the RTP timestamps over the arrival times, the slope
+// being the sender rate as seen from the local clock.
+#[derive(Default)]
+struct RateEstimator {
+    origin: Option<(f64, i64)>,
+    n: f64,
+    t: f64,
+    tt: f64,
+    x: f64,
+    tx: f64,
+}
+
+impl RateEstimator {
+    fn add(&mut self, arrival: f64, timestamp: i64) {
+        let (t0, x0) = *self.origin.get_or_insert((arrival, timestamp));
+        let t = arrival - t0;
+        let x = (timestamp - x0) as f64;
+        self.n += 1.0;
+        self.t += t;
+        self.tt += t * t;
+        self.x += x;
+        self.tx += t * x;
+    }
+
+    // needs a second of packets
+    fn rate(&self) -> Option<f64> {
+        let var = self.n * self.tt - self.t * self.t;
+        if self.n < 2.0 || self.tt / self.n < 0.25 || var <= 0.0 {
+            return None;
+        }
+        Some((self.n * self.tx - self.t * self.x) / var)
+    }
+}
+
+// Extends a wrapping counter to 64 bits around the last value seen.
+fn extend(last: i64, value: u32, bits: u32) -> i64 {
+    let modulo = 1i64 << bits;
+    let delta = (value as i64 - last).rem_euclid(modulo);
+    if delta >= modulo / 2 { last + delta - modulo } else { last + delta }
+}
+
+/*
+ * Receiver
+ */
+
+// Delivers the frames of one RTP source in timestamp order through a jitter
+// buffer: a period is handed out once the packets up to the jitter depth past
+// its end arrived. Frames of lost packets are silent, late packets dropped.
+pub struct RtpCapture {
+    config: Config,
+    options: RtpOptions,
+    socket: UdpSocket,
+    packet: Vec<u8>,
+    // frames indexed by extended timestamp modulo its size
+    ring: Vec<f32>,
+    ring_frames: usize,
+    jitter_frames: i64,
+    // extended timestamps of the next frame to deliver and past the newest received
+    next: Option<i64>,
+    end: i64,
+    ssrc: Option<u32>,
+    last_timestamp: i64,
+    first_sequence: i64,
+    last_sequence: i64,
+    received: u64,
+    last_arrival: f64,
+    transit: Option<f64>,
+    jitter: f64,
+    estimator: RateEstimator,
+    status: Arc<RtpStatus>,
+}
+
+impl RtpCapture {
+    pub fn open(options: RtpOptions, config: Config) -> backend::Result<RtpCapture> {
+        let socket = bind(&options.address)?;
+        socket.set_read_timeout(Some(POLL))?;
+
+        let jitter_frames = (options.jitter * config.rate as f64).ceil() as usize;
+        let max_packet_frames = MAX_PACKET / (options.encoding.bytes() * config.channels);
+        let ring_frames = 2 * jitter_frames + 4 * config.period_size * config.periods as usize + 2 * max_packet_frames;
+
+        Ok(RtpCapture {
+            ring: vec![0.0; ring_frames * config.channels],
+            ring_frames,
+            jitter_frames: jitter_frames as i64,
+            config,
+            options,
+            socket,
+            packet: vec![0; MAX_PACKET],
+            next: None,
+            end: 0,
+            ssrc: None,
+            last_timestamp: 0,
+            first_sequence: 0,
+            last_sequence: 0,
+            received: 0,
+            last_arrival: 0.0,
+            transit: None,
+            jitter: 0.0,
+            estimator: RateEstimator::default(),
+            status: Arc::new(RtpStatus::default()),
+        })
+    }
+
+    pub fn status(&self) -> Arc<RtpStatus> {
+        self.status.clone()
+    }
+
+    // Waits for a packet and queues its frames, false when none came in time.
+    fn receive(&mut self) -> backend::Result<bool> {
+        let len = match self.socket.recv(&mut self.packet) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let arrival = monotonic_time();
+        let channels = self.config.channels;
+        let frame_bytes = self.options.encoding.bytes() * channels;
+
+        let (header, start, end) = match parse(&self.packet[..len]) {
+            Some(parsed) => parsed,
+            None => return Ok(self.ignore()),
+        };
+        if self.options.payload_type.map_or(false, |pt| pt != header.payload_type)
+            || self.ssrc.map_or(false, |ssrc| ssrc != header.ssrc)
+            || (end - start) % frame_bytes != 0 {
+            return Ok(self.ignore());
+        }
+        let frames = (end - start) / frame_bytes;
+
+        let timestamp = match self.next {
+            None => {
+                // the first packet starts the stream
+                self.ssrc = Some(header.ssrc);
+                self.next = Some(header.timestamp as i64);
+                self.end = header.timestamp as i64;
+                self.first_sequence = header.sequence as i64;
+                self.last_sequence = header.sequence as i64;
+                header.timestamp as i64
+            }
+            Some(_) => extend(self.last_timestamp, header.timestamp, 32),
+        };
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        self.last_sequence = self.last_sequence.max(extend(self.last_sequence, header.sequence as u32, 16));
+        self.received += 1;
+        self.last_arrival = arrival;
+
+        // RFC 3550 interarrival jitter, in timestamp units
+        let rate = self.config.rate as f64;
+        let transit = arrival * rate - timestamp as f64;
+        if let Some(previous) = self.transit {
+            self.jitter += ((transit - previous).abs() - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+        self.estimator.add(arrival, timestamp);
+
+        let next = self.next.unwrap();
+        if timestamp + frames as i64 <= next {
+            self.status.late.fetch_add(1, Ordering::Relaxed);
+        } else {
+            // frames past the ring are the reader falling behind, start over from this packet
+            if timestamp + frames as i64 > next + self.ring_frames as i64 {
+                for x in self.ring.iter_mut() {
+                    *x = 0.0;
+                }
+                self.next = Some(timestamp);
+            }
+            let next = self.next.unwrap();
+            let first = (next - timestamp).max(0) as usize;
+            for i in first..frames {
+                let slot = ((timestamp + i as i64) as usize % self.ring_frames) * channels;
+                let bytes = start + i * frame_bytes;
+                decode(&self.packet[bytes..bytes + frame_bytes], &mut self.ring[slot..slot + channels],
+                       self.options.encoding);
+            }
+            self.end = self.end.max(timestamp + frames as i64);
+        }
+
+        let expected = (self.last_sequence - self.first_sequence + 1) as u64;
+        self.status.packets.store(self.received, Ordering::Relaxed);
+        self.status.lost.store(expected.saturating_sub(self.received), Ordering::Relaxed);
+        self.status.jitter.store((self.jitter / rate).to_bits(), Ordering::Relaxed);
+        if let Some(sender_rate) = self.estimator.rate() {
+            self.status.sender_rate.store(sender_rate.to_bits(), Ordering::Relaxed);
+        }
+        Ok(true)
+    }
+
+    fn ignore(&self) -> bool {
+        self.status.ignored.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+fn bind(address: &SocketAddrV4) -> io::Result<UdpSocket> {
+    if address.ip().is_multicast() {
+        let socket = UdpSocket::bind(address)?;
+        socket.join_multicast_v4(address.ip(), &Ipv4Addr::new(0, 0, 0, 0))?;
+        Ok(socket)
+    } else {
+        UdpSocket::bind(address)
+    }
+}
+
+impl Capture for RtpCapture {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn start(&mut self) -> backend::Result<()> {
+        Ok(())
+    }
+
+    // Returns 0 frames when no packet came for a while, so that the caller can
+    // check whether to stop.
+    fn read(&mut self, buf: &mut [f32]) -> backend::Result<usize> {
+        let channels = self.config.channels;
+        let want = buf.len() / channels;
+        loop {
+            if let Some(next) = self.next {
+                let ready = self.end - next;
+                let timeout = monotonic_time() - self.last_arrival > self.options.timeout;
+                if ready >= want as i64 + self.jitter_frames || (timeout && ready > 0) {
+                    let frames = want.min(ready as usize);
+                    for i in 0..frames {
+                        let slot = ((next + i as i64) as usize % self.ring_frames) * channels;
+                        let frame = &mut self.ring[slot..slot + channels];
+                        buf[i * channels..(i + 1) * channels].copy_from_slice(frame);
+                        for x in frame.iter_mut() {
+                            *x = 0.0;
+                        }
+                    }
+                    self.next = Some(next + frames as i64);
+                    return Ok(frames);
+                }
+                if timeout {
+                    return Err(backend::Error::EndOfStream);
+                }
+            }
+            if !self.receive()? {
+                return Ok(0);
+            }
+        }
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        None
+    }
+}
+
+/*
+ * Sender
+ */
+
+// Packetizes interleaved frames and sends them, a packet of ptime at a time.
+pub struct RtpSender {
+    options: RtpOptions,
+    config: Config,
+    socket: UdpSocket,
+    packetizer: Packetizer,
+    packet_frames: usize,
+    pending: Vec<f32>,
+    packets: u64,
+}
+
+impl RtpSender {
+    pub fn open(options: RtpOptions, config: Config) -> backend::Result<RtpSender> {
+        let socket = UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), 0))?;
+        socket.connect(options.address)?;
+        if options.address.ip().is_multicast() {
+            socket.set_multicast_ttl_v4(options.ttl)?;
+        }
+
+        let packet_frames = ((options.ptime * config.rate as f64).round() as usize).max(1);
+        let max_packet_frames = (MAX_PACKET - HEADER_SIZE) / (options.encoding.bytes() * config.channels);
+        if packet_frames > max_packet_frames {
+            return Err(backend::Error::Device(format!("RTP packets hold at most {} frames", max_packet_frames)));
+        }
+        let packetizer = Packetizer::new(options.payload_type.unwrap_or(DEFAULT_PAYLOAD_TYPE),
+                                         options.encoding, config.channels);
+
+        Ok(RtpSender {
+            pending: Vec::with_capacity((packet_frames + config.period_size) * config.channels),
+            options,
+            config,
+            socket,
+            packetizer,
+            packet_frames,
+            packets: 0,
+        })
+    }
+
+    pub fn packet_frames(&self) -> usize {
+        self.packet_frames
+    }
+
+    // frames queued, not sent yet
+    pub fn pending(&self) -> usize {
+        self.pending.len() / self.config.channels
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    // RTP timestamp of the next frame queued
+    pub fn timestamp(&self) -> u32 {
+        self.packetizer.timestamp().wrapping_add(self.pending() as u32)
+    }
+
+    // Numbers the next frame queued, the pending frames are sent first.
+    pub fn set_timestamp(&mut self, timestamp: u32) -> backend::Result<()> {
+        self.flush()?;
+        self.packetizer.set_timestamp(timestamp);
+        Ok(())
+    }
+
+    pub fn queue(&mut self, samples: &[f32]) {
+        self.pending.extend_from_slice(samples);
+    }
+
+    // true when a full packet is queued
+    pub fn ready(&self) -> bool {
+        self.pending() >= self.packet_frames
+    }
+
+    // Sends a packet of the queued frames, returns its frames.
+    pub fn send(&mut self) -> backend::Result<usize> {
+        let frames = self.pending().min(self.packet_frames);
+        let samples = frames * self.config.channels;
+        if frames == 0 {
+            return Ok(0);
+        }
+        {
+            let packet = self.packetizer.packet(&self.pending[..samples]);
+            match self.socket.send(packet) {
+                // nobody listening yet, a stream goes on regardless
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        self.pending.drain(..samples);
+        self.packets += 1;
+        Ok(frames)
+    }
+
+    // Sends all queued frames, the last packet possibly short.
+    pub fn flush(&mut self) -> backend::Result<()> {
+        while self.pending() > 0 {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    // Session description for receivers to subscribe to the stream.
+    pub fn sdp(&self, name: &str) -> String {
+        let origin = match self.socket.local_addr() {
+            Ok(::std::net::SocketAddr::V4(a)) => *a.ip(),
+            _ => Ipv4Addr::new(0, 0, 0, 0),
+        };
+        let destination = self.options.address;
+        let connection = if destination.ip().is_multicast() {
+            format!("{}/{}", destination.ip(), self.options.ttl)
+        } else {
+            destination.ip().to_string()
+        };
+        let payload_type = self.options.payload_type.unwrap_or(DEFAULT_PAYLOAD_TYPE);
+        format!("v=0\r\n\
+                 o=- {ssrc} 0 IN IP4 {origin}\r\n\
+                 s={name}\r\n\
+                 c=IN IP4 {connection}\r\n\
+                 t=0 0\r\n\
+                 m=audio {port} RTP/AVP {pt}\r\n\
+                 a=rtpmap:{pt} {encoding}/{rate}/{channels}\r\n\
+                 a=ptime:{ptime}\r\n\
+                 a=recvonly\r\n",
+                ssrc = self.packetizer.ssrc(),
+                origin = origin,
+                name = name,
+                connection = connection,
+                port = destination.port(),
+                pt = payload_type,
+                encoding = self.options.encoding.name(),
+                rate = self.config.rate,
+                channels = self.config.channels,
+                ptime = self.packet_frames as f64 * 1000.0 / self.config.rate as f64)
+    }
+}
+
+// Sends the written frames as RTP packets of ptime, paced by the system clock
+// running at the nominal rate plus options.ppm. Stands in for a remote sender.
+pub struct RtpPlayback {
+    config: Config,
+    sender: RtpSender,
+    clock: SimClock,
+    sent: u64,
+}
+
+impl RtpPlayback {
+    pub fn open(options: RtpOptions, config: Config) -> backend::Result<RtpPlayback> {
+        let clock = SimClock::new(&config, &SimOptions { ppm: options.ppm, fast: false, tone: None, replay: None });
+        let sender = RtpSender::open(options, config.clone())?;
+        Ok(RtpPlayback { config, sender, clock, sent: 0 })
+    }
+
+    fn send(&mut self) -> backend::Result<()> {
+        // a packet leaves once its last frame was due
+        let frames = self.sender.pending().min(self.sender.packet_frames());
+        self.clock.sleep_until(self.sent + frames as u64);
+        self.sent += self.sender.send()? as u64;
+        Ok(())
+    }
+}
+
+impl Playback for RtpPlayback {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn write(&mut self, buf: &[f32]) -> backend::Result<usize> {
+        if !self.clock.started() {
+            self.clock.start();
+        }
+        self.sender.queue(buf);
+        while self.sender.ready() {
+            self.send()?;
+        }
+        Ok(buf.len() / self.config.channels)
+    }
+
+    fn drain(&mut self) -> backend::Result<()> {
+        while self.sender.pending() > 0 {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn timestamp(&self) -> Option<Timestamp> {
+        let delay = self.sent as i64 - self.clock.hw_frames() as i64;
+        self.clock.timestamp(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::SampleFormat;
+
+    #[test]
+    fn estimates_sender_rate() {
+        let mut estimator = RateEstimator::default();
+        let rate = 48000.0 * (1.0 + 50e-6);
+        for i in 0..1000 {
+            let arrival = 100.0 + i as f64 * 0.001 + if i % 2 == 0 { 0.0002 } else { 0.0 };
+            estimator.add(arrival, (i as f64 * 0.001 * rate) as i64);
+            // needs a second of packets
+            if i < 800 {
+                assert!(estimator.rate().is_none());
+            }
+        }
+        assert!((estimator.rate().unwrap() / rate - 1.0).abs() < 5e-6);
+    }
+
+    #[test]
+    fn receives_over_localhost() {
+        let address = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut options = RtpOptions::new(&address).unwrap();
+        options.jitter = 0.01;
+        options.timeout = 0.2;
+        let mut capture = RtpCapture::open(options, Config {
+            channels: 2,
+            rate: 48000,
+            format: SampleFormat::F32,
+            period_size: 48,
+            periods: 2,
+        }).unwrap();
+        let status = capture.status();
+
+        // nine packets of 48 frames counting up, the right channel negated
+        let mut packetizer = Packetizer::new(96, Encoding::L24, 2);
+        let packets: Vec<Vec<u8>> = (0..9).map(|p| {
+            let samples: Vec<f32> = (0..96).map(|i| {
+                let frame = (p * 48 + i / 2) as f32 / 1024.0;
+                if i % 2 == 0 { frame } else { -frame }
+            }).collect();
+            packetizer.packet(&samples).to_vec()
+        }).collect();
+        let mut other = Packetizer::new(96, Encoding::L24, 2);
+        assert_ne!(other.ssrc(), packetizer.ssrc());
+        let foreign = other.packet(&[0.5; 96]).to_vec();
+
+        // the fourth packet ahead of the third, the sixth lost
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for packet in [&packets[0], &packets[1], &packets[3], &packets[2], &foreign,
+                       &packets[4], &packets[6], &packets[7], &packets[8]].iter() {
+            socket.send_to(packet, &address).unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0.0; 96];
+        loop {
+            match capture.read(&mut buf) {
+                Ok(frames) => received.extend_from_slice(&buf[..frames * 2]),
+                Err(backend::Error::EndOfStream) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(received.len(), 9 * 96);
+        for (frame, samples) in received.chunks(2).enumerate() {
+            let expected = if frame / 48 == 5 { 0.0 } else { frame as f32 / 1024.0 };
+            assert!((samples[0] - expected).abs() < 1e-6, "frame {}: {:?}", frame, samples);
+            assert!((samples[1] + expected).abs() < 1e-6, "frame {}: {:?}", frame, samples);
+        }
+
+        let stats = status.snapshot();
+        assert_eq!((stats.packets, stats.lost, stats.late, stats.ignored), (8, 1, 0, 1));
+        assert_eq!(status.sender_rate(), None);
+    }
+}
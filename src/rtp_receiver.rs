@@ -0,0 +1,222 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod backend;
+mod config;
+mod control;
+mod drift;
+mod engine;
+mod fifo;
+mod interpolator;
+mod metrics;
+mod polyphase;
+mod realtime_priority;
+mod record;
+mod resampler;
+mod rt_check;
+mod rtp;
+mod session;
+mod shutdown;
+mod simd;
+mod spsc;
+mod telemetry;
+mod wav;
+
+use std::process;
+
+use backend::{Capture, Config, SampleFormat};
+use engine::{Engine, EngineConfig};
+use fifo::{Overflow, Underflow};
+use rtp::{Encoding, RtpCapture, RtpOptions};
+
+#[global_allocator]
+static ALLOCATOR: rt_check::CheckAllocator = rt_check::CheckAllocator;
+
+const USAGE: &str = "
+RTP receiver
+
+Receives L16 or L24 RTP audio (AES67 style) over UDP and plays it on a local
+device, following the sender clock with the asrc. Multicast addresses are
+joined. signal-generator --device=rtp:<address>:<port> stands in for a sender.
+
+Usage:
+  rtp-receiver [--config=<file>] [--encoding=<encoding> --channels=<nr> --sample-rate=<Hz> --payload-type=<pt> --jitter-buffer=<ms> --timeout=<seconds> --period-size=<frames> --playback-device=<alsa-device> --playback-sample-rate=<Hz> --playback-period-size=<frames> --playback-periods=<count> --format=<format> --resampler=<quality> --block-size=<frames> --max-ppm=<ppm> --fifo-target=<frames> --record-playback=<file> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock --telemetry-interval=<seconds> --telemetry-log=<file> --control-socket=<path> --metrics=<address> --duration=<seconds>] <source>
+  rtp-receiver (-h | --help)
+
+Arguments:
+  <source>                          Address and port to receive on, e.g. 239.69.1.1:5004 or 0.0.0.0:5004.
+
+Options:
+  -h --help                         Show this screen.
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
+  --encoding=<encoding>             Payload encoding: l16 or l24 [default: l24].
+  --channels=<nr>                   Channels of the stream [default: 2].
+  --sample-rate=<Hz>                Nominal sample rate of the stream [default: 48000].
+  --payload-type=<pt>               Only accept this RTP payload type, any by default.
+  --jitter-buffer=<ms>              Packets waited for past a period before it is played [default: 5].
+  --timeout=<seconds>               End of stream after this long without packets [default: 1].
+  --period-size=<frames>            Frames taken from the jitter buffer at once [default: 256].
+  --playback-device=<alsa-device>   ALSA device, file:<path> or sim to playback to [default: default]
+  --playback-sample-rate=<Hz>       Playback sample rate [default: 48000].
+  --playback-period-size=<frames>   Size of playback frames [default: 256].
+  --playback-periods=<count>        Amount of playback periods [default: 2].
+  --format=<format>                 Playback sample format: s16, s24, s32 or f32 [default: s16]
+  --resampler=<quality>             Resampler quality: low, medium, high or best windowed sinc, or linear,
+                                    hermite, lagrange4, lagrange6 or farrow for low latency,
+                                    or rational for an exact nominal ratio, rational-fixed
+                                    for clocks locked together [default: high].
+  --block-size=<frames>             Processing block size, 0 for the playback period size [default: 0].
+  --max-ppm=<ppm>                   Largest clock drift correction [default: 1000].
+  --fifo-target=<frames>            FIFO fill to keep in stream frames, 0 for three periods [default: 0].
+  --record-playback=<file>          Record the converted stream to a WAV or raw file.
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
+  --telemetry-interval=<seconds>    Seconds between status summaries [default: 1].
+  --telemetry-log=<file>            Write every telemetry record to a file.
+  --control-socket=<path>           Accept commands from asrc-ctl on a Unix socket.
+  --metrics=<address>               Serve Prometheus metrics on http://<address>/metrics, e.g. 127.0.0.1:9100.
+  --duration=<seconds>              Stop after this much received audio, 0 runs until the stream ends [default: 0].
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_source: String,
+    flag_encoding: String,
+    flag_channels: usize,
+    flag_sample_rate: u32,
+    flag_payload_type: Option<u8>,
+    flag_jitter_buffer: f64,
+    flag_timeout: f64,
+    flag_period_size: usize,
+    flag_playback_device: String,
+    flag_playback_sample_rate: u32,
+    flag_playback_period_size: usize,
+    flag_playback_periods: u32,
+    flag_format: String,
+    flag_resampler: String,
+    flag_block_size: usize,
+    flag_max_ppm: f64,
+    flag_fifo_target: usize,
+    flag_record_playback: Option<String>,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
+    flag_telemetry_interval: f64,
+    flag_telemetry_log: Option<String>,
+    flag_control_socket: Option<String>,
+    flag_metrics: Option<String>,
+    flag_duration: f64,
+}
+
+fn main() {
+    let args: Args = session::args(USAGE);
+    let rt = session::rt_config(&args.flag_rt_policy, args.flag_rt_priority,
+                                &args.flag_rt_cpus, args.flag_rt_mlock);
+    let format = session::format(&args.flag_format);
+    let quality = session::quality(&args.flag_resampler);
+
+    let mut options = RtpOptions::new(&args.arg_source).unwrap_or_else(|e| {
+        eprintln!("{}", config::error("source", e.to_string()));
+        process::exit(1);
+    });
+    options.encoding = Encoding::from_name(&args.flag_encoding).unwrap_or_else(|| {
+        eprintln!("{}", config::error("encoding", format!("Invalid encoding: {}", args.flag_encoding)));
+        process::exit(1);
+    });
+    if args.flag_payload_type.map_or(false, |pt| pt > 127) {
+        eprintln!("{}", config::error("payload-type", "Payload types go up to 127".to_string()));
+        process::exit(1);
+    }
+    options.payload_type = args.flag_payload_type;
+    options.jitter = args.flag_jitter_buffer / 1000.0;
+    options.timeout = args.flag_timeout;
+
+    let capture = RtpCapture::open(options.clone(), Config {
+        channels: args.flag_channels,
+        rate: args.flag_sample_rate,
+        format: SampleFormat::F32,
+        period_size: args.flag_period_size,
+        periods: 2,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot receive on {}: {}", args.arg_source, e);
+        process::exit(1);
+    });
+    let rtp_status = capture.status();
+    let capture_config = capture.config().clone();
+
+    let playback = backend::open_playback(&args.flag_playback_device, Config {
+        channels: args.flag_channels,
+        rate: args.flag_playback_sample_rate,
+        format,
+        period_size: args.flag_playback_period_size,
+        periods: args.flag_playback_periods,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_playback_device, e);
+        process::exit(1);
+    });
+    let playback_config = playback.config().clone();
+
+    eprintln!("RTP\n  source:   {}{}\n  encoding: {}\n  channels: {}\n  rate:     {}\n  jitter:   {} ms",
+              options.address,
+              if options.address.ip().is_multicast() { " (multicast)" } else { "" },
+              options.encoding.name(),
+              capture_config.channels,
+              capture_config.rate,
+              args.flag_jitter_buffer);
+    session::print_device("Playback", &args.flag_playback_device, &playback_config);
+
+    let ratio = playback_config.rate as f64 / capture_config.rate as f64;
+    eprintln!("Resampler: {}, ratio: {}", quality.name(), ratio);
+
+    let mut engine = Engine::new(Box::new(capture), playback, EngineConfig {
+        block_size: args.flag_block_size,
+        quality,
+        overflow: Overflow::DropOldest,
+        underflow: Underflow::Silence,
+        fifo_target: args.flag_fifo_target,
+        drift_control: true,
+        max_ppm: args.flag_max_ppm,
+        duration: args.flag_duration,
+        rt,
+    });
+    // the drift control starts from the sender rate once it is measured
+    let sender = rtp_status.clone();
+    engine.rate_hint(Box::new(move || sender.sender_rate()));
+    eprintln!("FIFO target: {} frames", engine.fifo_target());
+    eprintln!("Block size: {} frames", engine.block_size());
+
+    let playback_recorder = session::record(&args.flag_record_playback, &playback_config).map(|(tap, recorder)| {
+        engine.record_playback(tap);
+        recorder
+    });
+
+    let telemetry = session::telemetry(&mut engine, args.flag_telemetry_interval,
+                                       &args.flag_telemetry_log, &args.flag_metrics);
+
+    let _control = control::serve_option(&args.flag_control_socket, engine.control());
+
+    session::run(engine, |input: &[f32], output: &mut [f32], _frames: usize| {
+        output.copy_from_slice(input);
+    }, telemetry);
+
+    let rtp = rtp_status.snapshot();
+    eprintln!("RTP packets: {}, lost: {}, late: {}, ignored: {}, jitter: {:.3} ms",
+              rtp.packets, rtp.lost, rtp.late, rtp.ignored, rtp.jitter * 1000.0);
+    if rtp.sender_rate > 0.0 {
+        eprintln!("Sender rate: {:.3} Hz ({:+.2} ppm against the local clock)", rtp.sender_rate,
+                  (rtp.sender_rate / capture_config.rate as f64 - 1.0) * 1e6);
+    }
+
+    if let Some(recorder) = playback_recorder {
+        recorder.stop();
+    }
+}
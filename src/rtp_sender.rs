@@ -0,0 +1,247 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod backend;
+mod config;
+mod realtime_priority;
+mod rtp;
+mod shutdown;
+mod wav;
+
+use docopt::Docopt;
+use std::fs;
+use std::process;
+
+use backend::{Config, SampleFormat, SimClock, SimOptions};
+use realtime_priority::RtConfig;
+use rtp::{Encoding, RtpOptions, RtpSender};
+
+const USAGE: &str = "
+RTP sender
+
+Captures from a device and sends it as L16 or L24 RTP audio (AES67 style) over
+UDP, unicast or multicast. The RTP timestamps count the capture frames from the
+stream start on the system clock, so they follow the capture clock.
+
+Usage:
+  rtp-sender [--config=<file>] [--capture-device=<alsa-device> --channels=<nr> --sample-rate=<Hz> --format=<format> --period-size=<frames> --periods=<count> --encoding=<encoding> --payload-type=<pt> --ptime=<ms> --ttl=<hops> --sdp=<file> --session-name=<name> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock --duration=<seconds>] <destination>
+  rtp-sender (-h | --help)
+
+Arguments:
+  <destination>                     Address and port to send to, e.g. 239.69.1.1:5004 or 192.168.1.20:5004.
+
+Options:
+  -h --help                         Show this screen.
+  --config=<file>                   Read the options from a TOML file, command line flags take precedence.
+  --capture-device=<alsa-device>    ALSA device, file:<path> or sim[:<options>] to record from [default: default]
+  --channels=<nr>                   Channels to capture and send [default: 2].
+  --sample-rate=<Hz>                Capture sample rate [default: 48000].
+  --format=<format>                 Capture sample format: s16, s24, s32 or f32 [default: s16].
+  --period-size=<frames>            Capture period size [default: 48].
+  --periods=<count>                 Capture periods [default: 4].
+  --encoding=<encoding>             Payload encoding: l16 or l24 [default: l24].
+  --payload-type=<pt>               RTP payload type, dynamic range 96-127 [default: 96].
+  --ptime=<ms>                      Packet time [default: 1].
+  --ttl=<hops>                      Multicast time to live [default: 32].
+  --sdp=<file>                      Write the session description of the stream to a file.
+  --session-name=<name>             Session name in the SDP [default: asrc-rs].
+  --rt-policy=<policy>              Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>              Real-time priority [default: 3].
+  --rt-cpus=<list>                  Pin the capture thread to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                        Lock the process memory with mlockall.
+  --duration=<seconds>              Stop after this much captured audio, 0 runs forever [default: 0].
+";
+
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_destination: String,
+    flag_capture_device: String,
+    flag_channels: usize,
+    flag_sample_rate: u32,
+    flag_format: String,
+    flag_period_size: usize,
+    flag_periods: u32,
+    flag_encoding: String,
+    flag_payload_type: u8,
+    flag_ptime: f64,
+    flag_ttl: u32,
+    flag_sdp: Option<String>,
+    flag_session_name: String,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
+    flag_duration: f64,
+}
+
+fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    let rt = RtConfig::from_options(&args.flag_rt_policy, args.flag_rt_priority,
+                                    &args.flag_rt_cpus, args.flag_rt_mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let format = SampleFormat::from_name(&args.flag_format).unwrap_or_else(|| {
+        eprintln!("{}", config::error("format", format!("Invalid sample format: {}", args.flag_format)));
+        process::exit(1);
+    });
+
+    let mut options = RtpOptions::new(&args.arg_destination).unwrap_or_else(|e| {
+        eprintln!("{}", config::error("destination", e.to_string()));
+        process::exit(1);
+    });
+    options.encoding = Encoding::from_name(&args.flag_encoding).unwrap_or_else(|| {
+        eprintln!("{}", config::error("encoding", format!("Invalid encoding: {}", args.flag_encoding)));
+        process::exit(1);
+    });
+    let invalid = if args.flag_payload_type > 127 {
+        Some(("payload-type", "Payload types go up to 127"))
+    } else if args.flag_ptime <= 0.0 {
+        Some(("ptime", "The packet time must be positive"))
+    } else if args.flag_ttl == 0 || args.flag_ttl > 255 {
+        Some(("ttl", "The TTL goes from 1 to 255"))
+    } else {
+        None
+    };
+    if let Some((option, message)) = invalid {
+        eprintln!("{}", config::error(option, message.to_string()));
+        process::exit(1);
+    }
+    options.payload_type = Some(args.flag_payload_type);
+    options.ptime = args.flag_ptime / 1000.0;
+    options.ttl = args.flag_ttl;
+
+    let mut capture = backend::open_capture(&args.flag_capture_device, Config {
+        channels: args.flag_channels,
+        rate: args.flag_sample_rate,
+        format,
+        period_size: args.flag_period_size,
+        periods: args.flag_periods,
+    }).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_capture_device, e);
+        process::exit(1);
+    });
+    let config = capture.config().clone();
+
+    let mut sender = RtpSender::open(options.clone(), config.clone()).unwrap_or_else(|e| {
+        eprintln!("Cannot send to {}: {}", args.arg_destination, e);
+        process::exit(1);
+    });
+
+    eprintln!("Capture\n  card:     {}\n  channels: {}\n  rate:     {}\n  period:   {}\n  periods:  {}",
+              args.flag_capture_device,
+              config.channels,
+              config.rate,
+              config.period_size,
+              config.periods);
+    eprintln!("RTP\n  destination: {}{}\n  encoding:    {}\n  payload:     {}\n  packet:      {} frames",
+              options.address,
+              if options.address.ip().is_multicast() { " (multicast)" } else { "" },
+              options.encoding.name(),
+              args.flag_payload_type,
+              sender.packet_frames());
+
+    if let Some(ref path) = args.flag_sdp {
+        fs::write(path, sender.sdp(&args.flag_session_name)).unwrap_or_else(|e| {
+            eprintln!("Cannot write {}: {}", path, e);
+            process::exit(1);
+        });
+        eprintln!("Session description in {}", path);
+    }
+
+    // files are sent in real time, at the nominal rate
+    let mut pace = if capture.clocked() {
+        None
+    } else {
+        Some(SimClock::new(&config, &SimOptions { ppm: 0.0, fast: false, tone: None, replay: None }))
+    };
+
+    let rate = config.rate as f64;
+    let max_frames = (args.flag_duration * rate) as u64;
+    let mut buf = vec![0.0f32; config.period_size * config.channels];
+    let mut captured: u64 = 0;
+    let mut xruns = 0;
+    // the RTP timestamps are anchored on the stream start, again after an xrun
+    let mut anchored = false;
+    let mut failed = false;
+
+    shutdown::install();
+    eprintln!("Real-time: {}", rt.apply());
+    let start = backend::monotonic_time();
+    capture.start().unwrap_or_else(|e| {
+        eprintln!("Cannot start {}: {}", args.flag_capture_device, e);
+        process::exit(1);
+    });
+
+    while (max_frames == 0 || captured < max_frames) && !shutdown::requested() {
+        let frames = match capture.read(&mut buf) {
+            Ok(frames) => frames,
+            Err(backend::Error::XRun) => {
+                xruns += 1;
+                eprintln!("Capture xrun {}", xruns);
+                anchored = false;
+                continue;
+            }
+            Err(backend::Error::EndOfStream) => break,
+            Err(e) => {
+                eprintln!("Capture error: {}", e);
+                break;
+            }
+        };
+        if frames == 0 {
+            continue;
+        }
+
+        if let Some(ref mut clock) = pace {
+            if !clock.started() {
+                clock.start();
+            }
+            clock.sleep_until(captured + frames as u64);
+        }
+        if !anchored {
+            // media clock: frames of the monotonic clock, at the first frame read
+            let first = match capture.timestamp() {
+                Some(ts) if ts.trigger > 0.0 => ts.trigger,
+                _ => backend::monotonic_time() - frames as f64 / rate,
+            };
+            let result = sender.set_timestamp((first * rate).round() as u64 as u32);
+            if let Err(e) = result {
+                eprintln!("Send error: {}", e);
+                break;
+            }
+            anchored = true;
+        }
+
+        sender.queue(&buf[..frames * config.channels]);
+        captured += frames as u64;
+        while sender.ready() {
+            if let Err(e) = sender.send() {
+                eprintln!("Send error: {}", e);
+                failed = true;
+                break;
+            }
+        }
+        if failed {
+            break;
+        }
+    }
+
+    if let Err(e) = sender.flush() {
+        eprintln!("Send error: {}", e);
+    }
+    eprintln!("Run time: {:.3} s", backend::monotonic_time() - start);
+    eprintln!("Captured {} frames, {} xruns, sent {} packets", captured, xruns, sender.packets());
+}
@@ -0,0 +1,109 @@
+use docopt::Docopt;
+use serde::de::DeserializeOwned;
+use std::process;
+use std::time::Duration;
+
+use backend::{Config, SampleFormat};
+use config;
+use engine::{Engine, EngineStats, Processor};
+use metrics::{self, Metrics};
+use realtime_priority::RtConfig;
+use record::{self, RecordTap, Recorder};
+use resampler::Quality;
+use shutdown;
+use telemetry::{Reporter, ReporterHandle, Sink, Summary};
+
+// Setup and shutdown shared by the binaries running the engine between
+// devices: alsa-asrc-loopback, alsa-aggregate, alsa-fanout and rtp-receiver.
+// Invalid options and devices that cannot be used are printed and exit.
+
+// Parses the command line merged with the --config file.
+pub fn args<T: DeserializeOwned>(usage: &str) -> T {
+    let argv = config::argv(usage).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    Docopt::new(usage)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit())
+}
+
+pub fn rt_config(policy: &str, priority: i32, cpus: &Option<String>, mlock: bool) -> RtConfig {
+    RtConfig::from_options(policy, priority, cpus, mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+pub fn format(name: &str) -> SampleFormat {
+    SampleFormat::from_name(name).unwrap_or_else(|| {
+        eprintln!("{}", config::error("format", format!("Invalid sample format: {}", name)));
+        process::exit(1);
+    })
+}
+
+pub fn quality(name: &str) -> Quality {
+    Quality::from_name(name).unwrap_or_else(|| {
+        eprintln!("{}", config::error("resampler", format!("Invalid resampler quality: {}", name)));
+        process::exit(1);
+    })
+}
+
+// title is Capture or Playback, with a note such as (clock master)
+pub fn print_device(title: &str, device: &str, config: &Config) {
+    eprintln!("{}\n  card:     {}\n  channels: {}\n  rate:     {}\n  period:   {}\n  periods:  {}",
+              title, device, config.channels, config.rate, config.period_size, config.periods);
+}
+
+// Recordings are written from their own threads, the audio threads only queue.
+pub fn record(path: &Option<String>, config: &Config) -> Option<(RecordTap, Recorder)> {
+    path.as_ref().map(|path| {
+        let recording = record::start(path, config.clone()).unwrap_or_else(|e| {
+            eprintln!("Cannot record to {}: {}", path, e);
+            process::exit(1);
+        });
+        eprintln!("Recording to {}", path);
+        recording
+    })
+}
+
+pub struct Telemetry {
+    reporter: ReporterHandle,
+    metrics: Option<(Metrics, metrics::Server)>,
+}
+
+// Diagnostics are formatted and printed by the reporter thread, every interval
+// seconds, and optionally logged and served as Prometheus metrics.
+pub fn telemetry(engine: &mut Engine, interval: f64, log: &Option<String>, address: &Option<String>)
+                 -> Telemetry {
+    let mut reporter = Reporter::new();
+    engine.telemetry(&mut reporter);
+    let summary = Summary::new(log.as_ref().map(|s| s.as_str())).unwrap_or_else(|e| {
+        eprintln!("Cannot create telemetry log: {}", e);
+        process::exit(1);
+    });
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(summary)];
+    let metrics = metrics::serve_option(address, engine.capture_config().rate,
+                                        engine.playback_config().rate, &mut sinks);
+    let reporter = reporter.start(Duration::from_millis((interval * 1000.0) as u64), Box::new(sinks));
+    Telemetry { reporter, metrics }
+}
+
+// Runs the engine until the end of the stream or until SIGINT or SIGTERM,
+// then plays out the FIFO, stops the telemetry and prints the statistics.
+pub fn run<P: Processor + 'static>(engine: Engine, processor: P, telemetry: Telemetry) -> EngineStats {
+    shutdown::install();
+    let running = engine.start(processor);
+    if let Some((ref metrics, _)) = telemetry.metrics {
+        metrics.cpu_clocks(running.cpu_clocks());
+    }
+    if shutdown::wait_until(|| running.finished()) {
+        eprintln!("Stopping, playing out the FIFO");
+        running.stop();
+    }
+    let stats = running.wait();
+    telemetry.reporter.stop();
+
+    stats.print();
+    stats
+}
@@ -0,0 +1,45 @@
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+// Clean stop on SIGINT and SIGTERM. The handler only sets a flag the main
+// loops check once per period, so streams are drained and files finished
+// before the summary is printed. A second signal kills the process as usual.
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn install() {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // blocking device calls carry on, the default action is back for the next signal
+        action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+        for &signal in &[libc::SIGINT, libc::SIGTERM] {
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+// Polls until finished returns true, returns true when a signal came first.
+pub fn wait_until<F: Fn() -> bool>(finished: F) -> bool {
+    while !finished() {
+        if requested() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
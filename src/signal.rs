@@ -0,0 +1,219 @@
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    Sine(f64),
+    Multitone(Vec<f64>),
+    // exponential sweep: start Hz, end Hz, duration in seconds, repeated
+    Sweep(f64, f64, f64),
+    WhiteNoise,
+    PinkNoise,
+    // one full scale sample every n frames
+    Impulse(u64),
+    // maximum length sequence of the given order
+    Mls(u32),
+    Silence,
+}
+
+impl Signal {
+    // sine:<Hz>, multitone:<Hz>,<Hz>,..., sweep:<start>:<end>:<seconds>,
+    // white, pink, impulse:<frames>, mls:<order> or silence, rate being that
+    // of the stream
+    pub fn parse(spec: &str, rate: f64) -> Option<Signal> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let params = parts.next().unwrap_or("");
+
+        match name {
+            "sine" => params.parse().ok().map(Signal::Sine),
+            "multitone" => {
+                let freqs: Option<Vec<f64>> = params.split(',').map(|f| f.parse().ok()).collect();
+                freqs.and_then(|f| if f.is_empty() { None } else { Some(Signal::Multitone(f)) })
+            }
+            "sweep" => {
+                let v: Option<Vec<f64>> = params.split(':').map(|f| f.parse().ok()).collect();
+                match v {
+                    // at least a frame long
+                    Some(ref v) if v.len() == 3 && v[0] > 0.0 && v[1] > 0.0 && v[2] * rate >= 1.0 =>
+                        Some(Signal::Sweep(v[0], v[1], v[2])),
+                    _ => None,
+                }
+            }
+            "white" => Some(Signal::WhiteNoise),
+            "pink" => Some(Signal::PinkNoise),
+            "impulse" => params.parse().ok().and_then(|p| if p > 0 { Some(Signal::Impulse(p)) } else { None }),
+            "mls" => params.parse().ok().and_then(|o| {
+                if mls_taps(o).is_some() { Some(Signal::Mls(o)) } else { None }
+            }),
+            "silence" => Some(Signal::Silence),
+            _ => None,
+        }
+    }
+}
+
+pub struct Generator {
+    signal: Signal,
+    rate: f64,
+    amplitude: f64,
+    position: u64,
+    phases: Vec<f64>,
+    rng: u64,
+    pink: [f64; 7],
+    mls: u32,
+}
+
+impl Generator {
+    pub fn new(signal: Signal, rate: f64, amplitude: f64) -> Generator {
+        let tones = match signal {
+            Signal::Multitone(ref f) => f.len(),
+            _ => 1,
+        };
+
+        Generator {
+            signal,
+            rate,
+            amplitude,
+            position: 0,
+            phases: vec![0.0; tones],
+            rng: 0x2545_f491_4f6c_dd1d,
+            pink: [0.0; 7],
+            mls: 1,
+        }
+    }
+
+    // frames generated so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn next_sample(&mut self) -> f64 {
+        let value = match self.signal {
+            Signal::Sine(freq) => {
+                let v = (2.0 * PI * self.phases[0]).sin();
+                self.phases[0] = (self.phases[0] + freq / self.rate).fract();
+                v
+            }
+            Signal::Multitone(ref freqs) => {
+                let mut sum = 0.0;
+                for (phase, freq) in self.phases.iter_mut().zip(freqs.iter()) {
+                    sum += (2.0 * PI * *phase).sin();
+                    *phase = (*phase + freq / self.rate).fract();
+                }
+                sum / freqs.len() as f64
+            }
+            Signal::Sweep(start, end, duration) => {
+                let frames = ((duration * self.rate) as u64).max(1);
+                let t = (self.position % frames) as f64 / self.rate;
+                let k = (end / start).ln();
+                if k == 0.0 {
+                    // no sweep, a sine at the start frequency
+                    (2.0 * PI * start * t).sin()
+                } else {
+                    (2.0 * PI * start * duration / k * ((t * k / duration).exp() - 1.0)).sin()
+                }
+            }
+            Signal::WhiteNoise => self.white(),
+            Signal::PinkNoise => {
+                // Paul Kellet's refined pink noise filter
+                let w = self.white();
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + w * 0.0555179;
+                b[1] = 0.99332 * b[1] + w * 0.0750759;
+                b[2] = 0.96900 * b[2] + w * 0.1538520;
+                b[3] = 0.86650 * b[3] + w * 0.3104856;
+                b[4] = 0.55000 * b[4] + w * 0.5329522;
+                b[5] = -0.7616 * b[5] - w * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + w * 0.5362;
+                b[6] = w * 0.115926;
+                pink * 0.11
+            }
+            Signal::Impulse(period) => if self.position % period == 0 { 1.0 } else { 0.0 },
+            Signal::Mls(order) => {
+                let taps = mls_taps(order).unwrap();
+                let bit = self.mls & 1;
+                let feedback = (self.mls & taps).count_ones() & 1;
+                self.mls = (self.mls >> 1) | (feedback << (order - 1));
+                if bit == 1 { 1.0 } else { -1.0 }
+            }
+            Signal::Silence => 0.0,
+        };
+
+        self.position += 1;
+        value * self.amplitude
+    }
+
+    // fills interleaved frames, the same signal on every channel
+    pub fn fill(&mut self, buf: &mut [f32], channels: usize) {
+        for frame in buf.chunks_mut(channels) {
+            let v = self.next_sample() as f32;
+            for x in frame.iter_mut() {
+                *x = v;
+            }
+        }
+    }
+
+    // xorshift64*, uniform in [-1, 1)
+    fn white(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (r >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+// Fibonacci LFSR feedback masks of primitive polynomials, bit 0 being the output
+fn mls_taps(order: u32) -> Option<u32> {
+    let taps: &[u32] = match order {
+        2 => &[2, 1],
+        3 => &[3, 2],
+        4 => &[4, 3],
+        5 => &[5, 3],
+        6 => &[6, 5],
+        7 => &[7, 6],
+        8 => &[8, 6, 5, 4],
+        9 => &[9, 5],
+        10 => &[10, 7],
+        11 => &[11, 9],
+        12 => &[12, 11, 10, 4],
+        13 => &[13, 12, 11, 8],
+        14 => &[14, 13, 12, 2],
+        15 => &[15, 14],
+        16 => &[16, 15, 13, 4],
+        17 => &[17, 14],
+        18 => &[18, 11],
+        19 => &[19, 18, 17, 14],
+        20 => &[20, 17],
+        21 => &[21, 19],
+        22 => &[22, 21],
+        23 => &[23, 18],
+        24 => &[24, 23, 22, 17],
+        _ => return None,
+    };
+    Some(taps.iter().fold(0, |mask, t| mask | 1 << (order - t)))
+}
+
+pub fn db_to_amplitude(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_shorter_than_a_frame() {
+        assert_eq!(Signal::parse("sweep:20:20000:0.00001", 48000.0), None);
+        assert_eq!(Signal::parse("sweep:20:20000:0.0001", 48000.0), Some(Signal::Sweep(20.0, 20000.0, 0.0001)));
+    }
+
+    #[test]
+    fn sweep_without_range_is_a_sine() {
+        let mut sweep = Generator::new(Signal::parse("sweep:1000:1000:1", 48000.0).unwrap(), 48000.0, 1.0);
+        let mut sine = Generator::new(Signal::Sine(1000.0), 48000.0, 1.0);
+        for _ in 0..1000 {
+            let (a, b) = (sweep.next_sample(), sine.next_sample());
+            assert!((a - b).abs() < 1e-9, "{} {}", a, b);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
+extern crate toml;
+extern crate alsa;
+extern crate libc;
+
+mod backend;
+mod config;
+mod realtime_priority;
+mod rtp;
+mod shutdown;
+mod signal;
+mod wav;
+
+use std::process;
+use docopt::Docopt;
+
+use backend::{Config, SampleFormat};
+use realtime_priority::RtConfig;
+use signal::{Generator, Signal};
+
+const USAGE: &str = "
+Test signal generator
+
+Usage:
+  signal-generator [--config=<file>] <signal> [--device=<device> --format=<format> --channels=<nr> --sample-rate=<Hz> --level=<dBFS> --duration=<seconds> --offset=<frames> --period-size=<frames> --periods=<count> --rt-policy=<policy> --rt-priority=<prio> --rt-cpus=<list> --rt-mlock]
+  signal-generator (-h | --help)
+
+Options:
+  -h --help                     Show this screen.
+  --config=<file>               Read the options from a TOML file, command line flags take precedence.
+  <signal>                      sine:<Hz>, multitone:<Hz>,<Hz>,..., sweep:<start Hz>:<end Hz>:<seconds>,
+                                white, pink, impulse:<period frames>, mls:<order> or silence
+  -D --device=<device>          ALSA device, file:<path> or sim[:<options>] [default: default].
+  -f --format=<format>          Sample format: s16, s24, s32 or f32 [default: s16].
+  -n --channels=<nr>            Channels to play [default: 2].
+  -r --sample-rate=<Hz>         Playback sample rate [default: 48000].
+  -l --level=<dBFS>             Signal peak level [default: -20].
+  -d --duration=<seconds>       Signal duration, 0 plays forever [default: 0].
+  --offset=<frames>             Silence played before the signal starts [default: 0].
+  -s --period-size=<frames>     Period size in frames [default: 256].
+  -o --periods=<count>          Periods [default: 4].
+  --rt-policy=<policy>          Scheduling policy: fifo, rr, other or deadline:<runtime us>:<period us> [default: fifo].
+  --rt-priority=<prio>          Real-time priority [default: 3].
+  --rt-cpus=<list>              Pin the real-time threads to CPUs, e.g. 2,3 or 0-3.
+  --rt-mlock                    Lock the process memory with mlockall.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_signal: String,
+    flag_device: String,
+    flag_format: String,
+    flag_channels: usize,
+    flag_sample_rate: u32,
+    flag_level: f64,
+    flag_duration: f64,
+    flag_offset: u64,
+    flag_period_size: usize,
+    flag_periods: u32,
+    flag_rt_policy: String,
+    flag_rt_priority: i32,
+    flag_rt_cpus: Option<String>,
+    flag_rt_mlock: bool,
+}
+
+fn main() {
+    let argv = config::argv(USAGE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    let rt = RtConfig::from_options(&args.flag_rt_policy, args.flag_rt_priority,
+                                    &args.flag_rt_cpus, args.flag_rt_mlock).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let signal = Signal::parse(&args.arg_signal, args.flag_sample_rate as f64).unwrap_or_else(|| {
+        eprintln!("{}", config::error("signal", format!("Invalid signal: {}", args.arg_signal)));
+        process::exit(1);
+    });
+
+    let format = SampleFormat::from_name(&args.flag_format).unwrap_or_else(|| {
+        eprintln!("{}", config::error("format", format!("Invalid sample format: {}", args.flag_format)));
+        process::exit(1);
+    });
+
+    let config = Config {
+        channels: args.flag_channels,
+        rate: args.flag_sample_rate,
+        format,
+        period_size: args.flag_period_size,
+        periods: args.flag_periods,
+    };
+
+    let mut playback = backend::open_playback(&args.flag_device, config).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", args.flag_device, e);
+        process::exit(1);
+    });
+
+    let config = playback.config().clone();
+    eprintln!("Signal:       {:?}", signal);
+    eprintln!("Device:       {}", args.flag_device);
+    eprintln!("Format:       {}", config.format.name());
+    eprintln!("Channels:     {}", config.channels);
+    eprintln!("Sample rate:  {}", config.rate);
+    eprintln!("Period size:  {}", config.period_size);
+    eprintln!("Periods:      {}", config.periods);
+    eprintln!("Level:        {} dBFS", args.flag_level);
+
+    let mut generator = Generator::new(signal.clone(),
+                                       config.rate as f64,
+                                       signal::db_to_amplitude(args.flag_level));
+
+    // the signal starts at frame `offset` of the stream, 0 being the first frame played
+    let offset = args.flag_offset;
+    let end = if args.flag_duration > 0.0 {
+        Some(offset + (args.flag_duration * config.rate as f64) as u64)
+    } else {
+        None
+    };
+
+    let mut buf = vec![0.0f32; config.period_size * config.channels];
+    let mut frames_written: u64 = 0;
+    let mut xruns = 0;
+    let mut started = false;
+
+    shutdown::install();
+    eprintln!("Real-time: {}", rt.apply());
+    let start = backend::monotonic_time();
+
+    while !shutdown::requested() {
+        let mut frames = config.period_size;
+        if let Some(end) = end {
+            if frames_written >= end {
+                break;
+            }
+            frames = frames.min((end - frames_written) as usize);
+        }
+
+        for (i, frame) in buf[..frames * config.channels].chunks_mut(config.channels).enumerate() {
+            if frames_written + (i as u64) < offset {
+                for x in frame.iter_mut() {
+                    *x = 0.0;
+                }
+            } else {
+                generator.fill(frame, config.channels);
+            }
+        }
+
+        match playback.write(&buf[..frames * config.channels]) {
+            Ok(written) => frames_written += written as u64,
+            Err(backend::Error::XRun) => {
+                // restart the signal so it stays aligned with the stream start
+                xruns += 1;
+                eprintln!("Playback xrun {}, restarting signal", xruns);
+                frames_written = 0;
+                started = false;
+                generator = Generator::new(signal.clone(),
+                                           config.rate as f64,
+                                           signal::db_to_amplitude(args.flag_level));
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Playback error: {}", e);
+                process::exit(1);
+            }
+        }
+
+        if !started {
+            if let Some(ts) = playback.timestamp() {
+                if ts.trigger > 0.0 {
+                    started = true;
+                    eprintln!("Stream started at {:.9}, signal at {:.9}",
+                              ts.trigger,
+                              ts.trigger + offset as f64 / config.rate as f64);
+                }
+            }
+        }
+    }
+
+    playback.drain().unwrap();
+    eprintln!("Run time: {:.3} s", backend::monotonic_time() - start);
+    eprintln!("Played {} frames, {} signal frames, {} xruns",
+              frames_written, generator.position(), xruns);
+}
@@ -0,0 +1,200 @@
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Wait-free single producer single consumer ring buffer.
+// Positions only ever increase and wrap around, the capacity being a power
+// of two the slot is the position masked.
+struct Inner<T> {
+    buf: UnsafeCell<Box<[T]>>,
+    capacity: usize,
+    read: AtomicUsize,
+    write: AtomicUsize,
+}
+
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+pub struct Producer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+pub struct Consumer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+// capacity is rounded up to the next power of two
+pub fn ring<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.next_power_of_two();
+    let inner = Arc::new(Inner {
+        buf: UnsafeCell::new(vec![T::default(); capacity].into_boxed_slice()),
+        capacity,
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+    });
+
+    (Producer { inner: inner.clone() }, Consumer { inner })
+}
+
+impl<T: Copy> Producer<T> {
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        let write = self.inner.write.load(Ordering::Relaxed);
+        write.wrapping_sub(self.inner.read.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn free(&self) -> usize {
+        self.inner.capacity - self.len()
+    }
+
+    // Writes as much of data as fits, returns the number of items written.
+    pub fn push_slice(&mut self, data: &[T]) -> usize {
+        let inner = &*self.inner;
+        let write = inner.write.load(Ordering::Relaxed);
+        let read = inner.read.load(Ordering::Acquire);
+        let n = data.len().min(inner.capacity - write.wrapping_sub(read));
+
+        // only the free region is touched, which the consumer does not read
+        let start = write & (inner.capacity - 1);
+        let first = n.min(inner.capacity - start);
+        unsafe {
+            let buf = (*inner.buf.get()).as_mut_ptr();
+            ptr::copy_nonoverlapping(data.as_ptr(), buf.add(start), first);
+            ptr::copy_nonoverlapping(data.as_ptr().add(first), buf, n - first);
+        }
+
+        inner.write.store(write.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        let read = self.inner.read.load(Ordering::Relaxed);
+        self.inner.write.load(Ordering::Acquire).wrapping_sub(read)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Reads up to data.len() items, returns the number of items read.
+    pub fn pop_slice(&mut self, data: &mut [T]) -> usize {
+        let inner = &*self.inner;
+        let read = inner.read.load(Ordering::Relaxed);
+        let write = inner.write.load(Ordering::Acquire);
+        let n = data.len().min(write.wrapping_sub(read));
+
+        let start = read & (inner.capacity - 1);
+        let first = n.min(inner.capacity - start);
+        unsafe {
+            let buf = (*inner.buf.get()).as_ptr();
+            ptr::copy_nonoverlapping(buf.add(start), data.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(buf, data.as_mut_ptr().add(first), n - first);
+        }
+
+        inner.read.store(read.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    // Drops up to n items, returns the number of items skipped.
+    pub fn skip(&mut self, n: usize) -> usize {
+        let inner = &*self.inner;
+        let read = inner.read.load(Ordering::Relaxed);
+        let n = n.min(inner.write.load(Ordering::Acquire).wrapping_sub(read));
+        inner.read.store(read.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn capacity_is_a_power_of_two() {
+        let (producer, consumer) = ring::<u32>(100);
+        assert_eq!(producer.capacity(), 128);
+        assert_eq!(consumer.capacity(), 128);
+        assert!(producer.is_empty() && consumer.is_empty());
+        assert_eq!(producer.free(), 128);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = ring::<u32>(8);
+        let mut next = 0;
+        let mut expected = 0;
+        let mut out = [0u32; 8];
+
+        // odd sizes so that reads and writes straddle the end of the buffer
+        for _ in 0..50 {
+            let data: Vec<u32> = (next..next + 5).collect();
+            next += producer.push_slice(&data) as u32;
+            assert!(producer.len() <= 8);
+
+            let n = consumer.pop_slice(&mut out[..3]);
+            for &x in &out[..n] {
+                assert_eq!(x, expected);
+                expected += 1;
+            }
+        }
+
+        // the buffer filled up, so pushes got cut short
+        assert!(next < 50 * 5);
+        assert_eq!(producer.free(), 3);
+        assert_eq!(producer.push_slice(&[next, next + 1, next + 2, next + 3]), 3);
+        assert_eq!(consumer.skip(3), 3);
+        expected += 3;
+        let n = consumer.pop_slice(&mut out);
+        assert_eq!(n, 5);
+        assert_eq!(out[..n].to_vec(), (expected..expected + 5).collect::<Vec<u32>>());
+        assert!(consumer.is_empty());
+        assert_eq!(consumer.skip(1), 0);
+    }
+
+    #[test]
+    fn producer_and_consumer_threads() {
+        const COUNT: u64 = 1_000_000;
+        let (mut producer, mut consumer) = ring::<u64>(1000);
+
+        let writer = thread::spawn(move || {
+            let mut next = 0;
+            while next < COUNT {
+                let data: Vec<u64> = (next..(next + 37).min(COUNT)).collect();
+                let mut written = 0;
+                while written < data.len() {
+                    written += producer.push_slice(&data[written..]);
+                }
+                next += data.len() as u64;
+            }
+        });
+
+        let mut expected = 0;
+        let mut out = [0u64; 53];
+        while expected < COUNT {
+            let n = consumer.pop_slice(&mut out);
+            for &x in &out[..n] {
+                assert_eq!(x, expected);
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+        assert!(consumer.is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use spsc::{self, Consumer, Producer};
+
+const POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Capture,
+    Playback,
+}
+
+impl Stream {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stream::Capture => "capture",
+            Stream::Playback => "playback",
+        }
+    }
+}
+
+// Fixed size records, copied through the queue without allocating.
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    // a period went through the device
+    Period { stream: Stream, frames: u32 },
+    // device status after a period, timestamps in seconds
+    Status {
+        stream: Stream,
+        frames: u64,
+        delay: i64,
+        avail: i64,
+        avail_max: i64,
+        audio: f64,
+        trigger: f64,
+        system: f64,
+    },
+    XRun { stream: Stream, count: u32 },
+    // FIFO fill level in frames
+    Fill { frames: u32, target: u32 },
+    // resampling ratio, output rate / input rate
+    Ratio(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    // CLOCK_MONOTONIC seconds
+    pub time: f64,
+    pub event: Event,
+}
+
+impl Default for Record {
+    fn default() -> Record {
+        Record { time: 0.0, event: Event::Ratio(0.0) }
+    }
+}
+
+fn now() -> f64 {
+    unsafe {
+        let mut ts: libc::timespec = mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+        ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9
+    }
+}
+
+// Real-time side, one per thread: wait-free, records are dropped when the queue is full.
+pub struct Sender {
+    producer: Producer<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Sender {
+    pub fn send(&mut self, event: Event) {
+        let record = Record { time: now(), event };
+        if self.producer.push_slice(&[record]) == 0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Formats the records on the reporter thread.
+pub trait Sink: Send {
+    fn record(&mut self, source: &str, record: &Record);
+
+    // called about every interval
+    fn tick(&mut self) {}
+
+    fn finish(&mut self) {}
+}
+
+// Several sinks fed with the same records.
+impl Sink for Vec<Box<dyn Sink>> {
+    fn record(&mut self, source: &str, record: &Record) {
+        for sink in self.iter_mut() {
+            sink.record(source, record);
+        }
+    }
+
+    fn tick(&mut self) {
+        for sink in self.iter_mut() {
+            sink.tick();
+        }
+    }
+
+    fn finish(&mut self) {
+        for sink in self.iter_mut() {
+            sink.finish();
+        }
+    }
+}
+
+struct Source {
+    name: &'static str,
+    consumer: Consumer<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+pub struct Reporter {
+    sources: Vec<Source>,
+}
+
+impl Reporter {
+    pub fn new() -> Reporter {
+        Reporter { sources: Vec::new() }
+    }
+
+    // Adds a queue for one real-time thread, capacity in records.
+    pub fn sender(&mut self, name: &'static str, capacity: usize) -> Sender {
+        let (producer, consumer) = spsc::ring(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.sources.push(Source { name, consumer, dropped: dropped.clone() });
+        Sender { producer, dropped }
+    }
+
+    // Starts the reporter thread at normal priority.
+    pub fn start(self, interval: Duration, mut sink: Box<dyn Sink>) -> ReporterHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let mut sources = self.sources;
+
+        let handle = thread::spawn(move || {
+            unsafe {
+                let param = libc::sched_param { sched_priority: 0 };
+                libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_OTHER, &param);
+            }
+
+            let mut buf = vec![Record::default(); 256];
+            let mut reported_drops = vec![0; sources.len()];
+            let mut last_tick = Instant::now();
+            loop {
+                let stopping = thread_stop.load(Ordering::SeqCst);
+                for (i, source) in sources.iter_mut().enumerate() {
+                    loop {
+                        let n = source.consumer.pop_slice(&mut buf);
+                        for record in &buf[..n] {
+                            sink.record(source.name, record);
+                        }
+                        if n < buf.len() {
+                            break;
+                        }
+                    }
+
+                    let dropped = source.dropped.load(Ordering::Relaxed);
+                    if dropped != reported_drops[i] {
+                        eprintln!("Telemetry from {} fell behind, {} records dropped", source.name, dropped);
+                        reported_drops[i] = dropped;
+                    }
+                }
+
+                if last_tick.elapsed() >= interval {
+                    sink.tick();
+                    last_tick = Instant::now();
+                }
+
+                if stopping {
+                    break;
+                }
+                thread::sleep(POLL);
+            }
+            sink.finish();
+        });
+
+        ReporterHandle { handle, stop }
+    }
+}
+
+pub struct ReporterHandle {
+    handle: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ReporterHandle {
+    // reports what is left in the queues and returns
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap();
+    }
+}
+
+/*
+ * Default sink: a summary per source every interval, xruns as they happen,
+ * and optionally every record to a log file
+ */
+
+#[derive(Default)]
+struct Aggregate {
+    periods: u64,
+    frames: u64,
+    xruns: u64,
+    fill: Option<(u32, u32, u32)>,
+    ratio: Option<f64>,
+    delay: Option<(i64, i64)>,
+}
+
+impl Aggregate {
+    fn summary(&self) -> String {
+        let mut line = format!("{} periods, {} frames, {} xruns", self.periods, self.frames, self.xruns);
+        if let Some((min, max)) = self.delay {
+            line += &format!(", delay {}..{}", min, max);
+        }
+        if let Some((min, max, target)) = self.fill {
+            line += &format!(", fill {}..{}/{}", min, max, target);
+        }
+        if let Some(ratio) = self.ratio {
+            line += &format!(", ratio {:.6}", ratio);
+        }
+        line
+    }
+}
+
+pub struct Summary {
+    start: f64,
+    aggregates: BTreeMap<String, Aggregate>,
+    log: Option<BufWriter<File>>,
+}
+
+impl Summary {
+    pub fn new(log: Option<&str>) -> io::Result<Summary> {
+        let log = match log {
+            Some(path) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                writeln!(file, "# time source event values")?;
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(Summary { start: now(), aggregates: BTreeMap::new(), log })
+    }
+
+    fn log(&mut self, source: &str, record: &Record) -> io::Result<()> {
+        match self.log {
+            Some(ref mut file) => write_record(file, source, record),
+            None => Ok(()),
+        }
+    }
+}
+
+// One line of the telemetry log, also read back by the sim replay option.
+pub fn write_record<W: Write>(file: &mut W, source: &str, record: &Record) -> io::Result<()> {
+    write!(file, "{:.6} {} ", record.time, source)?;
+    match record.event {
+        Event::Period { stream, frames } =>
+            writeln!(file, "period {} {}", stream.name(), frames),
+        Event::Status { stream, frames, delay, avail, avail_max, audio, trigger, system } =>
+            writeln!(file, "status {} {} {} {} {} {:.9} {:.9} {:.9}",
+                     stream.name(), frames, delay, avail, avail_max, audio, trigger, system),
+        Event::XRun { stream, count } => writeln!(file, "xrun {} {}", stream.name(), count),
+        Event::Fill { frames, target } => writeln!(file, "fill {} {}", frames, target),
+        Event::Ratio(ratio) => writeln!(file, "ratio {:.9}", ratio),
+    }
+}
+
+impl Sink for Summary {
+    fn record(&mut self, source: &str, record: &Record) {
+        if let Err(e) = self.log(source, record) {
+            eprintln!("Cannot write telemetry log: {}", e);
+            self.log = None;
+        }
+
+        if !self.aggregates.contains_key(source) {
+            self.aggregates.insert(source.to_string(), Aggregate::default());
+        }
+        let aggregate = self.aggregates.get_mut(source).unwrap();
+        match record.event {
+            Event::Period { frames, .. } => {
+                aggregate.periods += 1;
+                aggregate.frames += frames as u64;
+            }
+            Event::Status { delay, .. } => {
+                aggregate.delay = Some(match aggregate.delay {
+                    Some((min, max)) => (min.min(delay), max.max(delay)),
+                    None => (delay, delay),
+                });
+            }
+            Event::XRun { stream, count } => {
+                aggregate.xruns += 1;
+                eprintln!("[{:.3}] {} {} xrun {}", record.time - self.start, source, stream.name(), count);
+            }
+            Event::Fill { frames, target } => {
+                aggregate.fill = Some(match aggregate.fill {
+                    Some((min, max, _)) => (min.min(frames), max.max(frames), target),
+                    None => (frames, frames, target),
+                });
+            }
+            Event::Ratio(ratio) => aggregate.ratio = Some(ratio),
+        }
+    }
+
+    fn tick(&mut self) {
+        let elapsed = now() - self.start;
+        for (source, aggregate) in self.aggregates.iter_mut() {
+            eprintln!("[{:.3}] {}: {}", elapsed, source, aggregate.summary());
+            *aggregate = Aggregate::default();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.tick();
+        if let Some(ref mut file) = self.log {
+            file.flush().unwrap_or_else(|e| eprintln!("Cannot write telemetry log: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Collect(Arc<Mutex<Vec<(String, f64)>>>);
+
+    impl Sink for Collect {
+        fn record(&mut self, source: &str, record: &Record) {
+            if let Event::Ratio(ratio) = record.event {
+                self.0.lock().unwrap().push((source.to_string(), ratio));
+            }
+        }
+    }
+
+    fn record(time: f64, event: Event) -> Record {
+        Record { time, event }
+    }
+
+    #[test]
+    fn full_queue_drops_records() {
+        let mut reporter = Reporter::new();
+        let mut sender = reporter.sender("engine", 4);
+        for i in 0..7 {
+            sender.send(Event::Ratio(i as f64));
+        }
+        assert_eq!(sender.dropped.load(Ordering::Relaxed), 3);
+
+        // the oldest records are kept
+        let received = Arc::new(Mutex::new(Vec::new()));
+        reporter.start(Duration::from_secs(1), Box::new(Collect(received.clone()))).stop();
+        let ratios: Vec<f64> = received.lock().unwrap().iter().map(|&(_, ratio)| ratio).collect();
+        assert_eq!(ratios, vec![0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn reporter_drains_every_source_on_stop() {
+        let mut reporter = Reporter::new();
+        let mut capture = reporter.sender("capture", 1024);
+        let mut playback = reporter.sender("playback", 1024);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handle = reporter.start(Duration::from_millis(10), Box::new(Collect(received.clone())));
+
+        for i in 0..1000 {
+            capture.send(Event::Ratio(i as f64));
+            playback.send(Event::Ratio(-i as f64));
+        }
+        handle.stop();
+
+        let received = received.lock().unwrap();
+        let from = |name: &str| -> Vec<f64> {
+            received.iter().filter(|r| r.0 == name).map(|r| r.1).collect()
+        };
+        assert_eq!(from("capture"), (0..1000).map(|i| i as f64).collect::<Vec<f64>>());
+        assert_eq!(from("playback"), (0..1000).map(|i| -i as f64).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn summary_aggregates_per_interval() {
+        let mut summary = Summary::new(None).unwrap();
+        let status = |delay| Event::Status {
+            stream: Stream::Playback, frames: 0, delay, avail: 0, avail_max: 0,
+            audio: 0.0, trigger: 0.0, system: 0.0,
+        };
+        summary.record("playback", &record(1.0, Event::Period { stream: Stream::Playback, frames: 256 }));
+        summary.record("playback", &record(1.1, Event::Period { stream: Stream::Playback, frames: 128 }));
+        summary.record("playback", &record(1.2, status(700)));
+        summary.record("playback", &record(1.3, status(300)));
+        summary.record("playback", &record(1.4, Event::XRun { stream: Stream::Playback, count: 1 }));
+        summary.record("fifo", &record(1.5, Event::Fill { frames: 500, target: 512 }));
+        summary.record("fifo", &record(1.6, Event::Fill { frames: 520, target: 512 }));
+        summary.record("fifo", &record(1.7, Event::Ratio(1.0001)));
+        summary.record("fifo", &record(1.8, Event::Ratio(1.0002)));
+
+        assert_eq!(summary.aggregates["playback"].summary(),
+                   "2 periods, 384 frames, 1 xruns, delay 300..700");
+        assert_eq!(summary.aggregates["fifo"].summary(),
+                   "0 periods, 0 frames, 0 xruns, fill 500..520/512, ratio 1.000200");
+
+        // every interval starts over
+        summary.tick();
+        assert_eq!(summary.aggregates["playback"].summary(), "0 periods, 0 frames, 0 xruns");
+        summary.record("playback", &record(2.0, Event::Period { stream: Stream::Playback, frames: 64 }));
+        assert_eq!(summary.aggregates["playback"].summary(), "1 periods, 64 frames, 0 xruns");
+    }
+
+    #[test]
+    fn log_line_format() {
+        let line = |event| {
+            let mut buf = Vec::new();
+            write_record(&mut buf, "engine", &record(12.5, event)).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        assert_eq!(line(Event::Period { stream: Stream::Capture, frames: 256 }),
+                   "12.500000 engine period capture 256\n");
+        assert_eq!(line(Event::Status {
+            stream: Stream::Playback, frames: 1024, delay: 512, avail: -3, avail_max: 600,
+            audio: 1.25, trigger: 0.5, system: 12.499,
+        }), "12.500000 engine status playback 1024 512 -3 600 1.250000000 0.500000000 12.499000000\n");
+        assert_eq!(line(Event::XRun { stream: Stream::Capture, count: 2 }),
+                   "12.500000 engine xrun capture 2\n");
+        assert_eq!(line(Event::Fill { frames: 480, target: 512 }),
+                   "12.500000 engine fill 480 512\n");
+        assert_eq!(line(Event::Ratio(1.088435374)), "12.500000 engine ratio 1.088435374\n");
+    }
+}
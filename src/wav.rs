@@ -0,0 +1,370 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
+use std::io::prelude::*;
+
+use backend::{SampleFormat, encode_sample, decode_sample};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// last 14 bytes of the KSDATAFORMAT_SUBTYPE GUIDs, after the format tag
+const SUBFORMAT_GUID_TAIL: [u8; 14] =
+    [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WavSpec {
+    pub channels: usize,
+    pub rate: u32,
+    pub format: SampleFormat,
+    // speaker positions, 0 when unspecified
+    pub channel_mask: u32,
+}
+
+// Default WAVEFORMATEXTENSIBLE speaker layouts for common channel counts.
+pub fn default_channel_mask(channels: usize) -> u32 {
+    match channels {
+        1 => 0x4,
+        2 => 0x3,
+        3 => 0x7,
+        4 => 0x33,
+        5 => 0x37,
+        6 => 0x3f,
+        8 => 0x63f,
+        _ => 0,
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u16_le(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn u32_le(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn sample_format(tag: u16, bits: u16) -> io::Result<SampleFormat> {
+    match (tag, bits) {
+        (WAVE_FORMAT_PCM, 16) => Ok(SampleFormat::S16),
+        (WAVE_FORMAT_PCM, 24) => Ok(SampleFormat::S24),
+        (WAVE_FORMAT_PCM, 32) => Ok(SampleFormat::S32),
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => Ok(SampleFormat::F32),
+        _ => Err(invalid(&format!("unsupported WAV format {} with {} bits", tag, bits))),
+    }
+}
+
+pub struct WavReader {
+    reader: BufReader<File>,
+    spec: WavSpec,
+    // bytes left in the data chunk
+    remaining: u64,
+    bytes: Vec<u8>,
+}
+
+impl WavReader {
+    pub fn open(path: &str) -> io::Result<WavReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(invalid("not a RIFF WAVE file"));
+        }
+
+        let mut spec = None;
+        loop {
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk)?;
+            let size = u32_le(&chunk[4..8]) as u64;
+
+            match &chunk[0..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0u8; size as usize];
+                    reader.read_exact(&mut fmt)?;
+                    if size & 1 == 1 {
+                        reader.seek(SeekFrom::Current(1))?;
+                    }
+                    spec = Some(parse_fmt(&fmt)?);
+                }
+                b"data" => {
+                    let spec = spec.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                    return Ok(WavReader { reader, spec, remaining: size, bytes: Vec::new() });
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current((size + (size & 1)) as i64))?;
+                }
+            }
+        }
+    }
+
+    pub fn spec(&self) -> &WavSpec {
+        &self.spec
+    }
+
+    // Reads interleaved frames into buf, returns the number of frames, 0 at the end.
+    pub fn read(&mut self, buf: &mut [f32]) -> io::Result<usize> {
+        let sample_bytes = self.spec.format.bytes();
+        let frame_bytes = sample_bytes * self.spec.channels;
+        let want = ((buf.len() / self.spec.channels * frame_bytes) as u64).min(self.remaining) as usize;
+        let want = want / frame_bytes * frame_bytes;
+
+        if self.bytes.len() < want {
+            self.bytes.resize(want, 0);
+        }
+
+        let mut filled = 0;
+        while filled < want {
+            match self.reader.read(&mut self.bytes[filled..want])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        self.remaining -= filled as u64;
+
+        let frames = filled / frame_bytes;
+        for i in 0..frames * self.spec.channels {
+            buf[i] = decode_sample(&self.bytes[i * sample_bytes..], self.spec.format);
+        }
+        Ok(frames)
+    }
+}
+
+fn parse_fmt(fmt: &[u8]) -> io::Result<WavSpec> {
+    if fmt.len() < 16 {
+        return Err(invalid("fmt chunk too short"));
+    }
+
+    let mut tag = u16_le(&fmt[0..2]);
+    let channels = u16_le(&fmt[2..4]) as usize;
+    let rate = u32_le(&fmt[4..8]);
+    let bits = u16_le(&fmt[14..16]);
+    let mut channel_mask = 0;
+
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        if fmt.len() < 40 {
+            return Err(invalid("WAVE_FORMAT_EXTENSIBLE fmt chunk too short"));
+        }
+        let valid_bits = u16_le(&fmt[18..20]);
+        if valid_bits != 0 && valid_bits != bits {
+            return Err(invalid("WAV files with padded samples are not supported"));
+        }
+        channel_mask = u32_le(&fmt[20..24]);
+        if fmt[26..40] != SUBFORMAT_GUID_TAIL {
+            return Err(invalid("unknown WAVE_FORMAT_EXTENSIBLE sub format"));
+        }
+        tag = u16_le(&fmt[24..26]);
+    }
+
+    if channels == 0 {
+        return Err(invalid("WAV file without channels"));
+    }
+
+    Ok(WavSpec { channels, rate, format: sample_format(tag, bits)?, channel_mask })
+}
+
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    spec: WavSpec,
+    data_bytes: u64,
+    bytes: Vec<u8>,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, spec: WavSpec) -> io::Result<WavWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&header(&spec, 0))?;
+        Ok(WavWriter { writer, spec, data_bytes: 0, bytes: Vec::new(), finished: false })
+    }
+
+    pub fn spec(&self) -> &WavSpec {
+        &self.spec
+    }
+
+    pub fn write(&mut self, buf: &[f32]) -> io::Result<usize> {
+        self.bytes.clear();
+        for &x in buf {
+            encode_sample(&mut self.bytes, x, self.spec.format);
+        }
+        self.writer.write_all(&self.bytes)?;
+        self.data_bytes += self.bytes.len() as u64;
+        Ok(buf.len() / self.spec.channels)
+    }
+
+    // Updates the chunk sizes so the file is valid up to this point.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_header()
+    }
+
+    // Pads the data chunk to an even size and writes the final header.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.data_bytes & 1 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let pos = self.writer.seek(SeekFrom::Current(0))?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header(&self.spec, self.data_bytes))?;
+        self.writer.seek(SeekFrom::Start(pos))?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Error finalizing WAV file: {}", e);
+        }
+    }
+}
+
+// RIFF header up to the data chunk payload
+fn header(spec: &WavSpec, data_bytes: u64) -> Vec<u8> {
+    let bits = spec.format.bytes() as u16 * 8;
+    let tag = if spec.format == SampleFormat::F32 { WAVE_FORMAT_IEEE_FLOAT } else { WAVE_FORMAT_PCM };
+    let extensible = spec.channels > 2 || bits > 16 || spec.channel_mask != 0;
+    let block_align = (spec.channels * spec.format.bytes()) as u16;
+    let fmt_size: u32 = if extensible { 40 } else { 16 };
+    let data_size = data_bytes.min(u32::max_value() as u64 - 64) as u32;
+
+    let mut h = Vec::with_capacity(68);
+    h.extend_from_slice(b"RIFF");
+    put_u32(&mut h, 4 + 8 + fmt_size + 8 + data_size + (data_size & 1));
+    h.extend_from_slice(b"WAVE");
+
+    h.extend_from_slice(b"fmt ");
+    put_u32(&mut h, fmt_size);
+    put_u16(&mut h, if extensible { WAVE_FORMAT_EXTENSIBLE } else { tag });
+    put_u16(&mut h, spec.channels as u16);
+    put_u32(&mut h, spec.rate);
+    put_u32(&mut h, spec.rate * block_align as u32);
+    put_u16(&mut h, block_align);
+    put_u16(&mut h, bits);
+    if extensible {
+        put_u16(&mut h, 22);
+        put_u16(&mut h, bits);
+        put_u32(&mut h, spec.channel_mask);
+        put_u16(&mut h, tag);
+        h.extend_from_slice(&SUBFORMAT_GUID_TAIL);
+    }
+
+    h.extend_from_slice(b"data");
+    put_u32(&mut h, data_size);
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // full scale steps of the format, all exact in f32
+    fn samples(format: SampleFormat, count: usize) -> Vec<f32> {
+        let bits = match format {
+            SampleFormat::S16 => 16,
+            SampleFormat::S24 | SampleFormat::S32 | SampleFormat::F32 => 24,
+        };
+        let scale = (1u32 << (bits - 1)) as f64;
+        (0..count)
+            .map(|i| {
+                let step = ((i as i64 * 7919) % (1 << bits)) - (1 << (bits - 1));
+                (step as f64 / scale) as f32
+            })
+            .collect()
+    }
+
+    fn round_trip(format: SampleFormat, channels: usize, channel_mask: u32) {
+        let name = format!("wav-test-{}-{}-{}-{:x}.wav", process::id(), format.name(), channels, channel_mask);
+        let path = env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        let spec = WavSpec { channels, rate: 44100, format, channel_mask };
+        let data = samples(format, channels * 1001);
+
+        {
+            let mut writer = WavWriter::create(path, spec.clone()).unwrap();
+            // in uneven blocks, as a playback period would
+            for block in data.chunks(channels * 300) {
+                assert_eq!(writer.write(block).unwrap(), block.len() / channels);
+            }
+            writer.finish().unwrap();
+        }
+
+        let mut reader = WavReader::open(path).unwrap();
+        assert_eq!(reader.spec(), &spec);
+        let mut read = vec![0.0f32; data.len() + channels * 10];
+        let mut frames = 0;
+        loop {
+            let n = reader.read(&mut read[frames * channels..]).unwrap();
+            if n == 0 {
+                break;
+            }
+            frames += n;
+        }
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(frames, 1001);
+        assert_eq!(&read[..frames * channels], &data[..]);
+    }
+
+    #[test]
+    fn round_trip_s16() {
+        round_trip(SampleFormat::S16, 2, 0);
+    }
+
+    #[test]
+    fn round_trip_s24() {
+        round_trip(SampleFormat::S24, 2, 0);
+    }
+
+    #[test]
+    fn round_trip_s32() {
+        round_trip(SampleFormat::S32, 1, 0);
+    }
+
+    #[test]
+    fn round_trip_f32() {
+        round_trip(SampleFormat::F32, 2, 0);
+    }
+
+    #[test]
+    fn round_trip_extensible() {
+        // more than 2 channels or a channel mask make a WAVE_FORMAT_EXTENSIBLE header
+        round_trip(SampleFormat::S16, 6, default_channel_mask(6));
+        round_trip(SampleFormat::S16, 2, default_channel_mask(2));
+        round_trip(SampleFormat::F32, 8, default_channel_mask(8));
+    }
+
+    #[test]
+    fn extensible_header() {
+        let spec = WavSpec { channels: 6, rate: 48000, format: SampleFormat::S24, channel_mask: 0x3f };
+        let h = header(&spec, 0);
+        assert_eq!(u16_le(&h[20..22]), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(parse_fmt(&h[20..60]).unwrap(), spec);
+
+        let spec = WavSpec { channels: 2, rate: 48000, format: SampleFormat::S16, channel_mask: 0 };
+        let h = header(&spec, 0);
+        assert_eq!(u16_le(&h[20..22]), WAVE_FORMAT_PCM);
+        assert_eq!(&h[36..40], b"data");
+    }
+}